@@ -0,0 +1,174 @@
+//! Minimal reader for the ACPI tables
+//! provided by the firmware. Only what
+//! is needed to count the processors
+//! listed in the MADT and to find the
+//! RTC century register in the FADT
+//! is implemented.
+
+use crate::memory;
+use x86_64::PhysAddr;
+
+// Signature at the start of the Root
+// System Description Pointer.
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+// Signature of the Multiple APIC
+// Description Table.
+const MADT_SIGNATURE: &[u8; 4] = b"APIC";
+
+// Signature of the Fixed ACPI
+// Description Table, and the offset
+// of its RTC century register index.
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+const FADT_CENTURY_OFFSET: u64 = 108;
+
+// Size of the header shared
+// by every system description table.
+const SDT_HEADER_SIZE: u64 = 36;
+
+// MADT entry type of a processor's
+// local APIC and its enabled flag.
+const MADT_LOCAL_APIC: u8 = 0;
+const LOCAL_APIC_ENABLED: u32 = 1;
+
+/// Reads a value of type T from the
+/// physical address. None is returned
+/// if physical memory is not mapped yet.
+fn read_phys<T: Copy>(addr: u64) -> Option<T> {
+    let virt = memory::phys_to_virt(PhysAddr::new(addr))?;
+    Some(unsafe { virt.as_ptr::<T>().read_unaligned() })
+}
+
+/// Returns true if the bytes of the
+/// structure at addr sum to zero.
+fn checksum_valid(addr: u64, len: u64) -> bool {
+    let mut sum = 0u8;
+    for offset in 0..len {
+        match read_phys::<u8>(addr + offset) {
+            Some(byte) => sum = sum.wrapping_add(byte),
+            None => return false,
+        }
+    }
+    sum == 0
+}
+
+/// Searches the extended BIOS data area
+/// and the BIOS read-only area for the
+/// RSDP and returns its physical address.
+fn find_rsdp() -> Option<u64> {
+    // The real mode segment of the
+    // EBDA is stored at 0x40e
+    let ebda = u64::from(read_phys::<u16>(0x40e)?) << 4;
+    let regions = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+
+    for &(start, end) in regions.iter() {
+        // The RSDP is always on
+        // a 16 byte boundary
+        for addr in (start..end).step_by(16) {
+            if &read_phys::<[u8; 8]>(addr)? == RSDP_SIGNATURE && checksum_valid(addr, 20) {
+                return Some(addr);
+            }
+        }
+    }
+    None
+}
+
+/// Calls f with the physical address
+/// of every table listed in the RSDT,
+/// or the XSDT for ACPI 2.0 and later.
+fn for_each_table(mut f: impl FnMut(u64)) -> Option<()> {
+    let rsdp = find_rsdp()?;
+    let revision = read_phys::<u8>(rsdp + 15)?;
+
+    // ACPI 2.0 added the XSDT with
+    // 64 bit table pointers
+    let (root, entry_size) = match revision {
+        0 => (u64::from(read_phys::<u32>(rsdp + 16)?), 4),
+        _ => (read_phys::<u64>(rsdp + 24)?, 8),
+    };
+
+    let length = u64::from(read_phys::<u32>(root + 4)?);
+    let entries = length.saturating_sub(SDT_HEADER_SIZE) / entry_size;
+    for i in 0..entries {
+        let entry = root + SDT_HEADER_SIZE + i * entry_size;
+        let table = match entry_size {
+            4 => u64::from(read_phys::<u32>(entry)?),
+            _ => read_phys::<u64>(entry)?,
+        };
+        f(table);
+    }
+    Some(())
+}
+
+/// Returns the physical address of
+/// the table with the signature.
+fn find_table(signature: &[u8; 4]) -> Option<u64> {
+    let mut found = None;
+    for_each_table(|table| {
+        if read_phys::<[u8; 4]>(table).as_ref() == Some(signature) {
+            found = Some(table);
+        }
+    })?;
+    found
+}
+
+/// Counts the enabled processors listed
+/// in the MADT. None is returned if the
+/// tables cannot be found, for example
+/// before physical memory is mapped.
+pub fn processor_count() -> Option<usize> {
+    let madt = find_table(MADT_SIGNATURE)?;
+
+    // Entries start after the header,
+    // the local APIC address and flags
+    let end = madt + u64::from(read_phys::<u32>(madt + 4)?);
+    let mut entry = madt + SDT_HEADER_SIZE + 8;
+    let mut count = 0;
+    while entry + 2 <= end {
+        let entry_type = read_phys::<u8>(entry)?;
+        let entry_len = u64::from(read_phys::<u8>(entry + 1)?);
+        if entry_len < 2 {
+            break;
+        }
+
+        if entry_type == MADT_LOCAL_APIC {
+            let flags = read_phys::<u32>(entry + 4)?;
+            if flags & LOCAL_APIC_ENABLED != 0 {
+                count += 1;
+            }
+        }
+        entry += entry_len;
+    }
+
+    if count == 0 {
+        None
+    } else {
+        Some(count)
+    }
+}
+
+/// Returns the CMOS register holding
+/// the century of the RTC date, as
+/// listed in the FADT. None is returned
+/// if the tables cannot be read or the
+/// firmware reports no such register.
+pub fn century_register() -> Option<u8> {
+    let fadt = find_table(FADT_SIGNATURE)?;
+    let length = u64::from(read_phys::<u32>(fadt + 4)?);
+    if length <= FADT_CENTURY_OFFSET {
+        return None;
+    }
+    match read_phys::<u8>(fadt + FADT_CENTURY_OFFSET)? {
+        0 => None,
+        register => Some(register),
+    }
+}
+
+// Ensures the single vCPU of the
+// default QEMU machine is the only
+// processor found.
+#[test_case]
+fn test_cpu_count_single_vcpu() {
+    assert_eq!(processor_count(), Some(1));
+    assert_eq!(crate::cpu_count(), 1);
+}
@@ -1,10 +1,17 @@
 //! Implementation of global
 //! heap memory allocator.
 
-use fixed_size_block::FixedSizeBlockAllocator;
+use crate::{error::KernelError, memory, println, util::InterruptGuard};
+use alloc::alloc::Layout;
+use core::{
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
     },
     VirtAddr,
 };
@@ -13,58 +20,539 @@ pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
 
+// Only one allocator feature can
+// back the global allocator
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed"),
+))]
+compile_error!("only one of alloc-bump, alloc-linked-list and alloc-fixed can be enabled");
+
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed"
+)))]
+compile_error!("one of alloc-bump, alloc-linked-list and alloc-fixed must be enabled");
+
+/// Allocator behind the global
+/// allocator, picked by the alloc-*
+/// feature so the strategies can be
+/// compared on the same kernel.
+#[cfg(feature = "alloc-bump")]
+pub type HeapAllocator = bump::BumpAllocator;
+#[cfg(feature = "alloc-linked-list")]
+pub type HeapAllocator = linked_list::LinkedListAllocator;
+#[cfg(feature = "alloc-fixed")]
+pub type HeapAllocator = fixed_size_block::FixedSizeBlockAllocator;
+
 // Static global memory allocator
 #[global_allocator]
-static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+static ALLOCATOR: Locked<HeapAllocator> = Locked::new(HeapAllocator::new());
+
+/// Function called when the allocator
+/// is out of memory. It is passed the
+/// top of the heap and the bytes
+/// needed, maps memory starting at
+/// the top and returns how many bytes
+/// it mapped, or 0 if none.
+pub type GrowFn = fn(heap_top: usize, min_size: usize) -> usize;
+
+/// Operations every heap allocator
+/// provides, so init_heap and the heap
+/// statistics work the same whichever
+/// one backs the global allocator.
+pub trait HeapStrategy {
+    /// Initializes the allocator over
+    /// the given heap range.
+    /// heap_start:   first heap address
+    /// heap_size:    size in bytes
+    ///
+    /// # Safety
+    ///
+    /// The range must be mapped and
+    /// unused, and must stay that way
+    /// for as long as the allocator is
+    /// used. It may only be called once.
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize);
+
+    /// Sets the function used to grow
+    /// the heap. Allocators that cannot
+    /// grow ignore it.
+    fn set_grow(&mut self, _grow: GrowFn) {}
 
-/// Constants used for setting
-/// the range for heap allocations
+    /// Returns the number of heap
+    /// bytes in use.
+    fn used(&self) -> usize;
+
+    /// Returns the number of heap bytes
+    /// that can still be handed out.
+    fn free(&self) -> usize;
+
+    /// Returns the number of allocations
+    /// that have not been freed yet.
+    fn allocations(&self) -> usize;
+}
+
+/// Default range of the heap,
+/// used by init_heap_default
 pub const HEAP_START: usize = 0x_4444_4444_0000;
 pub const HEAP_SIZE: usize = 100 * 1024;
 
+/// Largest size the heap grows to
+/// when it runs out of memory, and the
+/// least it grows by each time.
+pub const HEAP_MAX: usize = 4 * 1024 * 1024;
+pub const HEAP_GROWTH: usize = 16 * 1024;
+
+/// Size of the unmapped guard regions
+/// directly below the heap and above
+/// the largest size it grows to. An
+/// overrun of the heap touches one of
+/// them and page faults instead of
+/// corrupting the memory next to it.
+pub const HEAP_GUARD_SIZE: usize = 4096;
+
+// Set by the first call to init_heap
+// so the heap pages are not mapped
+// and the allocator is not initialized
+// over live memory a second time. It
+// is cleared again if that call fails,
+// which leaves no heap page mapped.
+static HEAP_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+// Start of the heap set by init_heap,
+// which HEAP_MAX is counted from.
+static HEAP_BASE: AtomicUsize = AtomicUsize::new(0);
+
 /// Initializes the heap using the
 /// provided mapper and allocator
-/// to the range provided by the
-/// above constants.
+/// to the given range. Only the first
+/// call that succeeds initializes the
+/// heap, every later call returns
+/// AlreadyInitialized. If the range is
+/// empty or not page aligned,
+/// InvalidHeapRange is returned, and if
+/// a heap page or one of the guard
+/// pages around the heap is already
+/// mapped, HeapOverlap. A call that
+/// fails unmaps the pages it mapped and
+/// frees their frames, so it can be
+/// tried again.
+/// heap_start:       first heap address
+/// heap_size:        initial size in bytes
+/// mapper:           active page table
+/// frame_allocator:  source of heap frames
 pub fn init_heap(
+    heap_start: VirtAddr,
+    heap_size: usize,
     mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), KernelError> {
+    // Refuse to initialize the
+    // heap more than once
+    if HEAP_INITIALIZED.swap(true, Ordering::AcqRel) {
+        return Err(KernelError::AlreadyInitialized);
+    }
+
+    // Allow another try if the range
+    // could not be mapped, which
+    // map_heap_pages left unmapped
+    let heap_start = heap_start.as_u64() as usize;
+    let mapped = check_guard_pages(heap_start, heap_size, mapper)
+        .and_then(|()| map_heap_pages(heap_start, heap_size, mapper, frame_allocator));
+    if let Err(error) = mapped {
+        HEAP_INITIALIZED.store(false, Ordering::Release);
+        return Err(error);
+    }
+
+    // Initialize the heap allocator
+    // over the mapped range
+    HEAP_BASE.store(heap_start, Ordering::Relaxed);
+    let mut allocator = ALLOCATOR.lock();
+    unsafe {
+        allocator.init(heap_start, heap_size);
+    }
+    allocator.set_grow(grow_heap);
+    #[cfg(feature = "alloc-fixed")]
+    {
+        allocator.set_publish(publish_stats);
+        publish_stats(&allocator.stats());
+    }
+
+    Ok(())
+}
+
+/// Initializes the heap at HEAP_START
+/// with HEAP_SIZE bytes, see init_heap.
+pub fn init_heap_default(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), KernelError> {
+    init_heap(
+        VirtAddr::new(HEAP_START as u64),
+        HEAP_SIZE,
+        mapper,
+        frame_allocator,
+    )
+}
+
+/// Maps pages from the top of the heap
+/// to make room for at least min_size
+/// more bytes, without passing HEAP_MAX,
+/// and returns the number of bytes
+/// mapped. This is called with the
+/// allocator locked, so it must not
+/// allocate or print. 0 is returned if
+/// the KERNEL_MEMORY is not available,
+/// including when it is locked by the
+/// code that is allocating.
+/// heap_top:     current end of the heap
+/// min_size:     bytes the heap is short of
+fn grow_heap(heap_top: usize, min_size: usize) -> usize {
+    let size = align_up(min_size.max(HEAP_GROWTH), 4096);
+    let heap_end = HEAP_BASE.load(Ordering::Relaxed) + HEAP_MAX;
+    let size = size.min(heap_end.saturating_sub(heap_top));
+
+    let mut memory = match memory::KERNEL_MEMORY.try_lock() {
+        Some(memory) => memory,
+        None => return 0,
+    };
+    let (mapper, frame_allocator) = match memory.as_mut() {
+        Some(memory) => memory,
+        None => return 0,
+    };
+
+    // Stop at the first page that cannot
+    // be mapped and keep the pages before
+    // it, so the heap stays contiguous
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut mapped = 0;
+    while mapped < size {
+        let page: Page = Page::containing_address(VirtAddr::new((heap_top + mapped) as u64));
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break,
+        };
+        match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+            Ok(flush) => flush.flush(),
+            Err(_) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                break;
+            }
+        }
+        mapped += 4096;
+    }
+    mapped
+}
+
+/// Returns the addresses of the guard
+/// pages of the heap range, or
+/// InvalidHeapRange if the range is
+/// empty, is not page aligned or has
+/// no room for a guard page below it
+/// or above the most it can grow to.
+fn guard_page_addrs(heap_start: usize, heap_size: usize) -> Result<[VirtAddr; 2], KernelError> {
+    let invalid = KernelError::InvalidHeapRange {
+        start: heap_start as u64,
+        size: heap_size,
+    };
+    if heap_size == 0 || heap_start % 4096 != 0 || heap_size % 4096 != 0 {
+        return Err(invalid);
+    }
+
+    let below = heap_start.checked_sub(HEAP_GUARD_SIZE);
+    let above = heap_start.checked_add(heap_size.max(HEAP_MAX));
+    let guard_end = above.and_then(|above| above.checked_add(HEAP_GUARD_SIZE - 1));
+    match (below, above, guard_end) {
+        (Some(below), Some(above), Some(guard_end)) => {
+            let below = VirtAddr::try_new(below as u64).map_err(|_| invalid)?;
+            VirtAddr::try_new(guard_end as u64).map_err(|_| invalid)?;
+            Ok([below, VirtAddr::new(above as u64)])
+        }
+        _ => Err(invalid),
+    }
+}
+
+/// Ensures the guard pages directly
+/// before the heap and after the most
+/// it can grow to are not mapped. The
+/// upper guard sits at HEAP_MAX rather
+/// than after the initial range, since
+/// grow_heap maps the pages above it.
+/// They are never mapped by the heap,
+/// so this only catches other mappings
+/// placed next to it.
+fn check_guard_pages(
+    heap_start: usize,
+    heap_size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<(), KernelError> {
+    for &addr in guard_page_addrs(heap_start, heap_size)?.iter() {
+        let page: Page = Page::containing_address(addr);
+        if mapper.translate_page(page).is_ok() {
+            return Err(KernelError::HeapOverlap(page.start_address()));
+        }
+    }
+    Ok(())
+}
+
+/// Maps every page in the heap range to
+/// a newly allocated frame. If the frame
+/// allocator runs out, the number of usable
+/// frames, the pages mapped so far, the
+/// requested size and whether a page or
+/// a page table was short of a frame are
+/// logged and returned so the failure can
+/// be told apart from a mapping bug. On
+/// any failure the pages mapped so far
+/// are unmapped again and every frame
+/// of a page is given back, while the
+/// page tables created are kept.
+fn map_heap_pages(
+    heap_start: usize,
+    heap_size: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut (impl FrameAllocator<Size4KiB> + FrameDeallocator<Size4KiB>),
+) -> Result<(), KernelError> {
     // Get the range of the pages that
-    // are in the range provided in the
-    // above constants.
+    // are in the provided heap range.
     let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start = VirtAddr::new(heap_start as u64);
+        let heap_end = heap_start + heap_size - 1u64;
         let heap_start_page = Page::containing_address(heap_start);
         let heap_end_page = Page::containing_address(heap_end);
         Page::range_inclusive(heap_start_page, heap_end_page)
     };
 
+    // Logs and returns running out of
+    // frames after mapped_pages pages
+    let out_of_frames = |mapped_pages, page_table| {
+        let error = KernelError::FrameAllocationFailed {
+            usable_frames: memory::frame_stats().usable,
+            mapped_pages,
+            requested_bytes: heap_size,
+            page_table,
+        };
+        println!("ERROR: heap initialization failed: {}", error);
+        error
+    };
+
     // For each page, allocate a
     // frame and map the corresponding
-    // page to the frame.
-    // If any of these allocations
-    // fail, return MapToError from
-    // the function.
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
+    // page to the frame. Mapping can
+    // also run out of frames when a
+    // new page table is needed.
+    for (mapped_pages, page) in page_range.enumerate() {
         let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+        let result = match frame_allocator.allocate_frame() {
+            Some(frame) => match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(())
+                }
+                Err(error) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    Err(match error {
+                        MapToError::FrameAllocationFailed => out_of_frames(mapped_pages, true),
+                        MapToError::PageAlreadyMapped(_) => {
+                            KernelError::HeapOverlap(page.start_address())
+                        }
+                        error => KernelError::from_map_to(error, page),
+                    })
+                }
+            },
+            None => Err(out_of_frames(mapped_pages, false)),
+        };
 
-    // Initialize the heap allocator
-    // using the heap size and start
-    // constants
-    unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        if let Err(error) = result {
+            unmap_heap_pages(heap_start, mapped_pages, mapper, frame_allocator);
+            return Err(error);
+        }
     }
 
     Ok(())
 }
 
+/// Unmaps the first pages of the heap
+/// range and gives their frames back,
+/// undoing a map_heap_pages call that
+/// failed after mapping them.
+/// heap_start:       first heap address
+/// pages:            number of pages mapped
+/// mapper:           active page table
+/// frame_allocator:  takes the frames back
+fn unmap_heap_pages(
+    heap_start: usize,
+    pages: usize,
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameDeallocator<Size4KiB>,
+) {
+    let first: Page = Page::containing_address(VirtAddr::new(heap_start as u64));
+    for page in (0..pages as u64).map(|index| first + index) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            unsafe { frame_allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+//// HEAP STATISTICS
+
+/// Snapshot of the global heap
+/// usage at one point in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub used: usize,
+    pub free: usize,
+    pub allocations: usize,
+}
+
+/// Returns the current usage of the
+/// global heap. Interrupts are disabled
+/// while the allocator is locked so an
+/// interrupt handler that allocates
+/// cannot deadlock on it.
+pub fn heap_stats() -> HeapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let allocator = ALLOCATOR.lock();
+        HeapStats {
+            used: allocator.used(),
+            free: allocator.free(),
+            allocations: allocator.allocations(),
+        }
+    })
+}
+
+// Counters of the global allocator as
+// of its last allocation or free. The
+// allocator publishes them, so reading
+// them never blocks an allocation.
+#[cfg(feature = "alloc-fixed")]
+static ALLOC_STATS: LockedRw<Option<fixed_size_block::AllocStats>> = LockedRw::new(None);
+
+/// Stores the counters published by the
+/// global allocator. This may run in an
+/// interrupt handler that allocates, so
+/// while the counters are being read
+/// the update is skipped instead of
+/// waited for.
+#[cfg(feature = "alloc-fixed")]
+fn publish_stats(stats: &fixed_size_block::AllocStats) {
+    if let Some(mut published) = ALLOC_STATS.try_write() {
+        *published = Some(*stats);
+    }
+}
+
+/// Prints the counters of the global
+/// allocator as last published by it.
+/// The counters are copied before
+/// printing, so they are not locked
+/// while the WRITER is, and the
+/// allocator is never locked.
+#[cfg(feature = "alloc-fixed")]
+pub fn print_stats() {
+    let stats = match *ALLOC_STATS.read() {
+        Some(stats) => stats,
+        None => return,
+    };
+
+    println!("heap: {} bytes allocated", stats.bytes_allocated);
+    println!("heap: {} bytes freed", stats.bytes_freed);
+    println!("heap: {} bytes live", stats.live_bytes);
+    for (index, size) in stats.block_sizes.iter().enumerate() {
+        println!(
+            "heap: {} byte blocks: {} free, {} live, {} peak",
+            size, stats.free_blocks[index], stats.live_blocks[index], stats.peak_blocks[index]
+        );
+    }
+}
+
+/// Allocates memory for the layout from
+/// the global allocator and returns
+/// OutOfMemory instead of calling the
+/// allocation error handler if it fails,
+/// so the caller can recover. Layouts
+/// larger than HEAP_MAX are rejected
+/// without growing the heap. A zero
+/// sized layout gets a dangling pointer,
+/// which must not be deallocated.
+/// layout:   size and alignment to allocate
+pub fn try_alloc(layout: Layout) -> Result<NonNull<u8>, KernelError> {
+    let error = KernelError::OutOfMemory {
+        size: layout.size(),
+        align: layout.align(),
+    };
+    if layout.size() == 0 {
+        return NonNull::new(layout.align() as *mut u8).ok_or(error);
+    }
+    if layout.size() > HEAP_MAX {
+        return Err(error);
+    }
+    NonNull::new(unsafe { alloc::alloc::alloc(layout) }).ok_or(error)
+}
+
+//// HEAP CANARY
+
+/// Bytes written after every allocation
+/// of the linked list and fixed size
+/// block allocators with the heap-canary
+/// feature, and checked when it is freed.
+pub const CANARY: [u8; 8] = [0xef, 0xbe, 0xad, 0xde, 0xef, 0xbe, 0xad, 0xde];
+
+/// Bytes reserved for the canary after
+/// each allocation, 0 without the
+/// heap-canary feature.
+pub const CANARY_SIZE: usize = if cfg!(feature = "heap-canary") {
+    CANARY.len()
+} else {
+    0
+};
+
+/// Returns the layout with room for the
+/// canary after it, or None if the size
+/// overflows.
+fn with_canary(layout: Layout) -> Option<Layout> {
+    let size = layout.size().checked_add(CANARY_SIZE)?;
+    Layout::from_size_align(size, layout.align()).ok()
+}
+
+/// Writes the canary right after the
+/// usable bytes of the allocation.
+/// ptr:      allocation of with_canary(layout)
+/// layout:   layout the caller asked for
+unsafe fn write_canary(ptr: *mut u8, layout: Layout) {
+    if cfg!(feature = "heap-canary") {
+        let canary = ptr.add(layout.size());
+        ptr::copy_nonoverlapping(CANARY.as_ptr(), canary, CANARY_SIZE);
+    }
+}
+
+/// Returns true if the canary after the
+/// allocation is untouched.
+/// ptr:      allocation of with_canary(layout)
+/// layout:   layout the caller asked for
+unsafe fn canary_intact(ptr: *const u8, layout: Layout) -> bool {
+    let canary = core::slice::from_raw_parts(ptr.add(layout.size()), CANARY_SIZE);
+    canary == &CANARY[..CANARY_SIZE]
+}
+
+/// Panics with the allocation if its
+/// canary was overwritten, which means
+/// something wrote past its end.
+/// ptr:      allocation of with_canary(layout)
+/// layout:   layout the caller asked for
+unsafe fn check_canary(ptr: *const u8, layout: Layout) {
+    if !canary_intact(ptr, layout) {
+        panic!(
+            "heap canary clobbered after allocation {:p} of {} bytes",
+            ptr,
+            layout.size()
+        );
+    }
+}
+
 /// Wrapper around mutex so traits can be
 /// implemented on the A type wrapped in
 /// a mutex.
@@ -86,6 +574,115 @@ impl<A> Locked<A> {
     }
 }
 
+/// Wrapper around a reader-writer lock
+/// for data that is read far more often
+/// than it is written. Any number of
+/// readers can hold it at once, while a
+/// writer holds it alone. Unlike Locked,
+/// interrupts are disabled while a guard
+/// is alive, so an interrupt handler
+/// cannot spin on a guard held by the
+/// code it stopped. Guards should be
+/// dropped in the reverse order they
+/// were taken in, since each one
+/// restores the interrupt flag it saw.
+pub struct LockedRw<A> {
+    inner: spin::RwLock<A>,
+}
+
+/// Shared access to the data of a
+/// LockedRw, with interrupts disabled
+/// until it is dropped.
+pub struct LockedRwReadGuard<'a, A> {
+    // Declared first so the lock is
+    // released before interrupts are
+    // enabled again
+    guard: spin::RwLockReadGuard<'a, A>,
+    _interrupts: InterruptGuard,
+}
+
+/// Exclusive access to the data of a
+/// LockedRw, with interrupts disabled
+/// until it is dropped.
+pub struct LockedRwWriteGuard<'a, A> {
+    guard: spin::RwLockWriteGuard<'a, A>,
+    _interrupts: InterruptGuard,
+}
+
+impl<A> LockedRw<A> {
+    /// Wrap inner A type in a
+    /// reader-writer lock
+    pub const fn new(inner: A) -> Self {
+        LockedRw {
+            inner: spin::RwLock::new(inner),
+        }
+    }
+
+    /// Acquire shared read access,
+    /// waiting while a writer holds it
+    pub fn read(&self) -> LockedRwReadGuard<A> {
+        let interrupts = InterruptGuard::new();
+        LockedRwReadGuard {
+            guard: self.inner.read(),
+            _interrupts: interrupts,
+        }
+    }
+
+    /// Acquire exclusive write access,
+    /// waiting while anyone holds it
+    pub fn write(&self) -> LockedRwWriteGuard<A> {
+        let interrupts = InterruptGuard::new();
+        LockedRwWriteGuard {
+            guard: self.inner.write(),
+            _interrupts: interrupts,
+        }
+    }
+
+    /// Acquire read access, or return
+    /// None if a writer holds it
+    pub fn try_read(&self) -> Option<LockedRwReadGuard<A>> {
+        let interrupts = InterruptGuard::new();
+        let guard = self.inner.try_read()?;
+        Some(LockedRwReadGuard {
+            guard,
+            _interrupts: interrupts,
+        })
+    }
+
+    /// Acquire write access, or return
+    /// None if anyone holds it
+    pub fn try_write(&self) -> Option<LockedRwWriteGuard<A>> {
+        let interrupts = InterruptGuard::new();
+        let guard = self.inner.try_write()?;
+        Some(LockedRwWriteGuard {
+            guard,
+            _interrupts: interrupts,
+        })
+    }
+}
+
+impl<A> Deref for LockedRwReadGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.guard
+    }
+}
+
+impl<A> Deref for LockedRwWriteGuard<'_, A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.guard
+    }
+}
+
+impl<A> DerefMut for LockedRwWriteGuard<'_, A> {
+    fn deref_mut(&mut self) -> &mut A {
+        &mut self.guard
+    }
+}
+
 // ADDRESS ALIGNMENT FOR ALLOCATOR
 
 /// Aligns the memory address to the next
@@ -93,3 +690,381 @@ impl<A> Locked<A> {
 fn align_up(addr: usize, align: usize) -> usize {
     (addr + align - 1) & !(align - 1)
 }
+
+// Maps a heap range with a frame
+// allocator that has no frames and
+// ensures the diagnostic reports how
+// far the mapping got.
+#[test_case]
+fn test_heap_frame_exhaustion_diagnostic() {
+    use crate::memory::EmptyFrameAllocator;
+
+    let mut memory = memory::KERNEL_MEMORY.lock();
+    let (mapper, _) = memory.as_mut().expect("test memory not initialized");
+
+    let heap_size = 4 * 4096;
+    let result = map_heap_pages(
+        0x_6666_6666_0000,
+        heap_size,
+        mapper,
+        &mut EmptyFrameAllocator,
+    );
+    match result {
+        Err(KernelError::FrameAllocationFailed {
+            usable_frames,
+            mapped_pages,
+            requested_bytes: requested,
+            page_table,
+        }) => {
+            assert_eq!(usable_frames, memory::frame_stats().usable);
+            assert_eq!(mapped_pages, 0);
+            assert_eq!(requested, heap_size);
+            assert!(!page_table);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+// Maps a heap range where new page
+// tables are needed with an allocator
+// holding a single frame, and ensures
+// the page table is reported as short
+// of a frame and the frame of the page
+// is given back.
+#[test_case]
+fn test_heap_page_table_exhaustion() {
+    use x86_64::structures::paging::PhysFrame;
+
+    // Hands out its one frame and
+    // records the frame given back
+    struct OneFrame {
+        frame: Option<PhysFrame>,
+        freed: Option<PhysFrame>,
+    }
+    unsafe impl FrameAllocator<Size4KiB> for OneFrame {
+        fn allocate_frame(&mut self) -> Option<PhysFrame> {
+            self.frame.take()
+        }
+    }
+    impl FrameDeallocator<Size4KiB> for OneFrame {
+        unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+            self.freed = Some(frame);
+        }
+    }
+
+    let mut memory = memory::KERNEL_MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+
+    let frame = frame_allocator.allocate_frame().expect("no frame");
+    let mut one_frame = OneFrame {
+        frame: Some(frame),
+        freed: None,
+    };
+    let result = map_heap_pages(0x_6666_6666_0000, 4096, mapper, &mut one_frame);
+    unsafe { frame_allocator.deallocate_frame(frame) };
+
+    match result {
+        Err(KernelError::FrameAllocationFailed {
+            mapped_pages,
+            page_table,
+            ..
+        }) => {
+            assert_eq!(mapped_pages, 0);
+            assert!(page_table);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(one_frame.freed, Some(frame));
+}
+
+// Maps eight heap pages with an
+// allocator holding four frames, so
+// it runs out after mapping at least
+// one, and ensures every page is left
+// unmapped and the frames of the
+// mapped pages are given back.
+#[test_case]
+fn test_heap_mapping_rolls_back() {
+    use alloc::vec::Vec;
+    use x86_64::structures::paging::PhysFrame;
+
+    // Hands out its frames and takes
+    // back the frames given back
+    struct Frames(Vec<PhysFrame>);
+    unsafe impl FrameAllocator<Size4KiB> for Frames {
+        fn allocate_frame(&mut self) -> Option<PhysFrame> {
+            self.0.pop()
+        }
+    }
+    impl FrameDeallocator<Size4KiB> for Frames {
+        unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+            self.0.push(frame);
+        }
+    }
+
+    let mut memory = memory::KERNEL_MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+
+    let heap_start = 0x_6666_0000_0000;
+    let mut frames = Frames(
+        (0..4)
+            .map(|_| frame_allocator.allocate_frame().expect("no frame"))
+            .collect(),
+    );
+    let result = map_heap_pages(heap_start, 8 * 4096, mapper, &mut frames);
+
+    // The frames left were not taken
+    // by new page tables
+    let returned = frames.0.len();
+    for frame in frames.0 {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+
+    match result {
+        Err(KernelError::FrameAllocationFailed { mapped_pages, .. }) => {
+            assert!(mapped_pages >= 1);
+            assert!(returned >= mapped_pages);
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    for index in 0..8u64 {
+        let addr = VirtAddr::new(heap_start as u64 + index * 4096);
+        assert_eq!(memory::translate(addr, mapper), None);
+    }
+}
+
+// Maps a heap range over the pages of
+// the existing heap and ensures the
+// overlap is reported instead of
+// the pages being remapped.
+#[test_case]
+fn test_heap_overlap() {
+    let mut memory = memory::KERNEL_MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+
+    let result = map_heap_pages(HEAP_START, 4096, mapper, frame_allocator);
+    assert_eq!(
+        result,
+        Err(KernelError::HeapOverlap(VirtAddr::new(HEAP_START as u64)))
+    );
+}
+
+// Ensures the guard pages of the
+// global heap were left unmapped, and
+// that a heap range next to a mapped
+// page is reported.
+#[test_case]
+fn test_heap_guard_pages() {
+    let mut memory = memory::KERNEL_MEMORY.lock();
+    let (mapper, _) = memory.as_mut().expect("test memory not initialized");
+
+    assert_eq!(check_guard_pages(HEAP_START, HEAP_SIZE, mapper), Ok(()));
+
+    // A heap that could grow up to where
+    // the global heap starts has it as
+    // its upper guard
+    let result = check_guard_pages(HEAP_START - HEAP_MAX, HEAP_SIZE, mapper);
+    assert_eq!(
+        result,
+        Err(KernelError::HeapOverlap(VirtAddr::new(HEAP_START as u64)))
+    );
+}
+
+// Ensures heap ranges that are not
+// page aligned, are empty or have no
+// room for their guard pages are
+// rejected before any page is looked
+// up.
+#[test_case]
+fn test_invalid_heap_range() {
+    let mut memory = memory::KERNEL_MEMORY.lock();
+    let (mapper, _) = memory.as_mut().expect("test memory not initialized");
+
+    let ranges = [
+        (HEAP_START + 8, HEAP_SIZE),
+        (HEAP_START, HEAP_SIZE + 8),
+        (HEAP_START, 0),
+        (0, HEAP_SIZE),
+        (0x_7fff_ffff_f000, HEAP_SIZE),
+        (0x_ffff_8000_0000_0000, HEAP_SIZE),
+        (usize::MAX - 4095, HEAP_SIZE),
+    ];
+    for &(start, size) in ranges.iter() {
+        assert_eq!(
+            check_guard_pages(start, size, mapper),
+            Err(KernelError::InvalidHeapRange {
+                start: start as u64,
+                size,
+            })
+        );
+    }
+}
+
+// Allocates more than the initial heap
+// size in total and ensures the heap
+// grows to fit the allocations.
+#[cfg(feature = "alloc-fixed")]
+#[test_case]
+fn test_heap_grows() {
+    use alloc::vec::Vec;
+
+    let blocks: Vec<Vec<u8>> = (0..8).map(|i| alloc::vec![i; 32 * 1024]).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        assert!(block.iter().all(|&byte| byte == i as u8));
+    }
+    assert!(heap_stats().used + heap_stats().free > HEAP_SIZE);
+}
+
+// Leaks a Box that fits in the 128
+// byte blocks and ensures the live
+// bytes grew by one block.
+#[cfg(feature = "alloc-fixed")]
+#[test_case]
+fn test_alloc_stats_count_leaked_box() {
+    use alloc::boxed::Box;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let before = ALLOCATOR.lock().stats();
+        Box::leak(Box::new([0u8; 100]));
+        let after = ALLOCATOR.lock().stats();
+
+        assert_eq!(after.live_bytes, before.live_bytes + 128);
+        assert_eq!(after.bytes_allocated, before.bytes_allocated + 128);
+        assert_eq!(after.bytes_freed, before.bytes_freed);
+    });
+    print_stats();
+}
+
+// Leaks a Box and ensures the counters
+// the allocator published match the
+// ones read from it under its lock.
+#[cfg(feature = "alloc-fixed")]
+#[test_case]
+fn test_alloc_stats_published() {
+    use alloc::boxed::Box;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        Box::leak(Box::new([0u8; 100]));
+        let published = ALLOC_STATS.read().expect("stats not published");
+        let stats = ALLOCATOR.lock().stats();
+
+        assert_eq!(published.live_bytes, stats.live_bytes);
+        assert_eq!(published.bytes_allocated, stats.bytes_allocated);
+        assert_eq!(published.live_blocks, stats.live_blocks);
+    });
+}
+
+// Ensures try_alloc returns an error
+// for a layout far larger than the
+// heap instead of panicking, and for
+// one just under HEAP_MAX that the
+// allocator cannot place while a Box
+// is live, and that a small layout
+// still succeeds.
+#[test_case]
+fn test_try_alloc_out_of_memory() {
+    use alloc::boxed::Box;
+
+    let huge = Layout::from_size_align(1 << 40, 4096).unwrap();
+    assert_eq!(
+        try_alloc(huge),
+        Err(KernelError::OutOfMemory {
+            size: 1 << 40,
+            align: 4096
+        })
+    );
+
+    // A page aligned block this large
+    // must start at the heap base and
+    // reach past the live Box
+    let live = Box::new([0u8; 64]);
+    let near_max = Layout::from_size_align(HEAP_MAX - 8, 4096).unwrap();
+    assert_eq!(
+        try_alloc(near_max),
+        Err(KernelError::OutOfMemory {
+            size: HEAP_MAX - 8,
+            align: 4096
+        })
+    );
+    drop(live);
+
+    let small = Layout::new::<u64>();
+    let ptr = try_alloc(small).expect("small allocation failed");
+    unsafe {
+        ptr.as_ptr().cast::<u64>().write(42);
+        alloc::alloc::dealloc(ptr.as_ptr(), small);
+    }
+}
+
+// Takes read guards one after another
+// while polling for write access and
+// ensures readers coexist but keep the
+// writer out, then ensures a writer
+// keeps readers and writers out.
+// Interrupts must stay disabled while
+// a guard is alive.
+#[test_case]
+fn test_locked_rw_readers_and_writer() {
+    use x86_64::instructions::interrupts;
+
+    let were_enabled = interrupts::are_enabled();
+    let lock = LockedRw::new(0u64);
+
+    let first = lock.read();
+    assert!(!interrupts::are_enabled());
+    let second = lock.try_read().expect("second reader blocked");
+    assert_eq!(*first + *second, 0);
+    assert!(lock.try_write().is_none());
+    drop(first);
+    assert!(lock.try_write().is_none());
+    drop(second);
+
+    let mut writer = lock.try_write().expect("writer blocked without readers");
+    assert!(!interrupts::are_enabled());
+    *writer = 7;
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+    drop(writer);
+    assert_eq!(*lock.read(), 7);
+    assert_eq!(interrupts::are_enabled(), were_enabled);
+}
+
+// Allocates from a linked list and a
+// fixed size block allocator, writes
+// one byte past each allocation and
+// ensures the canary reports it, then
+// restores the byte so dealloc passes.
+#[cfg(feature = "heap-canary")]
+#[test_case]
+fn test_canary_detects_overrun() {
+    use alloc::{alloc::GlobalAlloc, vec};
+    use fixed_size_block::FixedSizeBlockAllocator;
+    use linked_list::LinkedListAllocator;
+
+    let region = vec![0u8; 8 * 4096];
+    let half = region.len() / 2;
+    let linked = Locked::new(LinkedListAllocator::new());
+    let fixed = Locked::new(FixedSizeBlockAllocator::new());
+    unsafe {
+        linked.lock().init(region.as_ptr() as usize, half);
+        fixed.lock().init(region.as_ptr() as usize + half, half);
+    }
+
+    let layout = Layout::from_size_align(24, 8).unwrap();
+    let allocators: [&dyn GlobalAlloc; 2] = [&linked, &fixed];
+    for allocator in allocators {
+        unsafe {
+            let ptr = allocator.alloc(layout);
+            assert!(!ptr.is_null());
+            assert!(canary_intact(ptr, layout));
+
+            let past_end = ptr.add(layout.size());
+            let saved = past_end.read();
+            past_end.write(!saved);
+            assert!(!canary_intact(ptr, layout));
+
+            past_end.write(saved);
+            allocator.dealloc(ptr, layout);
+        }
+    }
+}
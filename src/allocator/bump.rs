@@ -8,10 +8,19 @@
 //! only be freed by freeing the entire
 //! heap.
 
-use super::{align_up, Locked};
+use super::{align_up, HeapStrategy, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
+/// State of a bump allocator saved by
+/// checkpoint, to free everything
+/// allocated after it with reset_to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BumpCheckpoint {
+    next: usize,
+    allocations: usize,
+}
+
 pub struct BumpAllocator {
     heap_start: usize,
     heap_end: usize,
@@ -34,15 +43,63 @@ impl BumpAllocator {
         }
     }
 
+    /// Saves the next free address and
+    /// the allocation count, so a batch
+    /// of allocations made after this
+    /// can be freed at once.
+    pub fn checkpoint(&self) -> BumpCheckpoint {
+        BumpCheckpoint {
+            next: self.next,
+            allocations: self.allocations,
+        }
+    }
+
+    /// Frees every allocation made since
+    /// the checkpoint by moving next back
+    /// to it and restoring the allocation
+    /// count. The checkpoint must be from
+    /// this allocator, which must not have
+    /// been reset past it since, such as
+    /// by freeing every allocation.
+    ///
+    /// # Safety
+    ///
+    /// The memory of the freed allocations
+    /// is handed out again, so none of
+    /// them may still be referenced.
+    /// Allocations from before the
+    /// checkpoint must not be freed in
+    /// between, or the restored count is
+    /// too high.
+    pub unsafe fn reset_to(&mut self, checkpoint: BumpCheckpoint) {
+        assert!(checkpoint.next >= self.heap_start && checkpoint.next <= self.next);
+        self.next = checkpoint.next;
+        self.allocations = checkpoint.allocations;
+    }
+}
+
+impl HeapStrategy for BumpAllocator {
     /// Initialize the bump allocator with
     /// the boundaries of the heap. The
     /// start and size must be valid and
     /// unused memory.
-    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
         self.heap_start = heap_start;
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    fn used(&self) -> usize {
+        self.next - self.heap_start
+    }
+
+    fn free(&self) -> usize {
+        self.heap_end - self.next
+    }
+
+    fn allocations(&self) -> usize {
+        self.allocations
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
@@ -77,10 +134,11 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         }
     }
 
-    /// Frees an allocation made at the end of
-    /// the heap. This is accomplished by simply
-    /// subtracting the number of allocations by 1
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    /// Frees an allocation. The memory is
+    /// only reused once every allocation
+    /// is freed, or right away if this
+    /// was the most recent allocation.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock();
 
         // Subtract the number of
@@ -90,6 +148,101 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
         bump.allocations -= 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
+        } else if ptr as usize + layout.size() == bump.next {
+            bump.next = ptr as usize;
         }
     }
+
+    /// Resizes an allocation. The most
+    /// recent allocation is resized in
+    /// place by moving next, if the heap
+    /// has room. Other allocations are
+    /// copied into a new allocation.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        {
+            let mut bump = self.lock();
+            let start = ptr as usize;
+            if start + layout.size() == bump.next {
+                if let Some(end) = start.checked_add(new_size) {
+                    if end <= bump.heap_end {
+                        bump.next = end;
+                        return ptr;
+                    }
+                }
+            }
+        }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+// Grows the most recent allocation in
+// place, then grows an older one and
+// ensures it is moved with its contents.
+#[test_case]
+fn test_realloc_grows_last_allocation_in_place() {
+    #[repr(align(16))]
+    struct Heap([u8; 256]);
+
+    let mut heap = Heap([0; 256]);
+    let bump = Locked::new(BumpAllocator::new());
+    unsafe { bump.lock().init(heap.0.as_mut_ptr() as usize, heap.0.len()) };
+
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    unsafe {
+        let first = bump.alloc(layout);
+        first.write(42);
+        assert_eq!(bump.realloc(first, layout, 32), first);
+        assert_eq!(bump.lock().next, first as usize + 32);
+
+        // The first allocation is no
+        // longer the last one
+        let grown = Layout::from_size_align(32, 8).unwrap();
+        let second = bump.alloc(layout);
+        let moved = bump.realloc(first, grown, 64);
+        assert!(moved != first && moved != second);
+        assert_eq!(moved.read(), 42);
+
+        // Growing past the end of
+        // the heap fails
+        assert!(bump
+            .realloc(moved, Layout::from_size_align(64, 8).unwrap(), 512)
+            .is_null());
+    }
+}
+
+// Allocates a batch after a checkpoint,
+// resets to it and ensures the batch
+// is freed and the next allocation
+// reuses the checkpointed address.
+#[test_case]
+fn test_reset_to_checkpoint() {
+    #[repr(align(16))]
+    struct Heap([u8; 256]);
+
+    let mut heap = Heap([0; 256]);
+    let bump = Locked::new(BumpAllocator::new());
+    unsafe { bump.lock().init(heap.0.as_mut_ptr() as usize, heap.0.len()) };
+
+    let layout = Layout::from_size_align(16, 8).unwrap();
+    unsafe {
+        let kept = bump.alloc(layout);
+        let checkpoint = bump.lock().checkpoint();
+        let first = bump.alloc(layout);
+        for _ in 0..3 {
+            assert!(!bump.alloc(layout).is_null());
+        }
+        assert_eq!(bump.lock().allocations(), 5);
+
+        bump.lock().reset_to(checkpoint);
+        assert_eq!(bump.lock().allocations(), 1);
+        assert_eq!(bump.alloc(layout), first);
+        assert_ne!(first, kept);
+    }
 }
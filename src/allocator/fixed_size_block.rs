@@ -1,14 +1,16 @@
 //! Heap allocator that implements
 //! the fixed-size block allocation
 //! method using a few constant,
-//! different sized blocks. This handles
+//! different sized blocks, BLOCK_SIZES
+//! unless others are picked with
+//! with_block_sizes. This handles
 //! memory very efficiently, particularly
 //! for smaller allocations, and it does
 //! not require the linked list traversal
 //! that is performed in the linked list
 //! heap allocator implementation.
 
-use super::Locked;
+use super::{check_canary, with_canary, write_canary, GrowFn, HeapStrategy, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{
     mem,
@@ -26,9 +28,15 @@ struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
-// Different heap block sizes used
-// during heap allocation.
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+/// Block sizes used by the allocator
+/// created with new.
+pub const BLOCK_SIZES: [usize; 9] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Size in bytes of the chunk refill
+/// takes from the fallback allocator
+/// and splits into blocks. Larger
+/// blocks are refilled one at a time.
+pub const REFILL_CHUNK_SIZE: usize = 4096;
 
 /// Allocator that uses the fixed-size
 /// block allocation strategy. This allows
@@ -37,65 +45,304 @@ const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 /// are made, the fallback_allocator field
 /// uses an implementation of the linked
 /// list allocator (like allocator/linked_list.rs).
-pub struct FixedSizeBlockAllocator {
-    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+/// N is the number of block sizes.
+pub struct FixedSizeBlockAllocator<const N: usize = { BLOCK_SIZES.len() }> {
+    block_sizes: [usize; N],
+    list_heads: [Option<&'static mut ListNode>; N],
     fallback_allocator: linked_list_allocator::Heap,
+    allocations: usize,
+    bytes_allocated: usize,
+    bytes_freed: usize,
+    free_blocks: [usize; N],
+    live_blocks: [usize; N],
+    peak_blocks: [usize; N],
+    grow: Option<GrowFn>,
+    publish: Option<fn(&AllocStats<N>)>,
+}
+
+/// Counters of the fixed-size block
+/// allocator. Block allocations count
+/// the whole block size, while larger
+/// allocations count the layout size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocStats<const N: usize = { BLOCK_SIZES.len() }> {
+    /// Bytes allocated since boot
+    pub bytes_allocated: usize,
+    /// Bytes freed since boot
+    pub bytes_freed: usize,
+    /// Bytes allocated and not freed
+    pub live_bytes: usize,
+    /// Block sizes of the allocator,
+    /// smallest first
+    pub block_sizes: [usize; N],
+    /// Length of the free list of each
+    /// block size, smallest first
+    pub free_blocks: [usize; N],
+    /// Blocks of each size allocated
+    /// and not freed
+    pub live_blocks: [usize; N],
+    /// Most blocks of each size that
+    /// were live at the same time
+    pub peak_blocks: [usize; N],
 }
 
 impl FixedSizeBlockAllocator {
-    /// Creates a new allocator. Note
+    /// Creates a new allocator with the
+    /// default BLOCK_SIZES. Note
     /// that this does not initialize the
     /// heap, it just initializes the fields.
     /// Call the init function after this
     /// function with a heap range to
     /// initialize a heap.
     pub const fn new() -> Self {
+        Self::with_block_sizes(BLOCK_SIZES)
+    }
+}
+
+impl<const N: usize> FixedSizeBlockAllocator<N> {
+    /// Creates a new allocator with other
+    /// block sizes, like new. The sizes
+    /// must be ascending powers of two of
+    /// at least 8 bytes, since every block
+    /// is aligned to its size and holds a
+    /// list node while it is free.
+    /// block_sizes:  sizes, smallest first
+    pub const fn with_block_sizes(block_sizes: [usize; N]) -> Self {
+        let mut i = 0;
+        while i < N {
+            let size = block_sizes[i];
+            assert!(
+                size.is_power_of_two() && size >= mem::size_of::<ListNode>(),
+                "block sizes must be powers of two of at least 8 bytes"
+            );
+            assert!(
+                i == 0 || block_sizes[i - 1] < size,
+                "block sizes must be ascending"
+            );
+            i += 1;
+        }
+
         const EMPTY: Option<&'static mut ListNode> = None;
         FixedSizeBlockAllocator {
-            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            block_sizes,
+            list_heads: [EMPTY; N],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            allocations: 0,
+            bytes_allocated: 0,
+            bytes_freed: 0,
+            free_blocks: [0; N],
+            live_blocks: [0; N],
+            peak_blocks: [0; N],
+            grow: None,
+            publish: None,
         }
     }
 
-    /// Initializes the fallback linked
-    /// list heap allocator with the
-    /// provided heap start and size.
-    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.fallback_allocator.init(heap_start, heap_size);
+    /// Returns the allocation counters.
+    pub fn stats(&self) -> AllocStats<N> {
+        AllocStats {
+            block_sizes: self.block_sizes,
+            bytes_allocated: self.bytes_allocated,
+            bytes_freed: self.bytes_freed,
+            live_bytes: self.bytes_allocated - self.bytes_freed,
+            free_blocks: self.free_blocks,
+            live_blocks: self.live_blocks,
+            peak_blocks: self.peak_blocks,
+        }
+    }
+
+    /// Sets the function that is passed
+    /// the counters after every allocation
+    /// and free, so they can be read
+    /// without locking the allocator. It
+    /// is called with the allocator
+    /// locked, so it must not allocate.
+    /// publish:  receives the counters
+    pub fn set_publish(&mut self, publish: fn(&AllocStats<N>)) {
+        self.publish = Some(publish);
+    }
+
+    /// Passes the counters to the publish
+    /// function, if one is set.
+    fn publish_stats(&self) {
+        if let Some(publish) = self.publish {
+            publish(&self.stats());
+        }
+    }
+
+    /// Takes a REFILL_CHUNK_SIZE chunk from
+    /// the fallback allocator and splits it
+    /// into blocks of the list at index, so
+    /// a run of small allocations does not
+    /// go through the fallback allocator
+    /// one block at a time. Returns the
+    /// number of blocks added, or 0 if the
+    /// fallback allocator is out of memory.
+    /// index:    index into the block sizes
+    pub fn refill(&mut self, index: usize) -> usize {
+        let block_size = self.block_sizes[index];
+        let chunk_size = REFILL_CHUNK_SIZE.max(block_size);
+        let layout = Layout::from_size_align(chunk_size, block_size).unwrap();
+        let chunk = self.fallback_alloc(layout);
+        if chunk.is_null() {
+            return 0;
+        }
+
+        let count = chunk_size / block_size;
+        for i in 0..count {
+            unsafe { self.push_block(index, chunk.add(i * block_size)) };
+        }
+        count
+    }
+
+    /// Adds the free block at ptr to
+    /// the list at index.
+    /// index:    index into the block sizes
+    /// ptr:      block aligned to its size
+    unsafe fn push_block(&mut self, index: usize, ptr: *mut u8) {
+        let new_node = ListNode {
+            next: self.list_heads[index].take(),
+        };
+
+        // Ensure that the block size
+        // is big enough for the aligned
+        // ListNode struct to insert
+        assert!(mem::size_of::<ListNode>() <= self.block_sizes[index]);
+        assert!(mem::align_of::<ListNode>() <= self.block_sizes[index]);
+
+        // Write the ListNode struct into
+        // the newly freed block of memory,
+        // and set the list_heads index
+        // for the block size equal to the
+        // newly freed block
+        let new_node_ptr = ptr as *mut ListNode;
+        new_node_ptr.write(new_node);
+        self.list_heads[index] = Some(&mut *new_node_ptr);
+        self.free_blocks[index] += 1;
     }
 
     /// Function called when the fallback
     /// allocator needs to make an allocation.
+    /// If it is out of memory, the heap is
+    /// grown and the allocation retried.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // Ask for the alignment too, since
+        // the new memory may need padding
+        let grow = match self.grow {
+            Some(grow) => grow,
+            None => return ptr::null_mut(),
+        };
+        let added = grow(
+            self.fallback_allocator.top(),
+            layout.size() + layout.align(),
+        );
+        if added == 0 {
+            return ptr::null_mut();
+        }
+        unsafe { self.fallback_allocator.extend(added) };
         match self.fallback_allocator.allocate_first_fit(layout) {
             Ok(ptr) => ptr.as_ptr(),
             Err(_) => ptr::null_mut(),
         }
     }
+
+    /// Returns the index of the smallest
+    /// block size that is greater than
+    /// or equal to the aligned size
+    /// of the layout requested.
+    fn list_index(&self, layout: &Layout) -> Option<usize> {
+        let size = layout.size().max(layout.align());
+        self.block_sizes.iter().position(|&s| s >= size)
+    }
+
+    /// Returns the index of the block
+    /// size a fallback allocation can be
+    /// reused as once it is freed. That is
+    /// the case when its size is exactly a
+    /// block size and it is aligned to it,
+    /// such as a 64 byte layout with page
+    /// alignment.
+    fn reusable_index(&self, ptr: *mut u8, layout: &Layout) -> Option<usize> {
+        let index = self.block_sizes.iter().position(|&s| s == layout.size())?;
+        if ptr as usize & (self.block_sizes[index] - 1) == 0 {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the number of bytes an
+    /// allocation of the layout uses,
+    /// which is the whole block if it
+    /// fits in one.
+    fn allocated_size(&self, layout: &Layout) -> usize {
+        match self.list_index(layout) {
+            Some(index) => self.block_sizes[index],
+            None => layout.size(),
+        }
+    }
 }
 
-/// Returns the index of the smallest
-/// BLOCK_SIZE element that is greater
-/// than or equal to the aligned size
-/// of the layout requested.
-fn list_index(layout: &Layout) -> Option<usize> {
-    let size = layout.size().max(layout.align());
-    BLOCK_SIZES.iter().position(|&s| s >= size)
+impl<const N: usize> HeapStrategy for FixedSizeBlockAllocator<N> {
+    /// Initializes the fallback linked
+    /// list heap allocator with the
+    /// provided heap start and size.
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    /// Sets the function used to grow
+    /// the heap when the fallback
+    /// allocator runs out of memory.
+    fn set_grow(&mut self, grow: GrowFn) {
+        self.grow = Some(grow);
+    }
+
+    /// Returns the number of heap bytes
+    /// handed out by the fallback allocator.
+    /// Free blocks cached in the block
+    /// lists still count as used.
+    fn used(&self) -> usize {
+        self.fallback_allocator.used()
+    }
+
+    /// Returns the number of heap bytes
+    /// the fallback allocator can still
+    /// hand out.
+    fn free(&self) -> usize {
+        self.fallback_allocator.free()
+    }
+
+    fn allocations(&self) -> usize {
+        self.allocations
+    }
 }
 
-unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+unsafe impl<const N: usize> GlobalAlloc for Locked<FixedSizeBlockAllocator<N>> {
     /// Allocate the provided layout of memory
     /// in the heap. Upon success, a pointer
     /// to the newly allocated memory is returned.
     /// Otherwise, a null pointer is returned.
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // Make room for the canary after
+        // the requested bytes
+        let requested = layout;
+        let layout = match with_canary(layout) {
+            Some(layout) => layout,
+            None => return ptr::null_mut(),
+        };
+
         // Get the mutex lock on the allocator
         let mut allocator = self.lock();
 
         // Find the smallest block size that
         // is big enough to store the byte
         // aligned layout
-        match list_index(&layout) {
+        let ptr = match allocator.list_index(&layout) {
             // There is a block size big enough
             // in the fixed block size allocator
             Some(index) => {
@@ -109,13 +356,14 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                     // a pointer to the block of memory
                     Some(node) => {
                         allocator.list_heads[index] = node.next.take();
+                        allocator.free_blocks[index] -= 1;
                         node as *mut ListNode as *mut u8
                     }
 
                     // Otherwise, get the fallback
                     // linked-list allocator to allocate a block
                     None => {
-                        let block_size = BLOCK_SIZES[index];
+                        let block_size = allocator.block_sizes[index];
                         let block_align = block_size;
                         let layout = Layout::from_size_align(block_size, block_align).unwrap();
                         allocator.fallback_alloc(layout)
@@ -127,7 +375,22 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
             // big enough the fallback
             // allocator will allocate the memory
             None => allocator.fallback_alloc(layout),
+        };
+
+        // Count the allocation if
+        // it succeeded
+        if !ptr.is_null() {
+            allocator.allocations += 1;
+            allocator.bytes_allocated += allocator.allocated_size(&layout);
+            if let Some(index) = allocator.list_index(&layout) {
+                allocator.live_blocks[index] += 1;
+                allocator.peak_blocks[index] =
+                    allocator.peak_blocks[index].max(allocator.live_blocks[index]);
+            }
+            write_canary(ptr, requested);
+            allocator.publish_stats();
         }
+        ptr
     }
 
     /// Frees the memory specified by the
@@ -136,38 +399,37 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     /// region to one of the linked lists
     /// of blocks.
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // Check the canary before the
+        // block can be handed out again
+        check_canary(ptr, layout);
+        let layout = with_canary(layout).expect("overflow");
+
         // Get the mutex lock on the allocator
         let mut allocator = self.lock();
+        allocator.allocations -= 1;
+        allocator.bytes_freed += allocator.allocated_size(&layout);
+
+        // Only allocations that were served
+        // as blocks were counted as live,
+        // not fallback memory that is kept
+        // as a block once freed
+        let index = allocator.list_index(&layout);
+        if let Some(index) = index {
+            allocator.live_blocks[index] = allocator.live_blocks[index].saturating_sub(1);
+        }
 
         // Find out if there is a
         // big enough block size
-        // to add to a linked list
-        match list_index(&layout) {
+        // to add to a linked list.
+        // Fallback allocations of
+        // exactly a block size are
+        // also kept as blocks, to
+        // replenish the lists
+        match index.or_else(|| allocator.reusable_index(ptr, &layout)) {
             // If there is a size big
-            // enough, create a new
-            // node with the next node
-            // set as the node in the
-            // list heads
-            Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-
-                // Ensure that the block size
-                // is big enough for the aligned
-                // ListNode struct to insert
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-
-                // Write the ListNode struct into
-                // the newly freed block of memory,
-                // and set the list_heads index
-                // for the block size equal to the
-                // newly freed block
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
+            // enough, push the block
+            // onto its list
+            Some(index) => allocator.push_block(index, ptr),
 
             // If there is no block size big
             // enough, add the free memory to
@@ -177,5 +439,201 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 allocator.fallback_allocator.deallocate(ptr, layout);
             }
         }
+        allocator.publish_stats();
+    }
+
+    /// Resizes an allocation. If the old
+    /// and new size fit in the same block
+    /// size, the block is kept and the
+    /// pointer is returned unchanged.
+    /// Otherwise new memory is allocated,
+    /// the contents are copied and the
+    /// old allocation is freed.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if let (Some(old), Some(new)) = (with_canary(layout), with_canary(new_layout)) {
+            let allocator = self.lock();
+            let old = allocator.list_index(&old);
+            if old.is_some() && old == allocator.list_index(&new) {
+                check_canary(ptr, layout);
+                write_canary(ptr, new_layout);
+                return ptr;
+            }
+        }
+
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+// Grows a Vec within the 16 byte
+// block size and ensures its buffer
+// is not moved, then grows it past
+// the block and ensures the contents
+// are copied. This uses the global
+// heap, so it only runs when that is
+// the fixed size block allocator.
+#[cfg(feature = "alloc-fixed")]
+#[test_case]
+fn test_realloc_within_block_keeps_pointer() {
+    use alloc::vec::Vec;
+
+    let mut vec: Vec<u8> = Vec::with_capacity(10);
+    vec.extend_from_slice(b"abs_os");
+    let ptr = vec.as_ptr();
+
+    vec.reserve_exact(16 - vec.len());
+    assert_eq!(vec.capacity(), 16);
+    assert_eq!(vec.as_ptr(), ptr);
+
+    vec.reserve_exact(64);
+    assert_ne!(vec.as_ptr(), ptr);
+    assert_eq!(vec, b"abs_os");
+}
+
+// Builds an allocator with the given
+// block sizes that hands out memory
+// from region, for the tests below.
+// The region must outlive it.
+#[cfg(all(test, not(feature = "heap-canary")))]
+fn region_allocator<const N: usize>(
+    region: &mut [u8],
+    block_sizes: [usize; N],
+) -> Locked<FixedSizeBlockAllocator<N>> {
+    let allocator = Locked::new(FixedSizeBlockAllocator::with_block_sizes(block_sizes));
+    unsafe {
+        allocator
+            .lock()
+            .init(region.as_mut_ptr() as usize, region.len())
+    };
+    allocator
+}
+
+// Interleaves large allocations with
+// over-aligned small ones, which both
+// come from the fallback allocator, and
+// ensures freeing the small ones fills
+// their free list. Then ensures refill
+// splits a whole chunk into blocks.
+// Uses its own allocator on a region
+// of the global heap. The canary
+// changes which block a layout gets,
+// so this and the next tests only run
+// without it.
+#[cfg(not(feature = "heap-canary"))]
+#[test_case]
+fn test_fallback_blocks_fill_free_lists() {
+    use alloc::{vec, vec::Vec};
+
+    let mut region = vec![0u8; 16 * 4096];
+    let allocator = region_allocator(&mut region, BLOCK_SIZES);
+
+    let large = Layout::from_size_align(4096, 8).unwrap();
+    let small = Layout::from_size_align(64, 4096).unwrap();
+    let index = BLOCK_SIZES.iter().position(|&s| s == 64).unwrap();
+
+    let mut blocks = Vec::new();
+    for _ in 0..4 {
+        unsafe {
+            let large_ptr = allocator.alloc(large);
+            let small_ptr = allocator.alloc(small);
+            assert!(!large_ptr.is_null() && !small_ptr.is_null());
+            blocks.push((large_ptr, small_ptr));
+        }
+    }
+    assert_eq!(allocator.lock().stats().free_blocks[index], 0);
+    for (large_ptr, small_ptr) in blocks {
+        unsafe {
+            allocator.dealloc(small_ptr, small);
+            allocator.dealloc(large_ptr, large);
+        }
+    }
+    assert_eq!(allocator.lock().stats().free_blocks[index], 4);
+
+    let index = BLOCK_SIZES.iter().position(|&s| s == 16).unwrap();
+    let added = allocator.lock().refill(index);
+    assert_eq!(added, REFILL_CHUNK_SIZE / 16);
+    assert_eq!(allocator.lock().stats().free_blocks[index], added);
+    assert_eq!(allocator.lock().allocations(), 0);
+}
+
+// Allocates and frees 32 byte blocks
+// in a pattern that peaks at three
+// live blocks and ensures the peak is
+// recorded while the live count drops
+// back to 0. A large allocation must
+// not touch the block counters.
+#[cfg(not(feature = "heap-canary"))]
+#[test_case]
+fn test_peak_blocks() {
+    use alloc::vec;
+
+    let mut region = vec![0u8; 4 * 4096];
+    let allocator = region_allocator(&mut region, BLOCK_SIZES);
+
+    let layout = Layout::from_size_align(32, 8).unwrap();
+    let large = Layout::from_size_align(4096, 8).unwrap();
+    let index = BLOCK_SIZES.iter().position(|&s| s == 32).unwrap();
+    unsafe {
+        let a = allocator.alloc(layout);
+        let b = allocator.alloc(layout);
+        allocator.dealloc(a, layout);
+        let c = allocator.alloc(layout);
+        let d = allocator.alloc(layout);
+        allocator.dealloc(b, layout);
+        allocator.dealloc(c, layout);
+        let e = allocator.alloc(layout);
+        allocator.dealloc(d, layout);
+        allocator.dealloc(e, layout);
+
+        let big = allocator.alloc(large);
+        allocator.dealloc(big, large);
+    }
+
+    let stats = allocator.lock().stats();
+    assert_eq!(stats.peak_blocks[index], 3);
+    assert_eq!(stats.live_blocks, [0; BLOCK_SIZES.len()]);
+    assert_eq!(stats.peak_blocks.iter().sum::<usize>(), 3);
+}
+
+// Builds allocators with two different
+// sets of block sizes and ensures the
+// same layouts are served from the
+// lists of the matching sizes, or by
+// the fallback allocator if no block
+// is large enough.
+#[cfg(not(feature = "heap-canary"))]
+#[test_case]
+fn test_custom_block_sizes() {
+    use alloc::vec;
+
+    let mut region = vec![0u8; 8 * 4096];
+    let (low, high) = region.split_at_mut(4 * 4096);
+    let coarse = region_allocator(low, [16, 64, 256]);
+    let fine = region_allocator(high, [8, 32, 128, 512, 4096]);
+
+    let small = Layout::from_size_align(20, 4).unwrap();
+    let page = Layout::from_size_align(4096, 8).unwrap();
+    unsafe {
+        let ptrs = [
+            coarse.alloc(small),
+            coarse.alloc(page),
+            fine.alloc(small),
+            fine.alloc(page),
+        ];
+        assert!(ptrs.iter().all(|ptr| !ptr.is_null()));
+        assert_eq!(coarse.lock().stats().live_blocks, [0, 1, 0]);
+        assert_eq!(fine.lock().stats().live_blocks, [0, 1, 0, 0, 1]);
+
+        coarse.dealloc(ptrs[0], small);
+        coarse.dealloc(ptrs[1], page);
+        fine.dealloc(ptrs[2], small);
+        fine.dealloc(ptrs[3], page);
     }
+    assert_eq!(coarse.lock().stats().free_blocks, [0, 1, 0]);
+    assert_eq!(fine.lock().stats().free_blocks, [0, 1, 0, 0, 1]);
 }
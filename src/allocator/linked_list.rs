@@ -2,7 +2,7 @@
 //! heap allocator using cons list
 //! of heap allocations.
 
-use super::{align_up, Locked};
+use super::{align_up, check_canary, with_canary, write_canary, HeapStrategy, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr};
 
@@ -39,6 +39,8 @@ impl ListNode {
 /// the first link of the heap
 pub struct LinkedListAllocator {
     head: ListNode,
+    size: usize,
+    allocations: usize,
 }
 
 impl LinkedListAllocator {
@@ -48,33 +50,58 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            size: 0,
+            allocations: 0,
         }
     }
 
-    /// Initializes the linked list allocator
-    /// with the start and end addresses of the heap
-    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
-        self.add_free_region(heap_start, heap_size);
-    }
-
     /// Add the memory region provided to the
-    /// start of the linked list
+    /// linked list. The list is kept sorted
+    /// by address, and the region is merged
+    /// with the regions directly before and
+    /// after it so freed memory does not
+    /// stay split into small blocks.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // Ensure there is enough memory
         // for the ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // Create a new ListNode and set the
-        // next value to the head link
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        // Find the last region that starts
+        // before the new one. The head is
+        // not a region and is never merged.
+        let head_addr = self.head.start_addr();
+        let mut current = &mut self.head;
+        while matches!(current.next, Some(ref next) if next.start_addr() < addr) {
+            current = current.next.as_mut().unwrap();
+        }
 
-        // Write the new node into
-        // the newly allocated memory
-        let node_ptr = addr as *mut ListNode;
-        node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        // Merge the following region into
+        // the new one if they touch. Sizes
+        // only grow, so merged regions can
+        // always hold a ListNode.
+        let mut size = size;
+        let mut next = current.next.take();
+        if matches!(next, Some(ref following) if following.start_addr() == addr + size) {
+            let following = next.take().unwrap();
+            size += following.size;
+            next = following.next.take();
+        }
+
+        // Grow the preceding region if it
+        // ends where the new one starts,
+        // otherwise write a new node into
+        // the freed memory
+        if current.start_addr() != head_addr && current.end_addr() == addr {
+            current.size += size;
+            current.next = next;
+        } else {
+            let mut node = ListNode::new(size);
+            node.next = next;
+            let node_ptr = addr as *mut ListNode;
+            node_ptr.write(node);
+            current.next = Some(&mut *node_ptr)
+        }
     }
 
     fn find_region(&mut self, size: usize, align: usize) -> Option<(&'static mut ListNode, usize)> {
@@ -147,15 +174,49 @@ impl LinkedListAllocator {
     }
 }
 
+impl HeapStrategy for LinkedListAllocator {
+    /// Initializes the linked list allocator
+    /// with the start and end addresses of the heap
+    unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.size = heap_size;
+        self.add_free_region(heap_start, heap_size);
+    }
+
+    fn used(&self) -> usize {
+        self.size - self.free()
+    }
+
+    /// Adds up the sizes of the
+    /// regions in the free list.
+    fn free(&self) -> usize {
+        let mut free = 0;
+        let mut current = &self.head.next;
+        while let Some(region) = current {
+            free += region.size;
+            current = &region.next;
+        }
+        free
+    }
+
+    fn allocations(&self) -> usize {
+        self.allocations
+    }
+}
+
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     /// Allocates the provided region in the heap
     /// using a linked list of ListNode structs.
     /// A null pointer is returned on failure.
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         // Get the size and alignment of
-        // the provided layout, and get
-        // the lock on the global allocator.
-        let (size, align) = LinkedListAllocator::size_align(layout);
+        // the provided layout with room
+        // for the canary, and get the
+        // lock on the global allocator.
+        let guarded = match with_canary(layout) {
+            Some(guarded) => guarded,
+            None => return ptr::null_mut(),
+        };
+        let (size, align) = LinkedListAllocator::size_align(guarded);
         let mut allocator = self.lock();
 
         if let Some((region, alloc_start)) = allocator.find_region(size, align) {
@@ -164,16 +225,58 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
             if excess_size > 0 {
                 allocator.add_free_region(alloc_end, excess_size);
             }
+            allocator.allocations += 1;
+            write_canary(alloc_start as *mut u8, layout);
             alloc_start as *mut u8
         } else {
             ptr::null_mut()
         }
     }
 
-    /// Deallocate the provided layout
+    /// Deallocate the provided layout,
+    /// after checking its canary
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let (size, _) = LinkedListAllocator::size_align(layout);
+        check_canary(ptr, layout);
+        let guarded = with_canary(layout).expect("overflow");
+        let (size, _) = LinkedListAllocator::size_align(guarded);
 
-        self.lock().add_free_region(ptr as usize, size)
+        let mut allocator = self.lock();
+        allocator.allocations -= 1;
+        allocator.add_free_region(ptr as usize, size)
+    }
+}
+
+// Allocates three adjacent blocks that
+// fill a small heap, frees them out of
+// order and ensures one block spanning
+// all three can be allocated, which
+// needs the free regions to be merged.
+// The canary makes each block too big
+// for the heap, so this only runs
+// without it.
+#[cfg(not(feature = "heap-canary"))]
+#[test_case]
+fn test_free_regions_are_merged() {
+    #[repr(align(16))]
+    struct Heap([u8; 3 * 256]);
+
+    let mut heap = Heap([0; 3 * 256]);
+    let allocator = Locked::new(LinkedListAllocator::new());
+    unsafe {
+        allocator
+            .lock()
+            .init(heap.0.as_mut_ptr() as usize, heap.0.len())
+    };
+
+    let block = Layout::from_size_align(256, 8).unwrap();
+    let blocks = [0; 3].map(|_| unsafe { allocator.alloc(block) });
+    assert!(blocks.iter().all(|ptr| !ptr.is_null()));
+
+    for &index in [1, 0, 2].iter() {
+        unsafe { allocator.dealloc(blocks[index], block) };
     }
+
+    let spanning = Layout::from_size_align(3 * 256, 8).unwrap();
+    let ptr = unsafe { allocator.alloc(spanning) };
+    assert_eq!(ptr, heap.0.as_mut_ptr());
 }
@@ -0,0 +1,241 @@
+//! Driver for the timer of the local
+//! APIC, as an alternative to the PIT.
+//! The PIC path stays the default:
+//! enable_timer switches the tick
+//! source at runtime and disable_timer
+//! switches back. The other devices are
+//! still routed through the 8259 PICs,
+//! since there is no I/O APIC driver,
+//! so only the PIT line is masked.
+
+use crate::{cpu, error::KernelError, interrupts};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+/// Interrupt vector of the APIC timer,
+/// right after the vectors of the PICs.
+pub const TIMER_VECTOR: u8 = interrupts::PIC_2_OFFSET + 8;
+
+/// Interrupt vector of spurious APIC
+/// interrupts. They need no end of
+/// interrupt.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// Virtual address the APIC registers
+// are mapped at, since they are not
+// part of the physical memory mapping.
+// The page stays mapped once the timer
+// is first enabled, so it is reserved
+// for the APIC and must not be used by
+// tests or the heap.
+const APIC_VIRT_ADDR: u64 = 0x_5a5a_5a5a_0000;
+
+// Model specific register holding the
+// physical base of the APIC and its
+// global enable bit
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Register offsets
+const EOI: usize = 0xb0;
+const SPURIOUS_INTERRUPT: usize = 0xf0;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+// Register bits
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+const DIVIDE_BY_16: u32 = 0b0011;
+
+// PIT ticks the APIC timer is
+// counted over to calibrate it
+const CALIBRATION_TICKS: u64 = 10;
+
+// Set once the registers are mapped
+static MAPPED: AtomicBool = AtomicBool::new(false);
+
+// Set while the APIC timer drives
+// the tick counter
+static TIMER_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if the tick counter
+/// is driven by the APIC timer.
+pub fn timer_enabled() -> bool {
+    TIMER_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Reads an APIC register.
+unsafe fn read(register: usize) -> u32 {
+    ((APIC_VIRT_ADDR as usize + register) as *const u32).read_volatile()
+}
+
+/// Writes an APIC register.
+unsafe fn write(register: usize, value: u32) {
+    ((APIC_VIRT_ADDR as usize + register) as *mut u32).write_volatile(value)
+}
+
+/// Signals the end of an interrupt
+/// raised by the APIC.
+pub(crate) fn end_of_interrupt() {
+    unsafe { write(EOI, 0) };
+}
+
+/// Maps the registers of the APIC
+/// uncached at APIC_VIRT_ADDR and sets
+/// its global enable bit.
+fn map_registers(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), KernelError> {
+    if MAPPED.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { apic_base.read() };
+    unsafe { apic_base.write(base | APIC_BASE_ENABLE) };
+
+    let frame = PhysFrame::containing_address(PhysAddr::new(base & APIC_BASE_MASK));
+    let page = Page::containing_address(VirtAddr::new(APIC_VIRT_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator) }
+        .map_err(|error| KernelError::from_map_to(error, page))?
+        .flush();
+    MAPPED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Returns the number of APIC timer
+/// counts in one PIT tick. The PIT has
+/// to be driving the ticks, with
+/// interrupts enabled.
+fn calibrate() -> u32 {
+    // Start counting on a tick edge
+    let start = interrupts::ticks();
+    while interrupts::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+
+    let start = interrupts::ticks();
+    unsafe { write(TIMER_INITIAL_COUNT, u32::MAX) };
+    while interrupts::ticks() - start < CALIBRATION_TICKS {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = u32::MAX - unsafe { read(TIMER_CURRENT_COUNT) };
+    unsafe { write(TIMER_INITIAL_COUNT, 0) };
+
+    (elapsed / CALIBRATION_TICKS as u32).max(1)
+}
+
+/// Switches the tick counter from the
+/// PIT to the APIC timer. The timer is
+/// calibrated against the PIT, so it
+/// ticks at the current PIT frequency
+/// and tick based delays keep their
+/// length. Unsupported is returned if
+/// the processor has no APIC.
+/// The PIT interrupt is masked once the
+/// APIC timer runs, so from then on the
+/// ticks, and the preemption of threads
+/// by the scheduler, come from the APIC
+/// timer until disable_timer is called.
+/// mapper:           active page table
+/// frame_allocator:  source of page table frames
+///
+/// # Panics
+///
+/// Panics if interrupts are disabled,
+/// since the calibration halts until
+/// PIT ticks arrive and would never
+/// return.
+pub fn enable_timer(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), KernelError> {
+    use x86_64::instructions::interrupts::{are_enabled, without_interrupts};
+
+    assert!(
+        are_enabled(),
+        "enable_timer needs interrupts to calibrate against the PIT"
+    );
+    if !cpu::features().apic {
+        return Err(KernelError::Unsupported { feature: "APIC" });
+    }
+    if timer_enabled() {
+        return Err(KernelError::AlreadyInitialized);
+    }
+    map_registers(mapper, frame_allocator)?;
+
+    unsafe {
+        write(
+            SPURIOUS_INTERRUPT,
+            SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+        write(TIMER_DIVIDE, DIVIDE_BY_16);
+        write(LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+    }
+    let count = calibrate();
+
+    // The PIT stops raising ticks in
+    // the same step the APIC starts
+    without_interrupts(|| {
+        unsafe {
+            write(LVT_TIMER, LVT_PERIODIC | u32::from(TIMER_VECTOR));
+            write(TIMER_INITIAL_COUNT, count);
+        }
+        TIMER_ENABLED.store(true, Ordering::Relaxed);
+        interrupts::mask_irq(interrupts::InterruptIndex::Timer.irq_line());
+    });
+    Ok(())
+}
+
+/// Stops the APIC timer and lets the
+/// PIT drive the tick counter again.
+pub fn disable_timer() {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    if !timer_enabled() {
+        return;
+    }
+    without_interrupts(|| {
+        unsafe {
+            write(LVT_TIMER, LVT_MASKED | u32::from(TIMER_VECTOR));
+            write(TIMER_INITIAL_COUNT, 0);
+        }
+        TIMER_ENABLED.store(false, Ordering::Relaxed);
+        interrupts::unmask_irq(interrupts::InterruptIndex::Timer.irq_line());
+    });
+}
+
+// Switches the ticks to the APIC timer
+// and ensures they still advance, then
+// switches back to the PIT. The APIC
+// registers stay mapped afterwards.
+#[test_case]
+fn test_apic_timer_ticks() {
+    use crate::memory::KERNEL_MEMORY;
+
+    if !cpu::features().apic {
+        return;
+    }
+    {
+        let mut memory = KERNEL_MEMORY.lock();
+        let (mapper, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+        enable_timer(mapper, frame_allocator).expect("enabling the APIC timer failed");
+    }
+    assert!(timer_enabled());
+
+    let start = interrupts::ticks();
+    interrupts::delay_ms(50);
+    assert!(interrupts::ticks() >= start + interrupts::ms_to_ticks(50));
+    disable_timer();
+    assert!(!timer_enabled());
+}
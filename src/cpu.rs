@@ -0,0 +1,126 @@
+//! Detection of the features of the
+//! processor with the cpuid instruction,
+//! so code can check for a feature,
+//! such as the x2APIC, before using it.
+
+use conquer_once::spin::OnceCell;
+use core::{arch::x86_64::__cpuid, fmt, str};
+
+// Extended cpuid leaves, holding the
+// highest extended leaf and the
+// address sizes
+const EXTENDED_MAX_LEAF: u32 = 0x8000_0000;
+const ADDRESS_SIZES_LEAF: u32 = 0x8000_0008;
+
+// Address sizes assumed when the
+// processor does not report them
+const DEFAULT_PHYSICAL_BITS: u8 = 36;
+const DEFAULT_LINEAR_BITS: u8 = 48;
+
+/// Features of the processor the
+/// kernel is running on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuFeatures {
+    vendor: [u8; 12],
+    pub sse: bool,
+    pub sse2: bool,
+    pub avx: bool,
+    pub apic: bool,
+    pub x2apic: bool,
+    pub physical_address_bits: u8,
+    pub linear_address_bits: u8,
+}
+
+impl CpuFeatures {
+    /// Returns the vendor string, such
+    /// as "GenuineIntel" or "AuthenticAMD".
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} bit physical and {} bit linear addresses",
+            self.vendor(),
+            self.physical_address_bits,
+            self.linear_address_bits
+        )?;
+        let features = [
+            ("sse", self.sse),
+            ("sse2", self.sse2),
+            ("avx", self.avx),
+            ("apic", self.apic),
+            ("x2apic", self.x2apic),
+        ];
+        for (name, present) in features.iter() {
+            if *present {
+                write!(f, ", {}", name)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Features read by the first call
+// to features
+static FEATURES: OnceCell<CpuFeatures> = OnceCell::uninit();
+
+/// Returns the features of the
+/// processor, running cpuid only the
+/// first time, since it is slow under
+/// a hypervisor.
+pub fn features() -> CpuFeatures {
+    *FEATURES.get_or_init(detect)
+}
+
+/// Reads the vendor, the feature flags
+/// and the address sizes with cpuid.
+pub fn detect() -> CpuFeatures {
+    // Leaf 0 holds the vendor string
+    // in ebx, edx and ecx, in that order
+    let leaf_0 = __cpuid(0);
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&leaf_0.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf_0.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&leaf_0.ecx.to_le_bytes());
+
+    // Leaf 1 holds the feature flags
+    let leaf_1 = __cpuid(1);
+    let bit = |register: u32, bit: u32| register & (1 << bit) != 0;
+
+    let (physical_address_bits, linear_address_bits) =
+        if __cpuid(EXTENDED_MAX_LEAF).eax >= ADDRESS_SIZES_LEAF {
+            let sizes = __cpuid(ADDRESS_SIZES_LEAF).eax;
+            (sizes as u8, (sizes >> 8) as u8)
+        } else {
+            (DEFAULT_PHYSICAL_BITS, DEFAULT_LINEAR_BITS)
+        };
+
+    CpuFeatures {
+        vendor,
+        sse: bit(leaf_1.edx, 25),
+        sse2: bit(leaf_1.edx, 26),
+        avx: bit(leaf_1.ecx, 28),
+        apic: bit(leaf_1.edx, 9),
+        x2apic: bit(leaf_1.ecx, 21),
+        physical_address_bits,
+        linear_address_bits,
+    }
+}
+
+// Ensures the vendor string is read
+// and the address sizes are in the
+// range of x86_64 processors. Linear
+// addresses are 57 bits wide with
+// 5-level paging.
+#[test_case]
+fn test_detect() {
+    let features = detect();
+    assert!(!features.vendor().trim_matches('\0').is_empty());
+    assert!((32..=52).contains(&features.physical_address_bits));
+    assert!((32..=57).contains(&features.linear_address_bits));
+    assert_eq!(self::features(), features);
+}
@@ -0,0 +1,196 @@
+//! Diagnostics used while tuning the
+//! kernel. Stacks can be filled with a
+//! known pattern so the deepest byte
+//! that was overwritten reveals how
+//! much of the stack was really used.
+//! Memory can be hex dumped to serial.
+
+use crate::{gdt, serial};
+use core::{fmt, ops::Range};
+use x86_64::VirtAddr;
+
+/// Byte written over unused stacks.
+/// A stack grows down, so the lowest
+/// byte that no longer holds the
+/// pattern marks its deepest use.
+pub const STACK_PATTERN: u8 = 0xcd;
+
+/// Fills the stack range with the
+/// STACK_PATTERN byte.
+///
+/// # Safety
+///
+/// The range must be mapped and not
+/// currently in use as a stack.
+pub unsafe fn fill_stack_pattern(stack: Range<VirtAddr>) {
+    let start: *mut u8 = stack.start.as_mut_ptr();
+    let len = (stack.end - stack.start) as usize;
+    core::ptr::write_bytes(start, STACK_PATTERN, len);
+}
+
+/// Returns the number of bytes from the
+/// top of the stack down to the deepest
+/// byte that no longer holds the pattern.
+///
+/// # Safety
+///
+/// The range must be mapped and must have
+/// been filled by fill_stack_pattern.
+pub unsafe fn stack_high_water(stack: Range<VirtAddr>) -> usize {
+    let len = (stack.end - stack.start) as usize;
+    let start: *const u8 = stack.start.as_ptr();
+
+    // Scan up from the bottom of the
+    // stack for the first used byte
+    for offset in 0..len {
+        if start.add(offset).read_volatile() != STACK_PATTERN {
+            return len - offset;
+        }
+    }
+    0
+}
+
+/// Returns the high-water mark of the
+/// double fault stack, which is filled
+/// with the pattern by gdt::init.
+pub fn double_fault_stack_high_water() -> usize {
+    unsafe { stack_high_water(gdt::double_fault_stack_range()) }
+}
+
+/// Returns the high-water mark of the
+/// page fault stack, which is filled
+/// with the pattern by gdt::init.
+pub fn page_fault_stack_high_water() -> usize {
+    unsafe { stack_high_water(gdt::page_fault_stack_range()) }
+}
+
+/// Bytes shown on each hexdump row.
+pub const HEXDUMP_ROW: usize = 16;
+
+/// Writes a hex dump of len bytes from
+/// addr to out. Rows start at addresses
+/// aligned to HEXDUMP_ROW and show the
+/// address, the bytes in hex and an
+/// ASCII gutter where bytes that are
+/// not printable appear as dots. Bytes
+/// of the first and last row outside
+/// the range are left blank. Memory is
+/// read with read_volatile.
+/// out:      destination of the dump
+/// addr:     first byte to dump
+/// len:      number of bytes to dump
+///
+/// # Safety
+///
+/// The range must be mapped and
+/// readable, and reading it must not
+/// have side effects, as it would for
+/// memory mapped registers.
+pub unsafe fn write_hexdump(out: &mut impl fmt::Write, addr: VirtAddr, len: usize) -> fmt::Result {
+    let start = addr.as_u64() as usize;
+    let end = start.saturating_add(len);
+    let mut row = start & !(HEXDUMP_ROW - 1);
+
+    while row < end {
+        // Bytes of the row in the range
+        let mut bytes = [None; HEXDUMP_ROW];
+        for (offset, byte) in bytes.iter_mut().enumerate() {
+            let addr = row + offset;
+            if addr >= start && addr < end {
+                *byte = Some((addr as *const u8).read_volatile());
+            }
+        }
+
+        write!(out, "{:016x}:", row)?;
+        for (offset, byte) in bytes.iter().enumerate() {
+            // Split the row into halves
+            if offset == HEXDUMP_ROW / 2 {
+                out.write_char(' ')?;
+            }
+            match byte {
+                Some(byte) => write!(out, " {:02x}", byte)?,
+                None => out.write_str("   ")?,
+            }
+        }
+        out.write_str("  |")?;
+        for byte in bytes.iter() {
+            let c = match byte {
+                Some(byte) if byte.is_ascii_graphic() || *byte == b' ' => *byte as char,
+                Some(_) => '.',
+                None => ' ',
+            };
+            out.write_char(c)?;
+        }
+        out.write_str("|\n")?;
+
+        row += HEXDUMP_ROW;
+    }
+    Ok(())
+}
+
+/// Prints a hex dump of len bytes from
+/// addr to serial, see write_hexdump.
+/// addr:     first byte to dump
+/// len:      number of bytes to dump
+///
+/// # Safety
+///
+/// The same as for write_hexdump.
+pub unsafe fn hexdump(addr: VirtAddr, len: usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ = write_hexdump(&mut *serial::SERIAL1.lock(), addr, len);
+    });
+}
+
+// Fills a buffer with the pattern,
+// writes into its top like a stack
+// would and ensures the reported
+// depth matches.
+#[test_case]
+fn test_stack_high_water() {
+    let mut buffer = [0u8; 256];
+    let ptr = buffer.as_mut_ptr();
+    let start = VirtAddr::from_ptr(ptr);
+    let stack = start..start + buffer.len();
+
+    unsafe {
+        fill_stack_pattern(stack.clone());
+        assert_eq!(stack_high_water(stack.clone()), 0);
+
+        // Use the top 100 bytes
+        core::ptr::write_bytes(ptr.add(256 - 100), 0, 100);
+        assert_eq!(stack_high_water(stack), 100);
+    }
+}
+
+// Dumps a static byte array that
+// starts 3 bytes into a row and
+// ensures the first row shows the
+// blanks, hex and ASCII expected, and
+// the partial last row ends early.
+#[test_case]
+fn test_hexdump_rows() {
+    use alloc::{format, string::String, vec::Vec};
+
+    #[repr(align(16))]
+    struct Bytes([u8; 29]);
+    static BYTES: Bytes = Bytes(*b"abs_os\x00\x01\xff hexdump test rows!!");
+
+    let addr = VirtAddr::from_ptr(BYTES.0.as_ptr()) + 3u64;
+    let mut dump = String::new();
+    unsafe { write_hexdump(&mut dump, addr, 20).unwrap() };
+    let rows: Vec<&str> = dump.lines().collect();
+    assert_eq!(rows.len(), 2);
+
+    let first = format!(
+        "{:016x}:          5f 6f 73 00 01  ff 20 68 65 78 64 75 6d  |   _os... hexdum|",
+        addr.as_u64() - 3
+    );
+    assert_eq!(rows[0], first);
+    let last = format!(
+        " 70 20 74 65 73 74 20{}|p test {}|",
+        " ".repeat(30),
+        " ".repeat(9)
+    );
+    assert!(rows[1].ends_with(&last));
+}
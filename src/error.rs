@@ -0,0 +1,161 @@
+//! Error type shared by the kernel
+//! initialization and memory helpers,
+//! so callers can decide how to handle
+//! a failure instead of the kernel
+//! panicking where it happens.
+
+use core::fmt;
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, UnmapError},
+        Page, Size4KiB,
+    },
+    VirtAddr,
+};
+
+/// Failure modes of the memory
+/// and heap setup code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelError {
+    /// The structure was already initialized
+    AlreadyInitialized,
+    /// The frame allocator ran out of frames
+    /// after mapping some of the pages
+    /// needed for a request. page_table is
+    /// true if the frame was needed for a
+    /// new page table rather than a page.
+    FrameAllocationFailed {
+        usable_frames: usize,
+        mapped_pages: usize,
+        requested_bytes: usize,
+        page_table: bool,
+    },
+    /// The page is already mapped, or a
+    /// huge page covers it
+    MappingConflict(VirtAddr),
+    /// The page is not mapped
+    PageNotMapped(VirtAddr),
+    /// The page is mapped for the
+    /// kernel only
+    NotUserAccessible(VirtAddr),
+    /// The heap range overlaps a
+    /// page that is already mapped
+    HeapOverlap(VirtAddr),
+    /// The heap range is empty, not
+    /// page aligned or leaves no room
+    /// for its guard pages
+    InvalidHeapRange { start: u64, size: usize },
+    /// The executor already holds
+    /// as many tasks as it can
+    ExecutorFull { capacity: usize },
+    /// A device did not reply in time
+    /// or rejected a command
+    DeviceNotResponding { device: &'static str },
+    /// A screen cell outside of
+    /// the VGA buffer was addressed
+    CellOutOfBounds { row: usize, col: usize },
+    /// As many kernel threads as the
+    /// scheduler supports are running
+    TooManyThreads { max: usize },
+    /// The processor lacks a feature
+    /// the operation needs
+    Unsupported { feature: &'static str },
+    /// The heap could not serve an
+    /// allocation of the layout
+    OutOfMemory { size: usize, align: usize },
+    /// A cursor shape with scanlines past
+    /// 15 or ending before it starts
+    InvalidCursorShape { start: u8, end: u8 },
+}
+
+impl KernelError {
+    /// Converts an error from mapping the
+    /// page. Frame exhaustion is reported
+    /// without any pages mapped; callers
+    /// mapping a range build that variant
+    /// themselves. map_to is given the
+    /// frame of the page, so running out
+    /// means a page table frame was
+    /// missing.
+    pub fn from_map_to(error: MapToError<Size4KiB>, page: Page<Size4KiB>) -> Self {
+        match error {
+            MapToError::FrameAllocationFailed => KernelError::FrameAllocationFailed {
+                usable_frames: crate::memory::frame_stats().usable,
+                mapped_pages: 0,
+                requested_bytes: page.size() as usize,
+                page_table: true,
+            },
+            MapToError::ParentEntryHugePage | MapToError::PageAlreadyMapped(_) => {
+                KernelError::MappingConflict(page.start_address())
+            }
+        }
+    }
+
+    /// Converts an error from unmapping
+    /// the page.
+    pub fn from_unmap(error: UnmapError, page: Page<Size4KiB>) -> Self {
+        match error {
+            UnmapError::PageNotMapped => KernelError::PageNotMapped(page.start_address()),
+            UnmapError::ParentEntryHugePage | UnmapError::InvalidFrameAddress(_) => {
+                KernelError::MappingConflict(page.start_address())
+            }
+        }
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KernelError::AlreadyInitialized => write!(f, "already initialized"),
+            KernelError::FrameAllocationFailed {
+                usable_frames,
+                mapped_pages,
+                requested_bytes,
+                page_table,
+            } => write!(
+                f,
+                "out of frames for a {} after mapping {} pages ({} bytes requested, {} usable frames)",
+                if *page_table { "page table" } else { "page" },
+                mapped_pages,
+                requested_bytes,
+                usable_frames
+            ),
+            KernelError::MappingConflict(addr) => {
+                write!(f, "page at {:#x} is already mapped", addr.as_u64())
+            }
+            KernelError::PageNotMapped(addr) => {
+                write!(f, "page at {:#x} is not mapped", addr.as_u64())
+            }
+            KernelError::NotUserAccessible(addr) => {
+                write!(f, "page at {:#x} is not user accessible", addr.as_u64())
+            }
+            KernelError::HeapOverlap(addr) => {
+                write!(f, "heap overlaps mapped page at {:#x}", addr.as_u64())
+            }
+            KernelError::InvalidHeapRange { start, size } => {
+                write!(f, "invalid heap range at {:#x} of {} bytes", start, size)
+            }
+            KernelError::ExecutorFull { capacity } => {
+                write!(f, "executor is full ({} tasks)", capacity)
+            }
+            KernelError::DeviceNotResponding { device } => {
+                write!(f, "{} is not responding", device)
+            }
+            KernelError::CellOutOfBounds { row, col } => {
+                write!(f, "screen cell ({}, {}) is out of bounds", row, col)
+            }
+            KernelError::TooManyThreads { max } => {
+                write!(f, "too many threads ({} running)", max)
+            }
+            KernelError::Unsupported { feature } => {
+                write!(f, "{} is not supported", feature)
+            }
+            KernelError::OutOfMemory { size, align } => {
+                write!(f, "out of memory ({} bytes aligned to {})", size, align)
+            }
+            KernelError::InvalidCursorShape { start, end } => {
+                write!(f, "invalid cursor scanlines {} to {}", start, end)
+            }
+        }
+    }
+}
@@ -1,10 +1,14 @@
 //! Module for the Task State
-//! Segment (TSS). This is used
-//! for storing information about
-//! the previous stack and program
-//! context.
+//! Segment (TSS) and the Global
+//! Descriptor Table (GDT). The TSS
+//! stores the stacks switched to on
+//! interrupts, and the GDT holds the
+//! kernel and user segments.
 
+use crate::error::KernelError;
+use core::ops::Range;
 use lazy_static::lazy_static;
+use x86_64::structures::paging::{Mapper, Page, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
@@ -13,6 +17,103 @@ use x86_64::VirtAddr;
 // Segment information.
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
+/// Index of the Interrupt Stack Table
+/// entry the page fault handler runs
+/// on, so a fault caused by a bad
+/// stack pointer is still handled.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+/// Usable size in bytes of the stack
+/// the double fault handler runs on.
+/// Must be a multiple of the page size.
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+/// Usable size in bytes of the stack
+/// the page fault handler runs on.
+/// Must be a multiple of the page size.
+pub const PAGE_FAULT_STACK_SIZE: usize = 4096 * 4;
+
+// Size of the guard page placed
+// below each interrupt stack.
+const GUARD_PAGE_SIZE: usize = 4096;
+
+// Page aligned backing memory for the
+// double fault stack. The lowest page
+// is the guard page and is never
+// part of the usable stack.
+#[repr(C, align(4096))]
+struct DoubleFaultStack([u8; GUARD_PAGE_SIZE + DOUBLE_FAULT_STACK_SIZE]);
+
+static mut DOUBLE_FAULT_STACK: DoubleFaultStack =
+    DoubleFaultStack([0; GUARD_PAGE_SIZE + DOUBLE_FAULT_STACK_SIZE]);
+
+// Page aligned backing memory for the
+// page fault stack, laid out like the
+// double fault stack.
+#[repr(C, align(4096))]
+struct PageFaultStack([u8; GUARD_PAGE_SIZE + PAGE_FAULT_STACK_SIZE]);
+
+static mut PAGE_FAULT_STACK: PageFaultStack =
+    PageFaultStack([0; GUARD_PAGE_SIZE + PAGE_FAULT_STACK_SIZE]);
+
+/// Returns the address range of the
+/// usable double fault stack. The stack
+/// grows down from the end of the range
+/// toward the guard page below it.
+pub fn double_fault_stack_range() -> Range<VirtAddr> {
+    let guard_start = VirtAddr::from_ptr(unsafe { &DOUBLE_FAULT_STACK });
+    let stack_start = guard_start + GUARD_PAGE_SIZE;
+    stack_start..stack_start + DOUBLE_FAULT_STACK_SIZE
+}
+
+/// Returns the address range of the
+/// usable page fault stack, which also
+/// has a guard page below it.
+pub fn page_fault_stack_range() -> Range<VirtAddr> {
+    let guard_start = VirtAddr::from_ptr(unsafe { &PAGE_FAULT_STACK });
+    let stack_start = guard_start + GUARD_PAGE_SIZE;
+    stack_start..stack_start + PAGE_FAULT_STACK_SIZE
+}
+
+/// Unmaps the guard pages below the
+/// double fault and page fault stacks
+/// so overflowing a stack causes a
+/// fault instead of silently corrupting
+/// the statics next to it. An overflow
+/// of the page fault stack ends in a
+/// double fault.
+pub fn protect_interrupt_stacks(mapper: &mut impl Mapper<Size4KiB>) -> Result<(), KernelError> {
+    unmap_guard_page(double_fault_stack_range().start, mapper)?;
+    unmap_guard_page(page_fault_stack_range().start, mapper)
+}
+
+/// Unmaps the guard page right below
+/// the usable stack starting at
+/// stack_start.
+fn unmap_guard_page(
+    stack_start: VirtAddr,
+    mapper: &mut impl Mapper<Size4KiB>,
+) -> Result<(), KernelError> {
+    let guard_page = Page::<Size4KiB>::containing_address(stack_start - GUARD_PAGE_SIZE);
+
+    // The frame belongs to the kernel
+    // image, so it is not given back
+    // to a frame allocator.
+    let (_frame, flush) = mapper
+        .unmap(guard_page)
+        .map_err(|error| KernelError::from_unmap(error, guard_page))?;
+    flush.flush();
+    Ok(())
+}
+
+/// Size in bytes of the stack the CPU
+/// switches to when an interrupt or a
+/// system call enters ring 0 from
+/// user mode.
+pub const PRIVILEGE_STACK_SIZE: usize = 4096 * 5;
+
+static mut PRIVILEGE_STACK: [u8; PRIVILEGE_STACK_SIZE] = [0; PRIVILEGE_STACK_SIZE];
+
 // One static Task State Segment is used
 // across the operating system. It stores
 // stack information about tasks when
@@ -20,13 +121,11 @@ pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_range().end;
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = page_fault_stack_range().end;
+        tss.privilege_stack_table[0] = {
+            let stack_start = VirtAddr::from_ptr(unsafe { &PRIVILEGE_STACK });
+            stack_start + PRIVILEGE_STACK_SIZE
         };
         tss
     };
@@ -46,11 +145,17 @@ lazy_static! {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
         let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        // User data comes right before user
+        // code, the order SYSRET expects
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
         (
             gdt,
             Selectors {
                 code_selector,
                 tss_selector,
+                user_code_selector,
+                user_data_selector,
             },
         )
     };
@@ -61,6 +166,60 @@ use x86_64::structures::gdt::SegmentSelector;
 struct Selectors {
     code_selector: SegmentSelector,
     tss_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+}
+
+/// Returns the selector of the user
+/// code segment, with RPL 3.
+pub fn user_code_selector() -> SegmentSelector {
+    GDT.1.user_code_selector
+}
+
+/// Returns the selector of the user
+/// data segment, with RPL 3.
+pub fn user_data_selector() -> SegmentSelector {
+    GDT.1.user_data_selector
+}
+
+/// Switches to ring 3 and starts
+/// running at entry with the given
+/// stack, by building the frame iretq
+/// returns through. Interrupts are
+/// enabled in user mode.
+/// entry:    first instruction to run
+/// stack:    top of the user stack
+///
+/// # Safety
+///
+/// The entry code and the stack must be
+/// mapped with USER_ACCESSIBLE, and
+/// init must have been called so the
+/// TSS has a stack to come back to
+/// ring 0 on.
+pub unsafe fn jump_to_user_mode(entry: VirtAddr, stack: VirtAddr) -> ! {
+    // Interrupt flag and the reserved
+    // bit that is always set
+    const USER_RFLAGS: u64 = 0x202;
+
+    let data = u64::from(user_data_selector().0);
+    let code = u64::from(user_code_selector().0);
+    core::arch::asm!(
+        "mov ds, {data:x}",
+        "mov es, {data:x}",
+        "push {data}",
+        "push {stack}",
+        "push {rflags}",
+        "push {code}",
+        "push {entry}",
+        "iretq",
+        data = in(reg) data,
+        stack = in(reg) stack.as_u64(),
+        rflags = in(reg) USER_RFLAGS,
+        code = in(reg) code,
+        entry = in(reg) entry.as_u64(),
+        options(noreturn),
+    );
 }
 
 // Initialize the Global Descriptor
@@ -70,9 +229,64 @@ pub fn init() {
     use x86_64::instructions::segmentation::{Segment, CS};
     use x86_64::instructions::tables::load_tss;
 
+    // The interrupt stacks are unused
+    // until the TSS is loaded, so they
+    // can be filled for the high-water
+    // marks
+    unsafe {
+        crate::debug::fill_stack_pattern(double_fault_stack_range());
+        crate::debug::fill_stack_pattern(page_fault_stack_range());
+    }
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+// Ensures the user selectors of the
+// GDT loaded at boot request privilege
+// level 3. init is not called again,
+// since loading the TSS a second time
+// faults on its busy flag.
+#[test_case]
+fn test_user_selectors_rpl() {
+    use x86_64::PrivilegeLevel;
+
+    assert_eq!(user_code_selector().0 & 0b11, 3);
+    assert_eq!(user_data_selector().0 & 0b11, 3);
+    assert_eq!(user_code_selector().rpl(), PrivilegeLevel::Ring3);
+
+    let privilege_stack_table = TSS.privilege_stack_table;
+    assert!(privilege_stack_table[0].as_u64() != 0);
+}
+
+// Documents the size and bounds of
+// the double fault and page fault
+// stacks and ensures the TSS points
+// at the top of each.
+#[test_case]
+fn test_interrupt_stack_ranges() {
+    // The TSS is packed, so the
+    // table is copied out first
+    let interrupt_stack_table = TSS.interrupt_stack_table;
+
+    let stacks = [
+        (
+            double_fault_stack_range(),
+            DOUBLE_FAULT_STACK_SIZE,
+            DOUBLE_FAULT_IST_INDEX,
+        ),
+        (
+            page_fault_stack_range(),
+            PAGE_FAULT_STACK_SIZE,
+            PAGE_FAULT_IST_INDEX,
+        ),
+    ];
+    for (range, size, index) in stacks {
+        assert_eq!(range.end - range.start, size as u64);
+        assert!(range.start.is_aligned(4096u64));
+        assert_eq!(interrupt_stack_table[index as usize], range.end);
+    }
+}
@@ -3,7 +3,7 @@
 //! Without this module, the OS only
 //! knows how to panic.
 
-use crate::{gdt, println};
+use crate::{gdt, try_println};
 
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
@@ -18,15 +18,43 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         unsafe {
             idt.double_fault
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
+        // System calls are made from ring 3,
+        // so the gate allows that privilege
+        unsafe {
+            idt[usize::from(crate::syscall::SYSCALL_VECTOR)]
+                .set_handler_addr(crate::syscall::entry_addr())
+                .set_privilege_level(x86_64::PrivilegeLevel::Ring3);
+        }
+        // The timer entry saves every
+        // register so it can switch threads
+        unsafe {
+            idt[InterruptIndex::Timer.as_usize()].set_handler_addr(crate::sched::timer_entry_addr());
+            idt[usize::from(crate::apic::TIMER_VECTOR)]
+                .set_handler_addr(crate::sched::timer_entry_addr());
+        }
+        idt[usize::from(crate::apic::SPURIOUS_VECTOR)].set_handler_fn(apic_spurious_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[InterruptIndex::SpuriousPrimary.as_usize()].set_handler_fn(spurious_primary_handler);
+        idt[InterruptIndex::SpuriousSecondary.as_usize()]
+            .set_handler_fn(spurious_secondary_handler);
         idt
     };
 }
@@ -45,7 +73,7 @@ pub fn init_idt() {
 // Called when a breakpoint exception
 // happens.
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    try_println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
 // Ensure that breakpoint exceptions
@@ -56,19 +84,111 @@ fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
 
+/// Prints the state of the CPU at a
+/// fault to the serial port, so it can
+/// be read even if the VGA buffer is
+/// broken. CR2 holds the last address
+/// that page faulted and CR3 the
+/// active level 4 table.
+/// name:         name of the exception
+/// stack_frame:  frame pushed by the CPU
+/// error_code:   error code, printed if any
+pub fn dump_fault_context(name: &str, stack_frame: &InterruptStackFrame, error_code: Option<u64>) {
+    use crate::serial_try_println;
+    use x86_64::registers::control::{Cr2, Cr3};
+
+    let (level_4_frame, _) = Cr3::read();
+    serial_try_println!("==== FAULT: {} ====", name);
+    if let Some(code) = error_code {
+        serial_try_println!("error code: {:#x}", code);
+    }
+    serial_try_println!(
+        "cs:rip:     {:#x}:{:#x}",
+        stack_frame.code_segment,
+        stack_frame.instruction_pointer.as_u64()
+    );
+    serial_try_println!("rsp:        {:#x}", stack_frame.stack_pointer.as_u64());
+    serial_try_println!("rflags:     {:#x}", stack_frame.cpu_flags);
+    serial_try_println!("cr2:        {:#x}", Cr2::read().as_u64());
+    serial_try_println!("cr3:        {:#x}", level_4_frame.start_address().as_u64());
+    serial_try_println!("====");
+}
+
 // Handler function called when
 // a fault occurs during a fault
-// handler function.
+// handler function. The context is
+// dumped before panicking, since the
+// panic handler only prints to VGA.
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    error_code: u64,
 ) -> ! {
+    dump_fault_context("DOUBLE FAULT", &stack_frame, Some(error_code));
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
-// PAGE FAULT
+// DIVIDE ERROR
 
 use crate::hlt_loop;
+
+// Called when a division by zero
+// or a division overflow happens.
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    try_println!("EXCEPTION: DIVIDE ERROR");
+    try_println!("{:#?}", stack_frame);
+    hlt_loop();
+}
+
+// OVERFLOW
+
+// Called by the INTO instruction when
+// the overflow flag is set. INTO is
+// invalid in 64-bit mode, so this is
+// only reached through int 4. It is
+// a trap, so execution continues.
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    try_println!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
+}
+
+// Ensures that overflow exceptions
+// are handled and return.
+#[test_case]
+fn test_overflow_exception() {
+    unsafe { core::arch::asm!("int 4") };
+}
+
+// INVALID OPCODE
+
+// Called when the CPU decodes an
+// undefined or unsupported instruction.
+// The instruction pointer is that of
+// the faulting instruction, so
+// returning would run it again.
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    try_println!("EXCEPTION: INVALID OPCODE");
+    try_println!("Instruction Pointer: {:?}", stack_frame.instruction_pointer);
+    try_println!("{:#?}", stack_frame);
+    hlt_loop();
+}
+
+// GENERAL PROTECTION FAULT
+
+// Called when a protection check
+// fails, such as loading an invalid
+// segment selector. The error code
+// is the selector index, if any.
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    try_println!("EXCEPTION: GENERAL PROTECTION FAULT");
+    try_println!("Error Code: {:#x}", error_code);
+    try_println!("{:#?}", stack_frame);
+    hlt_loop();
+}
+
+// PAGE FAULT
+
 use x86_64::structures::idt::PageFaultErrorCode;
 
 extern "x86-interrupt" fn page_fault_handler(
@@ -77,10 +197,11 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
+    dump_fault_context("PAGE FAULT", &stack_frame, Some(error_code.bits()));
+    try_println!("EXCEPTION: PAGE FAULT");
+    try_println!("Accessed Address: {:?}", Cr2::read());
+    try_println!("Error Code: {:?}", error_code);
+    try_println!("{:#?}", stack_frame);
     hlt_loop();
 }
 
@@ -98,57 +219,789 @@ pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
 // Indices for interrupts stored
-// in C-style enum
+// in C-style enum. Each index is
+// set explicitly to the PIC offset
+// plus the IRQ line of the device,
+// since the lines are not contiguous.
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
-    Keyboard,
+    Keyboard = PIC_1_OFFSET + 1,
+    Serial = PIC_1_OFFSET + 4,
+    Mouse = PIC_2_OFFSET + 4,
+    SpuriousPrimary = PIC_1_OFFSET + 7,
+    SpuriousSecondary = PIC_2_OFFSET + 7,
 }
 
+// Check at compile time that each
+// index is routed from the intended
+// IRQ line of the chained PICs.
+const _: () = assert!(InterruptIndex::Timer.irq_line() == 0);
+const _: () = assert!(InterruptIndex::Keyboard.irq_line() == 1);
+const _: () = assert!(InterruptIndex::Serial.irq_line() == 4);
+const _: () = assert!(InterruptIndex::Mouse.irq_line() == 12);
+const _: () = assert!(InterruptIndex::SpuriousPrimary.irq_line() == 7);
+const _: () = assert!(InterruptIndex::SpuriousSecondary.irq_line() == 15);
+
 impl InterruptIndex {
     fn as_u8(self) -> u8 {
         self as u8
     }
 
+    /// Returns the IRQ line of the
+    /// chained PICs that raises this
+    /// interrupt (0-7 on the primary
+    /// PIC, 8-15 on the secondary).
+    pub const fn irq_line(self) -> u8 {
+        self as u8 - PIC_1_OFFSET
+    }
+
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
 }
 
+// PIC MASKING
+
+/// Disables an IRQ line (0-15) in the
+/// mask of its PIC, so the device
+/// on it stops raising interrupts.
+/// line:     IRQ line to mask
+pub fn mask_irq(line: u8) {
+    set_irq_masked(line, true);
+}
+
+/// Enables an IRQ line (0-15) that
+/// was disabled with mask_irq.
+/// line:     IRQ line to unmask
+pub fn unmask_irq(line: u8) {
+    set_irq_masked(line, false);
+}
+
+/// Sets or clears the mask bit of the
+/// IRQ line, keeping the other bits.
+fn set_irq_masked(line: u8, masked: bool) {
+    use x86_64::instructions::interrupts;
+
+    assert!(line < 16, "IRQ line {} does not exist", line);
+    interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let mut masks = unsafe { pics.read_masks() };
+        let bit = 1 << (line % 8);
+        let mask = &mut masks[usize::from(line / 8)];
+        if masked {
+            *mask |= bit;
+        } else {
+            *mask &= !bit;
+        }
+        unsafe { pics.write_masks(masks[0], masks[1]) };
+    });
+}
+
+// SPURIOUS INTERRUPTS
+
+// Command ports of the PICs, and
+// the command that makes the next
+// read return the in-service register.
+const PIC_1_COMMAND_PORT: u16 = 0x20;
+const PIC_2_COMMAND_PORT: u16 = 0xa0;
+const PIC_READ_ISR: u8 = 0x0b;
+
+// Number of spurious IRQs ignored
+// since boot.
+static SPURIOUS_IRQS: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the number of spurious
+/// IRQs received since boot.
+pub fn spurious_irq_count() -> u64 {
+    SPURIOUS_IRQS.load(Ordering::Relaxed)
+}
+
+/// Returns true if the PIC at the
+/// command port is servicing IRQ 7 of
+/// its lines. A PIC raises IRQ 7 when
+/// an interrupt goes away before it is
+/// acknowledged, and then the bit is
+/// clear.
+fn line_7_in_service(command_port: u16) -> bool {
+    let mut command: Port<u8> = Port::new(command_port);
+    unsafe {
+        command.write(PIC_READ_ISR);
+        command.read() & 0b1000_0000 != 0
+    }
+}
+
+/// Function called for IRQ 7. A
+/// spurious IRQ 7 must not be sent an
+/// end of interrupt, since no IRQ is
+/// in service.
+extern "x86-interrupt" fn spurious_primary_handler(_stack_frame: InterruptStackFrame) {
+    if !line_7_in_service(PIC_1_COMMAND_PORT) {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SpuriousPrimary.as_u8());
+    }
+}
+
+/// Function called for IRQ 15. The
+/// primary PIC did raise the cascade
+/// line for a spurious IRQ 15, so it
+/// still needs an end of interrupt,
+/// but the secondary PIC must not get
+/// one.
+extern "x86-interrupt" fn spurious_secondary_handler(_stack_frame: InterruptStackFrame) {
+    if !line_7_in_service(PIC_2_COMMAND_PORT) {
+        SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+
+        // An index of the primary PIC
+        // only notifies the primary PIC
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::SpuriousPrimary.as_u8());
+        }
+        return;
+    }
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SpuriousSecondary.as_u8());
+    }
+}
+
+// Raises IRQ 7 in software, which
+// leaves the in-service register
+// clear, and ensures it is counted
+// as spurious.
+#[test_case]
+fn test_spurious_irq_7_ignored() {
+    use x86_64::instructions::interrupts;
+
+    let before = spurious_irq_count();
+    interrupts::without_interrupts(|| unsafe { core::arch::asm!("int 39") });
+    assert_eq!(spurious_irq_count(), before + 1);
+}
+
 // TIMER INTERRUPT
 
-use crate::print;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+// Base frequency of the PIT oscillator
+// in Hz. Each channel divides it by
+// a 16 bit divisor.
+pub(crate) const PIT_FREQUENCY: u32 = 1_193_182;
+
+// PIT ports for the mode/command
+// register and channel 0 data.
+pub(crate) const PIT_COMMAND_PORT: u16 = 0x43;
+const PIT_CHANNEL_0_PORT: u16 = 0x40;
+
+/// Timer interrupt frequency in Hz
+/// set up by init.
+pub const DEFAULT_PIT_FREQUENCY: u32 = 100;
+
+// Lowest frequency whose divisor
+// still fits in 16 bits.
+const MIN_PIT_FREQUENCY: u32 = PIT_FREQUENCY / 0xffff + 1;
+
+// Number of timer interrupts
+// handled since boot.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// Frequency in Hz that PIT channel 0
+// was programmed with.
+static PIT_HZ: AtomicU32 = AtomicU32::new(DEFAULT_PIT_FREQUENCY);
+
+/// Returns the number of timer
+/// interrupts handled since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time since boot in
+/// milliseconds, computed from the
+/// ticks and the PIT frequency. Ticks
+/// counted before the frequency was
+/// last changed are converted with
+/// the new frequency too.
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(ticks())
+}
+
+/// Converts a number of ticks to
+/// milliseconds at the current
+/// PIT frequency.
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    ticks * 1000 / u64::from(pit_frequency())
+}
+
+/// Converts milliseconds to a number
+/// of ticks at the current PIT
+/// frequency, rounding up so a delay
+/// is never shorter than requested.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let scaled = ms.saturating_mul(u64::from(pit_frequency()));
+    let ticks = scaled / 1000;
+    if scaled % 1000 == 0 {
+        ticks
+    } else {
+        ticks + 1
+    }
+}
+
+/// Waits for at least ms milliseconds,
+/// halting between timer interrupts.
+/// The tick counter only advances with
+/// interrupts enabled and the PIT set
+/// up by init, so this panics if
+/// interrupts are disabled instead of
+/// waiting forever. Async code should
+/// use task::timer::sleep instead.
+/// ms:       milliseconds to wait
+pub fn delay_ms(ms: u64) {
+    assert!(
+        x86_64::instructions::interrupts::are_enabled(),
+        "delay_ms called with interrupts disabled"
+    );
+
+    // One more tick is waited for, since
+    // the current one is partly over
+    let target = ticks() + ms_to_ticks(ms) + 1;
+    while ticks() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Returns the frequency in Hz of
+/// the timer interrupt.
+pub fn pit_frequency() -> u32 {
+    PIT_HZ.load(Ordering::Relaxed)
+}
+
+/// Returns the PIT channel 0 divisor
+/// for a frequency after clamping it
+/// to what the PIT can produce.
+fn pit_divisor(hz: u32) -> u16 {
+    let hz = hz.clamp(MIN_PIT_FREQUENCY, PIT_FREQUENCY);
+    (PIT_FREQUENCY / hz) as u16
+}
+
+/// Programs PIT channel 0 to raise
+/// the timer interrupt hz times per
+/// second and stores the frequency
+/// used by uptime_ms. The frequency
+/// is clamped to the range the PIT
+/// supports (19 Hz to 1.19 MHz).
+/// hz:       timer interrupt frequency
+pub fn set_pit_frequency(hz: u32) {
+    use x86_64::instructions::interrupts;
+
+    let divisor = pit_divisor(hz);
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut channel_0: Port<u8> = Port::new(PIT_CHANNEL_0_PORT);
+
+    // The two divisor bytes must not be
+    // split by another PIT access
+    interrupts::without_interrupts(|| unsafe {
+        // Channel 0, low byte then high
+        // byte, mode 3 (square wave)
+        command.write(0b0011_0110);
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+        PIT_HZ.store(PIT_FREQUENCY / u32::from(divisor), Ordering::Relaxed);
+    });
+}
+
+// Raises the timer interrupt vector
+// in software and ensures it was
+// routed to the timer handler by
+// checking that the tick count grew.
+#[test_case]
+fn test_timer_vector_routes_to_handler() {
+    use x86_64::instructions::interrupts;
+
+    assert_eq!(InterruptIndex::Timer.as_u8(), 32);
+    interrupts::without_interrupts(|| {
+        let before = ticks();
+        unsafe { core::arch::asm!("int 32") };
+        assert_eq!(ticks(), before + 1);
+    });
+}
+
+// Ensures the timer keeps ticking
+// with interrupts enabled and that
+// ticks are converted at the default
+// frequency.
+#[test_case]
+fn test_ticks_advance() {
+    assert_eq!(pit_frequency(), DEFAULT_PIT_FREQUENCY);
+    assert_eq!(ticks_to_ms(250), 2500);
+    assert_eq!(ms_to_ticks(50), 5);
+    assert_eq!(ms_to_ticks(1), 1);
+
+    let before = ticks();
+    for _ in 0..1000 {
+        if ticks() > before {
+            break;
+        }
+        x86_64::instructions::hlt();
+    }
+    assert!(ticks() > before);
+    assert!(uptime_ms() > 0);
+}
+
+// Waits 50 ms and ensures the tick
+// counter moved by about 5 ticks at
+// the default frequency.
+#[test_case]
+fn test_delay_ms() {
+    let before = ticks();
+    delay_ms(50);
+    let elapsed = ticks() - before;
+    assert!((5..=10).contains(&elapsed), "{} ticks elapsed", elapsed);
+}
+
+// Masks the timer line and ensures
+// no ticks are counted while it is
+// masked, then unmasks it and ensures
+// the ticks resume.
+#[test_case]
+fn test_mask_timer_irq() {
+    mask_irq(InterruptIndex::Timer.irq_line());
+    let before = ticks();
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    assert_eq!(ticks(), before);
 
-/// Function called when a hardware
-/// timer interrupt occurs
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+    unmask_irq(InterruptIndex::Timer.irq_line());
+    for _ in 0..1000 {
+        if ticks() > before {
+            break;
+        }
+        x86_64::instructions::hlt();
+    }
+    assert!(ticks() > before);
+}
 
+// Ensures frequencies outside the
+// range of the PIT are clamped.
+#[test_case]
+fn test_pit_divisor() {
+    assert_eq!(pit_divisor(100), 11931);
+    assert_eq!(pit_divisor(0), pit_divisor(MIN_PIT_FREQUENCY));
+    assert_eq!(pit_divisor(u32::MAX), 1);
+}
+
+/// Function called by the timer entry
+/// in sched when a hardware timer
+/// interrupt occurs, before it picks
+/// the thread to return to. The tick
+/// comes from the PIT or, once it is
+/// enabled, the APIC timer, which is
+/// sent the end of interrupt instead.
+pub(crate) fn on_timer_tick() {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::sound::on_tick(ticks);
+    crate::vga_buffer::on_tick(ticks);
+    crate::task::timer::wake_expired(ticks);
+
+    if crate::apic::timer_enabled() {
+        crate::apic::end_of_interrupt();
+        return;
+    }
     unsafe {
         PICS.lock()
             .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
     }
 }
 
+/// Function called for spurious APIC
+/// interrupts, which must not be sent
+/// an end of interrupt.
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: InterruptStackFrame) {
+    SPURIOUS_IRQS.fetch_add(1, Ordering::Relaxed);
+}
+
 // KEYBOARD INTERRUPT
 
+// PS/2 controller data and status
+// ports, and the status bit that is
+// set while the output buffer holds
+// a byte for the CPU.
+const PS2_DATA_PORT: u16 = 0x60;
+const PS2_STATUS_PORT: u16 = 0x64;
+const PS2_OUTPUT_FULL: u8 = 0b1;
+
+// Status bit set while the controller
+// is still processing a byte written
+// by the CPU, and the bit set when the
+// output byte came from the mouse.
+const PS2_INPUT_FULL: u8 = 0b10;
+const PS2_AUX_DATA: u8 = 0b10_0000;
+
+/// Most scancodes read in one keyboard
+/// interrupt, so a controller that
+/// keeps reporting data cannot hang
+/// the handler.
+pub const MAX_SCANCODES_PER_INTERRUPT: usize = 16;
+
+/// Source of bytes from the keyboard
+/// controller. Implemented by the PS/2
+/// ports and by mocks in tests.
+trait ScancodeSource {
+    /// Reads the status register
+    fn status(&mut self) -> u8;
+    /// Reads a byte from the output buffer
+    fn read_data(&mut self) -> u8;
+}
+
+/// The PS/2 controller ports.
+struct Ps2Controller {
+    data: Port<u8>,
+    status: Port<u8>,
+}
+
+impl Ps2Controller {
+    fn new() -> Self {
+        Ps2Controller {
+            data: Port::new(PS2_DATA_PORT),
+            status: Port::new(PS2_STATUS_PORT),
+        }
+    }
+}
+
+impl ScancodeSource for Ps2Controller {
+    fn status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        unsafe { self.data.read() }
+    }
+}
+
+/// Reads bytes from the source while
+/// its output buffer holds a keyboard
+/// byte, passing each one to push, and
+/// returns the number of bytes read.
+/// Mouse bytes are left for the mouse
+/// handler. At most
+/// MAX_SCANCODES_PER_INTERRUPT are read.
+fn drain_scancodes(source: &mut impl ScancodeSource, mut push: impl FnMut(u8)) -> usize {
+    let mut count = 0;
+    while count < MAX_SCANCODES_PER_INTERRUPT
+        && source.status() & (PS2_OUTPUT_FULL | PS2_AUX_DATA) == PS2_OUTPUT_FULL
+    {
+        push(source.read_data());
+        count += 1;
+    }
+    count
+}
+
 /// Function called when a keyboard
 /// interrupt occurs
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+    // Read every scancode waiting in
+    // the controller and add them to
+    // the keyboard scancode buffer
+    drain_scancodes(
+        &mut Ps2Controller::new(),
+        crate::task::keyboard::add_scancode,
+    );
 
-    // Scan the code of the character
-    // from the port on the hardware controller
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    }
+}
 
-    // Add scancode to the keyboard
-    // scancode buffer
-    crate::task::keyboard::add_scancode(scancode);
+/// Controller holding a list of bytes
+/// that reports its output buffer as
+/// full until all were read.
+#[cfg(test)]
+struct MockController {
+    bytes: alloc::vec::Vec<u8>,
+    read: usize,
+}
+
+#[cfg(test)]
+impl ScancodeSource for MockController {
+    fn status(&mut self) -> u8 {
+        if self.read < self.bytes.len() {
+            PS2_OUTPUT_FULL
+        } else {
+            0
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.read += 1;
+        self.bytes[self.read - 1]
+    }
+}
+
+// Ensures all queued bytes are
+// drained in order in one call.
+#[test_case]
+fn test_drain_multiple_scancodes() {
+    use alloc::vec::Vec;
+
+    let mut controller = MockController {
+        bytes: alloc::vec![0x1e, 0x9e, 0x30, 0xb0],
+        read: 0,
+    };
+    let mut drained = Vec::new();
+    let count = drain_scancodes(&mut controller, |byte| drained.push(byte));
+    assert_eq!(count, 4);
+    assert_eq!(drained, controller.bytes);
+}
+
+// Ensures the drain stops at a byte
+// that came from the mouse.
+#[test_case]
+fn test_drain_scancodes_skips_mouse_bytes() {
+    struct MouseByteController;
+
+    impl ScancodeSource for MouseByteController {
+        fn status(&mut self) -> u8 {
+            PS2_OUTPUT_FULL | PS2_AUX_DATA
+        }
+
+        fn read_data(&mut self) -> u8 {
+            panic!("mouse byte read by the keyboard handler");
+        }
+    }
+
+    assert_eq!(drain_scancodes(&mut MouseByteController, |_| {}), 0);
+}
+
+// Ensures a controller that never
+// empties is only read a bounded
+// number of times.
+#[test_case]
+fn test_drain_scancodes_bounded() {
+    let mut controller = MockController {
+        bytes: alloc::vec![0x1e; 100],
+        read: 0,
+    };
+    let count = drain_scancodes(&mut controller, |_| {});
+    assert_eq!(count, MAX_SCANCODES_PER_INTERRUPT);
+    assert_eq!(controller.read, MAX_SCANCODES_PER_INTERRUPT);
+}
+
+// MOUSE INTERRUPT
+
+use crate::error::KernelError;
+
+// Controller commands to enable the
+// mouse port, to read and write the
+// configuration byte, and to send the
+// next data byte to the mouse.
+const PS2_ENABLE_AUX: u8 = 0xa8;
+const PS2_READ_CONFIG: u8 = 0x20;
+const PS2_WRITE_CONFIG: u8 = 0x60;
+const PS2_WRITE_AUX: u8 = 0xd4;
+
+// Controller command that pulses the
+// CPU reset line low
+const PS2_PULSE_RESET: u8 = 0xfe;
+
+// Configuration bits enabling IRQ12
+// and disabling the mouse clock.
+const PS2_CONFIG_AUX_IRQ: u8 = 0b10;
+const PS2_CONFIG_AUX_CLOCK_OFF: u8 = 0b10_0000;
+
+// Mouse commands to restore default
+// settings and to start sending
+// movement packets, and its reply
+// when a command was accepted.
+const MOUSE_SET_DEFAULTS: u8 = 0xf6;
+const MOUSE_ENABLE_REPORTING: u8 = 0xf4;
+const MOUSE_ACK: u8 = 0xfa;
+
+// Number of status reads to wait for
+// the controller before giving up.
+const PS2_TIMEOUT: usize = 100_000;
+
+impl Ps2Controller {
+    /// Waits until the status has all
+    /// the bits of mask set, or clear if
+    /// set is false.
+    fn wait_status(&mut self, mask: u8, set: bool) -> Result<(), KernelError> {
+        for _ in 0..PS2_TIMEOUT {
+            if (self.status() & mask == mask) == set {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(KernelError::DeviceNotResponding {
+            device: "PS/2 controller",
+        })
+    }
+
+    /// Sends a command to the controller
+    fn write_command(&mut self, command: u8) -> Result<(), KernelError> {
+        self.wait_status(PS2_INPUT_FULL, false)?;
+        unsafe { self.status.write(command) };
+        Ok(())
+    }
+
+    /// Sends a data byte to the controller
+    fn write_byte(&mut self, byte: u8) -> Result<(), KernelError> {
+        self.wait_status(PS2_INPUT_FULL, false)?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    /// Waits for and reads the next
+    /// byte from the output buffer
+    fn read_byte(&mut self) -> Result<u8, KernelError> {
+        self.wait_status(PS2_OUTPUT_FULL, true)?;
+        Ok(self.read_data())
+    }
+
+    /// Sends a command to the mouse and
+    /// waits for it to be acknowledged
+    fn write_mouse(&mut self, command: u8) -> Result<(), KernelError> {
+        self.write_command(PS2_WRITE_AUX)?;
+        self.write_byte(command)?;
+        match self.read_byte()? {
+            MOUSE_ACK => Ok(()),
+            _ => Err(KernelError::DeviceNotResponding {
+                device: "PS/2 mouse",
+            }),
+        }
+    }
+}
+
+/// Enables the mouse port of the PS/2
+/// controller and its interrupt, and
+/// tells the mouse to start sending
+/// movement packets. The packets are
+/// read with task::mouse::MouseStream.
+/// This must be called before
+/// interrupts are enabled, since the
+/// replies are polled for and would
+/// otherwise be taken by the handlers.
+pub fn init_mouse() -> Result<(), KernelError> {
+    let mut controller = Ps2Controller::new();
+
+    controller.write_command(PS2_ENABLE_AUX)?;
+    controller.write_command(PS2_READ_CONFIG)?;
+    let config = controller.read_byte()?;
+    controller.write_command(PS2_WRITE_CONFIG)?;
+    controller.write_byte((config | PS2_CONFIG_AUX_IRQ) & !PS2_CONFIG_AUX_CLOCK_OFF)?;
+
+    controller.write_mouse(MOUSE_SET_DEFAULTS)?;
+    controller.write_mouse(MOUSE_ENABLE_REPORTING)
+}
+
+/// Function called when the mouse has
+/// sent a byte. IRQ12 is on the
+/// secondary PIC, so the end of
+/// interrupt is sent to both PICs,
+/// which notify_end_of_interrupt does
+/// for indices of the secondary PIC.
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let mut controller = Ps2Controller::new();
+
+    // Only read the byte if it came from
+    // the mouse, so a spurious interrupt
+    // does not take a keyboard scancode
+    let status = controller.status();
+    if status & (PS2_OUTPUT_FULL | PS2_AUX_DATA) == PS2_OUTPUT_FULL | PS2_AUX_DATA {
+        crate::task::mouse::add_byte(controller.read_data());
+    }
 
     unsafe {
         PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    }
+}
+
+/// Pulses the CPU reset line through
+/// the PS/2 controller, which resets
+/// the machine once the controller
+/// gets to it. DeviceNotResponding is
+/// returned if its input buffer does
+/// not clear in time to take the
+/// command.
+pub(crate) fn pulse_reset_line() -> Result<(), KernelError> {
+    Ps2Controller::new().write_command(PS2_PULSE_RESET)
+}
+
+// Polls the emulated controller until
+// its input buffer is clear and ensures
+// the bounded wait returns, without
+// sending a command.
+#[test_case]
+fn test_ps2_wait_input_clear() {
+    let mut controller = Ps2Controller::new();
+    assert_eq!(controller.wait_status(PS2_INPUT_FULL, false), Ok(()));
+}
+
+// Raises the mouse interrupt vector
+// with no movement and ensures the
+// handler returns.
+#[test_case]
+fn test_mouse_handler_registered() {
+    use x86_64::instructions::interrupts;
+
+    assert_eq!(InterruptIndex::Mouse.as_u8(), 44);
+    interrupts::without_interrupts(|| unsafe { core::arch::asm!("int 44") });
+}
+
+// SERIAL INTERRUPT
+
+// COM1 receive buffer and line status
+// ports, and the line status bit set
+// while a received byte is waiting.
+const COM1_DATA_PORT: u16 = 0x3f8;
+const COM1_LINE_STATUS_PORT: u16 = 0x3fd;
+const COM1_DATA_READY: u8 = 0b1;
+
+// Bytes the 16550 receive FIFO holds
+const COM1_FIFO_SIZE: usize = 16;
+
+/// Function called when the COM1
+/// serial port has received a byte
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let mut data: Port<u8> = Port::new(COM1_DATA_PORT);
+    let mut line_status: Port<u8> = Port::new(COM1_LINE_STATUS_PORT);
+
+    // Read the received bytes and add
+    // them to the serial input buffer.
+    // The status is checked first so a
+    // spurious interrupt does not wait
+    // for a byte that never arrives, and
+    // at most one FIFO worth is read.
+    for _ in 0..COM1_FIFO_SIZE {
+        if unsafe { line_status.read() } & COM1_DATA_READY == 0 {
+            break;
+        }
+        let byte = unsafe { data.read() };
+        crate::serial::add_byte(byte);
+    }
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial.as_u8());
     }
 }
+
+// Raises the serial interrupt vector
+// with no byte received and ensures
+// the handler returns.
+#[test_case]
+fn test_serial_handler_registered() {
+    use x86_64::instructions::interrupts;
+
+    assert_eq!(InterruptIndex::Serial.as_u8(), 36);
+    interrupts::without_interrupts(|| unsafe { core::arch::asm!("int 36") });
+}
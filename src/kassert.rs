@@ -0,0 +1,185 @@
+//! Assertions for invariants deep in
+//! the kernel, such as in the allocator
+//! and memory code, where the VGA writer
+//! may be unusable. A failed kassert!
+//! writes one report line to the serial
+//! port and halts instead of panicking.
+//! The line has the form
+//!
+//! KASSERT FAILED file=<file> line=<line> cond="<condition>" [left=<left> right=<right>]
+//!
+//! so host tooling can parse it. A `"`
+//! or `\` in the condition is escaped
+//! with a backslash.
+
+use crate::{exit_qemu, hlt_loop, serial, QemuExitCode};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+// Code fail exits QEMU with, which
+// is only changed by the test of
+// the failure path.
+static EXIT_CODE: AtomicU32 = AtomicU32::new(QemuExitCode::Failure as u32);
+
+/// Location and text of a failed
+/// assertion, built by the macros.
+pub struct Failure<'a> {
+    pub condition: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+    pub values: Option<fmt::Arguments<'a>>,
+}
+
+/// Condition text that is written with
+/// its quotes and backslashes escaped,
+/// so it cannot end the quoted field
+/// of the report early.
+struct Escaped<'a>(&'a str);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use fmt::Write;
+
+        for c in self.0.chars() {
+            if c == '"' || c == '\\' {
+                f.write_char('\\')?;
+            }
+            f.write_char(c)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the report line of the
+/// failure to out.
+pub fn write_report(out: &mut impl fmt::Write, failure: &Failure) -> fmt::Result {
+    write!(
+        out,
+        "KASSERT FAILED file={} line={} cond=\"{}\"",
+        failure.file,
+        failure.line,
+        Escaped(failure.condition)
+    )?;
+    if let Some(values) = failure.values {
+        write!(out, " {}", values)?;
+    }
+    writeln!(out)
+}
+
+/// Reports the failure on the serial
+/// port and halts. Interrupts are
+/// disabled and SERIAL1 is forced open,
+/// like for a panic report, since the
+/// kernel does not continue. Under QEMU
+/// the failure exit code is sent.
+#[doc(hidden)]
+#[cold]
+pub fn fail(failure: Failure) -> ! {
+    x86_64::instructions::interrupts::disable();
+    unsafe { serial::SERIAL1.force_unlock() };
+    let _ = write_report(&mut *serial::SERIAL1.lock(), &failure);
+
+    exit_qemu(exit_code());
+    hlt_loop();
+}
+
+/// Returns the code a failed assertion
+/// exits QEMU with, Failure unless it
+/// was changed with set_exit_code.
+pub fn exit_code() -> QemuExitCode {
+    if EXIT_CODE.load(Ordering::Relaxed) == QemuExitCode::Success as u32 {
+        QemuExitCode::Success
+    } else {
+        QemuExitCode::Failure
+    }
+}
+
+/// Changes the code a failed assertion
+/// exits QEMU with. This is only meant
+/// for the test of the failure path,
+/// which has to exit with Success for
+/// the test runner to pass it.
+#[doc(hidden)]
+pub fn set_exit_code(code: QemuExitCode) {
+    EXIT_CODE.store(code as u32, Ordering::Relaxed);
+}
+
+/// Halts with a serial report if
+/// the condition is false.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            $crate::kassert::fail($crate::kassert::Failure {
+                condition: stringify!($cond),
+                file: file!(),
+                line: line!(),
+                values: None,
+            });
+        }
+    };
+}
+
+/// Halts with a serial report holding
+/// both values if they are not equal.
+#[macro_export]
+macro_rules! kassert_eq {
+    ($left:expr, $right:expr $(,)?) => {
+        match (&$left, &$right) {
+            (left, right) => {
+                if !(*left == *right) {
+                    $crate::kassert::fail($crate::kassert::Failure {
+                        condition: concat!(stringify!($left), " == ", stringify!($right)),
+                        file: file!(),
+                        line: line!(),
+                        values: Some(format_args!("left={:?} right={:?}", left, right)),
+                    });
+                }
+            }
+        }
+    };
+}
+
+// Ensures passing assertions fall
+// through and that the report of a
+// failed kassert_eq is one parseable
+// line with both values, and that
+// quotes in the condition are escaped.
+// The failure path itself halts, so it
+// is run by tests/kassert.rs instead.
+#[test_case]
+fn test_kassert_report() {
+    use alloc::string::String;
+
+    let used = 0;
+    kassert!(used == 0);
+    kassert_eq!(2 * 21, 42);
+
+    let mut out = String::new();
+    let failure = Failure {
+        condition: "used == 0",
+        file: "src/allocator.rs",
+        line: 7,
+        values: Some(format_args!("left={:?} right={:?}", 3, 0)),
+    };
+    write_report(&mut out, &failure).unwrap();
+    assert_eq!(
+        out,
+        "KASSERT FAILED file=src/allocator.rs line=7 cond=\"used == 0\" left=3 right=0\n"
+    );
+
+    let mut out = String::new();
+    let failure = Failure {
+        condition: r#"name == "a\b""#,
+        file: "src/memory.rs",
+        line: 9,
+        values: None,
+    };
+    write_report(&mut out, &failure).unwrap();
+    assert_eq!(
+        out,
+        "KASSERT FAILED file=src/memory.rs line=9 cond=\"name == \\\"a\\\\b\\\"\"\n"
+    );
+}
@@ -7,17 +7,41 @@
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
 
+pub mod acpi;
 pub mod allocator;
+pub mod apic;
+pub mod cpu;
+pub mod debug;
+pub mod error;
 pub mod gdt;
 pub mod interrupts;
+pub mod kassert;
+pub mod log;
 pub mod memory;
+pub mod power;
+pub mod sched;
 pub mod serial;
+pub mod sound;
+pub mod stats;
+pub mod syscall;
 pub mod task;
+pub mod time;
+pub mod util;
 pub mod vga_buffer;
+pub mod watchdog;
+
+/// Keeps speaker::play_tone and
+/// speaker::off working, see sound.
+#[doc(hidden)]
+pub use sound as speaker;
 
 extern crate alloc;
 
-use core::panic::PanicInfo;
+use core::{
+    fmt,
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 //// TEST RUNNER CONFIGURATION
 
@@ -48,9 +72,17 @@ where
     /// information and run the T
     /// function type.
     fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
+        let name = core::any::type_name::<T>();
+        *CURRENT_TEST.lock() = name;
+        if cfg!(feature = "test-protocol") {
+            self();
+            serial_println!("{}", TestLine::Ok(name));
+        } else {
+            serial_print!("{}...\t", name);
+            self();
+            serial_println!("[ok]");
+        }
+        TESTS_PASSED.fetch_add(1, Ordering::Relaxed);
     }
 }
 
@@ -60,11 +92,24 @@ where
 // to Qemu, or runs the test
 // panic function defined above.
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
+    TESTS_TOTAL.store(tests.len(), Ordering::Relaxed);
+    if !cfg!(feature = "test-protocol") {
+        serial_println!("Running {} tests", tests.len());
+    }
     for test in tests {
         test.run();
     }
 
+    if cfg!(feature = "test-protocol") {
+        serial_println!(
+            "{}",
+            TestLine::Summary {
+                total: tests.len(),
+                passed: TESTS_PASSED.load(Ordering::Relaxed),
+                failed: TESTS_FAILED.load(Ordering::Relaxed),
+            }
+        );
+    }
     exit_qemu(QemuExitCode::Success);
 }
 
@@ -72,13 +117,178 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 // below so that the common functionality
 // can be used in other modules.
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
-    serial_println!("[failed]\n");
-    serial_println!("Error: {}\n", info);
+    let failed = TESTS_FAILED.fetch_add(1, Ordering::Relaxed) + 1;
+    if cfg!(feature = "test-protocol") {
+        let name = CURRENT_TEST.try_lock().map_or("unknown", |name| *name);
+        serial_try_println!("{}", TestLine::Fail(name, info));
+        serial_try_println!(
+            "{}",
+            TestLine::Summary {
+                total: TESTS_TOTAL.load(Ordering::Relaxed),
+                passed: TESTS_PASSED.load(Ordering::Relaxed),
+                failed,
+            }
+        );
+    } else {
+        serial_try_println!("[failed]\n");
+        serial_try_println!("Error: {}\n", info);
+    }
     exit_qemu(QemuExitCode::Failure);
 
     hlt_loop();
 }
 
+//// TEST RESULT PROTOCOL
+
+// Name of the running test and the
+// counts of the run, for the summary
+// printed by the panic handler
+static CURRENT_TEST: spin::Mutex<&str> = spin::Mutex::new("unknown");
+static TESTS_TOTAL: AtomicUsize = AtomicUsize::new(0);
+static TESTS_PASSED: AtomicUsize = AtomicUsize::new(0);
+static TESTS_FAILED: AtomicUsize = AtomicUsize::new(0);
+
+/// Line of the structured test output
+/// printed with the test-protocol
+/// feature, for tools that parse the
+/// serial output of QEMU:
+///
+/// TEST <name> RESULT ok
+/// TEST <name> RESULT fail reason=<panic message>
+/// TESTS total=<n> passed=<m> failed=<k>
+pub enum TestLine<'a> {
+    Ok(&'a str),
+    Fail(&'a str, &'a dyn fmt::Display),
+    Summary {
+        total: usize,
+        passed: usize,
+        failed: usize,
+    },
+}
+
+impl fmt::Display for TestLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TestLine::Ok(name) => write!(f, "TEST {} RESULT ok", name),
+            TestLine::Fail(name, reason) => {
+                write!(f, "TEST {} RESULT fail reason=", name)?;
+
+                // Keep the reason on the line
+                // by writing new lines as spaces
+                struct OneLine<'a, 'b>(&'a mut fmt::Formatter<'b>);
+                impl fmt::Write for OneLine<'_, '_> {
+                    fn write_str(&mut self, s: &str) -> fmt::Result {
+                        let mut lines = s.split('\n');
+                        if let Some(first) = lines.next() {
+                            self.0.write_str(first)?;
+                        }
+                        for line in lines {
+                            self.0.write_str(" ")?;
+                            self.0.write_str(line)?;
+                        }
+                        Ok(())
+                    }
+                }
+                fmt::write(&mut OneLine(f), format_args!("{}", reason))
+            }
+            TestLine::Summary {
+                total,
+                passed,
+                failed,
+            } => write!(
+                f,
+                "TESTS total={} passed={} failed={}",
+                total, passed, failed
+            ),
+        }
+    }
+}
+
+// Formats the lines of a trivial run,
+// one passing test and one failing
+// with a two line reason, and ensures
+// each is a single parseable line.
+#[test_case]
+fn test_test_line_format() {
+    use alloc::format;
+
+    assert_eq!(
+        format!("{}", TestLine::Ok("abs_os::trivial")),
+        "TEST abs_os::trivial RESULT ok"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            TestLine::Fail("abs_os::broken", &"panicked at src/lib.rs:1:1:\nboom")
+        ),
+        "TEST abs_os::broken RESULT fail reason=panicked at src/lib.rs:1:1: boom"
+    );
+    assert_eq!(
+        format!(
+            "{}",
+            TestLine::Summary {
+                total: 2,
+                passed: 1,
+                failed: 1,
+            }
+        ),
+        "TESTS total=2 passed=1 failed=1"
+    );
+}
+
+//// PANIC REPORT
+
+// Line printed above and below
+// a panic report.
+const PANIC_BANNER: &str = "==================== KERNEL PANIC ====================";
+
+/// Writes the banner, the message and
+/// location of the panic and the
+/// instruction pointer of the panic
+/// handler to out.
+pub fn write_panic_report(out: &mut impl fmt::Write, info: &PanicInfo) -> fmt::Result {
+    let rip: u64;
+    unsafe { core::arch::asm!("lea {}, [rip]", out(reg) rip) };
+
+    writeln!(out, "{}", PANIC_BANNER)?;
+    writeln!(out, "{}", info)?;
+    if let Some(location) = info.location() {
+        writeln!(
+            out,
+            "location: {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )?;
+    }
+    writeln!(out, "rip:      {:#x} (panic handler)", rip)?;
+    writeln!(out, "{}", PANIC_BANNER)
+}
+
+/// Reports a panic on the serial port
+/// and in red on the screen. Interrupts
+/// are disabled and the SERIAL1 and
+/// WRITER locks are forced open, since
+/// the panic may have happened while
+/// one was held and the kernel halts
+/// afterwards anyway.
+pub fn panic_report(info: &PanicInfo) {
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        serial::SERIAL1.force_unlock();
+        vga_buffer::WRITER.force_unlock();
+    }
+
+    // Serial first, so the report is
+    // kept even if the VGA buffer is
+    // broken
+    let _ = write_panic_report(&mut *serial::SERIAL1.lock(), info);
+
+    let mut writer = vga_buffer::WRITER.lock();
+    writer.set_color(vga_buffer::Color::Red, vga_buffer::Color::Black);
+    let _ = write_panic_report(&mut *writer, info);
+}
+
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
 
@@ -89,11 +299,28 @@ entry_point!(test_kernel_main);
 // own entry point to execute tests
 // from test_main.
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
     // Initialize the interrupt
     // descriptor table
     init();
 
+    // Initialize the heap so tests
+    // can make allocations
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap_default(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+    // Keep the freed frames on a list
+    // now that the heap is mapped
+    let frame_allocator = frame_allocator.promote_to_tracked();
+    vga_buffer::init_scrollback();
+    vga_buffer::init_shadow();
+    *memory::KERNEL_MEMORY.lock() = Some((mapper, frame_allocator));
+
     // Run the tests
     test_main();
 
@@ -133,9 +360,27 @@ pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::set_pit_frequency(interrupts::DEFAULT_PIT_FREQUENCY);
+    if let Err(error) = interrupts::init_mouse() {
+        warn!("mouse not enabled: {}", error);
+    }
     x86_64::instructions::interrupts::enable();
 }
 
+//// PROCESSOR COUNT
+
+/// Returns the number of processors
+/// reported by the ACPI tables, or 1 if
+/// they cannot be read. The kernel only
+/// supports a single CPU: the WRITER,
+/// the PICs and the executor queues are
+/// globals without per-CPU state, so
+/// starting application processors
+/// would make them unsound.
+pub fn cpu_count() -> usize {
+    acpi::processor_count().unwrap_or(1)
+}
+
 //// HALT FUNCTION
 
 pub fn hlt_loop() -> ! {
@@ -0,0 +1,152 @@
+//! Leveled logging on top of the VGA
+//! and serial printing. Every message
+//! goes to both: on VGA it is prefixed
+//! with a colored level tag, and on
+//! serial with a plain [LEVEL] tag.
+//! Messages less severe than the global
+//! log level are dropped.
+
+use crate::{serial_println, vga_buffer};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+/// Severity of a log message, most
+/// severe first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+impl Level {
+    /// Tag printed before a message
+    /// of the level.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Level::Error => "[ERROR] ",
+            Level::Warn => "[WARN] ",
+            Level::Info => "[INFO] ",
+            Level::Debug => "[DEBUG] ",
+        }
+    }
+
+    /// Color of the tag on VGA.
+    pub fn color(self) -> vga_buffer::Color {
+        use vga_buffer::Color;
+
+        match self {
+            Level::Error => Color::Red,
+            Level::Warn => Color::Yellow,
+            Level::Info => Color::Green,
+            Level::Debug => Color::LightGray,
+        }
+    }
+
+    fn from_u8(level: u8) -> Level {
+        match level {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            _ => Level::Debug,
+        }
+    }
+}
+
+/// Least severe level that can be
+/// logged at all. Release builds
+/// compile debug! messages out.
+pub const MAX_LEVEL: Level = if cfg!(debug_assertions) {
+    Level::Debug
+} else {
+    Level::Info
+};
+
+// Least severe level that is
+// currently logged
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Sets the least severe level that
+/// is logged. Levels past MAX_LEVEL
+/// stay suppressed.
+pub fn set_level(level: Level) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns the least severe level
+/// that is logged.
+pub fn level() -> Level {
+    Level::from_u8(LOG_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Returns whether messages of the
+/// level are logged.
+pub fn enabled(level: Level) -> bool {
+    level <= MAX_LEVEL && level <= self::level()
+}
+
+/// Prints the message to VGA and
+/// serial if the level is enabled and
+/// returns whether it was printed.
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) -> bool {
+    if !enabled(level) {
+        return false;
+    }
+
+    vga_buffer::print_tagged(level.tag(), level.color(), format_args!("{}\n", args));
+    serial_println!("{}{}", level.tag(), args);
+    true
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+// Lowers the log level to warnings
+// and ensures a debug message is
+// dropped while a warning is printed
+// after its tag.
+#[test_case]
+fn test_log_level_filters_and_tags() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let previous = level();
+        set_level(Level::Warn);
+
+        crate::println!("test_log_level_filters_and_tags");
+        assert!(!debug!("hidden"));
+        let rows = vga_buffer::screen_text();
+        assert_eq!(rows[rows.len() - 2], "test_log_level_filters_and_tags");
+
+        assert!(warn!("shown {}", 42));
+        let rows = vga_buffer::screen_text();
+        assert_eq!(rows[rows.len() - 2], "[WARN] shown 42");
+
+        set_level(previous);
+    });
+}
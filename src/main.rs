@@ -13,7 +13,7 @@ extern crate alloc;
 
 use abs_os::{
     println,
-    task::{keyboard, executor::Executor, Task},
+    task::{executor::Executor, shell, Task},
 };
 
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
@@ -31,6 +31,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     use x86_64::VirtAddr;
 
     println!("Hello World{}", "!");
+    println!("cpu: {}", abs_os::cpu::features());
 
     // Initialize the interrupt descriptor
     // table necessary for handling exceptions.
@@ -42,35 +43,55 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("failed to initialize heap");
+    memory::print_memory_map(&boot_info.memory_map);
+
+    // Turn an overflow of an interrupt
+    // stack into a fault
+    abs_os::gdt::protect_interrupt_stacks(&mut mapper)
+        .expect("failed to unmap interrupt stack guard pages");
+
+    allocator::init_heap_default(&mut mapper, &mut frame_allocator)
+        .expect("failed to initialize heap");
+    // Keep the freed frames on a list
+    // now that the heap is mapped
+    let frame_allocator = frame_allocator.promote_to_tracked();
+    abs_os::vga_buffer::init_scrollback();
+    abs_os::vga_buffer::init_shadow();
+    *memory::KERNEL_MEMORY.lock() = Some((mapper, frame_allocator));
+
+    // Warn on serial if the timer
+    // interrupt stops arriving
+    abs_os::watchdog::init(abs_os::watchdog::DEFAULT_WINDOW_MS);
 
     #[cfg(test)]
     test_main();
-    
+
     let mut executor = Executor::new();
-    executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::print_keypresses()));
+    let shell = shell::run(executor.spawner(), executor.canceller());
+    executor
+        .spawn(Task::named("shell", shell))
+        .expect("failed to spawn the shell");
+
+    // Answer the stats command sent
+    // by the host over serial
+    let (serial_reader, mut serial_writer) = abs_os::serial::split();
+    let stats = abs_os::stats::serve_commands(serial_reader, move |response: &[u8]| {
+        serial_writer.write_all(response)
+    });
+    executor
+        .spawn(Task::named("stats", stats))
+        .expect("failed to spawn the stats server");
     executor.run();
 
     println!("abs_os did not crash");
     abs_os::hlt_loop();
 }
 
-async fn async_number() -> u32 {
-  42
-}
-
-async fn example_task() {
-  let number = async_number().await;
-  println!("async number: {}", number);
-}
-
 // Called on panic
 #[cfg(not(test))] // User different panic for tests
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
+    abs_os::panic_report(info);
     abs_os::hlt_loop();
 }
 
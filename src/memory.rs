@@ -4,25 +4,62 @@
 
 // INITIALIZE LEVEL 4 TABLE
 
+use crate::error::KernelError;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
 use x86_64::{
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+// Virtual address at which the bootloader
+// mapped all of physical memory. It is
+// 0 until init has been called.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Page table mapper and frame allocator
+/// handed over by the entry point once
+/// the heap is set up, for code that
+/// maps pages later on, such as the
+/// heap when it grows.
+pub static KERNEL_MEMORY: spin::Mutex<Option<(OffsetPageTable<'static>, TrackedFrameAllocator)>> =
+    spin::Mutex::new(None);
+
 /// Initialize the page tables using
 /// an offset between the virtual and
 /// physical addresses. This is called
 /// once when initializing the OS, and
 /// it returns a page table with a
 /// static lifetime.
+///
+/// # Safety
+///
+/// All physical memory must be mapped
+/// at physical_memory_offset, and this
+/// must be called only once, since the
+/// level 4 table is borrowed mutably.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// Returns the virtual address that maps
+/// the given physical address, or None if
+/// init has not been called yet.
+pub fn phys_to_virt(addr: PhysAddr) -> Option<VirtAddr> {
+    match PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(VirtAddr::new(offset + addr.as_u64())),
+    }
+}
+
 /// Get a reference to the active level
 /// 4 page table. This is accomplished
 /// by getting the virtual address mapping
@@ -45,12 +82,30 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
+//// TLB INVALIDATION
+
+/// Removes the translation for the page
+/// containing addr from the TLB. This must
+/// be called after changing a page table
+/// entry unless the change was flushed
+/// through the returned MapperFlush.
+pub fn flush_tlb(addr: VirtAddr) {
+    x86_64::instructions::tlb::flush(addr);
+}
+
+/// Removes every non-global translation
+/// from the TLB by reloading CR3.
+pub fn flush_all() {
+    x86_64::instructions::tlb::flush_all();
+}
+
 /// Creates an example mapping for the given page to frame `0xb8000`.
+/// Returns MappingConflict if the page is already mapped.
 pub fn create_example_mapping(
     page: Page,
     mapper: &mut OffsetPageTable,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) {
+) -> Result<(), KernelError> {
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
@@ -60,7 +115,192 @@ pub fn create_example_mapping(
         // FIXME: this is not safe, we do it only for testing
         mapper.map_to(page, frame, flags, frame_allocator)
     };
-    map_to_result.expect("map_to failed").flush();
+    map_to_result
+        .map_err(|error| KernelError::from_map_to(error, page))?
+        .flush();
+    Ok(())
+}
+
+/// Removes the mapping of the page and
+/// flushes it from the TLB. The frame it
+/// was mapped to is returned so the
+/// caller can deallocate it, unless it
+/// is still mapped somewhere else.
+/// Returns PageNotMapped if the page
+/// has no mapping.
+pub fn unmap_page(page: Page, mapper: &mut OffsetPageTable) -> Result<PhysFrame, KernelError> {
+    let (frame, flush) = mapper
+        .unmap(page)
+        .map_err(|error| KernelError::from_unmap(error, page))?;
+    flush.flush();
+    Ok(frame)
+}
+
+/// Returns the physical address the
+/// virtual address is mapped to and
+/// the effective flags of the mapping,
+/// or None if it is not mapped. The
+/// flags are those of the entry that
+/// maps the frame, except WRITABLE and
+/// USER_ACCESSIBLE are only kept if
+/// every parent table entry has them
+/// and NO_EXECUTE is set if any has it.
+/// addr:     virtual address to look up
+/// mapper:   active page table
+pub fn translate(addr: VirtAddr, mapper: &OffsetPageTable) -> Option<(PhysAddr, PageTableFlags)> {
+    use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+
+    let (phys, mut flags) = match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => (frame.start_address() + offset, flags),
+        TranslateResult::NotMapped | TranslateResult::InvalidFrameAddress(_) => return None,
+    };
+
+    // Apply the restrictions of the
+    // parent entries, stopping at the
+    // entry of a huge page since its
+    // flags are already in flags
+    let parent_only = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    // The level 4 table is found through
+    // CR3, since OffsetPageTable only
+    // lends it out mutably
+    let (level_4_frame, _) = x86_64::registers::control::Cr3::read();
+    let level_4 = mapper.phys_offset() + level_4_frame.start_address().as_u64();
+    let mut table: &PageTable = unsafe { &*level_4.as_ptr::<PageTable>() };
+    for &index in [addr.p4_index(), addr.p3_index(), addr.p2_index()].iter() {
+        let entry_flags = table[index].flags();
+        if entry_flags.contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        flags &= entry_flags | !parent_only;
+        flags |= entry_flags & PageTableFlags::NO_EXECUTE;
+
+        let next = mapper.phys_offset() + table[index].addr().as_u64();
+        table = unsafe { &*next.as_ptr::<PageTable>() };
+    }
+    Some((phys, flags))
+}
+
+/// Returns the first page of the range
+/// that is not mapped, or None if every
+/// page is mapped. A range that wraps
+/// around or leaves the canonical
+/// addresses returns its start.
+fn first_unmapped(start: VirtAddr, len: usize, mapper: &OffsetPageTable) -> Option<VirtAddr> {
+    if len == 0 {
+        return None;
+    }
+    let last = match start
+        .as_u64()
+        .checked_add(len as u64 - 1)
+        .and_then(|last| VirtAddr::try_new(last).ok())
+    {
+        Some(last) => last,
+        None => return Some(start),
+    };
+
+    let first_page: Page = Page::containing_address(start);
+    let last_page: Page = Page::containing_address(last);
+    Page::range_inclusive(first_page, last_page)
+        .map(|page| page.start_address())
+        .find(|&addr| translate(addr, mapper).is_none())
+}
+
+/// Returns true if every page of the
+/// range is mapped, so it can be read
+/// without page faulting.
+/// start:    first address of the range
+/// len:      length of the range in bytes
+/// mapper:   active page table
+pub fn range_is_mapped(start: VirtAddr, len: usize, mapper: &OffsetPageTable) -> bool {
+    first_unmapped(start, len, mapper).is_none()
+}
+
+/// Copies dst.len() bytes from src into
+/// dst after checking that the whole
+/// source range is mapped for user
+/// code, so a bad pointer passed in by
+/// user code cannot page fault the
+/// kernel or read kernel memory. If a
+/// page is not mapped, PageNotMapped is
+/// returned with its address, and if it
+/// is mapped for the kernel only,
+/// NotUserAccessible. Nothing is copied
+/// in either case.
+/// dst:      buffer to copy into
+/// src:      address to copy from
+/// mapper:   active page table
+pub fn copy_from_user(
+    dst: &mut [u8],
+    src: VirtAddr,
+    mapper: &OffsetPageTable,
+) -> Result<(), KernelError> {
+    if let Some(addr) = first_unmapped(src, dst.len(), mapper) {
+        return Err(KernelError::PageNotMapped(addr));
+    }
+    if !dst.is_empty() {
+        let first_page: Page = Page::containing_address(src);
+        let last_page: Page = Page::containing_address(src + (dst.len() as u64 - 1));
+        for page in Page::range_inclusive(first_page, last_page) {
+            let addr = page.start_address();
+            let user = translate(addr, mapper)
+                .is_some_and(|(_, flags)| flags.contains(PageTableFlags::USER_ACCESSIBLE));
+            if !user {
+                return Err(KernelError::NotUserAccessible(addr));
+            }
+        }
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr::<u8>(), dst.as_mut_ptr(), dst.len());
+    }
+    Ok(())
+}
+
+//// MEMORY MAP
+
+/// Writes one line per region of the
+/// memory map with its type, physical
+/// range and size, then the total of
+/// the usable regions. The map is only
+/// read.
+/// out:      destination of the lines
+/// map:      memory map from the bootloader
+pub fn write_memory_map(out: &mut impl fmt::Write, map: &MemoryMap) -> fmt::Result {
+    let mut usable = 0;
+    let mut usable_regions = 0;
+    for region in map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        writeln!(
+            out,
+            "memory: {:#014x}-{:#014x} {:>10} KiB {:?}",
+            start,
+            end,
+            (end - start) / 1024,
+            region.region_type
+        )?;
+        if region.region_type == MemoryRegionType::Usable {
+            usable += end - start;
+            usable_regions += 1;
+        }
+    }
+    writeln!(
+        out,
+        "memory: {} KiB usable in {} regions",
+        usable / 1024,
+        usable_regions
+    )
+}
+
+/// Prints the memory map to serial,
+/// see write_memory_map.
+pub fn print_memory_map(map: &MemoryMap) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ = write_memory_map(&mut *crate::serial::SERIAL1.lock(), map);
+    });
 }
 
 //// FRAME ALLOCATORS
@@ -85,26 +325,132 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+impl FrameDeallocator<Size4KiB> for EmptyFrameAllocator {
+    /// Never called, since no frame is
+    /// handed out that could be freed.
+    unsafe fn deallocate_frame(&mut self, _frame: PhysFrame) {
+        unreachable!("EmptyFrameAllocator has no frames to free");
+    }
+}
+
 // BOOTINFO FRAME ALLOCATOR
 
+// Counters shared by every
+// BootInfoFrameAllocator so the
+// frame usage can be queried
+// without a reference to it.
+static FRAMES_USABLE: AtomicUsize = AtomicUsize::new(0);
+static FRAMES_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Snapshot of the physical frames
+/// reported by the bootloader and
+/// how many have been handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub usable: usize,
+    pub allocated: usize,
+}
+
+/// Returns the current frame usage
+/// of the BootInfoFrameAllocator.
+pub fn frame_stats() -> FrameStats {
+    FrameStats {
+        usable: FRAMES_USABLE.load(Ordering::Relaxed),
+        allocated: FRAMES_ALLOCATED.load(Ordering::Relaxed),
+    }
+}
+
+// Marks the end of the list of
+// freed frames.
+const FREE_LIST_END: u64 = u64::MAX;
+
+/// List of free frames. The list is
+/// intrusive: each frame holds the
+/// address of the next one, so it
+/// works before the heap exists and
+/// never allocates.
+struct FreeFrames {
+    head: u64,
+    len: usize,
+}
+
+impl FreeFrames {
+    /// Creates an empty list.
+    const fn new() -> Self {
+        FreeFrames {
+            head: FREE_LIST_END,
+            len: 0,
+        }
+    }
+
+    /// Returns a pointer to the first
+    /// bytes of the frame through the
+    /// physical memory mapping.
+    fn link(frame: PhysFrame) -> *mut u64 {
+        phys_to_virt(frame.start_address())
+            .expect("physical memory is not mapped")
+            .as_mut_ptr()
+    }
+
+    /// Adds the frame to the front of
+    /// the list. The frame is written
+    /// to, so it must not be in use.
+    unsafe fn push(&mut self, frame: PhysFrame) {
+        Self::link(frame).write_volatile(self.head);
+        self.head = frame.start_address().as_u64();
+        self.len += 1;
+    }
+
+    /// Takes the most recently added
+    /// frame off the list.
+    fn pop(&mut self) -> Option<PhysFrame> {
+        if self.head == FREE_LIST_END {
+            return None;
+        }
+
+        let frame = PhysFrame::containing_address(PhysAddr::new(self.head));
+        self.head = unsafe { Self::link(frame).read_volatile() };
+        self.len -= 1;
+        Some(frame)
+    }
+}
+
 /// Stores the memory map from
-/// the bootloader and the
-/// index of the next usable frame index
+/// the bootloader, a cursor to the
+/// next usable frame and a list of
+/// frames that were given back.
+/// The cursor is the index of a
+/// memory map region and the address
+/// of the next frame in it, so taking
+/// a frame does not walk the map.
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
-    next: usize,
+    region: usize,
+    next: u64,
+    free_list: FreeFrames,
 }
 
 impl BootInfoFrameAllocator {
     /// Initialize the memory map info
     /// passed to the kernel from the
-    /// bootloader. The next usable
-    /// frame index is started at 0.
+    /// bootloader. The cursor starts
+    /// at the first region of the map.
+    ///
+    /// # Safety
+    ///
+    /// Every frame the map marks as
+    /// usable must really be unused,
+    /// since the allocator hands them
+    /// out without checking.
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
+        let allocator = BootInfoFrameAllocator {
             memory_map,
+            region: 0,
             next: 0,
-        }
+            free_list: FreeFrames::new(),
+        };
+        FRAMES_USABLE.store(allocator.usable_frames().count(), Ordering::Relaxed);
+        allocator
     }
 
     /// Returns an iterator over the
@@ -126,6 +472,25 @@ impl BootInfoFrameAllocator {
             // Return an iterator of PhysFrames
             .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
+
+    /// Takes the frame at the cursor and
+    /// moves the cursor past it, skipping
+    /// to the next usable region once
+    /// the current one is used up.
+    fn next_usable(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.memory_map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                let addr = self.next.max(region.range.start_addr());
+                if addr < region.range.end_addr() {
+                    self.next = addr + 4096;
+                    return Some(PhysFrame::containing_address(PhysAddr::new(addr)));
+                }
+            }
+            self.region += 1;
+            self.next = 0;
+        }
+        None
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
@@ -133,10 +498,426 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     /// the BootInfoFrameAllocator.
     /// This uses the memory mapping
     /// passed to the kernel from
-    /// the bootloader.
+    /// the bootloader. Freed frames are
+    /// reused before new ones are taken.
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
+        let frame = match self.free_list.pop() {
+            Some(frame) => Some(frame),
+            None => self.next_usable(),
+        };
+        if frame.is_some() {
+            FRAMES_ALLOCATED.fetch_add(1, Ordering::Relaxed);
+        }
         frame
     }
 }
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    /// Gives a frame back so it is
+    /// returned by a later allocation.
+    /// The frame must have come from
+    /// this allocator, must no longer be
+    /// mapped anywhere and must not be
+    /// freed twice.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free_list.push(frame);
+        FRAMES_ALLOCATED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// TRACKED FRAME ALLOCATOR
+
+impl BootInfoFrameAllocator {
+    /// Turns this allocator into a
+    /// TrackedFrameAllocator, once the
+    /// heap has been mapped. Every usable
+    /// frame the cursor has not reached
+    /// yet is put on the free list of the
+    /// new allocator, after the frames
+    /// that were already freed, so a frame
+    /// handed out before the promotion is
+    /// never handed out again. Every one
+    /// of those frames is written to.
+    pub fn promote_to_tracked(mut self) -> TrackedFrameAllocator {
+        let mut free = FreeFrames::new();
+
+        // Push the frames from the highest
+        // one down, so the lowest frame is
+        // still handed out first
+        let regions = self.memory_map.iter().enumerate().skip(self.region);
+        for (index, region) in regions.rev() {
+            if region.region_type != MemoryRegionType::Usable {
+                continue;
+            }
+            let start = if index == self.region {
+                self.next.max(region.range.start_addr())
+            } else {
+                region.range.start_addr()
+            };
+            let frames = region.range.end_addr().saturating_sub(start).div_ceil(4096);
+            for frame in (0..frames).rev() {
+                let addr = PhysAddr::new(start + frame * 4096);
+                unsafe { free.push(PhysFrame::containing_address(addr)) };
+            }
+        }
+        while let Some(frame) = self.free_list.pop() {
+            unsafe { free.push(frame) };
+        }
+        TrackedFrameAllocator { free }
+    }
+}
+
+/// Frame allocator created from a
+/// BootInfoFrameAllocator by
+/// promote_to_tracked once the heap
+/// exists. All of the frames it can
+/// hand out are on its free list, so
+/// it knows how many are left, and
+/// deallocating a frame never
+/// allocates, which lets the heap grow
+/// and shrink with the allocator
+/// locked.
+pub struct TrackedFrameAllocator {
+    free: FreeFrames,
+}
+
+impl TrackedFrameAllocator {
+    /// Returns the number of frames
+    /// that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free.len
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for TrackedFrameAllocator {
+    /// Takes the most recently freed
+    /// frame, or the lowest frame that
+    /// was never handed out.
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.free.pop();
+        if frame.is_some() {
+            FRAMES_ALLOCATED.fetch_add(1, Ordering::Relaxed);
+        }
+        frame
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for TrackedFrameAllocator {
+    /// Gives a frame back. The frame must
+    /// have come from this allocator, must
+    /// no longer be mapped anywhere and
+    /// must not be freed twice.
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.free.push(frame);
+        FRAMES_ALLOCATED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Remaps a page to a second frame
+// without using the MapperFlush results
+// and ensures that after flush_tlb the
+// new frame is the one accessed. The
+// page is unmapped and both frames are
+// freed afterwards.
+#[test_case]
+fn test_flush_tlb_observes_remap() {
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let mut memory = KERNEL_MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+
+    let page: Page = Page::containing_address(VirtAddr::new(0x_5555_5555_0000));
+    let flags = Flags::PRESENT | Flags::WRITABLE;
+    let first_frame = frame_allocator.allocate_frame().expect("no frame");
+    let second_frame = frame_allocator.allocate_frame().expect("no frame");
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+
+    unsafe {
+        mapper
+            .map_to(page, first_frame, flags, frame_allocator)
+            .expect("map_to failed")
+            .flush();
+        ptr.write_volatile(1);
+
+        // Point the page at the second frame,
+        // leaving the old translation cached
+        mapper.unmap(page).expect("unmap failed").1.ignore();
+        mapper
+            .map_to(page, second_frame, flags, frame_allocator)
+            .expect("map_to failed")
+            .ignore();
+        flush_tlb(page.start_address());
+        ptr.write_volatile(2);
+    }
+
+    // Read both frames through the
+    // physical memory mapping
+    let read_frame = |frame: PhysFrame| {
+        let virt = mapper.phys_offset() + frame.start_address().as_u64();
+        unsafe { virt.as_ptr::<u64>().read_volatile() }
+    };
+    assert_eq!(read_frame(first_frame), 1);
+    assert_eq!(read_frame(second_frame), 2);
+
+    mapper.unmap(page).expect("unmap failed").1.flush();
+    unsafe {
+        frame_allocator.deallocate_frame(first_frame);
+        frame_allocator.deallocate_frame(second_frame);
+    }
+}
+
+// Maps the VGA frame over a page that
+// is already mapped and ensures the
+// conflict is returned as an error.
+#[test_case]
+fn test_example_mapping_conflict() {
+    let mut memory = KERNEL_MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+
+    let page = Page::containing_address(VirtAddr::new(crate::allocator::HEAP_START as u64));
+    let result = create_example_mapping(page, mapper, frame_allocator);
+    assert_eq!(
+        result,
+        Err(KernelError::MappingConflict(page.start_address()))
+    );
+}
+
+// Allocates and frees several frames
+// and ensures the next allocations
+// reuse the same frames.
+#[test_case]
+fn test_deallocated_frames_are_reused() {
+    use alloc::vec::Vec;
+
+    let mut memory = KERNEL_MEMORY.lock();
+    let (_, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+
+    let allocated = frame_stats().allocated;
+    let mut frames: Vec<PhysFrame> = (0..4)
+        .map(|_| frame_allocator.allocate_frame().expect("no frame"))
+        .collect();
+    for &frame in frames.iter() {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(frame_stats().allocated, allocated);
+
+    let free = frame_allocator.free_frames();
+    let mut reused: Vec<PhysFrame> = (0..4)
+        .map(|_| frame_allocator.allocate_frame().expect("no frame"))
+        .collect();
+    assert_eq!(frame_allocator.free_frames(), free - 4);
+
+    frames.sort();
+    reused.sort();
+    assert_eq!(frames, reused);
+
+    for frame in reused {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+// Allocates thousands of frames, which
+// takes far too long if each one walks
+// the memory map, and ensures they are
+// all distinct.
+#[test_case]
+fn test_allocate_many_frames() {
+    use alloc::vec::Vec;
+
+    let mut memory = KERNEL_MEMORY.lock();
+    let (_, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+
+    let allocated = frame_stats().allocated;
+    let mut frames: Vec<PhysFrame> = (0..3000)
+        .map(|_| frame_allocator.allocate_frame().expect("no frame"))
+        .collect();
+    frames.sort();
+    frames.dedup();
+    assert_eq!(frames.len(), 3000);
+
+    for frame in frames {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(frame_stats().allocated, allocated);
+}
+
+// Translates an address inside the
+// heap and ensures it is mapped as
+// present and writable, and that an
+// unmapped address is not translated.
+#[test_case]
+fn test_translate_heap_start() {
+    let memory = KERNEL_MEMORY.lock();
+    let (mapper, _) = memory.as_ref().expect("test memory not initialized");
+
+    let heap_start = VirtAddr::new(crate::allocator::HEAP_START as u64);
+    let (phys, flags) = translate(heap_start + 8u64, mapper).expect("heap not mapped");
+    assert_eq!(phys.as_u64() % 4096, 8);
+    assert!(flags.contains(PageTableFlags::PRESENT | PageTableFlags::WRITABLE));
+    assert_eq!(translate(VirtAddr::new(0x_7777_7777_0000), mapper), None);
+}
+
+// Copies from a page mapped for user
+// code and ensures the bytes arrive,
+// then copies a heap buffer, which is
+// mapped for the kernel only, and a
+// range straddling the guard page
+// below the heap and ensures both are
+// rejected and nothing is copied.
+#[test_case]
+fn test_copy_from_user() {
+    use crate::allocator::HEAP_START;
+    use alloc::boxed::Box;
+    use x86_64::structures::paging::PageTableFlags as Flags;
+
+    let mut memory = KERNEL_MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+
+    let page: Page = Page::containing_address(VirtAddr::new(0x_3333_3333_0000));
+    let frame = frame_allocator.allocate_frame().expect("no frame");
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+    unsafe {
+        mapper
+            .map_to(page, frame, flags, frame_allocator)
+            .expect("map_to failed")
+            .flush();
+        page.start_address()
+            .as_mut_ptr::<[u8; 16]>()
+            .write_volatile(*b"abs_os user data");
+    }
+    let mut dst = [0u8; 16];
+    let result = copy_from_user(&mut dst, page.start_address(), mapper);
+    let frame = unmap_page(page, mapper).expect("unmap failed");
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(result, Ok(()));
+    assert_eq!(&dst, b"abs_os user data");
+
+    let source = Box::new(*b"abs_os heap data");
+    let src = VirtAddr::from_ptr(source.as_ptr());
+    let mut dst = [0u8; 16];
+    assert!(range_is_mapped(src, dst.len(), mapper));
+    assert_eq!(
+        copy_from_user(&mut dst, src, mapper),
+        Err(KernelError::NotUserAccessible(src.align_down(4096u64)))
+    );
+    assert_eq!(dst, [0u8; 16]);
+
+    let heap_start = VirtAddr::new(HEAP_START as u64);
+    let straddling = heap_start - 8u64;
+    let mut dst = [0u8; 16];
+    assert!(!range_is_mapped(straddling, dst.len(), mapper));
+    assert_eq!(
+        copy_from_user(&mut dst, straddling, mapper),
+        Err(KernelError::PageNotMapped(heap_start - 4096u64))
+    );
+    assert_eq!(dst, [0u8; 16]);
+}
+
+// Ensures unmapping a page that
+// is not mapped returns an error.
+#[test_case]
+fn test_unmap_unmapped_page() {
+    let mut memory = KERNEL_MEMORY.lock();
+    let (mapper, _) = memory.as_mut().expect("test memory not initialized");
+
+    let page = Page::containing_address(VirtAddr::new(0x_7777_7777_0000));
+    assert_eq!(
+        unmap_page(page, mapper),
+        Err(KernelError::PageNotMapped(page.start_address()))
+    );
+}
+
+// Builds a memory map whose usable
+// regions are frames taken from the
+// kernel allocator, takes two frames
+// from a boot allocator over it,
+// promotes it and ensures the frames
+// taken plus the promoted ones add up
+// to the usable frames of the map,
+// that none is handed out twice and
+// that a freed frame is reused.
+#[test_case]
+fn test_promote_keeps_frame_count() {
+    use alloc::{boxed::Box, vec::Vec};
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    let mut memory = KERNEL_MEMORY.lock();
+    let (_, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+
+    let mut frames: Vec<PhysFrame> = (0..8)
+        .map(|_| frame_allocator.allocate_frame().expect("no frame"))
+        .collect();
+    frames.sort();
+    let mut map = MemoryMap::new();
+    for frame in frames.iter() {
+        let start = frame.start_address().as_u64();
+        map.add_region(MemoryRegion {
+            range: FrameRange::new(start, start + 4096),
+            region_type: MemoryRegionType::Usable,
+        });
+    }
+
+    // Built by hand so the frame counters
+    // of the kernel allocator are kept
+    let mut boot = BootInfoFrameAllocator {
+        memory_map: Box::leak(Box::new(map)),
+        region: 0,
+        next: 0,
+        free_list: FreeFrames::new(),
+    };
+    let usable = boot.usable_frames().count();
+    assert_eq!(usable, frames.len());
+
+    let taken = [
+        boot.next_usable().expect("no frame"),
+        boot.next_usable().expect("no frame"),
+    ];
+    let mut tracked = boot.promote_to_tracked();
+    assert_eq!(taken.len() + tracked.free_frames(), usable);
+
+    let mut promoted: Vec<PhysFrame> = core::iter::from_fn(|| tracked.allocate_frame()).collect();
+    assert_eq!(promoted.len(), usable - taken.len());
+    assert!(promoted.windows(2).all(|pair| pair[0] < pair[1]));
+    assert!(promoted.iter().all(|frame| !taken.contains(frame)));
+
+    let last = promoted.pop().expect("no frame");
+    unsafe { tracked.deallocate_frame(last) };
+    assert_eq!(tracked.allocate_frame(), Some(last));
+    promoted.push(last);
+
+    for frame in promoted {
+        unsafe { tracked.deallocate_frame(frame) };
+    }
+    for frame in frames {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+}
+
+// Builds a memory map with two usable
+// regions around a reserved one and
+// ensures the summary reports the sum
+// of the usable regions.
+#[test_case]
+fn test_memory_map_usable_total() {
+    use alloc::string::String;
+    use bootloader::bootinfo::{FrameRange, MemoryRegion};
+
+    let mut map = MemoryMap::new();
+    let regions = [
+        (0x0, 0x9f000, MemoryRegionType::Usable),
+        (0x9f000, 0x100000, MemoryRegionType::Reserved),
+        (0x100000, 0x300000, MemoryRegionType::Usable),
+    ];
+    for &(start, end, region_type) in regions.iter() {
+        map.add_region(MemoryRegion {
+            range: FrameRange::new(start, end),
+            region_type,
+        });
+    }
+
+    let mut out = String::new();
+    write_memory_map(&mut out, &map).unwrap();
+    assert_eq!(out.lines().count(), 4);
+    assert!(out.contains("0x000000100000-0x000000300000       2048 KiB Usable\n"));
+    assert!(out.ends_with("memory: 2684 KiB usable in 2 regions\n"));
+}
@@ -0,0 +1,132 @@
+//! Powering off and rebooting the
+//! machine. Unlike
+//! exit_qemu, which ends a test run
+//! with an exit code through the
+//! isa-debug-exit device, shutdown
+//! uses the ports emulators wire to
+//! their power off logic, so it works
+//! without any test device.
+
+use crate::{interrupts, serial_try_println};
+use x86_64::instructions::port::Port;
+
+// Spins given to the keyboard
+// controller to reset the machine
+// before falling back to a triple
+// fault
+const RESET_WAIT_SPINS: usize = 1_000_000;
+
+/// A write to an I/O port that powers
+/// off some machines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownWrite {
+    pub port: u16,
+    pub value: u16,
+}
+
+/// Writes tried by shutdown, in order.
+/// The first is the ACPI PM1a control
+/// register of QEMU's PIIX4 power
+/// management, set to enter sleep
+/// state S5. The second is the port
+/// older QEMU and Bochs versions use.
+pub const SHUTDOWN_SEQUENCE: [ShutdownWrite; 2] = [
+    ShutdownWrite {
+        port: 0x604,
+        value: 0x2000,
+    },
+    ShutdownWrite {
+        port: 0xb004,
+        value: 0x2000,
+    },
+];
+
+/// Passes each write of the shutdown
+/// sequence to write, in order.
+/// write:    performs one port write
+fn run_sequence(mut write: impl FnMut(ShutdownWrite)) {
+    for &step in SHUTDOWN_SEQUENCE.iter() {
+        write(step);
+    }
+}
+
+/// Powers off the machine. Interrupts
+/// are disabled first, so nothing runs
+/// in between the writes. If the
+/// machine is still running after the
+/// whole sequence, a message is printed
+/// to serial and the CPU halts for good.
+pub fn shutdown() -> ! {
+    x86_64::instructions::interrupts::disable();
+    run_sequence(|step| unsafe { Port::new(step.port).write(step.value) });
+
+    serial_try_println!("shutdown: no power off method worked, halting");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Restarts the machine by pulsing the
+/// CPU reset line through the PS/2
+/// controller. If the controller does
+/// not take the command, or the reset
+/// does not happen soon after, a triple
+/// fault is forced instead, which
+/// resets the CPU as well.
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    if interrupts::pulse_reset_line().is_ok() {
+        for _ in 0..RESET_WAIT_SPINS {
+            core::hint::spin_loop();
+        }
+    }
+
+    serial_try_println!("reboot: keyboard controller did not reset, forcing a triple fault");
+    triple_fault();
+}
+
+/// Loads an empty IDT and raises an
+/// exception. The CPU cannot deliver it
+/// or the double fault that follows,
+/// so it shuts down and resets.
+fn triple_fault() -> ! {
+    use x86_64::{instructions::tables::lidt, structures::DescriptorTablePointer, VirtAddr};
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+// Records the writes of the shutdown
+// sequence instead of sending them,
+// since they would power off the test
+// machine, and ensures the QEMU port
+// is tried before the older one.
+#[test_case]
+fn test_shutdown_sequence_order() {
+    use alloc::vec::Vec;
+
+    let mut writes = Vec::new();
+    run_sequence(|step| writes.push(step));
+    assert_eq!(
+        writes,
+        [
+            ShutdownWrite {
+                port: 0x604,
+                value: 0x2000
+            },
+            ShutdownWrite {
+                port: 0xb004,
+                value: 0x2000
+            },
+        ]
+    );
+}
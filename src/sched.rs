@@ -0,0 +1,425 @@
+//! Preemptive kernel threads, separate
+//! from the async executor. Every timer
+//! interrupt saves the registers of the
+//! running thread on its stack and
+//! returns into the next ready thread,
+//! round-robin. The code running when
+//! the first thread is spawned becomes
+//! the boot thread and takes its turn
+//! like the others.
+
+use crate::error::KernelError;
+use alloc::{collections::VecDeque, vec, vec::Vec};
+use core::{
+    mem,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+/// Size in bytes of the stack of
+/// every spawned thread.
+pub const THREAD_STACK_SIZE: usize = 4096 * 4;
+
+/// Most spawned threads alive at
+/// once, not counting the boot thread.
+pub const MAX_THREADS: usize = 16;
+
+// Interrupt flag and the reserved
+// bit that is always set
+const THREAD_RFLAGS: u64 = 0x202;
+
+//// TIMER ENTRY
+
+// Entry point of the timer interrupt.
+// Every general purpose register is
+// pushed below the interrupt frame,
+// which leaves the stack 16 byte
+// aligned for the call. timer_dispatch
+// gets the stack pointer, which points
+// at the saved Context, and returns
+// the one of the thread to resume.
+core::arch::global_asm!(
+    ".global timer_entry",
+    "timer_entry:",
+    "cld",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "call {dispatch}",
+    "mov rsp, rax",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    dispatch = sym timer_dispatch,
+);
+
+extern "C" {
+    fn timer_entry();
+}
+
+/// Returns the address of the timer
+/// interrupt entry point for the IDT.
+pub(crate) fn timer_entry_addr() -> VirtAddr {
+    VirtAddr::new(timer_entry as unsafe extern "C" fn() as usize as u64)
+}
+
+/// Handles the tick and returns the
+/// stack pointer to resume from.
+extern "C" fn timer_dispatch(rsp: u64) -> u64 {
+    crate::interrupts::on_timer_tick();
+    switch(rsp)
+}
+
+/// Registers saved by timer_entry,
+/// lowest address first, followed by
+/// the frame pushed by the CPU.
+#[repr(C)]
+struct Context {
+    r15: u64,
+    r14: u64,
+    r13: u64,
+    r12: u64,
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rbp: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rbx: u64,
+    rax: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+//// THREADS
+
+/// Each thread is given a unique
+/// ID when it is spawned. The boot
+/// thread has ID 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// Returns a newly generated
+    /// thread ID.
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// Thread control block. While the
+/// thread is not running, rsp points at
+/// the Context saved on its stack.
+struct Thread {
+    id: ThreadId,
+    rsp: u64,
+    // Only kept to be freed with the
+    // thread. None for the boot thread,
+    // which keeps its own stack
+    _stack: Option<Vec<u8>>,
+    finished: bool,
+}
+
+impl Thread {
+    /// Control block of the code that
+    /// was running before the first
+    /// switch. Its rsp is set when it
+    /// is switched away from.
+    fn boot() -> Self {
+        Thread {
+            id: ThreadId(0),
+            rsp: 0,
+            _stack: None,
+            finished: false,
+        }
+    }
+
+    /// Creates a thread that starts in
+    /// thread_start with entry as its
+    /// argument, by building the Context
+    /// timer_entry restores on top of a
+    /// new stack.
+    fn new(entry: fn()) -> Self {
+        use x86_64::instructions::segmentation::{Segment, CS, SS};
+
+        let mut stack = vec![0u8; THREAD_STACK_SIZE];
+        let top = (stack.as_mut_ptr() as u64 + THREAD_STACK_SIZE as u64) & !0xf;
+
+        // thread_start is entered as if
+        // called, with a return address
+        // of 0 on the stack
+        let entry_rsp = top - 8;
+        let rsp = entry_rsp - mem::size_of::<Context>() as u64;
+        let context = Context {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            r11: 0,
+            r10: 0,
+            r9: 0,
+            r8: 0,
+            rbp: 0,
+            rdi: entry as usize as u64,
+            rsi: 0,
+            rdx: 0,
+            rcx: 0,
+            rbx: 0,
+            rax: 0,
+            rip: thread_start as extern "C" fn(usize) -> ! as usize as u64,
+            cs: u64::from(CS::get_reg().0),
+            rflags: THREAD_RFLAGS,
+            rsp: entry_rsp,
+            ss: u64::from(SS::get_reg().0),
+        };
+        unsafe {
+            (entry_rsp as *mut u64).write(0);
+            (rsp as *mut Context).write(context);
+        }
+
+        Thread {
+            id: ThreadId::new(),
+            rsp,
+            _stack: Some(stack),
+            finished: false,
+        }
+    }
+}
+
+/// First code run by a spawned thread,
+/// with the address of its fn() entry.
+/// The thread exits once entry returns.
+extern "C" fn thread_start(entry: usize) -> ! {
+    let entry: fn() = unsafe { mem::transmute(entry) };
+    entry();
+    exit_thread();
+}
+
+//// SCHEDULER
+
+/// The running thread, the threads
+/// waiting for their turn and the
+/// finished threads whose stacks are
+/// not freed yet. Nothing is allocated
+/// or freed by the timer interrupt, so
+/// it cannot deadlock on the allocator.
+struct Scheduler {
+    current: Option<Thread>,
+    ready: VecDeque<Thread>,
+    finished: Vec<Thread>,
+}
+
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    current: None,
+    ready: VecDeque::new(),
+    finished: Vec::new(),
+});
+
+// Number of spawned threads
+// that have not exited yet
+static LIVE_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Saves the stack pointer of the
+/// running thread, moves it to the back
+/// of the ready list and returns the
+/// stack pointer of the thread at the
+/// front. If there is no other thread,
+/// or the scheduler is locked, the
+/// running thread continues.
+/// rsp:      stack pointer of the Context
+fn switch(rsp: u64) -> u64 {
+    let mut scheduler = match SCHEDULER.try_lock() {
+        Some(scheduler) => scheduler,
+        None => return rsp,
+    };
+    let next = match scheduler.ready.pop_front() {
+        Some(next) => next,
+        None => return rsp,
+    };
+
+    let mut previous = scheduler.current.take().unwrap_or_else(Thread::boot);
+    previous.rsp = rsp;
+    if previous.finished {
+        scheduler.finished.push(previous);
+    } else {
+        scheduler.ready.push_back(previous);
+    }
+
+    let rsp = next.rsp;
+    scheduler.current = Some(next);
+    rsp
+}
+
+/// Starts running entry on a new
+/// thread from the next timer tick on.
+/// The stacks of threads that exited
+/// are freed first. TooManyThreads is
+/// returned if MAX_THREADS spawned
+/// threads are still running.
+/// entry:    function the thread runs
+pub fn spawn_thread(entry: fn()) -> Result<ThreadId, KernelError> {
+    use x86_64::instructions::interrupts;
+
+    reap_threads();
+    if LIVE_THREADS.load(Ordering::Relaxed) >= MAX_THREADS {
+        return Err(KernelError::TooManyThreads { max: MAX_THREADS });
+    }
+
+    let thread = Thread::new(entry);
+    let id = thread.id;
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+
+        // Room for every thread and the
+        // boot thread, so switch never
+        // has to allocate
+        scheduler.ready.reserve(MAX_THREADS + 1);
+        scheduler.finished.reserve(MAX_THREADS + 1);
+        scheduler.ready.push_back(thread);
+    });
+    LIVE_THREADS.fetch_add(1, Ordering::Relaxed);
+    Ok(id)
+}
+
+/// Ends the running thread. Its stack
+/// is freed by a later spawn_thread
+/// or reap_threads. It panics if it is
+/// called from the boot thread, which
+/// was not spawned and cannot exit.
+pub fn exit_thread() -> ! {
+    let spawned = x86_64::instructions::interrupts::without_interrupts(|| {
+        match SCHEDULER.lock().current.as_mut() {
+            Some(thread) if thread.id != ThreadId(0) => {
+                thread.finished = true;
+                true
+            }
+            _ => false,
+        }
+    });
+    assert!(spawned, "exit_thread called from the boot thread");
+    LIVE_THREADS.fetch_sub(1, Ordering::Relaxed);
+
+    // The next tick switches away
+    // and never comes back
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Frees the stacks of the threads
+/// that exited. Each stack is dropped
+/// after the lock is released, with
+/// interrupts enabled again. The
+/// threads are popped one at a time,
+/// so the finished list keeps the room
+/// switch relies on.
+pub fn reap_threads() {
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    while let Some(thread) = without_interrupts(|| SCHEDULER.lock().finished.pop()) {
+        drop(thread);
+    }
+}
+
+/// Returns the number of spawned
+/// threads that have not exited.
+pub fn thread_count() -> usize {
+    LIVE_THREADS.load(Ordering::Relaxed)
+}
+
+// Counters of the test threads and
+// the flag that keeps them running
+#[cfg(test)]
+static COUNTERS: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+#[cfg(test)]
+static RUN_COUNTERS: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(false);
+
+#[cfg(test)]
+fn count_first() {
+    while RUN_COUNTERS.load(Ordering::Relaxed) {
+        COUNTERS[0].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+fn count_second() {
+    while RUN_COUNTERS.load(Ordering::Relaxed) {
+        COUNTERS[1].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Spawns two threads that never yield
+// and ensures both counters advance
+// while the test thread waits, so the
+// timer must be switching between all
+// three. The threads are stopped and
+// reaped afterwards, and reaping must
+// keep the room switch relies on.
+#[test_case]
+fn test_threads_are_preempted() {
+    use crate::interrupts::delay_ms;
+
+    RUN_COUNTERS.store(true, Ordering::Relaxed);
+    spawn_thread(count_first).expect("spawn failed");
+    spawn_thread(count_second).expect("spawn failed");
+    assert_eq!(thread_count(), 2);
+
+    delay_ms(100);
+    let first = [
+        COUNTERS[0].load(Ordering::Relaxed),
+        COUNTERS[1].load(Ordering::Relaxed),
+    ];
+    assert!(first[0] > 0 && first[1] > 0);
+
+    delay_ms(100);
+    assert!(COUNTERS[0].load(Ordering::Relaxed) > first[0]);
+    assert!(COUNTERS[1].load(Ordering::Relaxed) > first[1]);
+
+    RUN_COUNTERS.store(false, Ordering::Relaxed);
+    while thread_count() > 0 {
+        x86_64::instructions::hlt();
+    }
+    delay_ms(50);
+    reap_threads();
+
+    let (finished, capacity) = x86_64::instructions::interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        (scheduler.finished.len(), scheduler.finished.capacity())
+    });
+    assert_eq!(finished, 0);
+    assert!(capacity > MAX_THREADS);
+}
@@ -1,3 +1,14 @@
+use alloc::{string::String, vec::Vec};
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
+};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
@@ -13,11 +24,25 @@ lazy_static! {
     };
 }
 
+// Second serial port, used as a
+// separate channel such as debug
+// tracing. It is only initialized
+// when first used, so machines
+// without COM2 are not touched
+// unless something prints to it.
+lazy_static! {
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(0x2F8) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
 // Print function to write the
 // specified arguments to the
-// serial port
+// given serial port
 #[doc(hidden)]
-pub fn _print(args: ::core::fmt::Arguments) {
+pub fn _print(port: &Mutex<SerialPort>, args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
@@ -25,19 +50,35 @@ pub fn _print(args: ::core::fmt::Arguments) {
     // deadlocks from printing to
     // serial port.
     interrupts::without_interrupts(|| {
-        SERIAL1
-            .lock()
+        port.lock()
             .write_fmt(args)
             .expect("Printing to serial failed");
     });
 }
 
+/// Prints the arguments to the given
+/// serial port if it is not locked and
+/// returns whether they were printed.
+#[doc(hidden)]
+pub fn _try_print(port: &Mutex<SerialPort>, args: ::core::fmt::Arguments) -> bool {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| match port.try_lock() {
+        Some(mut port) => {
+            port.write_fmt(args).expect("Printing to serial failed");
+            true
+        }
+        None => false,
+    })
+}
+
 // Useful macro for printing
 // formatted text to the serial port
 #[macro_export]
 macro_rules! serial_print {
   ($($arg:tt)*) => {
-    $crate::serial::_print(format_args!($($arg)*));
+    $crate::serial::_print(&$crate::serial::SERIAL1, format_args!($($arg)*));
   }
 }
 
@@ -49,3 +90,214 @@ macro_rules! serial_println {
   ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
           concat!($fmt, "\n"), $($arg)*));
 }
+
+// Print to the serial port from
+// interrupt handlers and the test
+// panic handler. The output is dropped
+// if SERIAL1 is already locked, so the
+// interrupted code cannot deadlock them.
+#[macro_export]
+macro_rules! serial_try_print {
+  ($($arg:tt)*) => {
+    $crate::serial::_try_print(&$crate::serial::SERIAL1, format_args!($($arg)*))
+  }
+}
+
+#[macro_export]
+macro_rules! serial_try_println {
+  () => ($crate::serial_try_print!("\n"));
+  ($fmt:expr) => ($crate::serial_try_print!(concat!($fmt, "\n")));
+  ($fmt:expr, $($arg:tt)*) => ($crate::serial_try_print!(
+          concat!($fmt, "\n"), $($arg)*));
+}
+
+// Print to the second serial port
+#[macro_export]
+macro_rules! serial_print2 {
+  ($($arg:tt)*) => {
+    $crate::serial::_print(&$crate::serial::SERIAL2, format_args!($($arg)*));
+  }
+}
+
+// Println to the second serial port
+#[macro_export]
+macro_rules! serial_println2 {
+  () => ($crate::serial_print2!("\n"));
+  ($fmt:expr) => ($crate::serial_print2!(concat!($fmt, "\n")));
+  ($fmt:expr, $($arg:tt)*) => ($crate::serial_print2!(
+          concat!($fmt, "\n"), $($arg)*));
+}
+
+// Ensures writing to COM2 does not
+// panic, even though the output
+// cannot be read back in the test.
+#[test_case]
+fn test_serial_println2() {
+    serial_println2!("test_serial_println2 output");
+}
+
+//// STORE INCOMING SERIAL BYTES
+
+/// Queue of bytes received on the
+/// serial port. It is initialized
+/// by split so bytes are only
+/// buffered once a reader exists.
+static SERIAL_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+/// Wakes the task waiting on
+/// the SerialReader when a
+/// byte is received.
+static SERIAL_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Function used by the serial
+/// interrupt handler to add a
+/// received byte to the buffer.
+/// Bytes are dropped if the queue
+/// is full or uninitialized, since
+/// printing a warning over the same
+/// serial port would only add noise.
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = SERIAL_QUEUE.try_get() {
+        if queue.push(byte).is_ok() {
+            SERIAL_WAKER.wake();
+        }
+    }
+}
+
+//// ASYNC READER AND WRITER HALVES
+
+/// Splits the serial console into an
+/// async reader and a writer. This can
+/// only be called once, since there is
+/// only one queue of received bytes.
+pub fn split() -> (SerialReader, SerialWriter) {
+    SERIAL_QUEUE
+        .try_init_once(|| ArrayQueue::new(100))
+        .expect("serial::split should only be called once");
+    (SerialReader { _private: () }, SerialWriter { _private: () })
+}
+
+/// Read half of the serial console
+/// that yields each byte received
+/// by the serial interrupt handler.
+pub struct SerialReader {
+    _private: (),
+}
+
+impl Stream for SerialReader {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
+        let queue = SERIAL_QUEUE
+            .try_get()
+            .expect("SERIAL_QUEUE not initialized");
+
+        // Return a byte if one
+        // is already available
+        if let Ok(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        // Register the waker before checking
+        // again so a byte received in between
+        // is not missed
+        SERIAL_WAKER.register(context.waker());
+        match queue.pop() {
+            Ok(byte) => {
+                SERIAL_WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+/// Waits for a line from the serial
+/// console and returns it without the
+/// line ending. Both \n and \r\n end a
+/// line, and invalid UTF-8 is replaced.
+/// Any byte stream can be read, so
+/// tests can feed lines without the
+/// serial port.
+pub async fn read_serial_line<S>(reader: &mut S) -> String
+where
+    S: Stream<Item = u8> + Unpin,
+{
+    let mut line = Vec::new();
+    while let Some(byte) = reader.next().await {
+        match byte {
+            b'\n' => break,
+            b'\r' => {}
+            byte => line.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&line).into_owned()
+}
+
+/// Write half of the serial console.
+pub struct SerialWriter {
+    _private: (),
+}
+
+impl SerialWriter {
+    /// Sends every byte to the serial
+    /// port as is, so binary data such as
+    /// 0x08 and 0x7f is not turned into
+    /// backspaces. Sending polls the port
+    /// until each byte is accepted, so
+    /// this returns once all are sent.
+    pub fn write_all(&mut self, bytes: &[u8]) {
+        use x86_64::instructions::interrupts;
+
+        // Disable interrupts so the bytes
+        // are not interleaved with other
+        // serial output.
+        interrupts::without_interrupts(|| {
+            let mut serial = SERIAL1.lock();
+            for &byte in bytes {
+                serial.send_raw(byte);
+            }
+        });
+    }
+}
+
+// Injects bytes as if they were
+// received by the interrupt handler
+// and ensures the reader yields them
+// in order and reads them as a line,
+// then writes with the writer half.
+#[test_case]
+fn test_split_reader_writer() {
+    use alloc::boxed::Box;
+    use core::future::Future;
+    use futures_util::task::noop_waker_ref;
+
+    let (mut reader, mut writer) = split();
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        add_byte(b'o');
+        add_byte(b'k');
+    });
+    assert_eq!(
+        Pin::new(&mut reader).poll_next(&mut context),
+        Poll::Ready(Some(b'o'))
+    );
+    assert_eq!(
+        Pin::new(&mut reader).poll_next(&mut context),
+        Poll::Ready(Some(b'k'))
+    );
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for &byte in b"stats\r\n" {
+            add_byte(byte);
+        }
+    });
+    let mut line = Box::pin(read_serial_line(&mut reader));
+    assert_eq!(
+        line.as_mut().poll(&mut context),
+        Poll::Ready(String::from("stats"))
+    );
+
+    writer.write_all(b"serial writer output\n");
+}
@@ -0,0 +1,175 @@
+//! Driver for the PC speaker. The
+//! speaker is driven by channel 2 of
+//! the programmable interval timer
+//! (PIT) and gated through the system
+//! control port at 0x61. The module
+//! is also reachable as speaker.
+
+use crate::interrupts::{self, PIT_COMMAND_PORT, PIT_FREQUENCY};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+/// Silences the speaker, see tone_off.
+pub use self::tone_off as off;
+
+// PIT port for channel 2 data
+const PIT_CHANNEL_2_PORT: u16 = 0x42;
+
+// System control port B. Bit 0 gates
+// PIT channel 2 and bit 1 connects
+// its output to the speaker.
+const SPEAKER_PORT: u16 = 0x61;
+const SPEAKER_ENABLE_BITS: u8 = 0b11;
+
+/// Range of frequencies in Hz that
+/// tones are clamped to.
+pub const MIN_FREQUENCY: u32 = 20;
+pub const MAX_FREQUENCY: u32 = 20_000;
+
+// Tick at which the timer interrupt
+// turns the speaker off, or 0 if
+// no tone is scheduled to end.
+static OFF_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the PIT channel 2 divisor
+/// for a frequency after clamping it
+/// to the audible range.
+fn divisor(freq_hz: u32) -> u16 {
+    let freq_hz = freq_hz.clamp(MIN_FREQUENCY, MAX_FREQUENCY);
+    (PIT_FREQUENCY / freq_hz) as u16
+}
+
+/// Plays a tone on the speaker and
+/// returns immediately. The timer
+/// interrupt turns the speaker off
+/// once duration_ticks have passed.
+/// freq_hz:          tone frequency
+/// duration_ticks:   length of the tone
+pub fn play_tone(freq_hz: u32, duration_ticks: u64) {
+    // The deadline of an earlier tone
+    // must not end this one before the
+    // new deadline is stored
+    without_interrupts(|| {
+        tone_on(freq_hz);
+        let deadline = interrupts::ticks().saturating_add(duration_ticks.max(1));
+        OFF_DEADLINE.store(deadline, Ordering::Relaxed);
+    });
+}
+
+/// Plays a tone on the speaker and
+/// waits for it to end before turning
+/// the speaker off. This halts between
+/// timer interrupts, so it panics if
+/// interrupts are disabled.
+/// freq_hz:      tone frequency
+/// duration_ms:  length of the tone
+pub fn beep(freq_hz: u32, duration_ms: u64) {
+    // A tone started by play_tone
+    // must not cut this one short
+    without_interrupts(|| {
+        OFF_DEADLINE.store(0, Ordering::Relaxed);
+        tone_on(freq_hz);
+    });
+    interrupts::delay_ms(duration_ms);
+    tone_off();
+}
+
+/// Called by the timer interrupt handler
+/// to end a tone started by play_tone.
+pub(crate) fn on_tick(ticks: u64) {
+    let deadline = OFF_DEADLINE.load(Ordering::Relaxed);
+    if deadline != 0 && ticks >= deadline {
+        tone_off();
+    }
+}
+
+/// Returns true if the speaker is
+/// connected to PIT channel 2.
+pub fn is_on() -> bool {
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+    unsafe { speaker.read() & SPEAKER_ENABLE_BITS == SPEAKER_ENABLE_BITS }
+}
+
+/// Starts a square wave of the given
+/// frequency on the speaker. It keeps
+/// sounding until tone_off is called. The
+/// frequency is clamped to the range
+/// MIN_FREQUENCY..=MAX_FREQUENCY.
+/// Interrupts are disabled while the
+/// ports are written, so the timer
+/// interrupt cannot turn the speaker
+/// off in the middle of it.
+/// freq_hz:      tone frequency
+pub fn tone_on(freq_hz: u32) {
+    let divisor = divisor(freq_hz);
+
+    let mut command: Port<u8> = Port::new(PIT_COMMAND_PORT);
+    let mut channel_2: Port<u8> = Port::new(PIT_CHANNEL_2_PORT);
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+    without_interrupts(|| unsafe {
+        // Channel 2, low byte then high
+        // byte, mode 3 (square wave)
+        command.write(0b1011_0110);
+        channel_2.write(divisor as u8);
+        channel_2.write((divisor >> 8) as u8);
+
+        let value = speaker.read();
+        speaker.write(value | SPEAKER_ENABLE_BITS);
+    });
+}
+
+/// Silences the speaker by disconnecting
+/// it from PIT channel 2. The control
+/// port is read and written with
+/// interrupts disabled, so it cannot
+/// race the timer interrupt.
+pub fn tone_off() {
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+    without_interrupts(|| unsafe {
+        OFF_DEADLINE.store(0, Ordering::Relaxed);
+        let value = speaker.read();
+        speaker.write(value & !SPEAKER_ENABLE_BITS);
+    });
+}
+
+// Ensures frequencies outside the
+// audible range are clamped before
+// the divisor is computed.
+#[test_case]
+fn test_divisor_clamps_frequency() {
+    assert_eq!(divisor(440), (PIT_FREQUENCY / 440) as u16);
+    assert_eq!(divisor(0), divisor(MIN_FREQUENCY));
+    assert_eq!(divisor(1_000_000), divisor(MAX_FREQUENCY));
+}
+
+// Plays a short tone and ensures the
+// speaker is enabled until the timer
+// interrupt turns it off again.
+#[test_case]
+fn test_play_tone() {
+    crate::speaker::play_tone(440, 1);
+    assert!(is_on());
+
+    while OFF_DEADLINE.load(Ordering::Relaxed) != 0 {
+        x86_64::instructions::hlt();
+    }
+    assert!(!is_on());
+}
+
+// Beeps for a few milliseconds and
+// ensures the speaker is off afterwards
+// while the other writable bits of the
+// control port are left as they were.
+#[test_case]
+fn test_beep_turns_speaker_off() {
+    const OTHER_BITS: u8 = 0b1100;
+
+    let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+    let before = unsafe { speaker.read() };
+    beep(880, 5);
+    let after = unsafe { speaker.read() };
+
+    assert!(!is_on());
+    assert_eq!(after & SPEAKER_ENABLE_BITS, 0);
+    assert_eq!(after & OTHER_BITS, before & OTHER_BITS);
+}
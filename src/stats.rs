@@ -0,0 +1,201 @@
+//! Module for answering the host
+//! `stats` command sent over the
+//! serial port. The response is a
+//! fixed-layout snapshot of the heap,
+//! physical frames and timer ticks so
+//! host tooling can monitor the kernel
+//! during long test runs.
+
+use crate::{allocator, interrupts, memory, serial::read_serial_line};
+use core::sync::atomic::{AtomicU64, Ordering};
+use futures_util::stream::Stream;
+
+/// Command frame sent by the host
+/// to request a stats snapshot.
+/// Trailing line endings are ignored.
+pub const STATS_COMMAND: &[u8] = b"stats";
+
+/// Magic bytes at the start of
+/// every stats response frame.
+pub const RESPONSE_MAGIC: [u8; 4] = *b"STAT";
+
+// Number of u64 fields stored
+// in a StatsResponse.
+const RESPONSE_FIELDS: usize = 6;
+
+/// Length of an encoded response frame:
+/// the magic followed by each field
+/// as a little-endian u64.
+pub const RESPONSE_LEN: usize = RESPONSE_MAGIC.len() + RESPONSE_FIELDS * 8;
+
+/// Structured answer to the `stats`
+/// command. Fields are encoded in
+/// declaration order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct StatsResponse {
+    pub heap_used: u64,
+    pub heap_free: u64,
+    pub allocations: u64,
+    pub frames_usable: u64,
+    pub frames_allocated: u64,
+    pub ticks: u64,
+}
+
+impl StatsResponse {
+    /// Collects the current heap,
+    /// frame and tick statistics.
+    pub fn snapshot() -> Self {
+        let heap = allocator::heap_stats();
+        let frames = memory::frame_stats();
+        StatsResponse {
+            heap_used: heap.used as u64,
+            heap_free: heap.free as u64,
+            allocations: heap.allocations as u64,
+            frames_usable: frames.usable as u64,
+            frames_allocated: frames.allocated as u64,
+            ticks: interrupts::ticks(),
+        }
+    }
+
+    /// Returns the fields in the
+    /// order they are encoded.
+    fn fields(&self) -> [u64; RESPONSE_FIELDS] {
+        [
+            self.heap_used,
+            self.heap_free,
+            self.allocations,
+            self.frames_usable,
+            self.frames_allocated,
+            self.ticks,
+        ]
+    }
+
+    /// Encodes the response into
+    /// a fixed-length frame.
+    pub fn to_bytes(&self) -> [u8; RESPONSE_LEN] {
+        let mut bytes = [0; RESPONSE_LEN];
+        bytes[..RESPONSE_MAGIC.len()].copy_from_slice(&RESPONSE_MAGIC);
+
+        let fields = bytes[RESPONSE_MAGIC.len()..].chunks_exact_mut(8);
+        for (chunk, field) in fields.zip(self.fields().iter()) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes a response frame. None
+    /// is returned if the frame has the
+    /// wrong length or magic bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != RESPONSE_LEN || bytes[..RESPONSE_MAGIC.len()] != RESPONSE_MAGIC {
+            return None;
+        }
+
+        let mut fields = [0u64; RESPONSE_FIELDS];
+        let chunks = bytes[RESPONSE_MAGIC.len()..].chunks_exact(8);
+        for (field, chunk) in fields.iter_mut().zip(chunks) {
+            let mut raw = [0; 8];
+            raw.copy_from_slice(chunk);
+            *field = u64::from_le_bytes(raw);
+        }
+
+        Some(StatsResponse {
+            heap_used: fields[0],
+            heap_free: fields[1],
+            allocations: fields[2],
+            frames_usable: fields[3],
+            frames_allocated: fields[4],
+            ticks: fields[5],
+        })
+    }
+}
+
+/// Returns the encoded response for
+/// a command frame received from the
+/// host, or None if the frame is not
+/// a known command.
+pub fn handle_command(frame: &[u8]) -> Option<[u8; RESPONSE_LEN]> {
+    // Strip any line endings the
+    // host terminal appended
+    let mut end = frame.len();
+    while end > 0 && (frame[end - 1] == b'\n' || frame[end - 1] == b'\r') {
+        end -= 1;
+    }
+
+    if &frame[..end] == STATS_COMMAND {
+        Some(StatsResponse::snapshot().to_bytes())
+    } else {
+        None
+    }
+}
+
+// Number of command lines received
+// over the serial port that were
+// answered by serve_commands.
+static ANSWERED: AtomicU64 = AtomicU64::new(0);
+
+/// Task that reads command lines from
+/// the serial port and passes the
+/// response to each one to send. Lines
+/// that are not a command are ignored.
+/// bytes:    bytes received from the host
+/// send:     writes a response to the host
+pub async fn serve_commands<S, W>(mut bytes: S, mut send: W)
+where
+    S: Stream<Item = u8> + Unpin,
+    W: FnMut(&[u8]),
+{
+    loop {
+        let line = read_serial_line(&mut bytes).await;
+        if let Some(response) = handle_command(line.as_bytes()) {
+            send(&response);
+            ANSWERED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Returns the number of commands
+/// answered by serve_commands.
+pub fn answered_commands() -> u64 {
+    ANSWERED.load(Ordering::Relaxed)
+}
+
+// Feeds a line that is not a command
+// and a stats command to the serve
+// task and ensures only the command
+// is answered, with a response frame
+// that decodes to the current stats
+// and ticks. The responses are
+// collected instead of being sent
+// over serial.
+#[test_case]
+fn test_serve_commands() {
+    use crate::task::{executor::Executor, Task};
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+    use futures_util::stream::{iter, pending, StreamExt};
+
+    let bytes = iter(b"hello\r\nstats\r\n".iter().copied()).chain(pending());
+    let sent = Rc::new(RefCell::new(Vec::new()));
+    let send = {
+        let sent = sent.clone();
+        move |response: &[u8]| sent.borrow_mut().push(response.to_vec())
+    };
+    let answered = answered_commands();
+    let mut executor = Executor::new();
+    executor
+        .spawn(Task::new(serve_commands(bytes, send)))
+        .expect("spawn failed");
+    let before = interrupts::ticks();
+    executor.run_ready_tasks();
+    let after = interrupts::ticks();
+
+    assert_eq!(answered_commands(), answered + 1);
+    let sent = sent.borrow();
+    assert_eq!(sent.len(), 1);
+    let response = StatsResponse::from_bytes(&sent[0]).expect("invalid stats response");
+    assert!(response.heap_used > 0);
+    assert!(response.frames_allocated <= response.frames_usable);
+    assert!(response.ticks >= before && response.ticks <= after);
+}
@@ -0,0 +1,248 @@
+//! System calls made with int 0x80.
+//! This is the boundary between user
+//! programs and the kernel, so the
+//! register convention below is the
+//! ABI and must stay stable:
+//!
+//! rax:  system call number, and the
+//!       return value after the call
+//! rdi:  first argument
+//! rsi:  second argument
+//! rdx:  third argument
+//!
+//! Every other register is preserved.
+//! A call that fails returns
+//! SYSCALL_ERROR.
+
+use crate::{
+    hlt_loop,
+    memory::{self, KERNEL_MEMORY},
+    print, println,
+};
+use x86_64::VirtAddr;
+
+/// Interrupt vector of system calls
+pub const SYSCALL_VECTOR: u8 = 0x80;
+
+/// Writes the UTF-8 buffer at rdi of
+/// length rsi to the screen. Returns
+/// the number of bytes written. The
+/// buffer must be mapped for user code
+/// and at most 4096 bytes long.
+pub const SYS_WRITE: u64 = 0;
+/// Ends the calling program with the
+/// exit code in rdi. Does not return.
+pub const SYS_EXIT: u64 = 1;
+/// Returns the number of timer
+/// interrupts since boot.
+pub const SYS_GET_TICKS: u64 = 2;
+
+/// Returned in rax when a call fails
+/// or the number is unknown.
+pub const SYSCALL_ERROR: u64 = u64::MAX;
+
+/// Longest buffer SYS_WRITE accepts
+const MAX_WRITE_LEN: usize = 4096;
+
+// Entry point of the int 0x80 gate.
+// The CPU has pushed the interrupt
+// frame, which leaves the stack 8 bytes
+// off a 16 byte boundary. The caller
+// saved registers other than rax are
+// saved, which together with the 8
+// byte pad realigns the stack, and the
+// arguments are moved to the System V
+// registers of syscall_dispatch.
+core::arch::global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "cld",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "sub rsp, 8",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "call {dispatch}",
+    "add rsp, 8",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "iretq",
+    dispatch = sym syscall_dispatch,
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Returns the address of the int
+/// 0x80 entry point for the IDT.
+pub(crate) fn entry_addr() -> VirtAddr {
+    VirtAddr::new(syscall_entry as unsafe extern "C" fn() as usize as u64)
+}
+
+/// Runs the system call and returns
+/// the value placed in rax. Interrupts
+/// are disabled while it runs.
+extern "C" fn syscall_dispatch(number: u64, arg0: u64, arg1: u64, _arg2: u64) -> u64 {
+    match number {
+        SYS_WRITE => sys_write(arg0, arg1),
+        SYS_EXIT => sys_exit(arg0),
+        SYS_GET_TICKS => crate::interrupts::ticks(),
+        _ => SYSCALL_ERROR,
+    }
+}
+
+/// Prints the buffer if it is valid
+/// UTF-8 and not too long. The buffer
+/// is copied onto the kernel stack with
+/// copy_from_user first, so a pointer
+/// that is not mapped for user code
+/// fails the call instead of faulting
+/// the kernel. The call also fails if
+/// the page tables are locked by the
+/// code it interrupted.
+fn sys_write(ptr: u64, len: u64) -> u64 {
+    if len > MAX_WRITE_LEN as u64 {
+        return SYSCALL_ERROR;
+    }
+    let src = match VirtAddr::try_new(ptr) {
+        Ok(src) => src,
+        Err(_) => return SYSCALL_ERROR,
+    };
+
+    let mut buffer = [0u8; MAX_WRITE_LEN];
+    let bytes = &mut buffer[..len as usize];
+    let copied = match KERNEL_MEMORY.try_lock() {
+        Some(memory) => match memory.as_ref() {
+            Some((mapper, _)) => memory::copy_from_user(bytes, src, mapper),
+            None => return SYSCALL_ERROR,
+        },
+        None => return SYSCALL_ERROR,
+    };
+    if copied.is_err() {
+        return SYSCALL_ERROR;
+    }
+
+    match core::str::from_utf8(bytes) {
+        Ok(text) => {
+            print!("{}", text);
+            len
+        }
+        Err(_) => SYSCALL_ERROR,
+    }
+}
+
+/// There are no processes to end yet,
+/// so the CPU is halted.
+fn sys_exit(code: u64) -> u64 {
+    println!("program exited with code {}", code);
+    hlt_loop();
+}
+
+/// Makes a system call with up to
+/// three arguments and returns rax.
+/// number:   system call number
+/// args:     values for rdi, rsi, rdx
+///
+/// # Safety
+///
+/// The arguments must be valid for the
+/// call, such as a pointer and length
+/// that the kernel can read for write.
+pub unsafe fn syscall(number: u64, args: [u64; 3]) -> u64 {
+    let result: u64;
+    core::arch::asm!(
+        "int 0x80",
+        inout("rax") number => result,
+        in("rdi") args[0],
+        in("rsi") args[1],
+        in("rdx") args[2],
+    );
+    result
+}
+
+// Calls get_ticks from ring 0 and
+// ensures the count is between the
+// ticks before and after the call.
+#[test_case]
+fn test_syscall_get_ticks() {
+    let before = crate::interrupts::ticks();
+    let ticks = unsafe { syscall(SYS_GET_TICKS, [0; 3]) };
+    assert!(ticks >= before && ticks <= crate::interrupts::ticks());
+}
+
+// Writes a buffer from a page mapped
+// for user code and ensures its length
+// is returned, and that an unknown
+// number fails. The page tables are
+// not locked during the calls, since
+// sys_write needs them.
+#[test_case]
+fn test_syscall_write() {
+    use x86_64::structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags as Flags,
+    };
+
+    let text = b"syscall write\n";
+    let page: Page = Page::containing_address(VirtAddr::new(0x_3333_3334_0000));
+    {
+        let mut memory = KERNEL_MEMORY.lock();
+        let (mapper, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+        let frame = frame_allocator.allocate_frame().expect("no frame");
+        let flags = Flags::PRESENT | Flags::WRITABLE | Flags::USER_ACCESSIBLE;
+        unsafe {
+            mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .expect("map_to failed")
+                .flush();
+            page.start_address()
+                .as_mut_ptr::<[u8; 14]>()
+                .write_volatile(*text);
+        }
+    }
+
+    let ptr = page.start_address().as_u64();
+    let written = unsafe { syscall(SYS_WRITE, [ptr, text.len() as u64, 0]) };
+
+    let mut memory = KERNEL_MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("test memory not initialized");
+    let frame = memory::unmap_page(page, mapper).expect("unmap failed");
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    drop(memory);
+
+    assert_eq!(written, text.len() as u64);
+    assert_eq!(unsafe { syscall(1000, [0; 3]) }, SYSCALL_ERROR);
+}
+
+// Passes an unmapped pointer, a kernel
+// only buffer, a non-canonical pointer
+// and a buffer that is too long to
+// SYS_WRITE and ensures each fails
+// without faulting the kernel.
+#[test_case]
+fn test_syscall_write_rejects_bad_buffers() {
+    let kernel_text = "kernel only";
+    let calls = [
+        [0x_7777_7777_0000, 16, 0],
+        [kernel_text.as_ptr() as u64, kernel_text.len() as u64, 0],
+        [0x_8000_0000_0000, 16, 0],
+        [0x_7777_7777_0000, MAX_WRITE_LEN as u64 + 1, 0],
+    ];
+    for &args in calls.iter() {
+        assert_eq!(unsafe { syscall(SYS_WRITE, args) }, SYSCALL_ERROR);
+    }
+}
@@ -0,0 +1,383 @@
+//! Bounded channel for sending values
+//! from one or more tasks to another.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    task::{Context, Poll, Waker},
+};
+use crossbeam_queue::{ArrayQueue, PushError};
+use futures_util::task::AtomicWaker;
+
+/// State shared by both ends of a
+/// channel: the buffered values, the
+/// wakers of each end and the number
+/// of senders still alive. Each
+/// waiting send future has one waker
+/// slot, found by its waiter id.
+struct Channel<T> {
+    queue: ArrayQueue<T>,
+    receiver_waker: AtomicWaker,
+    sender_wakers: spin::Mutex<VecDeque<(usize, Waker)>>,
+    next_waiter: AtomicUsize,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+}
+
+impl<T> Channel<T> {
+    /// Stores the waker of a waiting send
+    /// future, replacing the one it
+    /// stored on an earlier poll.
+    /// waiter:   id of the send future
+    /// waker:    waker to store
+    fn register_sender(&self, waiter: usize, waker: &Waker) {
+        let mut wakers = self.sender_wakers.lock();
+        match wakers.iter_mut().find(|(id, _)| *id == waiter) {
+            Some((_, stored)) => {
+                if !stored.will_wake(waker) {
+                    *stored = waker.clone();
+                }
+            }
+            None => wakers.push_back((waiter, waker.clone())),
+        }
+    }
+
+    /// Removes the waker slot of a send
+    /// future. Returns false if there was
+    /// none, because the future has
+    /// already been woken.
+    /// waiter:   id of the send future
+    fn remove_sender(&self, waiter: usize) -> bool {
+        let mut wakers = self.sender_wakers.lock();
+        match wakers.iter().position(|(id, _)| *id == waiter) {
+            Some(index) => {
+                wakers.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes the sender that has waited
+    /// the longest for room.
+    fn wake_sender(&self) {
+        let waker = self.sender_wakers.lock().pop_front();
+        if let Some((_, waker)) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Wakes every waiting sender, used
+    /// when the receiver is dropped.
+    fn wake_all_senders(&self) {
+        let wakers: VecDeque<(usize, Waker)> = core::mem::take(&mut *self.sender_wakers.lock());
+        wakers.into_iter().for_each(|(_, waker)| waker.wake());
+    }
+}
+
+/// Creates a channel that buffers up to
+/// capacity values. Sending waits while
+/// the buffer is full, and receiving
+/// waits while it is empty. A channel
+/// has to hold at least one value, so
+/// this panics if capacity is 0.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be at least 1");
+
+    let channel = Arc::new(Channel {
+        queue: ArrayQueue::new(capacity),
+        receiver_waker: AtomicWaker::new(),
+        sender_wakers: spin::Mutex::new(VecDeque::new()),
+        next_waiter: AtomicUsize::new(0),
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+    });
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+//// SENDER
+
+/// Sending end of a channel. It can be
+/// cloned to send from several tasks;
+/// the channel closes once all clones
+/// are dropped.
+pub struct Sender<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Sender<T> {
+    /// Returns a future that sends the
+    /// value once there is room. It
+    /// resolves to Err with the value if
+    /// the receiver has been dropped.
+    pub fn send(&self, value: T) -> SendFuture<T> {
+        SendFuture {
+            channel: &self.channel,
+            value: Some(value),
+            waiter: None,
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.senders.fetch_add(1, Ordering::AcqRel);
+        Sender {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    /// Closes the channel when the last
+    /// sender is dropped, waking the
+    /// receiver so it can return None.
+    fn drop(&mut self) {
+        if self.channel.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.channel.receiver_waker.wake();
+        }
+    }
+}
+
+/// Future returned by Sender::send.
+/// waiter is the id of its waker slot
+/// once it has had to wait for room.
+pub struct SendFuture<'a, T> {
+    channel: &'a Channel<T>,
+    value: Option<T>,
+    waiter: Option<usize>,
+}
+
+// The value is only moved, never
+// pinned, so SendFuture can be Unpin.
+impl<'a, T> Unpin for SendFuture<'a, T> {}
+
+impl<'a, T> SendFuture<'a, T> {
+    /// Pushes the value into the queue,
+    /// or returns it if the queue is full.
+    fn try_push(&mut self) -> Option<T> {
+        let value = self
+            .value
+            .take()
+            .expect("SendFuture polled after completion");
+        match self.channel.queue.push(value) {
+            Ok(()) => {
+                if let Some(waiter) = self.waiter.take() {
+                    self.channel.remove_sender(waiter);
+                }
+                self.channel.receiver_waker.wake();
+                None
+            }
+            Err(PushError(value)) => Some(value),
+        }
+    }
+}
+
+impl<'a, T> Future for SendFuture<'a, T> {
+    type Output = Result<(), T>;
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<(), T>> {
+        if !self.channel.receiver_alive.load(Ordering::Acquire) {
+            let value = self
+                .value
+                .take()
+                .expect("SendFuture polled after completion");
+            return Poll::Ready(Err(value));
+        }
+
+        let value = match self.try_push() {
+            None => return Poll::Ready(Ok(())),
+            Some(value) => value,
+        };
+
+        // Register the waker before trying
+        // again so room made in between
+        // is not missed
+        let channel = self.channel;
+        let waiter = *self
+            .waiter
+            .get_or_insert_with(|| channel.next_waiter.fetch_add(1, Ordering::Relaxed));
+        channel.register_sender(waiter, context.waker());
+        self.value = Some(value);
+        match self.try_push() {
+            None => Poll::Ready(Ok(())),
+            Some(value) => {
+                self.value = Some(value);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<'a, T> Drop for SendFuture<'a, T> {
+    /// Removes the waker slot of a send
+    /// that never completed. If the slot
+    /// is gone the future was woken for
+    /// room it will not use, so the next
+    /// waiting sender is woken instead.
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            if !self.channel.remove_sender(waiter) {
+                self.channel.wake_sender();
+            }
+        }
+    }
+}
+
+//// RECEIVER
+
+/// Receiving end of a channel.
+pub struct Receiver<T> {
+    channel: Arc<Channel<T>>,
+}
+
+impl<T> Receiver<T> {
+    /// Returns a future that resolves to
+    /// the next value, or None once the
+    /// channel is empty and every sender
+    /// has been dropped.
+    pub fn recv(&mut self) -> RecvFuture<T> {
+        RecvFuture {
+            channel: &self.channel,
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    /// Lets waiting senders see that
+    /// nobody will receive their values.
+    fn drop(&mut self) {
+        self.channel.receiver_alive.store(false, Ordering::Release);
+        self.channel.wake_all_senders();
+    }
+}
+
+/// Future returned by Receiver::recv.
+pub struct RecvFuture<'a, T> {
+    channel: &'a Channel<T>,
+}
+
+impl<'a, T> RecvFuture<'a, T> {
+    /// Pops a value and makes room
+    /// for a waiting sender.
+    fn try_pop(&self) -> Option<T> {
+        let value = self.channel.queue.pop().ok()?;
+        self.channel.wake_sender();
+        Some(value)
+    }
+}
+
+impl<'a, T> Future for RecvFuture<'a, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        if let Some(value) = self.try_pop() {
+            return Poll::Ready(Some(value));
+        }
+
+        // Register the waker before checking
+        // again so a value or the last
+        // sender dropping is not missed
+        self.channel.receiver_waker.register(context.waker());
+        if let Some(value) = self.try_pop() {
+            return Poll::Ready(Some(value));
+        }
+        if self.channel.senders.load(Ordering::Acquire) == 0 {
+            // A value may have been sent
+            // just before the last sender
+            // was dropped
+            return Poll::Ready(self.try_pop());
+        }
+        Poll::Pending
+    }
+}
+
+// Sends 0..10 through a channel that
+// holds two values and ensures the
+// consumer receives them in order and
+// then sees the channel close.
+#[test_case]
+fn test_channel_in_order() {
+    use super::{executor::Executor, Task};
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    let (sender, mut receiver) = channel(2);
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+
+    executor
+        .spawn(Task::new(async move {
+            for value in 0..10 {
+                sender.send(value).await.expect("receiver dropped");
+            }
+        }))
+        .expect("spawn failed");
+    {
+        let received = received.clone();
+        executor
+            .spawn(Task::new(async move {
+                while let Some(value) = receiver.recv().await {
+                    received.borrow_mut().push(value);
+                }
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(*received.borrow(), (0..10).collect::<Vec<_>>());
+}
+
+// Fills a channel, lets one send wait
+// and then be dropped, and ensures its
+// waker slot is removed and a second
+// waiting send is still woken when the
+// receiver makes room.
+#[test_case]
+fn test_channel_dropped_send_keeps_wakeups() {
+    use super::{executor::Executor, Task};
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+    use futures_util::task::noop_waker_ref;
+
+    let (sender, mut receiver) = channel(1);
+    let sent = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    let mut send = sender.send(0);
+    assert!(Pin::new(&mut send).poll(&mut context).is_ready());
+    drop(send);
+    {
+        let mut send = sender.send(1);
+        assert!(Pin::new(&mut send).poll(&mut context).is_pending());
+        assert!(Pin::new(&mut send).poll(&mut context).is_pending());
+        assert_eq!(sender.channel.sender_wakers.lock().len(), 1);
+    }
+    assert!(sender.channel.sender_wakers.lock().is_empty());
+
+    {
+        let sent = sent.clone();
+        executor
+            .spawn(Task::new(async move {
+                sender.send(2).await.expect("receiver dropped");
+                sent.set(true);
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+    assert!(!sent.get());
+
+    let mut recv = receiver.recv();
+    assert_eq!(Pin::new(&mut recv).poll(&mut context), Poll::Ready(Some(0)));
+    executor.run_ready_tasks();
+    assert!(sent.get());
+    assert!(receiver.channel.sender_wakers.lock().is_empty());
+}
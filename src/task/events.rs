@@ -0,0 +1,217 @@
+//! Broadcast event bus. Producers such
+//! as input drivers publish events
+//! without knowing who consumes them,
+//! and every subscribed task receives
+//! its own copy.
+
+use alloc::{
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::{ArrayQueue, PushError};
+use futures_util::task::AtomicWaker;
+
+/// Queue and waker of one subscriber,
+/// and the number of its events that
+/// were dropped because it fell behind.
+struct Subscriber<E> {
+    queue: ArrayQueue<E>,
+    waker: AtomicWaker,
+    dropped: AtomicUsize,
+}
+
+impl<E> Subscriber<E> {
+    /// Pushes the event, dropping the
+    /// oldest queued events until there
+    /// is room, and wakes the subscriber.
+    fn push(&self, mut event: E) {
+        loop {
+            match self.queue.push(event) {
+                Ok(()) => break,
+                Err(PushError(rejected)) => {
+                    event = rejected;
+                    if self.queue.pop().is_ok() {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+        self.waker.wake();
+    }
+}
+
+/// Bus that delivers every published
+/// event to all of its subscribers.
+/// Each subscriber buffers up to
+/// capacity events; when it is full
+/// its oldest event is dropped.
+pub struct EventBus<E> {
+    capacity: usize,
+    subscribers: spin::Mutex<Vec<Weak<Subscriber<E>>>>,
+}
+
+impl<E: Clone> EventBus<E> {
+    /// Creates a bus without subscribers.
+    /// capacity:     events buffered per subscriber
+    pub const fn new(capacity: usize) -> Self {
+        EventBus {
+            capacity,
+            subscribers: spin::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns a receiver for every event
+    /// published from now on. Subscribers
+    /// whose receivers were dropped are
+    /// removed here, so publish never
+    /// frees memory.
+    pub fn subscribe(&self) -> Receiver<E> {
+        use x86_64::instructions::interrupts::without_interrupts;
+
+        let subscriber = Arc::new(Subscriber {
+            queue: ArrayQueue::new(self.capacity),
+            waker: AtomicWaker::new(),
+            dropped: AtomicUsize::new(0),
+        });
+        let weak = Arc::downgrade(&subscriber);
+        let removed: Vec<Weak<Subscriber<E>>> = without_interrupts(|| {
+            let mut subscribers = self.subscribers.lock();
+            let (alive, removed) = subscribers
+                .drain(..)
+                .partition(|subscriber| subscriber.strong_count() > 0);
+            *subscribers = alive;
+            subscribers.push(weak);
+            removed
+        });
+        drop(removed);
+        Receiver { subscriber }
+    }
+
+    /// Sends a copy of the event to every
+    /// subscriber and wakes them. Returns
+    /// the number of subscribers reached.
+    /// Interrupts are disabled while the
+    /// subscribers are locked, so interrupt
+    /// handlers can publish too.
+    pub fn publish(&self, event: E) -> usize {
+        use x86_64::instructions::interrupts::without_interrupts;
+
+        without_interrupts(|| {
+            let subscribers = self.subscribers.lock();
+            let mut reached = 0;
+            for subscriber in subscribers.iter().filter_map(Weak::upgrade) {
+                subscriber.push(event.clone());
+                reached += 1;
+            }
+            reached
+        })
+    }
+}
+
+/// Receiving end of a subscription.
+/// Dropping it unsubscribes.
+pub struct Receiver<E> {
+    subscriber: Arc<Subscriber<E>>,
+}
+
+impl<E> Receiver<E> {
+    /// Returns a future that resolves to
+    /// the next event.
+    pub fn recv(&mut self) -> RecvFuture<E> {
+        RecvFuture {
+            subscriber: &self.subscriber,
+        }
+    }
+
+    /// Returns the next event if one is
+    /// queued, without waiting.
+    pub fn try_recv(&mut self) -> Option<E> {
+        self.subscriber.queue.pop().ok()
+    }
+
+    /// Returns the number of events that
+    /// were dropped because the queue of
+    /// this subscriber was full.
+    pub fn dropped(&self) -> usize {
+        self.subscriber.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// Future returned by Receiver::recv.
+pub struct RecvFuture<'a, E> {
+    subscriber: &'a Subscriber<E>,
+}
+
+impl<'a, E> Future for RecvFuture<'a, E> {
+    type Output = E;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<E> {
+        if let Ok(event) = self.subscriber.queue.pop() {
+            return Poll::Ready(event);
+        }
+
+        // Register the waker before checking
+        // again so an event published in
+        // between is not missed
+        self.subscriber.waker.register(context.waker());
+        match self.subscriber.queue.pop() {
+            Ok(event) => Poll::Ready(event),
+            Err(_) => Poll::Pending,
+        }
+    }
+}
+
+// Spawns two subscribers and one
+// publisher and ensures both receive
+// the published event, then fills a
+// queue past its capacity and ensures
+// the oldest event is the one dropped.
+#[test_case]
+fn test_event_bus_broadcast() {
+    use super::{executor::Executor, Task};
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    let bus = Arc::new(EventBus::new(2));
+    let received = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+
+    for id in 0..2 {
+        let mut receiver = bus.subscribe();
+        let received = received.clone();
+        executor
+            .spawn(Task::new(async move {
+                let event = receiver.recv().await;
+                received.borrow_mut().push((id, event));
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+    {
+        let bus = bus.clone();
+        executor
+            .spawn(Task::new(async move {
+                assert_eq!(bus.publish(42), 2);
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+    assert_eq!(*received.borrow(), [(0, 42), (1, 42)]);
+
+    // Both receivers are dropped with
+    // their tasks
+    let mut receiver = bus.subscribe();
+    for event in 1..=3 {
+        bus.publish(event);
+    }
+    assert_eq!(receiver.dropped(), 1);
+    assert_eq!(receiver.try_recv(), Some(2));
+    assert_eq!(receiver.try_recv(), Some(3));
+    assert_eq!(bus.subscribers.lock().len(), 1);
+}
@@ -3,98 +3,387 @@
 //! tree to store the tasks with
 //! their unique IDs.
 
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
-use core::task::{Context, Poll, Waker};
+use super::{join, JoinHandle, Priority, Task, TaskID};
+use crate::error::KernelError;
+use alloc::{collections::BTreeMap, rc::Rc, sync::Arc, task::Wake};
+use core::future::Future;
+use core::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
 use crossbeam_queue::ArrayQueue;
-use super::{Task, TaskID};
+
+/// Number of tasks an Executor
+/// created with new can hold.
+pub const DEFAULT_CAPACITY: usize = 100;
+
+// Value of the foreground task ID
+// while there is no foreground task
+const NO_FOREGROUND: u64 = u64::MAX;
 
 /// Executor stores a tree of
 /// all the tasks, a queue of how
-/// they will be executed, and a
-/// tree of wakers for each of the tasks.
+/// they will be executed for each
+/// priority, and a tree of wakers
+/// for each of the tasks. It also
+/// keeps the foreground task, which
+/// Ctrl+C cancels, and the queues of
+/// tasks spawned and cancellations
+/// requested by tasks.
 pub struct Executor {
     tasks: BTreeMap<TaskID, Task>,
-    task_queue: Arc<ArrayQueue<TaskID>>,
+    task_queues: [Arc<ArrayQueue<TaskID>>; Priority::COUNT],
     waker_cache: BTreeMap<TaskID, Waker>,
+    capacity: usize,
+    foreground: Arc<AtomicU64>,
+    spawn_requests: Rc<ArrayQueue<Task>>,
+    cancel_requests: Arc<ArrayQueue<TaskID>>,
+    idle_hook: Option<fn()>,
+    idle_stats: IdleStats,
+}
+
+/// How often the executor ran out of
+/// ready tasks and how many timer
+/// ticks it spent halted waiting for
+/// an interrupt, to tell whether the
+/// kernel is busy or waiting.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IdleStats {
+    pub idle_count: u64,
+    pub halted_ticks: u64,
 }
 
 impl Executor {
-   
     /// Initializes the three data structures
     /// used to execute the tasks
     pub fn new() -> Self {
+        Executor::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Same as new, but up to capacity
+    /// tasks can be alive at once.
+    /// Every queue can hold all of them,
+    /// and a task is in at most one queue
+    /// at a time, so waking a task can
+    /// never find its queue full.
+    pub fn with_capacity(capacity: usize) -> Self {
         Executor {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queues: [
+                Arc::new(ArrayQueue::new(capacity)),
+                Arc::new(ArrayQueue::new(capacity)),
+                Arc::new(ArrayQueue::new(capacity)),
+            ],
             waker_cache: BTreeMap::new(),
+            capacity,
+            foreground: Arc::new(AtomicU64::new(NO_FOREGROUND)),
+            spawn_requests: Rc::new(ArrayQueue::new(capacity)),
+            cancel_requests: Arc::new(ArrayQueue::new(capacity)),
+            idle_hook: None,
+            idle_stats: IdleStats::default(),
         }
     }
 
     /// Adds the provided task to
-    /// the tree of task IDs 
+    /// the tree of task IDs
     /// and Tasks as well as the
-    /// ID of the task in the task_queue
-    pub fn spawn(&mut self, task: Task) {
+    /// ID of the task in the queue
+    /// of its priority. If the executor
+    /// already holds capacity tasks, or
+    /// the queue has no room, the task
+    /// is dropped and ExecutorFull is
+    /// returned.
+    pub fn spawn(&mut self, task: Task) -> Result<(), KernelError> {
+        if self.tasks.len() >= self.capacity {
+            return Err(KernelError::ExecutorFull {
+                capacity: self.capacity,
+            });
+        }
+
         let task_id = task.id;
-        if self.tasks.insert(task_id, task).is_some() {
+        let queue = &self.task_queues[task.priority.index()];
+        if self.tasks.contains_key(&task_id) {
             panic!("existing task has the same ID");
         }
-        self.task_queue.push(task_id).expect("queue is full");
+
+        // Fewer than capacity tasks are
+        // alive and cancel removes the ID
+        // of a task from its queue, so a
+        // full queue is a bug, but it must
+        // not leave the task marked as
+        // queued and never polled
+        task.queued.store(true, Ordering::Release);
+        if queue.push(task_id).is_err() {
+            task.queued.store(false, Ordering::Release);
+            return Err(KernelError::ExecutorFull {
+                capacity: self.capacity,
+            });
+        }
+        self.tasks.insert(task_id, task);
+        Ok(())
+    }
+
+    /// Spawns the future as a task with
+    /// normal priority and returns a
+    /// handle that resolves to its
+    /// output when awaited.
+    pub fn spawn_with_result<T: 'static>(
+        &mut self,
+        future: impl Future<Output = T> + 'static,
+    ) -> Result<JoinHandle<T>, KernelError> {
+        let (task, handle) = join::join_pair(future);
+        self.spawn(Task::new(task))?;
+        Ok(handle)
+    }
+
+    /// Makes the task the foreground
+    /// task, the one Ctrl+C cancels.
+    /// None leaves no foreground task.
+    /// The foreground is cleared again
+    /// when the task finishes.
+    pub fn set_foreground(&mut self, task_id: Option<TaskID>) {
+        let id = task_id.map_or(NO_FOREGROUND, |id| id.0);
+        self.foreground.store(id, Ordering::Release);
+    }
+
+    /// Returns the foreground task, if
+    /// there is one.
+    pub fn foreground(&self) -> Option<TaskID> {
+        load_foreground(&self.foreground)
+    }
+
+    /// Returns a handle tasks can use to
+    /// spawn tasks, since they cannot
+    /// reach the executor.
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            foreground: self.foreground.clone(),
+            requests: self.spawn_requests.clone(),
+        }
+    }
+
+    /// Returns a handle tasks can use to
+    /// cancel the foreground task, since
+    /// they cannot reach the executor.
+    pub fn canceller(&self) -> Canceller {
+        Canceller {
+            foreground: self.foreground.clone(),
+            requests: self.cancel_requests.clone(),
+        }
+    }
+
+    /// Removes the task and its waker and
+    /// drops it, which drops its future
+    /// and runs its destructors. A
+    /// JoinHandle of the task resolves to
+    /// Cancelled. Returns false if no
+    /// such task is alive.
+    /// This borrows the executor mutably,
+    /// so it cannot run while a task is
+    /// being polled. Dropping the pinned
+    /// box is sound, since the future is
+    /// dropped in place before its memory
+    /// is freed. The task ID is removed
+    /// from its queue, so it does not
+    /// take up room a new task needs.
+    pub fn cancel(&mut self, task_id: TaskID) -> bool {
+        self.waker_cache.remove(&task_id);
+        if self.foreground() == Some(task_id) {
+            self.set_foreground(None);
+        }
+        let task = match self.tasks.remove(&task_id) {
+            Some(task) => task,
+            None => return false,
+        };
+        if task.queued.load(Ordering::Acquire) {
+            Self::remove_queued(&self.task_queues[task.priority.index()], task_id);
+        }
+        true
+    }
+
+    /// Removes the task ID from the queue
+    /// and keeps the order of the other
+    /// IDs. A wake from an interrupt may
+    /// push meanwhile, but only for a live
+    /// task, so the queue still has room.
+    fn remove_queued(queue: &ArrayQueue<TaskID>, task_id: TaskID) {
+        for _ in 0..queue.len() {
+            match queue.pop() {
+                Ok(id) if id == task_id => {}
+                Ok(id) => {
+                    let _ = queue.push(id);
+                }
+                Err(_) => break,
+            }
+        }
+    }
+
+    /// Spawns the tasks that were
+    /// requested through a Spawner. A
+    /// task that does not fit is dropped,
+    /// so its JoinHandle is cancelled.
+    fn spawn_requested(&mut self) {
+        while let Ok(task) = self.spawn_requests.pop() {
+            let _ = self.spawn(task);
+        }
+    }
+
+    /// Cancels the tasks that were
+    /// requested through a Canceller.
+    fn cancel_requested(&mut self) {
+        while let Ok(task_id) = self.cancel_requests.pop() {
+            self.cancel(task_id);
+        }
+    }
+
+    /// Sets the function called each time
+    /// the executor runs out of ready
+    /// tasks, such as to blank the screen.
+    /// Before a halt it is called with
+    /// interrupts disabled, so it must be
+    /// short and must not wait for one.
+    pub fn on_idle(&mut self, hook: fn()) {
+        self.idle_hook = Some(hook);
+    }
+
+    /// Returns the idle counters.
+    pub fn idle_stats(&self) -> IdleStats {
+        self.idle_stats
+    }
+
+    /// Counts going idle and calls the
+    /// idle hook.
+    fn enter_idle(&mut self) {
+        self.idle_stats.idle_count += 1;
+        if let Some(hook) = self.idle_hook {
+            hook();
+        }
+    }
+
+    /// Prints the id and name of
+    /// every task that has not
+    /// finished yet.
+    pub fn dump_tasks(&self) {
+        let mut out = alloc::string::String::new();
+        self.write_tasks(&mut out).expect("formatting tasks failed");
+        crate::print!("{}", out);
+    }
+
+    /// Writes one line with the id and
+    /// name of each live task.
+    fn write_tasks(&self, out: &mut impl core::fmt::Write) -> core::fmt::Result {
+        for (id, task) in self.tasks.iter() {
+            writeln!(out, "task {}: {}", id.0, task.name())?;
+        }
+        Ok(())
+    }
+
+    /// Takes the next task ID from the
+    /// highest priority queue that is
+    /// not empty.
+    fn next_task(task_queues: &[Arc<ArrayQueue<TaskID>>]) -> Option<TaskID> {
+        task_queues.iter().find_map(|queue| queue.pop().ok())
     }
 
     /// Runs all the tasks that
     /// are currently ready to be run.
-    fn run_ready_tasks(&mut self) {
+    /// Spawns and cancellations requested
+    /// by a task are carried out after
+    /// its poll, spawns first so a task
+    /// can be cancelled right after it
+    /// was spawned.
+    pub(crate) fn run_ready_tasks(&mut self) {
+        self.spawn_requested();
+        self.cancel_requested();
+
+        // Get a task ID from the queues,
+        // checking the higher priorities
+        // again before every task
+        while let Some(task_id) = Self::next_task(&self.task_queues) {
+            self.poll_task(task_id);
+            self.spawn_requested();
+            self.cancel_requested();
+        }
+    }
 
+    /// Polls the task once and removes
+    /// it if it finished.
+    fn poll_task(&mut self, task_id: TaskID) {
         // Get the structures currently
         // held by self to avoid borrow
         // checker complaints
         let Self {
             tasks,
-            task_queue,
+            task_queues,
             waker_cache,
+            foreground,
+            ..
         } = self;
 
-        // Get a task ID from the queue
-        while let Ok(task_id) = task_queue.pop() {
-
-            // Get the associated task from
-            // the BTreeMap
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue,
-            };
-
-            // Get the waker if it exists,
-            // or create a new waker using
-            // TaskWaker
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-
-            // Get the context
-            let mut context = Context::from_waker(waker);
-            
-            // Poll the task
-            match task.poll(&mut context) {
-
-                // Remove from executor queue
-                // if the task is finisehd
-                Poll::Ready(()) => {
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
+        // Get the associated task from
+        // the BTreeMap, it may have
+        // been cancelled
+        let task = match tasks.get_mut(&task_id) {
+            Some(task) => task,
+            None => return,
+        };
+
+        // Get the waker if it exists,
+        // or create a new waker using
+        // TaskWaker
+        let waker = waker_cache.entry(task_id).or_insert_with(|| {
+            let queue = task_queues[task.priority.index()].clone();
+            TaskWaker::new(task_id, queue, task.queued.clone())
+        });
+
+        // The task is no longer in the
+        // queue, so a wake while it is
+        // polled must queue it again
+        task.queued.store(false, Ordering::Release);
+
+        // Get the context
+        let mut context = Context::from_waker(waker);
 
-                // Otherwise keep the task in
-                // the queue and continue
-                Poll::Pending => {}
+        // Poll the task
+        match task.poll(&mut context) {
+            // Remove from executor queue
+            // if the task is finisehd
+            Poll::Ready(()) => {
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
+                let _ = foreground.compare_exchange(
+                    task_id.0,
+                    NO_FOREGROUND,
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                );
             }
+
+            // Otherwise keep the task in
+            // the queue and continue
+            Poll::Pending => {}
         }
     }
 
+    /// Polls the ready tasks until none
+    /// are left in the queues and returns
+    /// without halting. Tasks that are
+    /// still pending stay in the executor
+    /// until they are woken. Running out
+    /// of tasks counts as going idle and
+    /// calls the idle hook.
+    pub fn run_until_idle(&mut self) {
+        self.run_ready_tasks();
+        self.enter_idle();
+    }
+
     /// Loop of executor running
-    /// all the tasks that are available
+    /// all the tasks that are available.
+    /// The executor assumes it is the only
+    /// one running, so a second CPU fails
+    /// loudly in debug builds.
     pub fn run(&mut self) -> ! {
+        debug_assert!(crate::cpu_count() == 1, "Executor requires a single CPU");
         loop {
             self.run_ready_tasks();
             self.sleep_if_idle();
@@ -102,44 +391,172 @@ impl Executor {
     }
 
     /// If there are no tasks in the
-    /// queue, the CPU will halt until
-    /// an interrupt occurs
-    fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{enable_and_hlt, self};
+    /// queue, the idle hook is called and
+    /// the CPU will halt until an
+    /// interrupt occurs. The ticks spent
+    /// halted are counted. While the
+    /// watchdog reports a stalled timer
+    /// the executor polls instead, since
+    /// the halt may never end.
+    fn sleep_if_idle(&mut self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if crate::watchdog::check() {
+            interrupts::enable();
+            return;
+        }
+        if self.task_queues.iter().all(|queue| queue.is_empty()) {
+            self.enter_idle();
+            let start = crate::interrupts::ticks();
             enable_and_hlt();
+            let halted = crate::interrupts::ticks().wrapping_sub(start);
+            self.idle_stats.halted_ticks += halted;
         } else {
             interrupts::enable();
         }
     }
 }
 
+/// Drops every task, including those
+/// waiting to be spawned, and every
+/// cached waker and empties the
+/// queues. Wakers held elsewhere still
+/// point at the queues, so waking them
+/// later is harmless.
+impl Drop for Executor {
+    fn drop(&mut self) {
+        for queue in self.task_queues.iter() {
+            while queue.pop().is_ok() {}
+        }
+        while self.spawn_requests.pop().is_ok() {}
+        self.waker_cache.clear();
+        self.tasks.clear();
+    }
+}
+
+/// Reads the foreground task ID.
+fn load_foreground(foreground: &AtomicU64) -> Option<TaskID> {
+    match foreground.load(Ordering::Acquire) {
+        NO_FOREGROUND => None,
+        id => Some(TaskID(id)),
+    }
+}
+
+/// Handle to the foreground task of an
+/// executor, for tasks such as the
+/// Ctrl+C watcher that need to cancel
+/// it. Cancellation is only requested
+/// here and carried out by the executor
+/// after the current poll.
+#[derive(Clone)]
+pub struct Canceller {
+    foreground: Arc<AtomicU64>,
+    requests: Arc<ArrayQueue<TaskID>>,
+}
+
+impl Canceller {
+    /// Requests cancelling the foreground
+    /// task and returns its ID, or None if
+    /// there is no foreground task.
+    pub fn cancel_foreground(&self) -> Option<TaskID> {
+        let task_id = load_foreground(&self.foreground)?;
+
+        // The queue holds as many IDs as
+        // the executor holds tasks, so a
+        // full queue only holds duplicates
+        let _ = self.requests.push(task_id);
+        Some(task_id)
+    }
+}
+
+/// Handle to the executor for tasks
+/// that need to spawn other tasks,
+/// such as the shell running commands.
+/// Spawning is only requested here and
+/// carried out by the executor after
+/// the current poll. Tasks are not
+/// Send, so neither is this handle.
+#[derive(Clone)]
+pub struct Spawner {
+    foreground: Arc<AtomicU64>,
+    requests: Rc<ArrayQueue<Task>>,
+}
+
+impl Spawner {
+    /// Requests spawning the task and
+    /// returns its ID. If as many spawns
+    /// as the executor holds tasks are
+    /// waiting, the task is dropped and
+    /// ExecutorFull is returned.
+    pub fn spawn(&self, task: Task) -> Result<TaskID, KernelError> {
+        let task_id = task.id();
+        self.requests
+            .push(task)
+            .map_err(|_| KernelError::ExecutorFull {
+                capacity: self.requests.capacity(),
+            })?;
+        Ok(task_id)
+    }
+
+    /// Spawns the future as the foreground
+    /// task, the one Ctrl+C cancels, and
+    /// returns a handle that resolves to
+    /// its output, or to Cancelled if it
+    /// was cancelled.
+    pub fn spawn_foreground<T: 'static>(
+        &self,
+        future: impl Future<Output = T> + 'static,
+    ) -> Result<JoinHandle<T>, KernelError> {
+        let (task, handle) = join::join_pair(future);
+        let task_id = self.spawn(Task::new(task))?;
+        self.foreground.store(task_id.0, Ordering::Release);
+        Ok(handle)
+    }
+}
+
+/// Waker of a task. It holds the
+/// queue of the task's priority, so
+/// a woken task is queued again with
+/// the same priority.
 struct TaskWaker {
     task_id: TaskID,
     task_queue: Arc<ArrayQueue<TaskID>>,
+    queued: Arc<AtomicBool>,
 }
 
 impl TaskWaker {
-
-    /// Creates a new waker from a 
+    /// Creates a new waker from a
     /// TaskWaker to be used by
     /// the executor
-    fn new(task_id: TaskID, task_queue: Arc<ArrayQueue<TaskID>>) -> Waker {
+    fn new(task_id: TaskID, task_queue: Arc<ArrayQueue<TaskID>>, queued: Arc<AtomicBool>) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
             task_queue,
+            queued,
         }))
     }
 
     /// Wakes the task by adding it
     /// to the queue of task IDs that
-    /// are ready to be polled
+    /// are ready to be polled. A task
+    /// can legitimately be woken many
+    /// times before it is polled, for
+    /// example by a timer and a channel,
+    /// so if the task is already in the
+    /// queue the wake is dropped.
     fn wake_task(&self) {
-        self.task_queue
-            .push(self.task_id)
-            .expect("task queue full");
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        // The executor never holds more
+        // tasks than a queue has room for,
+        // but a lost push must not leave
+        // the task marked as queued
+        if self.task_queue.push(self.task_id).is_err() {
+            self.queued.store(false, Ordering::Release);
+        }
     }
 }
 
@@ -154,3 +571,385 @@ impl Wake for TaskWaker {
         self.wake_task();
     }
 }
+
+// Wakes a task that is already in
+// the queue and ensures it is not
+// pushed a second time.
+#[test_case]
+fn test_wake_queued_task_does_not_grow_queue() {
+    let mut executor = Executor::new();
+    let task = Task::new(async {});
+    let task_id = task.id;
+    let queued = task.queued.clone();
+    executor.spawn(task).expect("spawn failed");
+    let queue = executor.task_queues[Priority::Normal.index()].clone();
+    assert_eq!(queue.len(), 1);
+
+    let waker = TaskWaker::new(task_id, queue.clone(), queued);
+    waker.wake_by_ref();
+    waker.wake_by_ref();
+    assert_eq!(queue.len(), 1);
+}
+
+// Wakes a pending task 50 times before
+// it is polled again and ensures its
+// id is queued once and it is only
+// polled once for all the wakes.
+#[test_case]
+fn test_repeated_wakes_queue_task_once() {
+    use alloc::rc::Rc;
+    use core::{cell::Cell, future::poll_fn};
+
+    let polls = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    let task = {
+        let polls = polls.clone();
+        Task::new(poll_fn(move |_| {
+            polls.set(polls.get() + 1);
+            Poll::<()>::Pending
+        }))
+    };
+    let task_id = task.id;
+    executor.spawn(task).expect("spawn failed");
+    executor.run_ready_tasks();
+    assert_eq!(polls.get(), 1);
+
+    let queue = executor.task_queues[Priority::Normal.index()].clone();
+    let waker = executor.waker_cache[&task_id].clone();
+    for _ in 0..50 {
+        waker.wake_by_ref();
+        assert_eq!(queue.len(), 1);
+    }
+
+    executor.run_ready_tasks();
+    assert_eq!(polls.get(), 2);
+    assert!(queue.is_empty());
+}
+
+// Spawns a task that sleeps for 50 ms
+// and ensures it only completes after
+// enough timer ticks have passed.
+#[test_case]
+fn test_sleep_wakes_task() {
+    use super::timer::sleep;
+    use crate::interrupts;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let mut executor = Executor::new();
+    {
+        let done = done.clone();
+        executor
+            .spawn(Task::new(async move {
+                sleep(50).await;
+                done.store(true, Ordering::Release);
+            }))
+            .expect("spawn failed");
+    }
+
+    let start = interrupts::ticks();
+    executor.run_ready_tasks();
+    assert!(!done.load(Ordering::Acquire));
+
+    while !done.load(Ordering::Acquire) && interrupts::ticks() - start < 100 {
+        executor.sleep_if_idle();
+        executor.run_ready_tasks();
+    }
+    assert!(done.load(Ordering::Acquire));
+    assert!(interrupts::ticks() - start >= interrupts::ms_to_ticks(50));
+    assert!(executor.tasks.is_empty());
+}
+
+// Spawns a low priority task before
+// a high priority one and ensures
+// the high priority task runs first.
+#[test_case]
+fn test_high_priority_runs_first() {
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    let order = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for &priority in [Priority::Low, Priority::High].iter() {
+        let order = order.clone();
+        executor
+            .spawn(Task::with_priority(
+                async move { order.borrow_mut().push(priority) },
+                priority,
+            ))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(*order.borrow(), [Priority::High, Priority::Low]);
+}
+
+// Spawns a task computing 21 + 21 and a
+// higher priority driver that awaits its
+// handle before the result exists, and
+// ensures the driver is woken with 42.
+#[test_case]
+fn test_join_handle_returns_result() {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    let result = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    let handle = executor
+        .spawn_with_result(async { 21 + 21 })
+        .expect("spawn failed");
+    {
+        let result = result.clone();
+        executor
+            .spawn(Task::with_priority(
+                async move { result.set(handle.await.expect("task cancelled")) },
+                Priority::High,
+            ))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(result.get(), 42);
+    assert!(executor.tasks.is_empty());
+}
+
+// Runs two tasks that record each step
+// and yield in between, and ensures
+// their steps interleave.
+#[test_case]
+fn test_yield_now_interleaves_tasks() {
+    use super::yield_now;
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    let steps = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    for &name in b"ab".iter() {
+        let steps = steps.clone();
+        executor
+            .spawn(Task::new(async move {
+                for _ in 0..3 {
+                    steps.borrow_mut().push(name);
+                    yield_now().await;
+                }
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(*steps.borrow(), b"ababab");
+}
+
+// Spawns two named tasks and an
+// unnamed one and ensures the task
+// dump lists all of them.
+#[test_case]
+fn test_dump_tasks_names() {
+    use alloc::string::String;
+
+    let mut executor = Executor::new();
+    executor
+        .spawn(Task::named("shell", async {}))
+        .expect("spawn failed");
+    executor
+        .spawn(Task::named("logger", async {}))
+        .expect("spawn failed");
+    executor.spawn(Task::new(async {})).expect("spawn failed");
+
+    let mut out = String::new();
+    executor.write_tasks(&mut out).unwrap();
+    assert!(out.contains(": shell\n"));
+    assert!(out.contains(": logger\n"));
+    assert!(out.contains(": <unnamed>\n"));
+    executor.dump_tasks();
+}
+
+// Spawns more tasks than the default
+// capacity into a larger executor and
+// ensures they all run, then ensures
+// a full executor rejects a task
+// instead of panicking.
+#[test_case]
+fn test_with_capacity() {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    let count = Rc::new(Cell::new(0));
+    let mut executor = Executor::with_capacity(2 * DEFAULT_CAPACITY);
+    for _ in 0..DEFAULT_CAPACITY + 50 {
+        let count = count.clone();
+        executor
+            .spawn(Task::new(async move { count.set(count.get() + 1) }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+    assert_eq!(count.get(), DEFAULT_CAPACITY + 50);
+
+    let mut executor = Executor::with_capacity(2);
+    executor.spawn(Task::new(async {})).expect("spawn failed");
+    executor.spawn(Task::new(async {})).expect("spawn failed");
+    assert_eq!(
+        executor.spawn(Task::new(async {})),
+        Err(KernelError::ExecutorFull { capacity: 2 })
+    );
+}
+
+// Runs a few finite tasks and a pending
+// one until idle and ensures only the
+// pending task is left, then drops the
+// executor and ensures it was dropped.
+#[test_case]
+fn test_run_until_idle_and_drop() {
+    use alloc::rc::Rc;
+    use core::{cell::Cell, future::pending};
+
+    let count = Rc::new(Cell::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        let count = count.clone();
+        executor
+            .spawn(Task::new(async move { count.set(count.get() + 1) }))
+            .expect("spawn failed");
+    }
+    executor.run_until_idle();
+    assert_eq!(count.get(), 3);
+    assert!(executor.tasks.is_empty());
+    assert!(executor.waker_cache.is_empty());
+
+    let held = Rc::new(());
+    {
+        let held = held.clone();
+        executor
+            .spawn(Task::new(async move {
+                let _held = held;
+                pending::<()>().await
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_until_idle();
+    assert_eq!(executor.tasks.len(), 1);
+    let queue = executor.task_queues[Priority::Normal.index()].clone();
+
+    drop(executor);
+    assert_eq!(Rc::strong_count(&held), 1);
+    assert!(queue.is_empty());
+}
+
+// Spawns a task that never completes as
+// the foreground task through a spawner,
+// and a task racing its handle against
+// the Ctrl+C watcher. Injects Ctrl+C and
+// ensures the task was removed, its
+// future dropped and its handle
+// resolved to Cancelled.
+#[test_case]
+fn test_ctrl_c_cancels_foreground_task() {
+    use super::{
+        keyboard::{self, cancel_on_ctrl_c, KeyReader},
+        Cancelled,
+    };
+    use alloc::{boxed::Box, rc::Rc};
+    use core::{cell::Cell, future::pending};
+    use futures_util::future::{select, Either};
+
+    let held = Rc::new(());
+    let outcome = Rc::new(Cell::new(None));
+    let mut executor = Executor::new();
+    let handle = {
+        let held = held.clone();
+        executor
+            .spawner()
+            .spawn_foreground(async move {
+                let _held = held;
+                pending::<()>().await
+            })
+            .expect("spawn failed")
+    };
+    {
+        let outcome = outcome.clone();
+        let canceller = executor.canceller();
+        executor
+            .spawn(Task::new(async move {
+                let mut keys = KeyReader::new(keyboard::test_scancode_stream());
+                let watcher = Box::pin(cancel_on_ctrl_c(&mut keys, &canceller));
+                let race = select(handle, watcher).await;
+                if let Either::Left((result, _)) = race {
+                    outcome.set(Some(result));
+                }
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+    let task_id = executor.foreground().expect("no foreground task");
+    assert!(executor.tasks.contains_key(&task_id));
+    assert_eq!(outcome.get(), None);
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // Ctrl down, C down and up, Ctrl up
+        for &scancode in [0x1d, 0x2e, 0xae, 0x9d].iter() {
+            keyboard::add_scancode(scancode);
+        }
+    });
+    executor.run_ready_tasks();
+
+    assert!(!executor.tasks.contains_key(&task_id));
+    assert!(!executor.waker_cache.contains_key(&task_id));
+    assert_eq!(executor.foreground(), None);
+    assert_eq!(Rc::strong_count(&held), 1);
+    assert_eq!(outcome.get(), Some(Err(Cancelled)));
+}
+
+// Registers an idle hook that counts its
+// calls, runs a task until idle and
+// ensures the hook fired once and the
+// idle count matches.
+#[test_case]
+fn test_idle_hook_fires() {
+    use core::sync::atomic::AtomicUsize;
+
+    static IDLE_CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn count_idle() {
+        IDLE_CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    let mut executor = Executor::new();
+    executor.on_idle(count_idle);
+    executor.spawn(Task::new(async {})).expect("spawn failed");
+    executor.run_until_idle();
+
+    assert_eq!(IDLE_CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(executor.idle_stats().idle_count, 1);
+    assert_eq!(executor.idle_stats().halted_ticks, 0);
+}
+
+// Fills an executor with pending tasks
+// and cancels them all while they are
+// queued, then ensures as many new
+// tasks can be spawned and are polled.
+#[test_case]
+fn test_spawn_after_cancel_up_to_capacity() {
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::Cell;
+
+    const CAPACITY: usize = 4;
+    let mut executor = Executor::with_capacity(CAPACITY);
+    let ids: Vec<TaskID> = (0..CAPACITY)
+        .map(|_| {
+            let task = Task::new(core::future::pending());
+            let task_id = task.id;
+            executor.spawn(task).expect("spawn failed");
+            task_id
+        })
+        .collect();
+    for task_id in ids {
+        assert!(executor.cancel(task_id));
+    }
+
+    let polled = Rc::new(Cell::new(0));
+    for _ in 0..CAPACITY {
+        let polled = polled.clone();
+        let task = Task::new(async move { polled.set(polled.get() + 1) });
+        executor.spawn(task).expect("spawn after cancel failed");
+    }
+    executor.run_ready_tasks();
+    assert_eq!(polled.get(), CAPACITY);
+}
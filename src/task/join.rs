@@ -0,0 +1,126 @@
+//! Handles for awaiting the output
+//! of a spawned task.
+
+use alloc::sync::Arc;
+use core::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+/// Error a JoinHandle resolves to when
+/// its task was cancelled, or dropped
+/// for any other reason, before it
+/// finished.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "task was cancelled")
+    }
+}
+
+/// Output of a task and the waker
+/// of the task awaiting it, shared
+/// by the task and its JoinHandle.
+/// done is set once the output or
+/// Cancelled has been stored.
+struct JoinState<T> {
+    result: Option<Result<T, Cancelled>>,
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// Part of a task body that stores its
+/// output for the JoinHandle. If the
+/// body is dropped before it finishes,
+/// Cancelled is stored instead, so the
+/// handle does not wait forever.
+struct Completion<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> Completion<T> {
+    /// Stores the result unless one was
+    /// already stored, and wakes the task
+    /// awaiting the handle.
+    fn complete(&self, result: Result<T, Cancelled>) {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.done {
+                return;
+            }
+            state.done = true;
+            state.result = Some(result);
+            state.waker.take()
+        };
+
+        // Wake after unlocking so the
+        // handle can take the result
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Drop for Completion<T> {
+    fn drop(&mut self) {
+        self.complete(Err(Cancelled));
+    }
+}
+
+/// Future that resolves to the output
+/// of a spawned task once it finishes,
+/// or to Cancelled if the task is
+/// dropped first.
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+/// Wraps the future in a task body that
+/// stores its output for the returned
+/// JoinHandle and wakes whoever awaits it.
+pub(crate) fn join_pair<T: 'static>(
+    future: impl Future<Output = T> + 'static,
+) -> (impl Future<Output = ()> + 'static, JoinHandle<T>) {
+    let state = Arc::new(Mutex::new(JoinState {
+        result: None,
+        done: false,
+        waker: None,
+    }));
+
+    // The completion is moved into the
+    // body before it is first polled, so
+    // dropping an unpolled task also
+    // resolves the handle
+    let completion = Completion {
+        state: state.clone(),
+    };
+    let task = async move {
+        let result = future.await;
+        completion.complete(Ok(result));
+    };
+    (task, JoinHandle { state })
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, Cancelled>;
+
+    /// Returns the output once the task
+    /// has stored it. Until then the
+    /// waker is kept so the task can wake
+    /// the awaiting task when it finishes.
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<Result<T, Cancelled>> {
+        let mut state = self.state.lock();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -3,56 +3,75 @@
 //! performed by the hardware
 //! interrupt handler function.
 
+use super::executor::Canceller;
+use crate::{print, println};
+use alloc::string::String;
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
 };
-use crate::{print, println};
 use crossbeam_queue::ArrayQueue;
 use futures_util::{
-    stream::{Stream, StreamExt}, 
-    task::AtomicWaker
+    stream::{Stream, StreamExt},
+    task::AtomicWaker,
 };
-use pc_keyboard::{DecodedKey, HandleControl, Keyboard, layouts, ScancodeSet1};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
 
 //// STORE INCOMING SCANCODES
 
-/// OnceCell wrapping allows for a 
+/// OnceCell wrapping allows for a
 /// compile-time static memory allocation
 /// before the heap is initialized.
 /// ArrayQueue in crossbeam uses atomics
 /// to allow concurrent mutations of
 /// the array without the need of a mutex.
-static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>>
-    = OnceCell::uninit();
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
 
-/// Atomic waker that does not require 
+/// Atomic waker that does not require
 /// mutex to be accessed. This wakes
 /// up the executor when a task has
 /// been completed after being Task::Pending
 static WAKER: AtomicWaker = AtomicWaker::new();
 
+/// Number of scancodes the queue
+/// holds when created by
+/// ScancodeStream::new.
+pub const SCANCODE_QUEUE_CAPACITY: usize = 100;
+
+/// Number of scancodes dropped because
+/// the queue was full or not created yet.
+static DROPPED_SCANCODES: AtomicU64 = AtomicU64::new(0);
+
 /// Function used by the keyboard hardware
 /// interrupt handler to add a key press
-/// scancode to the buffer
+/// scancode to the buffer. Dropped
+/// scancodes are only counted, since
+/// printing here could deadlock on the
+/// WRITER held by interrupted code.
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
-            println!("WARNING: scancode queue full; dropping keyboard input");
-        } else {
+        if queue.push(scancode).is_ok() {
             WAKER.wake();
+            return;
         }
-    } else {
-        println!("WARNING: scancode queue uninitialized");
     }
+    DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of scancodes
+/// dropped since boot, such as by a
+/// paste burst overrunning the queue.
+pub fn dropped_scancodes() -> u64 {
+    DROPPED_SCANCODES.load(Ordering::Relaxed)
 }
 
 //// ASYNCHRONOUSLY PROCESS SCANCODES
 
 /// Scancode reader used to implement
 /// a single async reader. The private
-/// unit type field is private and 
+/// unit type field is private and
 /// prevents the struct from being created
 /// outside of this module.
 pub struct ScancodeStream {
@@ -60,14 +79,23 @@ pub struct ScancodeStream {
 }
 
 impl ScancodeStream {
-    
     /// Create a new ScancodeStream.
     /// This is initialized with a new
     /// ArrayQueue that is only initialized
     /// once. If it has already been initialized,
     /// the program will panic.
     pub fn new() -> Self {
-        SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(100))
+        Self::with_capacity(SCANCODE_QUEUE_CAPACITY)
+    }
+
+    /// Create a new ScancodeStream whose
+    /// queue holds capacity scancodes.
+    /// Like new, this panics if the
+    /// queue was already initialized.
+    /// capacity:   scancodes buffered
+    pub fn with_capacity(capacity: usize) -> Self {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(capacity))
             .expect("ScancodeStream::new should only be called once");
         ScancodeStream { _private: () }
     }
@@ -81,18 +109,17 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<u8>> {
-       
         // Try to access the scancode buffer
         let queue = SCANCODE_QUEUE
             .try_get()
             .expect("SCANCODE_QUEUE not initialized");
-       
+
         // Return a scancode if
         // it is available
         if let Ok(s) = queue.pop() {
             return Poll::Ready(Some(s));
         }
-   
+
         // Start up the atomic waker using
         // the provided context
         WAKER.register(&context.waker());
@@ -101,7 +128,228 @@ impl Stream for ScancodeStream {
                 WAKER.take();
                 Poll::Ready(Some(s))
             }
-            Err(crossbeam_queue::PopError) => Poll::Pending
+            Err(crossbeam_queue::PopError) => Poll::Pending,
+        }
+    }
+}
+
+//// MODIFIER STATE
+
+/// Modifier keys that were held, or
+/// toggled on, when a key was pressed.
+/// Left and right keys are merged.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+}
+
+/// State of the modifier keys, updated
+/// from every key event. The decoder
+/// tracks the same keys but keeps them
+/// private, and does not track Alt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyboardState {
+    left_shift: bool,
+    right_shift: bool,
+    left_ctrl: bool,
+    right_ctrl: bool,
+    left_alt: bool,
+    right_alt: bool,
+    caps_lock: bool,
+    num_lock: bool,
+}
+
+impl KeyboardState {
+    /// Creates the state of a keyboard
+    /// with no keys held. Num lock starts
+    /// on, as in the decoder.
+    pub const fn new() -> Self {
+        KeyboardState {
+            left_shift: false,
+            right_shift: false,
+            left_ctrl: false,
+            right_ctrl: false,
+            left_alt: false,
+            right_alt: false,
+            caps_lock: false,
+            num_lock: true,
+        }
+    }
+
+    /// Updates the state with a key event.
+    /// Events of other keys are ignored.
+    /// event:    key press or release
+    pub fn update(&mut self, event: &pc_keyboard::KeyEvent) {
+        let down = event.state == KeyState::Down;
+        match event.code {
+            KeyCode::ShiftLeft => self.left_shift = down,
+            KeyCode::ShiftRight => self.right_shift = down,
+            KeyCode::ControlLeft => self.left_ctrl = down,
+            KeyCode::ControlRight => self.right_ctrl = down,
+            KeyCode::AltLeft => self.left_alt = down,
+            KeyCode::AltRight => self.right_alt = down,
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if down => self.num_lock = !self.num_lock,
+            _ => {}
+        }
+    }
+
+    /// Returns the current modifiers.
+    pub fn modifiers(&self) -> Modifiers {
+        Modifiers {
+            shift: self.left_shift || self.right_shift,
+            ctrl: self.left_ctrl || self.right_ctrl,
+            alt: self.left_alt || self.right_alt,
+            caps_lock: self.caps_lock,
+            num_lock: self.num_lock,
+        }
+    }
+}
+
+impl Default for KeyboardState {
+    /// Same as new, with no keys held.
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Key press together with the
+/// modifiers active when it was
+/// pressed, for shortcuts like Ctrl+C.
+/// With Ctrl held, letters are still
+/// decoded as the letter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: DecodedKey,
+    pub modifiers: Modifiers,
+}
+
+impl KeyEvent {
+    /// Returns true for Ctrl+C, with or
+    /// without Shift.
+    pub fn is_ctrl_c(&self) -> bool {
+        self.modifiers.ctrl
+            && matches!(
+                self.key,
+                DecodedKey::Unicode('c') | DecodedKey::Unicode('C')
+            )
+    }
+}
+
+//// DECODED KEY STREAM
+
+/// Decodes the scancodes from a
+/// ScancodeStream into keys. The
+/// decoder keeps the modifier state
+/// between keys, so one reader should
+/// be kept for as long as input is read.
+pub struct KeyReader {
+    scancodes: ScancodeStream,
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    state: KeyboardState,
+}
+
+impl KeyReader {
+    /// Creates a reader decoding the
+    /// scancodes with the US layout.
+    pub fn new(scancodes: ScancodeStream) -> Self {
+        KeyReader {
+            scancodes,
+            keyboard: Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore),
+            state: KeyboardState::new(),
+        }
+    }
+
+    /// Returns the modifier state after
+    /// the last scancode read.
+    pub fn state(&self) -> &KeyboardState {
+        &self.state
+    }
+
+    /// Waits for the next key press and
+    /// returns it with its modifiers.
+    /// Releases and modifier keys are
+    /// consumed without being returned.
+    pub async fn next_event(&mut self) -> Option<KeyEvent> {
+        while let Some(scancode) = self.scancodes.next().await {
+            if let Ok(Some(key_event)) = self.keyboard.add_byte(scancode) {
+                self.state.update(&key_event);
+                if let Some(key) = self.keyboard.process_keyevent(key_event) {
+                    return Some(KeyEvent {
+                        key,
+                        modifiers: self.state.modifiers(),
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Waits for the next key press,
+    /// like next_event without the
+    /// modifiers.
+    pub async fn next_key(&mut self) -> Option<DecodedKey> {
+        self.next_event().await.map(|event| event.key)
+    }
+}
+
+//// LINE READER
+
+/// Longest line read_line returns.
+/// Characters typed past it are
+/// ignored and not echoed.
+pub const MAX_LINE_LEN: usize = 256;
+
+/// Reads keys until Enter is pressed
+/// and returns the line without the
+/// newline. Each character is echoed,
+/// and backspace removes the last one
+/// from the line and the screen. This
+/// allocates, so it can only be used
+/// once the heap is initialized.
+pub async fn read_line(keys: &mut KeyReader) -> String {
+    let mut line = String::new();
+    while let Some(key) = keys.next_key().await {
+        match key {
+            DecodedKey::Unicode('\n') => {
+                println!();
+                break;
+            }
+            DecodedKey::Unicode('\u{8}') => {
+                if line.pop().is_some() {
+                    print!("\u{8}");
+                }
+            }
+            DecodedKey::Unicode(character) => {
+                if line.len() + character.len_utf8() <= MAX_LINE_LEN {
+                    line.push(character);
+                    print!("{}", character);
+                }
+            }
+            DecodedKey::RawKey(_) => {}
+        }
+    }
+    line
+}
+
+//// CTRL+C CANCELLATION
+
+/// Reads keys and cancels the
+/// foreground task of the executor
+/// each time Ctrl+C is pressed. Other
+/// keys are dropped. This only returns
+/// once the keys end, so it is raced
+/// against the foreground task, the
+/// way the shell does while a command
+/// runs.
+pub async fn cancel_on_ctrl_c(keys: &mut KeyReader, canceller: &Canceller) {
+    while let Some(event) = keys.next_event().await {
+        if event.is_ctrl_c() && canceller.cancel_foreground().is_some() {
+            println!("^C");
         }
     }
 }
@@ -113,18 +361,166 @@ impl Stream for ScancodeStream {
 /// buffer and asynchronously handling
 /// the key press events in a loop
 pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(layouts::Us104Key, ScancodeSet1,
-        HandleControl::Ignore);
-   
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
-            }
+    let mut keys = KeyReader::new(ScancodeStream::new());
+
+    while let Some(key) = keys.next_key().await {
+        match key {
+            DecodedKey::Unicode(character) => print!("{}", character),
+            DecodedKey::RawKey(key) => print!("{:?}", key),
         }
     }
 }
+
+/// Returns a ScancodeStream for tests.
+/// Unlike ScancodeStream::new, this can
+/// be called by every test that needs
+/// one, since they run one at a time.
+#[cfg(test)]
+pub(crate) fn test_scancode_stream() -> ScancodeStream {
+    let _ = SCANCODE_QUEUE.try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_CAPACITY));
+    ScancodeStream { _private: () }
+}
+
+// Adds a scancode the way the keyboard
+// interrupt handler does and ensures
+// a new ScancodeStream yields it.
+#[test_case]
+fn test_add_scancode_feeds_stream() {
+    use futures_util::task::noop_waker_ref;
+
+    let mut scancodes = test_scancode_stream();
+    let mut context = Context::from_waker(noop_waker_ref());
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        add_scancode(0x1e);
+    });
+    assert_eq!(
+        Pin::new(&mut scancodes).poll_next(&mut context),
+        Poll::Ready(Some(0x1e))
+    );
+}
+
+// Injects the scancodes of typing
+// "hx", backspace, "i" and Enter and
+// ensures read_line returns "hi".
+#[test_case]
+fn test_read_line() {
+    use super::{simple_executor::SimpleExecutor, Task};
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    // Press and release of each key
+    let scancodes = [
+        0x23, 0xa3, // h
+        0x2d, 0xad, // x
+        0x0e, 0x8e, // backspace
+        0x17, 0x97, // i
+        0x1c, 0x9c, // enter
+    ];
+    let _ = test_scancode_stream();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for &scancode in scancodes.iter() {
+            add_scancode(scancode);
+        }
+    });
+
+    let line = Rc::new(RefCell::new(None));
+    let mut executor = SimpleExecutor::new();
+    {
+        let line = line.clone();
+        executor.spawn(Task::new(async move {
+            let mut keys = KeyReader::new(test_scancode_stream());
+            *line.borrow_mut() = Some(read_line(&mut keys).await);
+        }));
+    }
+    executor.run();
+
+    assert_eq!(line.borrow().as_deref(), Some("hi"));
+}
+
+// Adds more scancodes than the queue
+// holds and ensures every one that did
+// not fit is counted as dropped.
+#[test_case]
+fn test_dropped_scancodes_counted() {
+    let _ = test_scancode_stream();
+    let queue = SCANCODE_QUEUE.try_get().unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        while queue.pop().is_ok() {}
+
+        let dropped = dropped_scancodes();
+        for _ in 0..queue.capacity() + 5 {
+            add_scancode(0x1e);
+        }
+        assert_eq!(dropped_scancodes(), dropped + 5);
+
+        while queue.pop().is_ok() {}
+    });
+}
+
+// Injects Shift down, A down and up,
+// Shift up and B down on an executor
+// and ensures the event for A carries
+// the shift modifier and the event for
+// B does not.
+#[test_case]
+fn test_key_events_carry_modifiers() {
+    use super::{executor::Executor, Task};
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+
+    let scancodes = [
+        0x2a, // shift down
+        0x1e, 0x9e, // a
+        0xaa, // shift up
+        0x30, // b down
+    ];
+    let _ = test_scancode_stream();
+    let queue = SCANCODE_QUEUE.try_get().unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        while queue.pop().is_ok() {}
+        for &scancode in scancodes.iter() {
+            add_scancode(scancode);
+        }
+    });
+
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    {
+        let events = events.clone();
+        executor
+            .spawn(Task::new(async move {
+                let mut keys = KeyReader::new(test_scancode_stream());
+                for _ in 0..2 {
+                    let event = keys.next_event().await.unwrap();
+                    events.borrow_mut().push(event);
+                }
+                assert!(!keys.state().modifiers().shift);
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+
+    let shifted = Modifiers {
+        shift: true,
+        num_lock: true,
+        ..Modifiers::default()
+    };
+    let plain = Modifiers {
+        num_lock: true,
+        ..Modifiers::default()
+    };
+    assert_eq!(
+        *events.borrow(),
+        [
+            KeyEvent {
+                key: DecodedKey::Unicode('A'),
+                modifiers: shifted
+            },
+            KeyEvent {
+                key: DecodedKey::Unicode('b'),
+                modifiers: plain
+            },
+        ]
+    );
+}
@@ -2,25 +2,40 @@
 //! tasks that can be completed
 //! asynchronously using core::future
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, sync::Arc};
 use core::{
     future::Future, 
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
     task::{
         Context,
         Poll,
     }
 };
 
+pub mod channel;
+pub mod events;
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod mouse;
+pub mod retry;
+pub mod shell;
 pub mod simple_executor;
+pub mod stream;
+pub mod sync;
+pub mod timer;
+pub mod yield_now;
+
+pub use join::{Cancelled, JoinHandle};
+pub use retry::retry;
+pub use timer::{timeout, Timeout};
+pub use yield_now::yield_now;
 
 /// Each task is given a unique
 /// ID when it is initialized
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskID(u64);
+pub struct TaskID(u64);
 
 impl TaskID {
 
@@ -32,12 +47,36 @@ impl TaskID {
     }
 }
 
+/// Scheduling priority of a task. The
+/// executor always runs ready tasks of
+/// a higher priority first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    /// Number of priority levels
+    pub const COUNT: usize = 3;
+
+    /// Index of the priority, where
+    /// 0 is the highest.
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
 /// Represents a task that can be
 /// complete using the asynchronous
 /// future library
 pub struct Task {
     future: Pin<Box<dyn Future<Output = ()>>>,
     id: TaskID,
+    name: &'static str,
+    priority: Priority,
+    queued: Arc<AtomicBool>,
 }
 
 impl Task {
@@ -46,7 +85,40 @@ impl Task {
     /// the inner future type wrapped
     /// in a pinned box (immovable/immutable ref)
     pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
-        Task { future: Box::pin(future), id: TaskID::new() }
+        Task::with_priority(future, Priority::Normal)
+    }
+
+    /// Same as new, but the task is
+    /// scheduled with the given priority
+    pub fn with_priority(future: impl Future<Output = ()> + 'static, priority: Priority) -> Task {
+        Task {
+            future: Box::pin(future),
+            id: TaskID::new(),
+            name: "<unnamed>",
+            priority,
+            queued: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Same as new, but the task carries
+    /// a name shown when debugging the
+    /// executor
+    pub fn named(name: &'static str, future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            name,
+            ..Task::new(future)
+        }
+    }
+
+    /// Returns the name of the task
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Returns the ID of the task, used
+    /// to refer to it once spawned
+    pub fn id(&self) -> TaskID {
+        self.id
     }
 
     /// Polls the inner future type using
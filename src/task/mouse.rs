@@ -0,0 +1,209 @@
+//! Mouse packet buffer and decoder.
+//! The mouse interrupt handler only
+//! queues the raw bytes it reads, and
+//! they are assembled into movement
+//! packets by an async stream.
+
+use conquer_once::spin::OnceCell;
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::{stream::Stream, task::AtomicWaker};
+
+//// STORE INCOMING MOUSE BYTES
+
+/// Queue of bytes received from the
+/// mouse. It is initialized by
+/// MouseStream::new so bytes are only
+/// buffered once a reader exists.
+static MOUSE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+
+/// Wakes the task waiting on the
+/// MouseStream when a byte arrives.
+static MOUSE_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// Function used by the mouse interrupt
+/// handler to add a received byte to
+/// the buffer. Bytes are dropped if the
+/// queue is full or uninitialized, since
+/// a mouse that nobody reads is normal.
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = MOUSE_QUEUE.try_get() {
+        if queue.push(byte).is_ok() {
+            MOUSE_WAKER.wake();
+        }
+    }
+}
+
+//// PACKET DECODING
+
+// Bits of the first byte of a packet
+const LEFT_BUTTON: u8 = 0b0000_0001;
+const RIGHT_BUTTON: u8 = 0b0000_0010;
+const MIDDLE_BUTTON: u8 = 0b0000_0100;
+const ALWAYS_ONE: u8 = 0b0000_1000;
+const X_SIGN: u8 = 0b0001_0000;
+const Y_SIGN: u8 = 0b0010_0000;
+const X_OVERFLOW: u8 = 0b0100_0000;
+const Y_OVERFLOW: u8 = 0b1000_0000;
+
+/// Movement and buttons reported by
+/// one mouse packet. Positive y is
+/// movement away from the user (up).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseState {
+    pub x_delta: i16,
+    pub y_delta: i16,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Assembles 3-byte packets from the
+/// bytes sent by the mouse.
+#[derive(Debug, Default)]
+struct PacketDecoder {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl PacketDecoder {
+    /// Adds a byte and returns the state
+    /// once a packet is complete. A first
+    /// byte without the always-one bit is
+    /// dropped, so the decoder falls back
+    /// in step after a lost byte.
+    fn add_byte(&mut self, byte: u8) -> Option<MouseState> {
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.bytes.len() {
+            return None;
+        }
+        self.len = 0;
+
+        let [flags, x, y] = self.bytes;
+        Some(MouseState {
+            x_delta: delta(x, flags & X_SIGN != 0, flags & X_OVERFLOW != 0),
+            y_delta: delta(y, flags & Y_SIGN != 0, flags & Y_OVERFLOW != 0),
+            left: flags & LEFT_BUTTON != 0,
+            right: flags & RIGHT_BUTTON != 0,
+            middle: flags & MIDDLE_BUTTON != 0,
+        })
+    }
+}
+
+/// Returns the 9 bit two's complement
+/// movement made of the sign bit and
+/// the packet byte. Movement that
+/// overflowed is not reliable and is
+/// reported as none.
+fn delta(value: u8, negative: bool, overflow: bool) -> i16 {
+    if overflow {
+        0
+    } else if negative {
+        i16::from(value) - 0x100
+    } else {
+        i16::from(value)
+    }
+}
+
+//// ASYNCHRONOUSLY PROCESS MOUSE PACKETS
+
+/// Stream of the packets sent by the
+/// mouse. Only one can exist, since
+/// there is only one byte queue.
+pub struct MouseStream {
+    decoder: PacketDecoder,
+}
+
+impl MouseStream {
+    /// Create a new MouseStream. This
+    /// initializes the byte queue, so
+    /// the program will panic if it is
+    /// called more than once.
+    pub fn new() -> Self {
+        MOUSE_QUEUE
+            .try_init_once(|| ArrayQueue::new(96))
+            .expect("MouseStream::new should only be called once");
+        MouseStream {
+            decoder: PacketDecoder::default(),
+        }
+    }
+}
+
+impl Default for MouseStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseState;
+
+    fn poll_next(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<MouseState>> {
+        let queue = MOUSE_QUEUE.try_get().expect("MOUSE_QUEUE not initialized");
+
+        // Decode the bytes already
+        // queued until a packet is done
+        while let Ok(byte) = queue.pop() {
+            if let Some(state) = self.decoder.add_byte(byte) {
+                return Poll::Ready(Some(state));
+            }
+        }
+
+        // Register the waker before checking
+        // again so a byte received in between
+        // is not missed
+        MOUSE_WAKER.register(context.waker());
+        while let Ok(byte) = queue.pop() {
+            if let Some(state) = self.decoder.add_byte(byte) {
+                MOUSE_WAKER.take();
+                return Poll::Ready(Some(state));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+// Decodes packets with negative and
+// overflowed movement and ensures a
+// stray byte is skipped until the
+// start of the next packet.
+#[test_case]
+fn test_decode_mouse_packets() {
+    let mut decoder = PacketDecoder::default();
+
+    // Left button, x = 5, y = -3
+    assert_eq!(decoder.add_byte(0b0010_1001), None);
+    assert_eq!(decoder.add_byte(5), None);
+    assert_eq!(
+        decoder.add_byte(0xfd),
+        Some(MouseState {
+            x_delta: 5,
+            y_delta: -3,
+            left: true,
+            ..MouseState::default()
+        })
+    );
+
+    // Stray byte, then right and middle
+    // buttons with x overflowed
+    assert_eq!(decoder.add_byte(0x02), None);
+    assert_eq!(decoder.add_byte(0b0100_1110), None);
+    assert_eq!(decoder.add_byte(0xff), None);
+    assert_eq!(
+        decoder.add_byte(0x10),
+        Some(MouseState {
+            x_delta: 0,
+            y_delta: 16,
+            right: true,
+            middle: true,
+            ..MouseState::default()
+        })
+    );
+}
@@ -0,0 +1,78 @@
+//! Combinator for retrying futures
+//! that can fail transiently.
+
+use super::timer::sleep_ticks;
+use core::future::Future;
+
+/// Calls f and awaits the returned future
+/// until it produces Ok or it has been
+/// tried attempts times. After each Err
+/// the task sleeps before trying again,
+/// starting at base_delay_ticks and
+/// doubling the delay each time. The
+/// first Ok or the last Err is returned.
+/// The future is always tried at least
+/// once, even if attempts is 0.
+pub async fn retry<F, Fut, T, E>(attempts: usize, base_delay_ticks: u64, mut f: F) -> Result<T, E>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut delay = base_delay_ticks;
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt >= attempts => return Err(error),
+            Err(_) => {
+                sleep_ticks(delay).await;
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+// Retries an operation that fails
+// twice before succeeding and ensures
+// it took three attempts and waited
+// for the doubled backoff delays.
+#[test_case]
+fn test_retry_succeeds_after_failures() {
+    use super::{simple_executor::SimpleExecutor, Task};
+    use crate::interrupts;
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    let attempts = Rc::new(Cell::new(0));
+    let result = Rc::new(Cell::new(None));
+    let start = interrupts::ticks();
+
+    let mut executor = SimpleExecutor::new();
+    {
+        let attempts = attempts.clone();
+        let result = result.clone();
+        executor.spawn(Task::new(async move {
+            let value = retry(5, 1, || {
+                attempts.set(attempts.get() + 1);
+                let attempt = attempts.get();
+                async move {
+                    if attempt < 3 {
+                        Err(attempt)
+                    } else {
+                        Ok(attempt)
+                    }
+                }
+            })
+            .await;
+            result.set(Some(value));
+        }));
+    }
+    executor.run();
+
+    assert_eq!(result.get(), Some(Ok(3)));
+    assert_eq!(attempts.get(), 3);
+
+    // Backoff of 1 tick, then 2 ticks
+    assert!(interrupts::ticks() - start >= 3);
+}
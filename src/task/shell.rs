@@ -0,0 +1,318 @@
+//! Minimal interactive shell that
+//! reads commands from the keyboard
+//! and runs a few built-in commands.
+
+use super::{
+    executor::{Canceller, Spawner},
+    keyboard::{cancel_on_ctrl_c, read_line, KeyReader, ScancodeStream},
+    timer,
+};
+use crate::{allocator, interrupts, power, print, println, vga_buffer};
+use alloc::{boxed::Box, string::String, vec::Vec};
+use futures_util::future::{select, Either, LocalBoxFuture};
+
+// Printed before each command
+const PROMPT: &str = "> ";
+
+/// A built-in command. The handler is
+/// called with the words after the
+/// command name.
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: Run,
+}
+
+/// Handler of a built-in command.
+enum Run {
+    /// Returns once the command is
+    /// done, so it cannot be cancelled
+    Blocking(fn(&[&str])),
+    /// Returns a future that runs the
+    /// command, so Ctrl+C can cancel it
+    /// while it waits
+    Async(fn(Vec<String>) -> LocalBoxFuture<'static, ()>),
+}
+
+// Table of the built-in commands. A
+// new command only needs an entry here.
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "clear",
+        help: "clear the screen",
+        run: Run::Blocking(clear),
+    },
+    Command {
+        name: "echo",
+        help: "print the arguments",
+        run: Run::Blocking(echo),
+    },
+    Command {
+        name: "help",
+        help: "list the commands",
+        run: Run::Blocking(help),
+    },
+    Command {
+        name: "meminfo",
+        help: "print heap statistics",
+        run: Run::Blocking(meminfo),
+    },
+    Command {
+        name: "reboot",
+        help: "restart the machine",
+        run: Run::Blocking(reboot),
+    },
+    Command {
+        name: "shutdown",
+        help: "power off the machine",
+        run: Run::Blocking(shutdown),
+    },
+    Command {
+        name: "sleep",
+        help: "wait for the given milliseconds",
+        run: Run::Async(sleep),
+    },
+    Command {
+        name: "uptime",
+        help: "print the time since boot",
+        run: Run::Blocking(uptime),
+    },
+];
+
+/// Runs the shell, reading and running
+/// one command per line forever. Each
+/// command runs as the foreground task
+/// of the executor, so Ctrl+C cancels
+/// it. This takes over the keyboard, so
+/// it cannot run next to another task
+/// reading scancodes.
+/// spawner:      spawns the commands
+/// canceller:    cancels them on Ctrl+C
+pub async fn run(spawner: Spawner, canceller: Canceller) {
+    let mut keys = KeyReader::new(ScancodeStream::new());
+    loop {
+        run_line(&mut keys, &spawner, &canceller).await;
+    }
+}
+
+/// Prints the prompt, reads one line
+/// and runs it as the foreground task.
+/// Keys are read while it runs, so
+/// Ctrl+C can cancel it; other keys
+/// typed meanwhile are dropped.
+async fn run_line(keys: &mut KeyReader, spawner: &Spawner, canceller: &Canceller) {
+    print!("{}", PROMPT);
+    let line = read_line(keys).await;
+    let (command, args) = match parse(&line) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+
+    let handle = spawner.spawn_foreground(async move {
+        match command.run {
+            Run::Blocking(run) => {
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                run(&args);
+            }
+            Run::Async(run) => run(args).await,
+        }
+    });
+    let handle = match handle {
+        Ok(handle) => handle,
+        Err(error) => {
+            println!("cannot run {}: {}", command.name, error);
+            return;
+        }
+    };
+
+    // The handle resolves to Cancelled
+    // after Ctrl+C, which already
+    // printed ^C
+    let watcher = Box::pin(cancel_on_ctrl_c(keys, canceller));
+    if let Either::Right(((), handle)) = select(handle, watcher).await {
+        let _ = handle.await;
+    }
+}
+
+/// Splits the line on whitespace and
+/// finds the command named by the
+/// first word, returning it with the
+/// other words. Empty lines and
+/// unknown commands return None.
+fn parse(line: &str) -> Option<(&'static Command, Vec<String>)> {
+    let mut words = line.split_whitespace();
+    let name = words.next()?;
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => Some((command, words.map(String::from).collect())),
+        None => {
+            println!("unknown command: {}", name);
+            None
+        }
+    }
+}
+
+//// COMMANDS
+
+fn clear(_args: &[&str]) {
+    vga_buffer::clear_screen(vga_buffer::ClearMode::PreserveToScrollback);
+}
+
+fn echo(args: &[&str]) {
+    println!("{}", args.join(" "));
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("{:<10}{}", command.name, command.help);
+    }
+}
+
+fn meminfo(_args: &[&str]) {
+    let stats = allocator::heap_stats();
+    println!(
+        "heap: {} bytes used, {} bytes free, {} allocations",
+        stats.used, stats.free, stats.allocations
+    );
+    #[cfg(feature = "alloc-fixed")]
+    allocator::print_stats();
+}
+
+fn reboot(_args: &[&str]) {
+    println!("rebooting");
+    power::reboot();
+}
+
+fn shutdown(_args: &[&str]) {
+    println!("powering off");
+    power::shutdown();
+}
+
+fn sleep(args: Vec<String>) -> LocalBoxFuture<'static, ()> {
+    Box::pin(async move {
+        match args.first().map(|ms| ms.parse::<u64>()) {
+            Some(Ok(ms)) => timer::sleep(ms).await,
+            _ => println!("usage: sleep <ms>"),
+        }
+    })
+}
+
+fn uptime(_args: &[&str]) {
+    let ms = interrupts::uptime_ms();
+    println!("up {}.{:03} s", ms / 1000, ms % 1000);
+}
+
+// Types "echo hello" and Enter through
+// the scancode queue and ensures the
+// output is on the row after the
+// echoed command, and that the
+// command was the foreground task
+// only while it ran.
+#[test_case]
+fn test_shell_echo() {
+    use super::{executor::Executor, keyboard, Task};
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    // Press and release of each key
+    let mut scancodes = alloc::vec::Vec::new();
+    for &press in [
+        0x12, 0x2e, 0x23, 0x18, // echo
+        0x39, // space
+        0x23, 0x12, 0x26, 0x26, 0x18, // hello
+        0x1c, // enter
+    ]
+    .iter()
+    {
+        scancodes.push(press);
+        scancodes.push(press | 0x80);
+    }
+
+    // Interrupts stay disabled so the
+    // timer does not print between the
+    // rows that are checked
+    without_interrupts(|| {
+        let mut executor = Executor::new();
+        let spawner = executor.spawner();
+        let canceller = executor.canceller();
+        executor
+            .spawn(Task::new(async move {
+                let mut keys = KeyReader::new(keyboard::test_scancode_stream());
+                for scancode in scancodes {
+                    keyboard::add_scancode(scancode);
+                }
+                run_line(&mut keys, &spawner, &canceller).await;
+            }))
+            .expect("spawn failed");
+        executor.run_until_idle();
+        assert_eq!(executor.foreground(), None);
+
+        let rows = vga_buffer::screen_text();
+        let height = rows.len();
+        assert_eq!(rows[height - 3], "> echo hello");
+        assert_eq!(rows[height - 2], "hello");
+    });
+}
+
+// Types "sleep 60000" and Enter, then
+// Ctrl+C while the command waits for
+// the timer, and ensures the command
+// is cancelled, ^C is printed and the
+// shell goes on to the next line.
+// Interrupts stay disabled, so the
+// sleep cannot end by itself.
+#[test_case]
+fn test_shell_sleep_cancelled() {
+    use super::{executor::Executor, keyboard, Task};
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+    use x86_64::instructions::interrupts::without_interrupts;
+
+    // Press and release of each key
+    let mut scancodes = alloc::vec::Vec::new();
+    for &press in [
+        0x1f, 0x26, 0x12, 0x12, 0x19, // sleep
+        0x39, // space
+        0x07, 0x0b, 0x0b, 0x0b, 0x0b, // 60000
+        0x1c, // enter
+    ]
+    .iter()
+    {
+        scancodes.push(press);
+        scancodes.push(press | 0x80);
+    }
+
+    without_interrupts(|| {
+        let done = Rc::new(Cell::new(false));
+        let mut executor = Executor::new();
+        let spawner = executor.spawner();
+        let canceller = executor.canceller();
+        {
+            let done = done.clone();
+            executor
+                .spawn(Task::new(async move {
+                    let mut keys = KeyReader::new(keyboard::test_scancode_stream());
+                    for scancode in scancodes {
+                        keyboard::add_scancode(scancode);
+                    }
+                    run_line(&mut keys, &spawner, &canceller).await;
+                    done.set(true);
+                }))
+                .expect("spawn failed");
+        }
+        executor.run_until_idle();
+        assert!(executor.foreground().is_some());
+        assert!(!done.get());
+
+        // Ctrl down, C down and up, Ctrl up
+        for &scancode in [0x1d, 0x2e, 0xae, 0x9d].iter() {
+            keyboard::add_scancode(scancode);
+        }
+        executor.run_until_idle();
+        assert_eq!(executor.foreground(), None);
+        assert!(done.get());
+
+        let rows = vga_buffer::screen_text();
+        let height = rows.len();
+        assert_eq!(rows[height - 3], "> sleep 60000");
+        assert_eq!(rows[height - 2], "^C");
+    });
+}
@@ -0,0 +1,163 @@
+//! Combinators for input streams, so
+//! one task can read the keyboard, the
+//! mouse and serial input together.
+//! The streams have to be Unpin, which
+//! the input streams of the kernel
+//! are; others can be wrapped with
+//! Box::pin first.
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use futures_util::stream::Stream;
+
+//// MERGE
+
+/// Stream of the items of two streams
+/// of the same item type, in the order
+/// they become ready. It ends once both
+/// streams have ended.
+pub struct Merge<A, B> {
+    a: Option<A>,
+    b: Option<B>,
+    b_first: bool,
+}
+
+/// Interleaves the items of a and b
+/// into one stream. The stream polled
+/// first alternates, so a busy stream
+/// cannot starve the other.
+/// a:    first stream
+/// b:    second stream
+pub fn merge<A, B>(a: A, b: B) -> Merge<A, B>
+where
+    A: Stream + Unpin,
+    B: Stream<Item = A::Item> + Unpin,
+{
+    Merge {
+        a: Some(a),
+        b: Some(b),
+        b_first: false,
+    }
+}
+
+/// Polls the stream in the slot and
+/// empties the slot once it has ended.
+fn poll_slot<S: Stream + Unpin>(
+    slot: &mut Option<S>,
+    context: &mut Context,
+) -> Poll<Option<S::Item>> {
+    let stream = match slot {
+        Some(stream) => stream,
+        None => return Poll::Ready(None),
+    };
+    let poll = Pin::new(stream).poll_next(context);
+    if let Poll::Ready(None) = poll {
+        *slot = None;
+    }
+    poll
+}
+
+impl<A, B> Stream for Merge<A, B>
+where
+    A: Stream + Unpin,
+    B: Stream<Item = A::Item> + Unpin,
+{
+    type Item = A::Item;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<A::Item>> {
+        let this = self.get_mut();
+        let b_first = this.b_first;
+        this.b_first = !b_first;
+
+        // Poll both streams, in the order
+        // picked for this call
+        for &poll_b in [b_first, !b_first].iter() {
+            let poll = if poll_b {
+                poll_slot(&mut this.b, context)
+            } else {
+                poll_slot(&mut this.a, context)
+            };
+            if let Poll::Ready(Some(item)) = poll {
+                return Poll::Ready(Some(item));
+            }
+        }
+
+        // Both streams are pending or ended
+        if this.a.is_none() && this.b.is_none() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+//// MAP
+
+/// Stream of the items of a stream
+/// transformed by a function.
+pub struct Map<S, F> {
+    stream: S,
+    f: F,
+}
+
+/// Applies f to every item of the
+/// stream.
+/// stream:   stream to transform
+/// f:        function applied to each item
+pub fn map<S, F, T>(stream: S, f: F) -> Map<S, F>
+where
+    S: Stream + Unpin,
+    F: FnMut(S::Item) -> T + Unpin,
+{
+    Map { stream, f }
+}
+
+impl<S, F, T> Stream for Map<S, F>
+where
+    S: Stream + Unpin,
+    F: FnMut(S::Item) -> T + Unpin,
+{
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.stream).poll_next(context) {
+            Poll::Ready(item) => Poll::Ready(item.map(&mut this.f)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Merges two synthetic streams of odd
+// and even numbers, one mapped to
+// negative values, on an executor and
+// ensures every item arrives with the
+// streams interleaved.
+#[test_case]
+fn test_merge_and_map() {
+    use super::{executor::Executor, Task};
+    use alloc::{rc::Rc, vec::Vec};
+    use core::cell::RefCell;
+    use futures_util::stream::{iter, StreamExt};
+
+    let items = Rc::new(RefCell::new(Vec::new()));
+    let mut executor = Executor::new();
+    {
+        let items = items.clone();
+        executor
+            .spawn(Task::new(async move {
+                let odd = iter([1, 3, 5].iter().copied());
+                let even = map(iter([2, 4, 6].iter().copied()), |n: i32| -n);
+                let mut merged = merge(odd, even);
+                while let Some(item) = merged.next().await {
+                    items.borrow_mut().push(item);
+                }
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(*items.borrow(), [1, -2, 3, -4, 5, -6]);
+}
@@ -0,0 +1,302 @@
+//! Synchronization primitives for
+//! tasks. Unlike spin::Mutex they do
+//! not block the executor: a task
+//! waiting for a lock is suspended
+//! and other tasks keep running.
+
+use alloc::collections::VecDeque;
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+/// Whether the lock is held and the
+/// wakers of the tasks waiting for it,
+/// each with the id of its Lock future.
+struct MutexState {
+    locked: bool,
+    waiters: VecDeque<(usize, Waker)>,
+    next_waiter: usize,
+}
+
+impl MutexState {
+    /// Stores the waker of a waiting lock
+    /// future, replacing the one it
+    /// stored on an earlier poll.
+    /// waiter:   id of the lock future
+    /// waker:    waker to store
+    fn register(&mut self, waiter: usize, waker: &Waker) {
+        match self.waiters.iter_mut().find(|(id, _)| *id == waiter) {
+            Some((_, stored)) => {
+                if !stored.will_wake(waker) {
+                    *stored = waker.clone();
+                }
+            }
+            None => self.waiters.push_back((waiter, waker.clone())),
+        }
+    }
+
+    /// Removes the waker slot of a lock
+    /// future. Returns false if there was
+    /// none, because the future has
+    /// been woken since.
+    fn remove(&mut self, waiter: usize) -> bool {
+        match self.waiters.iter().position(|(id, _)| *id == waiter) {
+            Some(index) => {
+                self.waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Mutex whose lock is awaited. The
+/// internal state is protected by a
+/// spin lock that is only held for
+/// a few instructions at a time.
+pub struct Mutex<T> {
+    state: spin::Mutex<MutexState>,
+    value: UnsafeCell<T>,
+}
+
+// The value is only accessed through
+// a guard, and there is at most one.
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    /// Creates an unlocked mutex
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: spin::Mutex::new(MutexState {
+                locked: false,
+                waiters: VecDeque::new(),
+                next_waiter: 0,
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Returns a future that resolves to
+    /// a guard once the lock is acquired.
+    /// It is ready at once if the mutex
+    /// is not locked.
+    pub fn lock(&self) -> Lock<T> {
+        Lock {
+            mutex: self,
+            waiter: None,
+        }
+    }
+
+    /// Takes the lock if it is free,
+    /// without waiting.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            None
+        } else {
+            state.locked = true;
+            Some(MutexGuard::new(self))
+        }
+    }
+
+    /// Wakes the task that has waited
+    /// the longest, if the mutex is
+    /// not locked.
+    fn wake_next(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            if state.locked {
+                None
+            } else {
+                state.waiters.pop_front()
+            }
+        };
+
+        // Wake after unlocking the state
+        // so the woken task can lock it
+        if let Some((_, waker)) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by Mutex::lock. It
+/// has one waker slot in the mutex
+/// while it waits, with the id in
+/// waiter.
+pub struct Lock<'a, T> {
+    mutex: &'a Mutex<T>,
+    waiter: Option<usize>,
+}
+
+impl<'a, T> Future for Lock<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    /// Takes the lock if it is free.
+    /// Otherwise the waker is stored in
+    /// the slot of this future and woken
+    /// when a guard is dropped.
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<MutexGuard<'a, T>> {
+        let mutex = self.mutex;
+        let mut state = mutex.state.lock();
+        if state.locked {
+            let waiter = match self.waiter {
+                Some(waiter) => waiter,
+                None => {
+                    let waiter = state.next_waiter;
+                    state.next_waiter = state.next_waiter.wrapping_add(1);
+                    waiter
+                }
+            };
+            state.register(waiter, context.waker());
+            drop(state);
+            self.waiter = Some(waiter);
+            Poll::Pending
+        } else {
+            state.locked = true;
+            if let Some(waiter) = self.waiter.take() {
+                state.remove(waiter);
+            }
+            drop(state);
+            Poll::Ready(MutexGuard::new(mutex))
+        }
+    }
+}
+
+impl<'a, T> Drop for Lock<'a, T> {
+    /// Removes the waker slot of a lock
+    /// future that never got the lock. If
+    /// the slot is gone the future was
+    /// woken, so the wake is passed on
+    /// to the next waiter.
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            if !self.mutex.state.lock().remove(waiter) {
+                self.mutex.wake_next();
+            }
+        }
+    }
+}
+
+/// Gives access to the value of a
+/// locked Mutex. The lock is released
+/// when the guard is dropped. The guard
+/// hands out &mut T, so it is only
+/// Send if T is and Sync if T is.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    _value: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    /// Creates the guard of a mutex the
+    /// caller has locked.
+    fn new(mutex: &'a Mutex<T>) -> Self {
+        MutexGuard {
+            mutex,
+            _value: PhantomData,
+        }
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    /// Unlocks the mutex and wakes
+    /// the next waiting task.
+    fn drop(&mut self) {
+        self.mutex.state.lock().locked = false;
+        self.mutex.wake_next();
+    }
+}
+
+// Runs two tasks that each increment
+// a counter 1000 times, yielding while
+// the lock is held, and ensures no
+// update is lost.
+#[test_case]
+fn test_mutex_counter() {
+    use super::{executor::Executor, yield_now, Task};
+    use alloc::sync::Arc;
+
+    let counter = Arc::new(Mutex::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..2 {
+        let counter = counter.clone();
+        executor
+            .spawn(Task::new(async move {
+                for _ in 0..1000 {
+                    let mut value = counter.lock().await;
+                    let old = *value;
+                    yield_now().await;
+                    *value = old + 1;
+                }
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+
+    assert_eq!(*counter.try_lock().expect("mutex still locked"), 2000);
+}
+
+// Polls a waiting lock future twice and
+// ensures it keeps one waker slot, that
+// dropping it frees the slot, and that
+// a task waiting after it still gets
+// the lock once the guard is dropped.
+#[test_case]
+fn test_lock_keeps_one_waiter_slot() {
+    use super::{executor::Executor, Task};
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+    use futures_util::task::noop_waker_ref;
+
+    let mutex = Rc::new(Mutex::new(0));
+    let guard = mutex.try_lock().expect("mutex locked");
+    let mut context = Context::from_waker(noop_waker_ref());
+    {
+        let mut lock = mutex.lock();
+        assert!(Pin::new(&mut lock).poll(&mut context).is_pending());
+        assert!(Pin::new(&mut lock).poll(&mut context).is_pending());
+        assert_eq!(mutex.state.lock().waiters.len(), 1);
+    }
+    assert!(mutex.state.lock().waiters.is_empty());
+
+    let locked = Rc::new(Cell::new(false));
+    let mut executor = Executor::new();
+    {
+        let mutex = mutex.clone();
+        let locked = locked.clone();
+        executor
+            .spawn(Task::new(async move {
+                *mutex.lock().await += 1;
+                locked.set(true);
+            }))
+            .expect("spawn failed");
+    }
+    executor.run_ready_tasks();
+    assert!(!locked.get());
+
+    drop(guard);
+    executor.run_ready_tasks();
+    assert!(locked.get());
+    assert_eq!(*mutex.try_lock().expect("mutex still locked"), 1);
+}
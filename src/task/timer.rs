@@ -0,0 +1,232 @@
+//! Futures that complete after a
+//! number of timer interrupts have
+//! been handled.
+
+use crate::interrupts;
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+//// SLEEPING TASKS
+
+/// Wakers of the sleeping tasks keyed
+/// by the tick they should wake at.
+/// Tasks only lock it with interrupts
+/// disabled, so the timer interrupt
+/// never finds it locked on a
+/// single CPU.
+static SLEEPERS: Mutex<BTreeMap<u64, Vec<Waker>>> = Mutex::new(BTreeMap::new());
+
+/// Called by the timer interrupt handler
+/// to wake the tasks whose deadline has
+/// passed. Like add_scancode, it does as
+/// little as possible: the wakers are
+/// only woken, never removed, so no
+/// memory is freed in the interrupt.
+/// Each Sleep removes its own entry
+/// when it is polled or dropped.
+pub(crate) fn wake_expired(ticks: u64) {
+    if let Some(sleepers) = SLEEPERS.try_lock() {
+        for waker in sleepers.range(..=ticks).flat_map(|(_, wakers)| wakers) {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+/// Future returned by sleep that is
+/// ready once the tick count reaches
+/// its deadline.
+pub struct Sleep {
+    deadline: u64,
+    waker: Option<Waker>,
+}
+
+/// Returns a future that completes
+/// after at least ms milliseconds. The
+/// task is woken by the timer interrupt
+/// instead of being polled repeatedly.
+pub fn sleep(ms: u64) -> Sleep {
+    sleep_ticks(interrupts::ms_to_ticks(ms))
+}
+
+/// Returns a future that completes
+/// after the given number of timer
+/// ticks have passed.
+pub fn sleep_ticks(ticks: u64) -> Sleep {
+    Sleep {
+        deadline: interrupts::ticks().saturating_add(ticks),
+        waker: None,
+    }
+}
+
+impl Sleep {
+    /// Removes the waker registered
+    /// for the deadline, if any.
+    fn unregister(&mut self, sleepers: &mut BTreeMap<u64, Vec<Waker>>) {
+        let waker = match self.waker.take() {
+            Some(waker) => waker,
+            None => return,
+        };
+
+        if let Some(wakers) = sleepers.get_mut(&self.deadline) {
+            if let Some(index) = wakers.iter().position(|w| w.will_wake(&waker)) {
+                wakers.swap_remove(index);
+            }
+            if wakers.is_empty() {
+                sleepers.remove(&self.deadline);
+            }
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    /// Completes once the deadline has
+    /// passed. Until then the waker of
+    /// the task is registered so the
+    /// timer interrupt can wake it.
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        // Checking the ticks with interrupts
+        // disabled means the deadline cannot
+        // pass between the check and the
+        // registration
+        without_interrupts(|| {
+            let mut sleepers = SLEEPERS.lock();
+            self.unregister(&mut sleepers);
+            if interrupts::ticks() >= self.deadline {
+                return Poll::Ready(());
+            }
+
+            let waker = context.waker().clone();
+            sleepers
+                .entry(self.deadline)
+                .or_default()
+                .push(waker.clone());
+            self.waker = Some(waker);
+            Poll::Pending
+        })
+    }
+}
+
+impl Drop for Sleep {
+    /// Removes the waker of a sleep
+    /// that is dropped before it
+    /// completes.
+    fn drop(&mut self) {
+        if self.waker.is_some() {
+            without_interrupts(|| self.unregister(&mut SLEEPERS.lock()));
+        }
+    }
+}
+
+//// TIMEOUT
+
+/// Error of a future that did not
+/// complete before its timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timeout;
+
+impl core::fmt::Display for Timeout {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "timed out")
+    }
+}
+
+/// Future returned by timeout.
+pub struct WithTimeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+/// Returns a future that resolves to
+/// the output of future if it completes
+/// within ms milliseconds, or to
+/// Err(Timeout) once they have passed.
+/// The future is dropped on timeout.
+/// ms:       longest time to wait
+/// future:   operation that may hang
+pub fn timeout<F: Future>(ms: u64, future: F) -> WithTimeout<F> {
+    WithTimeout {
+        future: Box::pin(future),
+        sleep: sleep(ms),
+    }
+}
+
+impl<F: Future> Future for WithTimeout<F> {
+    type Output = Result<F::Output, Timeout>;
+
+    /// Polls the future before the sleep,
+    /// so a future that completes in the
+    /// same poll as the deadline passes
+    /// returns its value.
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(value) = self.future.as_mut().poll(context) {
+            return Poll::Ready(Ok(value));
+        }
+        match Pin::new(&mut self.sleep).poll(context) {
+            Poll::Ready(()) => Poll::Ready(Err(Timeout)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+// Awaits a future that never completes
+// with a 20 ms timeout and ensures it
+// times out after the deadline.
+#[test_case]
+fn test_timeout_expires() {
+    use super::{simple_executor::SimpleExecutor, Task};
+    use alloc::rc::Rc;
+    use core::{cell::Cell, future::pending};
+
+    let result = Rc::new(Cell::new(None));
+    let start = interrupts::ticks();
+
+    let mut executor = SimpleExecutor::new();
+    {
+        let result = result.clone();
+        executor.spawn(Task::new(async move {
+            result.set(Some(timeout(20, pending::<u32>()).await));
+        }));
+    }
+    executor.run();
+
+    assert_eq!(result.get(), Some(Err(Timeout)));
+    assert!(interrupts::ticks() - start >= interrupts::ms_to_ticks(20));
+}
+
+// Awaits a future that completes after
+// yielding once with a long timeout and
+// ensures its value wins, then ensures
+// a ready future wins against a timeout
+// that has already passed.
+#[test_case]
+fn test_timeout_quick_future_wins() {
+    use super::{simple_executor::SimpleExecutor, yield_now, Task};
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+
+    let results = Rc::new(Cell::new((None, None)));
+    let mut executor = SimpleExecutor::new();
+    {
+        let results = results.clone();
+        executor.spawn(Task::new(async move {
+            let quick = timeout(1000, async {
+                yield_now().await;
+                42
+            })
+            .await;
+            let expired = timeout(0, async { 7 }).await;
+            results.set((Some(quick), Some(expired)));
+        }));
+    }
+    executor.run();
+
+    assert_eq!(results.get(), (Some(Ok(42)), Some(Ok(7))));
+}
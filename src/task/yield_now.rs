@@ -0,0 +1,40 @@
+//! Future that lets a task give up
+//! the CPU to the other ready tasks.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Future returned by yield_now.
+pub struct YieldNow {
+    yielded: bool,
+}
+
+/// Returns a future that is pending
+/// the first time it is polled and
+/// ready the second time. Awaiting it
+/// in a long computation lets the
+/// other queued tasks run in between.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    /// The task wakes itself before
+    /// returning Pending, so the executor
+    /// queues it again behind the tasks
+    /// that are already waiting.
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+
+        self.yielded = true;
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
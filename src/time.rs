@@ -0,0 +1,6 @@
+//! Wall-clock time. The timer interrupt
+//! only counts time since boot, so the
+//! date comes from the CMOS real-time
+//! clock.
+
+pub mod rtc;
@@ -0,0 +1,207 @@
+//! Reader for the CMOS real-time clock.
+//! The clock keeps the date while the
+//! machine is off and is read through
+//! the CMOS index and data ports.
+
+use core::fmt;
+use x86_64::instructions::{interrupts, port::Port};
+
+// CMOS ports selecting a register
+// and reading its value.
+const CMOS_INDEX_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+// RTC registers of each date field
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+
+// Status register A and the bit set
+// while the clock updates its fields.
+const STATUS_A: u8 = 0x0a;
+const UPDATE_IN_PROGRESS: u8 = 0b1000_0000;
+
+// Status register B and its bits for
+// 24-hour and binary (not BCD) mode.
+const STATUS_B: u8 = 0x0b;
+const HOUR_24: u8 = 0b10;
+const BINARY: u8 = 0b100;
+
+// Bit of the hours register set for
+// PM times in 12-hour mode.
+const HOUR_PM: u8 = 0b1000_0000;
+
+/// Date and time read from the RTC.
+/// The RTC has no time zone; firmware
+/// usually keeps it in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+/// Formats the time as
+/// YYYY-MM-DD HH:MM:SS.
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Raw values of the date registers
+/// and the century register, if any,
+/// in the format set in status B.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+/// Reads a CMOS register. The index
+/// write and data read must not be
+/// split by another CMOS access.
+fn read_register(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(CMOS_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    interrupts::without_interrupts(|| unsafe {
+        index.write(register);
+        data.read()
+    })
+}
+
+/// Waits for a running update to end
+/// and reads every date register.
+fn read_raw(century: Option<u8>) -> RawTime {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime {
+        second: read_register(SECONDS),
+        minute: read_register(MINUTES),
+        hour: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: century.map(read_register),
+    }
+}
+
+/// Converts a BCD byte to binary
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Converts the raw registers to a
+/// DateTime using the mode bits of
+/// status B. Without a century
+/// register the year is taken to be
+/// in the 2000s.
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let convert = |value: u8| {
+        if status_b & BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+
+    // In 12-hour mode 12 AM is hour 0
+    // and PM adds 12 to every other hour
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        let pm = raw.hour & HOUR_PM != 0;
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = raw.century.map(convert).unwrap_or(20);
+    DateTime {
+        year: u16::from(century) * 100 + u16::from(convert(raw.year)),
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+/// Reads the current date and time.
+/// The registers are read until two
+/// reads match, so a value that
+/// changed halfway through a read is
+/// never returned. The century
+/// register is found in the ACPI
+/// tables, which needs physical
+/// memory to be mapped.
+pub fn read_datetime() -> DateTime {
+    let century = crate::acpi::century_register();
+    let mut raw = read_raw(century);
+    loop {
+        let again = read_raw(century);
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+    decode(raw, read_register(STATUS_B))
+}
+
+// Decodes a BCD time in 12-hour mode
+// and a binary time in 24-hour mode.
+#[test_case]
+fn test_decode_rtc_modes() {
+    let raw = RawTime {
+        second: 0x59,
+        minute: 0x30,
+        hour: HOUR_PM | 0x12,
+        day: 0x31,
+        month: 0x12,
+        year: 0x99,
+        century: Some(0x19),
+    };
+    let time = decode(raw, 0);
+    assert_eq!(time.year, 1999);
+    assert_eq!((time.month, time.day), (12, 31));
+    assert_eq!((time.hour, time.minute, time.second), (12, 30, 59));
+
+    let raw = RawTime {
+        hour: 23,
+        year: 26,
+        century: None,
+        ..raw
+    };
+    let time = decode(raw, HOUR_24 | BINARY);
+    assert_eq!((time.year, time.hour), (2026, 23));
+}
+
+// Reads the clock twice and ensures
+// every field is in range and time
+// does not go backwards.
+#[test_case]
+fn test_read_datetime() {
+    let first = read_datetime();
+    assert!((1..=12).contains(&first.month));
+    assert!((1..=31).contains(&first.day));
+    assert!(first.hour < 24 && first.minute < 60 && first.second < 60);
+
+    let second = read_datetime();
+    assert!(second >= first);
+}
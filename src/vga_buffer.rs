@@ -22,6 +22,35 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    // Every color, in the order of
+    // their values
+    const ALL: [Color; 16] = [
+        Color::Black,
+        Color::Blue,
+        Color::Green,
+        Color::Cyan,
+        Color::Red,
+        Color::Magenta,
+        Color::Brown,
+        Color::LightGray,
+        Color::DarkGray,
+        Color::LightBlue,
+        Color::LightGreen,
+        Color::LightCyan,
+        Color::LightRed,
+        Color::Pink,
+        Color::Yellow,
+        Color::White,
+    ];
+
+    // Returns the color of the lower
+    // four bits of value
+    fn from_nibble(value: u8) -> Color {
+        Color::ALL[usize::from(value & 0x0f)]
+    }
+}
+
 // ColorCode is a wrapper for u8
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)]
@@ -31,11 +60,35 @@ struct ColorCode(u8);
 // with the specified background and
 // foreground color
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    const fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    // Replaces the lower four bits
+    fn with_foreground(self, foreground: Color) -> ColorCode {
+        ColorCode(self.0 & 0xf0 | foreground as u8)
+    }
+
+    // Replaces the upper four bits
+    fn with_background(self, background: Color) -> ColorCode {
+        ColorCode(self.0 & 0x0f | (background as u8) << 4)
+    }
+
+    // Returns the lower four bits
+    fn foreground(self) -> Color {
+        Color::from_nibble(self.0)
+    }
+
+    // Returns the upper four bits
+    fn background(self) -> Color {
+        Color::from_nibble(self.0 >> 4)
+    }
 }
 
+// Colors used when the writer is
+// created and after an ANSI reset.
+const DEFAULT_COLOR: ColorCode = ColorCode::new(Color::White, Color::Black);
+
 // Stores the ascii character
 // type and the color values
 // associated with the second
@@ -59,18 +112,80 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+// A row whose cells all changed, such
+// as a cleared or scrolled one, is
+// written as one plain row of cells by
+// CellSink::write_row, which relies on
+// Volatile adding no bytes
+const _: () = assert!(
+    core::mem::size_of::<[Volatile<ScreenChar>; BUFFER_WIDTH]>()
+        == core::mem::size_of::<[ScreenChar; BUFFER_WIDTH]>()
+);
+
+/// How the writer responds to
+/// the BEL (0x07) character.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BellMode {
+    /// Ignore the bell
+    Silent,
+    /// Briefly invert the screen colors
+    Visual,
+    /// Beep the PC speaker
+    Audible,
+}
+
+// Frequency of the audible bell in
+// Hz and how long the bell lasts
+// in milliseconds.
+const BELL_FREQUENCY: u32 = 880;
+const BELL_DURATION_MS: u64 = 50;
+
+// Tick at which the timer interrupt
+// ends the visual bell, or 0 if the
+// screen is not inverted.
+static BELL_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
 // The writer keeps track of the
 // position, the current color
 // value and a buffer to write
 // to that is static for each
-// execution
+// execution. Output is drawn into
+// the back array and copied to the
+// VGA buffer by flush, so the screen
+// never shows a half scrolled frame.
+// Once the heap is up, front holds a
+// copy of what the screen shows, so
+// flush only writes changed cells.
 pub struct Writer {
     column_position: usize,
     row_position: usize,
     color_code: ColorCode,
+    bell_mode: BellMode,
+    tab_width: usize,
+    ansi_state: AnsiState,
+    scrollback: Scrollback,
+    back: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    dirty_rows: u32,
+    inverted: bool,
+    front: Option<Box<[Row; BUFFER_HEIGHT]>>,
     buffer: &'static mut Buffer,
 }
 
+// Blank cell in the default colors
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: DEFAULT_COLOR,
+};
+
+// Bit mask with a bit for every row
+const ALL_ROWS: u32 = (1 << BUFFER_HEIGHT) - 1;
+
+/// Columns between tab stops until
+/// set_tab_width is called.
+pub const DEFAULT_TAB_WIDTH: usize = 8;
+
+use crate::error::KernelError;
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -84,7 +199,18 @@ lazy_static! {
     pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
         column_position: 0,
         row_position: BUFFER_HEIGHT - 1,
-        color_code: ColorCode::new(Color::White, Color::Black),
+        color_code: DEFAULT_COLOR,
+        bell_mode: BellMode::Visual,
+        tab_width: DEFAULT_TAB_WIDTH,
+        ansi_state: AnsiState::Normal,
+        scrollback: Scrollback::new(),
+        // The screen still shows what was
+        // there before, so the first flush
+        // draws every row
+        back: [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        dirty_rows: ALL_ROWS,
+        inverted: false,
+        front: None,
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
     });
 }
@@ -113,18 +239,235 @@ pub fn _print(args: fmt::Arguments) {
     });
 }
 
+// Print macros for interrupt handlers.
+// If the WRITER is already locked, such
+// as by the code that was interrupted,
+// the output is dropped instead of
+// waiting on the lock forever.
+#[macro_export]
+macro_rules! try_print {
+  ($($arg:tt)*) => ($crate::vga_buffer::_try_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! try_println {
+  () => ($crate::try_print!("\n"));
+  ($($arg:tt)*) => ($crate::try_print!("{}\n", format_args!($($arg)*)));
+}
+
+/// Prints the arguments if the WRITER
+/// is not locked and returns whether
+/// they were printed.
+#[doc(hidden)]
+pub fn _try_print(args: fmt::Arguments) -> bool {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| match WRITER.try_lock() {
+        Some(mut writer) => {
+            writer.write_fmt(args).unwrap();
+            true
+        }
+        None => false,
+    })
+}
+
+/// Sets the colors used for text
+/// written to the WRITER afterwards.
+pub fn set_color(foreground: Color, background: Color) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().set_color(foreground, background);
+    });
+}
+
+/// Prints the tag in the foreground
+/// color and then the arguments in the
+/// colors used before, with the WRITER
+/// locked once so nothing is printed
+/// in between.
+/// tag:          text printed first
+/// foreground:   color of the tag
+/// args:         text printed after the tag
+pub fn print_tagged(tag: &str, foreground: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let color_code = writer.color_code;
+        writer.color_code = color_code.with_foreground(foreground);
+        writer.write_string(tag);
+        writer.color_code = color_code;
+        writer.write_fmt(args).unwrap();
+    });
+}
+
+/// Sets how the BEL character
+/// is handled by the WRITER.
+pub fn set_bell(mode: BellMode) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().bell_mode = mode;
+    });
+}
+
 impl Writer {
+    /// Sets the colors of characters
+    /// written from now on. Cells that
+    /// are already drawn keep their
+    /// colors, while rows cleared later
+    /// are filled with the new background.
+    /// foreground:   text color
+    /// background:   cell color
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /// Sets the columns between tab
+    /// stops. A width of 0 is taken as 1.
+    /// width:    columns per tab stop
+    pub fn set_tab_width(&mut self, width: usize) {
+        self.tab_width = width.max(1);
+    }
+
+    /// Returns the character shown in a
+    /// cell and its text and cell colors,
+    /// or None if the cell is outside of
+    /// the screen. Code page 437 glyphs
+    /// are decoded to their characters,
+    /// and bytes without one in the table
+    /// are returned as U+FFFD.
+    /// row:      row of the cell
+    /// col:      column of the cell
+    pub fn read_at(&self, row: usize, col: usize) -> Option<(char, Color, Color)> {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return None;
+        }
+
+        let cell = self.cell(row, col);
+        let c = match cell.ascii_character {
+            byte @ 0x20..=0x7e => char::from(byte),
+            byte => cp437_char(byte).unwrap_or(char::REPLACEMENT_CHARACTER),
+        };
+        Some((
+            c,
+            cell.color_code.foreground(),
+            cell.color_code.background(),
+        ))
+    }
+
+    /// Writes a character in the given
+    /// colors to a cell, without moving
+    /// the cursor or changing the colors
+    /// used for printing. Characters
+    /// without a code page 437 glyph are
+    /// drawn as 0xfe. A cell outside of
+    /// the screen is not written and
+    /// CellOutOfBounds is returned.
+    /// row:          row of the cell
+    /// col:          column of the cell
+    /// c:            character to draw
+    /// fg:           text color
+    /// bg:           cell color
+    pub fn write_at(
+        &mut self,
+        row: usize,
+        col: usize,
+        c: char,
+        fg: Color,
+        bg: Color,
+    ) -> Result<(), KernelError> {
+        if row >= BUFFER_HEIGHT || col >= BUFFER_WIDTH {
+            return Err(KernelError::CellOutOfBounds { row, col });
+        }
+
+        let ascii_character = match c {
+            ' '..='~' => c as u8,
+            c => cp437_byte(c).unwrap_or(0xfe),
+        };
+        self.set_cell(
+            row,
+            col,
+            ScreenChar {
+                ascii_character,
+                color_code: ColorCode::new(fg, bg),
+            },
+        );
+        self.flush();
+        Ok(())
+    }
+
     /// Function to write a byte to the
     /// screen. This will put the character
     /// at the position of the cursor, and
     /// increment the cursor.
     /// byte:     character to write
     pub fn write_byte(&mut self, byte: u8) {
+        self.put_byte(byte);
+        self.flush();
+    }
+
+    /// Copies the rows changed since the
+    /// last flush from the back array to
+    /// the VGA buffer, see flush_rows.
+    /// Returns the number of cells
+    /// written.
+    pub fn flush(&mut self) -> usize {
+        let writes = flush_rows(
+            &self.back,
+            self.front.as_deref_mut(),
+            self.dirty_rows,
+            self.inverted,
+            &mut *self.buffer,
+        );
+        self.dirty_rows = 0;
+        writes
+    }
+
+    /// Returns the cell in the back array
+    fn cell(&self, row: usize, col: usize) -> ScreenChar {
+        self.back[row][col]
+    }
+
+    /// Sets a cell in the back array and
+    /// marks its row for the next flush
+    fn set_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.back[row][col] = character;
+        self.dirty_rows |= 1 << row;
+    }
+
+    /// Writes a byte to the back array
+    /// without flushing it.
+    fn put_byte(&mut self, byte: u8) {
+        // New output is always written
+        // to the live screen
+        self.scroll_to_bottom();
+
         match byte {
             // If the byte is a new line,
             // skip a line
             b'\n' => self.new_line(),
 
+            // The bell does not print a
+            // glyph or move the cursor
+            0x07 => self.bell(),
+
+            // A carriage return moves the
+            // cursor back to the start of
+            // the line, so the next output
+            // overwrites it
+            b'\r' => {
+                self.column_position = 0;
+                self.update_cursor(self.row_position, 0);
+            }
+
+            // A tab fills the line with
+            // spaces up to the next stop
+            b'\t' => self.tab(),
+
             // For all other bytes, write
             // the character into the buffer
             // in the writer, and increment
@@ -143,61 +486,144 @@ impl Writer {
                 // Write the character into
                 // the buffer using the current
                 // color, and increment the column number
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
+                self.set_cell(
+                    row,
+                    col,
+                    ScreenChar {
+                        ascii_character: byte,
+                        color_code,
+                    },
+                );
                 self.column_position += 1;
+                self.update_cursor(self.row_position, self.column_position);
             }
         }
     }
 
+    /// Writes spaces in the current color
+    /// up to the next tab stop. If the
+    /// stop is past the end of the line,
+    /// a new line is started instead.
+    fn tab(&mut self) {
+        if self.column_position >= BUFFER_WIDTH {
+            self.new_line();
+        }
+        let stop = (self.column_position / self.tab_width + 1) * self.tab_width;
+        if stop > BUFFER_WIDTH {
+            self.new_line();
+            return;
+        }
+
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        for col in self.column_position..stop {
+            self.set_cell(self.row_position, col, blank);
+        }
+        self.column_position = stop;
+        self.update_cursor(self.row_position, self.column_position);
+    }
+
     /// Write a string of bytes
-    /// into the vga buffer.
+    /// into the vga buffer. ANSI escape
+    /// sequences are interpreted instead
+    /// of printed, even when they are
+    /// split across several calls.
     /// s:    string to print
     pub fn write_string(&mut self, s: &str) {
         for byte in s.bytes() {
-            match byte {
-                // Print all printable characters
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-
-                // If the character is 0x08,
-                // do a backspace.
-                // TODO Make backspace go up one row
-                // if the cursor is at the far left
-                // of the "screen"
-                0x08 => self.backspace(),
-
-                // Print 0x7e if not printable
-                _ => self.write_byte(0xfe),
+            self.write_text_byte(byte);
+        }
+        self.flush();
+    }
+
+    /// Writes a byte of a string to the
+    /// back array, interpreting escape
+    /// sequences and control bytes.
+    fn write_text_byte(&mut self, byte: u8) {
+        if self.ansi_state != AnsiState::Normal || byte == ESC {
+            self.ansi_byte(byte);
+            return;
+        }
+
+        match byte {
+            // Print all printable characters
+            0x20..=0x7e | b'\n' | b'\r' | b'\t' | 0x07 => self.put_byte(byte),
+
+            // If the character is 0x08,
+            // do a backspace.
+            0x08 => self.backspace(),
+
+            // Print 0xfe (■) if not printable
+            _ => self.put_byte(0xfe),
+        }
+    }
+
+    /// Same as write_string, but characters
+    /// outside of ASCII are drawn with their
+    /// code page 437 glyph, such as box
+    /// drawing characters and arrows. Those
+    /// without one are printed as 0xfe.
+    /// s:    string to print
+    pub fn write_cp437(&mut self, s: &str) {
+        for character in s.chars() {
+            if character.is_ascii() {
+                self.write_text_byte(character as u8);
+            } else {
+                self.put_byte(cp437_byte(character).unwrap_or(0xfe));
             }
         }
+        self.flush();
     }
 
     /// Skips a line on the VGA
-    /// buffer. This requires copying
+    /// buffer. Above the bottom row the
+    /// cursor moves down one row. On the
+    /// bottom row this requires copying
     /// the rows to the row above
     /// and clearing the last row.
     fn new_line(&mut self) {
-        // Copy each row to the row
-        // above it, stopping before
-        // the bottom row.
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
-            }
-        }
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+        } else {
+            // Keep the top row in the
+            // scrollback before it is
+            // overwritten
+            let top_row = self.read_row(0);
+            self.scrollback.push(top_row);
+
+            // Copy each row to the row
+            // above it, stopping before
+            // the bottom row.
+            self.back.copy_within(1.., 0);
+            self.dirty_rows = ALL_ROWS;
 
-        // Clear the bottom row.
-        self.clear_row(BUFFER_HEIGHT - 1);
+            // Clear the bottom row.
+            self.clear_row(BUFFER_HEIGHT - 1);
+        }
 
         // Set the cursor position to
         // the start of the new line.
         self.column_position = 0;
-        if self.row_position < BUFFER_HEIGHT - 1 {
-            self.row_position += 1;
-        }
+        self.update_cursor(self.row_position, self.column_position);
+    }
+
+    /// Moves the blinking hardware cursor
+    /// to the given cell by writing the
+    /// cell index into the CRTC cursor
+    /// location registers. The position
+    /// is clamped to the screen, so a full
+    /// line leaves it on the last column.
+    /// row:      row position
+    /// col:      column position
+    fn update_cursor(&self, row: usize, col: usize) {
+        let row = row.min(BUFFER_HEIGHT - 1);
+        let col = col.min(BUFFER_WIDTH - 1);
+        let position = (row * BUFFER_WIDTH + col) as u16;
+
+        write_crtc(CURSOR_LOCATION_LOW, position as u8);
+        write_crtc(CURSOR_LOCATION_HIGH, (position >> 8) as u8);
     }
 
     /// Function called to delete a
@@ -211,10 +637,15 @@ impl Writer {
     /// row:      row position
     /// col:      column position
     fn delete_char(&mut self, row: usize, col: usize) {
-        self.buffer.chars[row][col].write(ScreenChar {
-            ascii_character: b' ',
-            color_code: self.color_code,
-        });
+        let color_code = self.color_code;
+        self.set_cell(
+            row,
+            col,
+            ScreenChar {
+                ascii_character: b' ',
+                color_code,
+            },
+        );
     }
 
     /// Clear the provided row
@@ -222,8 +653,48 @@ impl Writer {
     /// it with space characters.
     /// row:      row number to clear
     fn clear_row(&mut self, row: usize) {
-        for col in 0..BUFFER_WIDTH {
-            self.delete_char(row, col);
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        self.back[row] = [blank; BUFFER_WIDTH];
+        self.dirty_rows |= 1 << row;
+    }
+
+    /// Called when a BEL character is
+    /// written. Depending on the bell
+    /// mode the screen colors are
+    /// inverted or the speaker beeps
+    /// for a short moment. Both are
+    /// ended by the timer interrupt, so
+    /// the writer is not held meanwhile.
+    fn bell(&mut self) {
+        let ticks = crate::interrupts::ms_to_ticks(BELL_DURATION_MS);
+        match self.bell_mode {
+            BellMode::Silent => {}
+            BellMode::Visual => {
+                // A bell while inverted only
+                // moves the deadline
+                if !self.inverted {
+                    self.inverted = true;
+                    self.dirty_rows = ALL_ROWS;
+                    self.flush();
+                }
+                let deadline = crate::interrupts::ticks().saturating_add(ticks.max(1));
+                BELL_DEADLINE.store(deadline, Ordering::Relaxed);
+            }
+            BellMode::Audible => crate::sound::play_tone(BELL_FREQUENCY, ticks),
+        }
+    }
+
+    /// Ends the visual bell by drawing
+    /// the screen in its own colors again.
+    fn end_bell(&mut self) {
+        BELL_DEADLINE.store(0, Ordering::Relaxed);
+        if self.inverted {
+            self.inverted = false;
+            self.dirty_rows = ALL_ROWS;
+            self.flush();
         }
     }
 
@@ -234,6 +705,8 @@ impl Writer {
     /// at the beginning of the first line,
     /// nothing happens.
     fn backspace(&mut self) {
+        self.scroll_to_bottom();
+
         // If the cursor is at the start
         // of the line, it needs to wrap
         // around to the end of the
@@ -257,54 +730,1601 @@ impl Writer {
 
         // Delete the character at the cursor position
         self.delete_char(self.row_position, self.column_position);
+        self.update_cursor(self.row_position, self.column_position);
+        self.flush();
     }
 }
 
-// Implements usage of the vga
-// buffer using write macro like
-// the following:
-//      write!(writer, "I have {} apples", 12);
-use core::fmt;
-impl fmt::Write for Writer {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_string(s);
-        Ok(())
+//// SCREEN SNAPSHOTS
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+/// Heap-backed copy of every cell in
+/// the VGA buffer (characters and colors)
+/// along with the cursor position, used
+/// to restore the screen after it has
+/// been temporarily drawn over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScreenSnapshot {
+    chars: Vec<ScreenChar>,
+    column_position: usize,
+    row_position: usize,
+}
+
+impl Writer {
+    /// Copies the entire screen and
+    /// the cursor position into a
+    /// snapshot on the heap.
+    pub fn save_screen(&self) -> ScreenSnapshot {
+        let mut chars = Vec::with_capacity(BUFFER_HEIGHT * BUFFER_WIDTH);
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                chars.push(self.cell(row, col));
+            }
+        }
+
+        ScreenSnapshot {
+            chars,
+            column_position: self.column_position,
+            row_position: self.row_position,
+        }
+    }
+
+    /// Writes every cell of the snapshot
+    /// back into the VGA buffer and moves
+    /// the cursor to where it was when
+    /// the snapshot was taken.
+    /// snapshot:     screen to restore
+    pub fn restore_screen(&mut self, snapshot: &ScreenSnapshot) {
+        for (i, &character) in snapshot.chars.iter().enumerate() {
+            let row = i / BUFFER_WIDTH;
+            let col = i % BUFFER_WIDTH;
+            self.set_cell(row, col, character);
+        }
+        self.flush();
+
+        self.column_position = snapshot.column_position;
+        self.row_position = snapshot.row_position;
+        self.update_cursor(self.row_position, self.column_position);
     }
 }
 
-// Test ensures that a single print
-// macro call will not panic.
-#[test_case]
-fn test_println_simple() {
-    println!("test_println_simple output");
+//// SCROLLBACK
+
+/// Number of lines kept in the
+/// scrollback history.
+pub const SCROLLBACK_LINES: usize = 200;
+
+// One row of cells on the screen
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+/// How clear_screen treats the
+/// content that is on the screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClearMode {
+    /// Drop the cleared content
+    Discard,
+    /// Push the cleared content into
+    /// the scrollback history first
+    PreserveToScrollback,
 }
 
-// Test ensures that many print
-// statements will not cause a
-// panic.
-#[test_case]
-fn test_println_many() {
-    for _ in 0..200 {
-        println!("test_println_simple output");
+/// Ring buffer of lines that are no
+/// longer on the screen. While the
+/// view is scrolled up, the live
+/// screen is kept in live_rows so
+/// it can be drawn again.
+struct Scrollback {
+    lines: Vec<Row>,
+    oldest: usize,
+    offset: usize,
+    live_rows: Vec<Row>,
+}
+
+impl Scrollback {
+    /// Creates an empty history without
+    /// allocating, so the writer can be
+    /// used before the heap exists.
+    const fn new() -> Self {
+        Scrollback {
+            lines: Vec::new(),
+            oldest: 0,
+            offset: 0,
+            live_rows: Vec::new(),
+        }
+    }
+
+    /// Returns true once the ring
+    /// has been allocated by init.
+    fn is_initialized(&self) -> bool {
+        self.lines.capacity() > 0
+    }
+
+    /// Returns the number of lines
+    /// in the history.
+    fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// Returns the line at index,
+    /// where 0 is the oldest line.
+    fn get(&self, index: usize) -> &Row {
+        &self.lines[(self.oldest + index) % self.lines.len()]
+    }
+
+    /// Adds a line to the history,
+    /// replacing the oldest line when
+    /// the ring is full. Lines are
+    /// dropped until the ring is
+    /// initialized.
+    fn push(&mut self, row: Row) {
+        if !self.is_initialized() {
+            return;
+        }
+
+        if self.lines.len() < SCROLLBACK_LINES {
+            self.lines.push(row);
+        } else {
+            self.lines[self.oldest] = row;
+            self.oldest = (self.oldest + 1) % SCROLLBACK_LINES;
+        }
     }
 }
 
-// Prints out a single-line string
-// and ensures that the characters
-// in the VGA buffer match the characters
-// passed to the println! macro.
-#[test_case]
-fn test_println_output() {
-    use core::fmt::Write;
+/// Allocates the shadow of the screen
+/// the WRITER diffs against when it
+/// flushes. It starts as a copy of the
+/// VGA buffer. This must be called
+/// after the heap is initialized;
+/// until then whole rows are written.
+pub fn init_shadow() {
     use x86_64::instructions::interrupts;
 
-    let s = "Here are some words in a string for testing";
+    // Allocate before locking the writer
+    // so an allocation error can still
+    // be printed.
+    let mut front = Box::new([[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT]);
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
-        writeln!(writer, "\n{}", s).expect("writeln failed");
-        for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
-            assert_eq!(char::from(screen_char.ascii_character), c);
+        if writer.front.is_some() {
+            return;
+        }
+        for (row, cells) in front.iter_mut().enumerate() {
+            for (col, cell) in cells.iter_mut().enumerate() {
+                *cell = writer.buffer.chars[row][col].read();
+            }
+        }
+        writer.front = Some(front);
+    });
+}
+
+/// Allocates the scrollback history of
+/// the WRITER. This must be called after
+/// the heap is initialized; lines that
+/// scroll off before then are lost.
+pub fn init_scrollback() {
+    use x86_64::instructions::interrupts;
+
+    // Allocate before locking the writer
+    // so an allocation error can still
+    // be printed.
+    let lines = Vec::with_capacity(SCROLLBACK_LINES);
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        if !writer.scrollback.is_initialized() {
+            writer.scrollback.lines = lines;
+        }
+    });
+}
+
+impl Writer {
+    /// Copies the cells of one row.
+    /// row:      row number to read
+    fn read_row(&self, row: usize) -> Row {
+        let mut cells = [ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        }; BUFFER_WIDTH];
+        for (col, cell) in cells.iter_mut().enumerate() {
+            *cell = self.cell(row, col);
+        }
+        cells
+    }
+
+    /// Overwrites one row with the cells.
+    /// row:      row number to write
+    /// cells:    new content of the row
+    fn write_row(&mut self, row: usize, cells: &Row) {
+        for (col, &cell) in cells.iter().enumerate() {
+            self.set_cell(row, col, cell);
+        }
+    }
+
+    /// Clears the screen and moves the
+    /// cursor to the top left cell, (0,0).
+    /// With PreserveToScrollback the
+    /// screen is first pushed into the
+    /// scrollback history.
+    /// mode:     what to do with the content
+    pub fn clear_screen(&mut self, mode: ClearMode) {
+        self.scroll_to_bottom();
+
+        for row in 0..BUFFER_HEIGHT {
+            if mode == ClearMode::PreserveToScrollback {
+                let cells = self.read_row(row);
+                self.scrollback.push(cells);
+            }
+            self.clear_row(row);
         }
+
+        self.column_position = 0;
+        self.row_position = 0;
+        self.update_cursor(self.row_position, self.column_position);
+        self.flush();
+    }
+
+    /// Scrolls the view up into the
+    /// history by the given number
+    /// of lines, stopping at the
+    /// oldest line.
+    pub fn scroll_up(&mut self, lines: usize) {
+        if self.scrollback.len() == 0 {
+            return;
+        }
+
+        if self.scrollback.offset == 0 {
+            // Keep the live screen so it
+            // can be drawn again later
+            self.scrollback.live_rows = (0..BUFFER_HEIGHT).map(|row| self.read_row(row)).collect();
+        }
+
+        let max_offset = self.scrollback.len();
+        self.scrollback.offset = (self.scrollback.offset + lines).min(max_offset);
+        self.draw_scrollback();
+    }
+
+    /// Scrolls the view back down toward
+    /// the live screen by the given
+    /// number of lines.
+    pub fn scroll_down(&mut self, lines: usize) {
+        if self.scrollback.offset == 0 {
+            return;
+        }
+
+        self.scrollback.offset = self.scrollback.offset.saturating_sub(lines);
+        self.draw_scrollback();
+    }
+
+    /// Returns the view to the live
+    /// screen if it is scrolled up.
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll_down(self.scrollback.offset);
+    }
+
+    /// Draws the rows visible at the
+    /// current scroll offset. The view
+    /// is the history followed by the
+    /// live screen, ending offset lines
+    /// above the bottom.
+    fn draw_scrollback(&mut self) {
+        let history = self.scrollback.len();
+        for row in 0..BUFFER_HEIGHT {
+            let line = history - self.scrollback.offset + row;
+            let cells = if line < history {
+                *self.scrollback.get(line)
+            } else {
+                self.scrollback.live_rows[line - history]
+            };
+            self.write_row(row, &cells);
+        }
+
+        self.flush();
+
+        // Back at the live screen, the
+        // saved rows are not needed
+        if self.scrollback.offset == 0 {
+            self.scrollback.live_rows.clear();
+        }
+    }
+}
+
+/// Returns the text of every row on
+/// the screen of the WRITER, from top
+/// to bottom, without trailing spaces.
+pub fn screen_text() -> Vec<String> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        (0..BUFFER_HEIGHT)
+            .map(|row| {
+                let cells = writer.read_row(row);
+                let text: String = cells
+                    .iter()
+                    .map(|c| char::from(c.ascii_character))
+                    .collect();
+                String::from(text.trim_end())
+            })
+            .collect()
+    })
+}
+
+/// Clears the screen of the WRITER.
+pub fn clear_screen(mode: ClearMode) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().clear_screen(mode);
     });
 }
+
+//// CODE PAGE 437
+
+// Unicode characters with a glyph in
+// code page 437, the character set of
+// the VGA text mode. The bullet is
+// mapped to 0xf9 since 0x07 rings
+// the bell instead.
+const CP437: &[(char, u8)] = &[
+    // Single box drawing
+    ('─', 0xc4),
+    ('│', 0xb3),
+    ('┌', 0xda),
+    ('┐', 0xbf),
+    ('└', 0xc0),
+    ('┘', 0xd9),
+    ('├', 0xc3),
+    ('┤', 0xb4),
+    ('┬', 0xc2),
+    ('┴', 0xc1),
+    ('┼', 0xc5),
+    // Double box drawing
+    ('═', 0xcd),
+    ('║', 0xba),
+    ('╔', 0xc9),
+    ('╗', 0xbb),
+    ('╚', 0xc8),
+    ('╝', 0xbc),
+    ('╠', 0xcc),
+    ('╣', 0xb9),
+    ('╦', 0xcb),
+    ('╩', 0xca),
+    ('╬', 0xce),
+    // Shades and blocks
+    ('░', 0xb0),
+    ('▒', 0xb1),
+    ('▓', 0xb2),
+    ('█', 0xdb),
+    ('▄', 0xdc),
+    ('▀', 0xdf),
+    ('■', 0xfe),
+    // Arrows
+    ('↑', 0x18),
+    ('↓', 0x19),
+    ('→', 0x1a),
+    ('←', 0x1b),
+    // Symbols
+    ('•', 0xf9),
+    ('∙', 0xf9),
+    ('·', 0xfa),
+    ('°', 0xf8),
+    ('±', 0xf1),
+    ('÷', 0xf6),
+    ('√', 0xfb),
+    ('²', 0xfd),
+    ('♥', 0x03),
+    ('♦', 0x04),
+    ('♣', 0x05),
+    ('♠', 0x06),
+    // Accented letters
+    ('ü', 0x81),
+    ('é', 0x82),
+    ('ä', 0x84),
+    ('à', 0x85),
+    ('ç', 0x87),
+    ('è', 0x8a),
+    ('Ä', 0x8e),
+    ('ö', 0x94),
+    ('Ö', 0x99),
+    ('Ü', 0x9a),
+    ('ñ', 0xa4),
+    ('ß', 0xe1),
+];
+
+/// Returns the code page 437 byte
+/// of the character, if it has one.
+fn cp437_byte(character: char) -> Option<u8> {
+    CP437
+        .iter()
+        .find(|&&(unicode, _)| unicode == character)
+        .map(|&(_, byte)| byte)
+}
+
+/// Returns the character of the code
+/// page 437 byte, if it is in the table.
+fn cp437_char(byte: u8) -> Option<char> {
+    CP437
+        .iter()
+        .find(|&&(_, cp437)| cp437 == byte)
+        .map(|&(unicode, _)| unicode)
+}
+
+//// ANSI ESCAPE SEQUENCES
+
+// Escape byte starting a sequence
+const ESC: u8 = 0x1b;
+
+// Longest CSI parameter string that
+// is kept. Longer sequences are still
+// swallowed, but never applied.
+const CSI_MAX_LEN: usize = 16;
+
+// Most parameters a CSI sequence may
+// have. Sequences with more are ignored.
+const CSI_MAX_PARAMS: usize = 8;
+
+// VGA colors for the ANSI color
+// numbers 0 to 7.
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+
+/// Fixed-size buffer holding the
+/// parameter bytes of a CSI sequence,
+/// so parsing does not need the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CsiBuffer {
+    bytes: [u8; CSI_MAX_LEN],
+    len: usize,
+    overflowed: bool,
+}
+
+impl CsiBuffer {
+    const fn new() -> Self {
+        CsiBuffer {
+            bytes: [0; CSI_MAX_LEN],
+            len: 0,
+            overflowed: false,
+        }
+    }
+
+    /// Appends a parameter byte. If the
+    /// buffer is full the sequence is
+    /// marked so it is ignored.
+    fn push(&mut self, byte: u8) {
+        if self.len < CSI_MAX_LEN {
+            self.bytes[self.len] = byte;
+            self.len += 1;
+        } else {
+            self.overflowed = true;
+        }
+    }
+
+    /// Returns the parameters separated
+    /// by ';', where an empty parameter
+    /// is None. Err is returned if any
+    /// parameter is not a number or there
+    /// are more than CSI_MAX_PARAMS.
+    fn params(&self) -> Result<CsiParams, ()> {
+        if self.overflowed {
+            return Err(());
+        }
+
+        let mut params = CsiParams::new();
+        for param in self.bytes[..self.len].split(|&byte| byte == b';') {
+            let value = if param.is_empty() {
+                None
+            } else {
+                let value = param.iter().try_fold(0usize, |value, &byte| match byte {
+                    b'0'..=b'9' => Ok(value
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as usize)),
+                    _ => Err(()),
+                })?;
+                Some(value)
+            };
+            params.push(value)?;
+        }
+        Ok(params)
+    }
+}
+
+/// Parameters of a CSI sequence, kept
+/// in a fixed array since the WRITER
+/// may be locked before the heap is
+/// ready or while it is out of memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct CsiParams {
+    values: [Option<usize>; CSI_MAX_PARAMS],
+    count: usize,
+}
+
+impl CsiParams {
+    const fn new() -> Self {
+        CsiParams {
+            values: [None; CSI_MAX_PARAMS],
+            count: 0,
+        }
+    }
+
+    /// Appends a parameter. Err is
+    /// returned if the array is full.
+    fn push(&mut self, value: Option<usize>) -> Result<(), ()> {
+        if self.count == CSI_MAX_PARAMS {
+            return Err(());
+        }
+        self.values[self.count] = value;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns the parameters that were
+    /// parsed.
+    fn as_slice(&self) -> &[Option<usize>] {
+        &self.values[..self.count]
+    }
+}
+
+/// Where the writer is inside an
+/// ANSI escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AnsiState {
+    /// Bytes are printed
+    Normal,
+    /// ESC was read
+    Escape,
+    /// ESC [ was read, followed by
+    /// the parameters collected so far
+    Csi(CsiBuffer),
+}
+
+impl Writer {
+    /// Feeds one byte of an escape
+    /// sequence to the parser. Complete
+    /// sequences are applied, while
+    /// unsupported ones are dropped.
+    /// byte:     next byte of the sequence
+    fn ansi_byte(&mut self, byte: u8) {
+        self.ansi_state = match (self.ansi_state, byte) {
+            (AnsiState::Normal, ESC) => AnsiState::Escape,
+            (AnsiState::Escape, b'[') => AnsiState::Csi(CsiBuffer::new()),
+
+            // Other escapes are only two
+            // bytes long and not supported
+            (AnsiState::Escape, _) => AnsiState::Normal,
+
+            // A byte in 0x40-0x7e ends the
+            // sequence and selects the command
+            (AnsiState::Csi(buffer), 0x40..=0x7e) => {
+                self.apply_csi(&buffer, byte);
+                AnsiState::Normal
+            }
+            (AnsiState::Csi(mut buffer), _) => {
+                buffer.push(byte);
+                AnsiState::Csi(buffer)
+            }
+            (AnsiState::Normal, _) => AnsiState::Normal,
+        };
+    }
+
+    /// Runs a complete CSI sequence.
+    /// Only SGR colors (m) and cursor
+    /// positioning (H) are supported.
+    /// buffer:   parameters of the sequence
+    /// command:  final byte of the sequence
+    fn apply_csi(&mut self, buffer: &CsiBuffer, command: u8) {
+        let params = match buffer.params() {
+            Ok(params) => params,
+            Err(()) => return,
+        };
+        let params = params.as_slice();
+
+        match command {
+            b'm' => {
+                for param in params {
+                    match param.unwrap_or(0) {
+                        0 => self.color_code = DEFAULT_COLOR,
+                        code @ 30..=37 => {
+                            let color = ANSI_COLORS[code - 30];
+                            self.color_code = self.color_code.with_foreground(color);
+                        }
+                        code @ 40..=47 => {
+                            let color = ANSI_COLORS[code - 40];
+                            self.color_code = self.color_code.with_background(color);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
+            // Rows and columns start at 1
+            b'H' | b'f' => {
+                let row = params.first().copied().flatten().unwrap_or(1);
+                let col = params.get(1).copied().flatten().unwrap_or(1);
+
+                self.scroll_to_bottom();
+                self.row_position = row.clamp(1, BUFFER_HEIGHT) - 1;
+                self.column_position = col.clamp(1, BUFFER_WIDTH) - 1;
+                self.update_cursor(self.row_position, self.column_position);
+            }
+            _ => {}
+        }
+    }
+}
+
+//// DIFFERENTIAL RENDERING
+
+/// Destination of the cells flushed
+/// from the back array. The VGA buffer
+/// implements it, and tests use a fake
+/// that counts the writes.
+trait CellSink {
+    /// Writes one cell.
+    fn write_cell(&mut self, row: usize, col: usize, character: ScreenChar);
+
+    /// Writes a whole row at once.
+    fn write_row(&mut self, row: usize, cells: Row);
+}
+
+impl CellSink for Buffer {
+    fn write_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.chars[row][col].write(character);
+    }
+
+    fn write_row(&mut self, row: usize, cells: Row) {
+        // One volatile write of the whole
+        // row instead of one per cell, so
+        // it is still never optimized out
+        let row_ptr = &mut self.chars[row] as *mut _ as *mut Row;
+        unsafe { row_ptr.write_volatile(cells) };
+    }
+}
+
+/// Writes the dirty rows of back to the
+/// sink and returns the number of cells
+/// written. With a shadow of the screen
+/// only the cells that differ from it
+/// are written, and the shadow is
+/// updated. A row whose cells all
+/// changed, such as after a scroll or
+/// clear, and every dirty row without a
+/// shadow, is written in one piece.
+/// Colors are swapped if inverted.
+/// back:         cells to show
+/// front:        what the sink shows
+/// dirty_rows:   bit mask of rows to check
+/// inverted:     visual bell is ringing
+/// sink:         destination of the cells
+fn flush_rows(
+    back: &[Row; BUFFER_HEIGHT],
+    mut front: Option<&mut [Row; BUFFER_HEIGHT]>,
+    dirty_rows: u32,
+    inverted: bool,
+    sink: &mut impl CellSink,
+) -> usize {
+    let mut writes = 0;
+    for row in 0..BUFFER_HEIGHT {
+        if dirty_rows & (1 << row) == 0 {
+            continue;
+        }
+
+        let mut cells = back[row];
+        if inverted {
+            for cell in cells.iter_mut() {
+                let ColorCode(code) = cell.color_code;
+                cell.color_code = ColorCode(code.rotate_right(4));
+            }
+        }
+
+        let shown = match front.as_deref_mut() {
+            Some(front) => &mut front[row],
+            None => {
+                sink.write_row(row, cells);
+                writes += BUFFER_WIDTH;
+                continue;
+            }
+        };
+        let changed = cells
+            .iter()
+            .zip(shown.iter())
+            .filter(|(a, b)| a != b)
+            .count();
+        if changed == BUFFER_WIDTH {
+            sink.write_row(row, cells);
+        } else {
+            for col in 0..BUFFER_WIDTH {
+                if cells[col] != shown[col] {
+                    sink.write_cell(row, col, cells[col]);
+                }
+            }
+        }
+        *shown = cells;
+        writes += changed;
+    }
+    writes
+}
+
+//// HARDWARE CURSOR
+
+use x86_64::instructions::port::Port;
+
+// CRTC index and data ports used to
+// select and write a VGA register.
+const CRTC_INDEX_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+
+// CRTC registers for the cursor shape
+// and the cursor location (high and
+// low byte of the cell index).
+const CURSOR_START: u8 = 0x0a;
+const CURSOR_END: u8 = 0x0b;
+const CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+// Bit of the cursor start register
+// that hides the cursor.
+const CURSOR_DISABLE: u8 = 0x20;
+
+/// Writes a value into a CRTC register.
+fn write_crtc(register: u8, value: u8) {
+    let mut index: Port<u8> = Port::new(CRTC_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        index.write(register);
+        data.write(value);
+    }
+}
+
+/// Reads the value of a CRTC register.
+fn read_crtc(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(CRTC_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+/// Last scanline of a character cell
+/// in the standard text mode.
+pub const MAX_SCANLINE: u8 = 15;
+
+// Bits of the cursor start and end
+// registers that hold the scanline.
+const SCANLINE_MASK: u8 = 0x1f;
+
+impl Writer {
+    /// Sets the scanlines the hardware
+    /// cursor covers, such as 0 to 15 for
+    /// a block or 14 to 15 for an
+    /// underline. The other bits of the
+    /// registers are kept, so a hidden
+    /// cursor stays hidden.
+    /// start:    first scanline (0-15)
+    /// end:      last scanline (start-15)
+    pub fn set_cursor_shape(&mut self, start: u8, end: u8) -> Result<(), KernelError> {
+        if end > MAX_SCANLINE || start > end {
+            return Err(KernelError::InvalidCursorShape { start, end });
+        }
+        write_crtc(
+            CURSOR_START,
+            (read_crtc(CURSOR_START) & !SCANLINE_MASK) | start,
+        );
+        write_crtc(CURSOR_END, (read_crtc(CURSOR_END) & !SCANLINE_MASK) | end);
+        Ok(())
+    }
+
+    /// Returns the first and last scanline
+    /// of the cursor as read from the CRTC.
+    pub fn cursor_shape(&self) -> (u8, u8) {
+        (
+            read_crtc(CURSOR_START) & SCANLINE_MASK,
+            read_crtc(CURSOR_END) & SCANLINE_MASK,
+        )
+    }
+
+    /// Hides the hardware cursor and
+    /// keeps its shape.
+    pub fn hide_cursor(&mut self) {
+        write_crtc(CURSOR_START, read_crtc(CURSOR_START) | CURSOR_DISABLE);
+    }
+
+    /// Shows the hardware cursor again
+    /// with the shape it had.
+    pub fn show_cursor(&mut self) {
+        write_crtc(CURSOR_START, read_crtc(CURSOR_START) & !CURSOR_DISABLE);
+    }
+
+    /// Returns true unless the hardware
+    /// cursor is hidden.
+    pub fn cursor_visible(&self) -> bool {
+        read_crtc(CURSOR_START) & CURSOR_DISABLE == 0
+    }
+}
+
+/// Shows the hardware cursor of the
+/// WRITER with the scanlines
+/// cursor_start to cursor_end (0-15),
+/// see Writer::set_cursor_shape.
+pub fn enable_cursor(cursor_start: u8, cursor_end: u8) -> Result<(), KernelError> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.set_cursor_shape(cursor_start, cursor_end)?;
+        writer.show_cursor();
+        Ok(())
+    })
+}
+
+/// Hides the hardware cursor of the
+/// WRITER and keeps its shape.
+pub fn disable_cursor() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| WRITER.lock().hide_cursor());
+}
+
+/// Called by the timer interrupt handler
+/// to end a visual bell. If the writer
+/// is locked the bell is ended on a
+/// later tick instead of waiting for it.
+pub(crate) fn on_tick(ticks: u64) {
+    let deadline = BELL_DEADLINE.load(Ordering::Relaxed);
+    if deadline == 0 || ticks < deadline {
+        return;
+    }
+    if let Some(mut writer) = WRITER.try_lock() {
+        writer.end_bell();
+    }
+}
+
+// Implements usage of the vga
+// buffer using write macro like
+// the following:
+//      write!(writer, "I have {} apples", 12);
+use core::fmt;
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+// Test ensures that a single print
+// macro call will not panic.
+#[test_case]
+fn test_println_simple() {
+    println!("test_println_simple output");
+}
+
+// Test ensures that many print
+// statements will not cause a
+// panic.
+#[test_case]
+fn test_println_many() {
+    for _ in 0..200 {
+        println!("test_println_simple output");
+    }
+}
+
+// Prints out a single-line string
+// and ensures that the characters
+// in the VGA buffer match the characters
+// passed to the println! macro.
+#[test_case]
+fn test_println_output() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    let s = "Here are some words in a string for testing";
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    });
+}
+
+// Draws a small box with box drawing
+// characters and an unmappable one,
+// and ensures the screen holds their
+// code page 437 bytes.
+#[test_case]
+fn test_write_cp437_box() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_cp437("\n┌──┐\n│a€│\n└──┘\n");
+
+        let expected = [
+            [0xda, 0xc4, 0xc4, 0xbf],
+            [0xb3, b'a', 0xfe, 0xb3],
+            [0xc0, 0xc4, 0xc4, 0xd9],
+        ];
+        for (i, bytes) in expected.iter().enumerate() {
+            let row = BUFFER_HEIGHT - 4 + i;
+            for (col, &byte) in bytes.iter().enumerate() {
+                assert_eq!(writer.buffer.chars[row][col].read().ascii_character, byte);
+            }
+        }
+    });
+}
+
+// Writes styled cells to the top left
+// and bottom right corners and ensures
+// the screen holds their glyph and
+// color bytes while the cursor stays.
+#[test_case]
+fn test_write_at_corners() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let cursor = (writer.row_position, writer.column_position);
+        let saved = [writer.cell(0, 0), writer.cell(24, 79)];
+
+        writer
+            .write_at(0, 0, 'A', Color::Yellow, Color::Blue)
+            .expect("write_at failed");
+        writer
+            .write_at(24, 79, '┘', Color::Red, Color::Black)
+            .expect("write_at failed");
+
+        let top_left = writer.buffer.chars[0][0].read();
+        assert_eq!(top_left.ascii_character, b'A');
+        assert_eq!(top_left.color_code.0, 0x1e);
+        let bottom_right = writer.buffer.chars[24][79].read();
+        assert_eq!(bottom_right.ascii_character, 0xd9);
+        assert_eq!(bottom_right.color_code.0, 0x04);
+        assert_eq!((writer.row_position, writer.column_position), cursor);
+
+        writer.set_cell(0, 0, saved[0]);
+        writer.set_cell(24, 79, saved[1]);
+        writer.flush();
+    });
+}
+
+// Writes just past the last row and
+// column and ensures both writes are
+// rejected and the screen is unchanged.
+#[test_case]
+fn test_write_at_out_of_bounds() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let before = writer.save_screen();
+
+        assert_eq!(
+            writer.write_at(25, 0, 'x', Color::White, Color::Black),
+            Err(KernelError::CellOutOfBounds { row: 25, col: 0 })
+        );
+        assert_eq!(
+            writer.write_at(0, 80, 'x', Color::White, Color::Black),
+            Err(KernelError::CellOutOfBounds { row: 0, col: 80 })
+        );
+        assert_eq!(writer.save_screen().chars, before.chars);
+    });
+}
+
+// Holds the WRITER lock, as an
+// interrupted print would, and ensures
+// the try path returns without
+// printing instead of hanging.
+#[test_case]
+fn test_try_print_while_locked() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _writer = WRITER.lock();
+        assert!(!try_println!("test_try_print_while_locked output"));
+    });
+    assert!(try_println!("test_try_print_while_locked output"));
+}
+
+// Writes several lines and ensures
+// after the flush every cell of the
+// VGA buffer matches the back array.
+#[test_case]
+fn test_flush_matches_back_array() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for i in 0..5 {
+            writeln!(writer, "double buffered line {}", i).expect("writeln failed");
+        }
+        writer.flush();
+        assert_eq!(writer.dirty_rows, 0);
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                assert_eq!(writer.buffer.chars[row][col].read(), writer.back[row][col]);
+            }
+        }
+    });
+}
+
+// Saves the screen, draws over it
+// in a different color and ensures
+// restoring the snapshot brings back
+// the exact characters, colors and
+// cursor column.
+#[test_case]
+fn test_save_restore_screen() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\nscreen content to save").expect("writeln failed");
+        write!(writer, "partial line").expect("write failed");
+        let snapshot = writer.save_screen();
+        let column_position = writer.column_position;
+
+        // Draw over the whole
+        // screen in another color
+        let color_code = writer.color_code;
+        writer.color_code = ColorCode::new(Color::Yellow, Color::Blue);
+        for _ in 0..BUFFER_HEIGHT {
+            writeln!(writer, "dialog").expect("writeln failed");
+        }
+        writer.color_code = color_code;
+        assert_ne!(writer.save_screen(), snapshot);
+
+        writer.restore_screen(&snapshot);
+        assert_eq!(writer.save_screen(), snapshot);
+        assert_eq!(writer.column_position, column_position);
+    });
+}
+
+// Writes a BEL in visual mode and
+// ensures no glyph is printed, the
+// cursor does not move, the screen
+// is inverted until the timer ends
+// the bell and text written meanwhile
+// keeps its colors.
+#[test_case]
+fn test_visual_bell() {
+    use x86_64::instructions::interrupts;
+
+    let bell_mode = interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let bell_mode = writer.bell_mode;
+        writer.bell_mode = BellMode::Visual;
+
+        writer.write_string("\nbell");
+        let snapshot = writer.save_screen();
+        let before = writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+        writer.write_string("\x07");
+        assert_eq!(writer.save_screen(), snapshot);
+        assert_eq!(writer.column_position, 4);
+
+        let ColorCode(code) = before.color_code;
+        let inverted = writer.buffer.chars[BUFFER_HEIGHT - 1][0].read();
+        assert_eq!(inverted.color_code, ColorCode(code.rotate_right(4)));
+        writer.write_string("!");
+        bell_mode
+    });
+
+    while BELL_DEADLINE.load(Ordering::Relaxed) != 0 {
+        x86_64::instructions::hlt();
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for col in 0..5 {
+            let cell = writer.buffer.chars[BUFFER_HEIGHT - 1][col].read();
+            assert_eq!(cell, writer.back[BUFFER_HEIGHT - 1][col]);
+        }
+        writer.bell_mode = bell_mode;
+    });
+}
+
+// Ensures moving the hardware cursor
+// while printing many lines and
+// toggling it does not panic, and
+// that hiding it keeps its shape.
+#[test_case]
+fn test_cursor_follows_many_prints() {
+    use x86_64::instructions::interrupts;
+
+    enable_cursor(14, 15).expect("invalid cursor shape");
+    for _ in 0..200 {
+        println!("test_cursor_follows_many_prints output");
+    }
+    disable_cursor();
+    enable_cursor(14, 15).expect("invalid cursor shape");
+
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        assert!(writer.cursor_visible());
+        assert_eq!(writer.cursor_shape(), (14, 15));
+    });
+}
+
+// Sets a cursor shape, hides and shows
+// the cursor and ensures the CRTC
+// registers read back the written
+// values, then ensures invalid shapes
+// are rejected.
+#[test_case]
+fn test_cursor_shape_round_trip() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let saved = (read_crtc(CURSOR_START), read_crtc(CURSOR_END));
+
+        writer.set_cursor_shape(3, 12).unwrap();
+        assert_eq!(writer.cursor_shape(), (3, 12));
+        assert_eq!(read_crtc(CURSOR_START) & SCANLINE_MASK, 3);
+        assert_eq!(read_crtc(CURSOR_END) & SCANLINE_MASK, 12);
+
+        writer.hide_cursor();
+        assert!(!writer.cursor_visible());
+        assert_eq!(writer.cursor_shape(), (3, 12));
+        writer.show_cursor();
+        assert!(writer.cursor_visible());
+
+        assert_eq!(
+            writer.set_cursor_shape(0, 16),
+            Err(KernelError::InvalidCursorShape { start: 0, end: 16 })
+        );
+        assert_eq!(
+            writer.set_cursor_shape(9, 4),
+            Err(KernelError::InvalidCursorShape { start: 9, end: 4 })
+        );
+        assert_eq!(writer.cursor_shape(), (3, 12));
+
+        write_crtc(CURSOR_START, saved.0);
+        write_crtc(CURSOR_END, saved.1);
+    });
+}
+
+// Clears the screen while preserving
+// it to the scrollback, then scrolls
+// up and ensures the content from
+// before the clear is visible again.
+#[test_case]
+fn test_clear_preserves_to_scrollback() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\ncontent before the clear").expect("writeln failed");
+        let before: Vec<Row> = (0..BUFFER_HEIGHT).map(|row| writer.read_row(row)).collect();
+
+        writer.clear_screen(ClearMode::PreserveToScrollback);
+        let cleared: Vec<Row> = (0..BUFFER_HEIGHT).map(|row| writer.read_row(row)).collect();
+        assert!(cleared
+            .iter()
+            .all(|row| row.iter().all(|c| c.ascii_character == b' ')));
+
+        writer.scroll_up(BUFFER_HEIGHT);
+        for (row, cells) in before.iter().enumerate() {
+            assert!(writer.read_row(row) == *cells);
+        }
+
+        writer.scroll_to_bottom();
+        for (row, cells) in cleared.iter().enumerate() {
+            assert!(writer.read_row(row) == *cells);
+        }
+    });
+}
+
+// Ensures a discarding clear does
+// not add lines to the scrollback.
+#[test_case]
+fn test_clear_discard() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let history = writer.scrollback.len();
+        writer.clear_screen(ClearMode::Discard);
+        assert_eq!(writer.scrollback.len(), history);
+    });
+}
+
+// Sets the color to red on black and
+// ensures a printed character has the
+// color byte 0x04 while the previous
+// character keeps its color.
+#[test_case]
+fn test_set_color() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let color_code = writer.color_code;
+        writer.write_string("\nw");
+
+        writer.set_color(Color::Red, Color::Black);
+        writer.write_byte(b'r');
+        let row = writer.row_position;
+        let before = writer.buffer.chars[row][0].read();
+        let after = writer.buffer.chars[row][1].read();
+        assert_eq!(after.color_code.0, 0x04);
+        assert_eq!(before.color_code, color_code);
+
+        writer.color_code = color_code;
+    });
+}
+
+// Writes "a\tb" with a tab width of 4
+// and ensures 'b' lands in column 4
+// with spaces in columns 1 to 3.
+#[test_case]
+fn test_tab_stops() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.set_tab_width(4);
+        writer.write_string("\n");
+        for &byte in b"a\tb" {
+            writer.write_byte(byte);
+        }
+        writer.set_tab_width(DEFAULT_TAB_WIDTH);
+
+        let row = writer.row_position;
+        let line: [u8; 5] =
+            core::array::from_fn(|col| writer.buffer.chars[row][col].read().ascii_character);
+        assert_eq!(&line, b"a   b");
+        assert_eq!(writer.column_position, 5);
+    });
+}
+
+// Writes "100%\rDONE" and ensures the
+// text after the carriage return
+// overwrote the line from column 0
+// without starting a new line.
+#[test_case]
+fn test_carriage_return_overwrites_line() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let (row, col) = (writer.row_position, writer.column_position);
+        writer.row_position = BUFFER_HEIGHT - 1;
+        writer.write_string("\n");
+        for &byte in b"100%\rDONE" {
+            writer.write_byte(byte);
+        }
+
+        let last_row = BUFFER_HEIGHT - 1;
+        assert_eq!(writer.row_position, last_row);
+        let line: [u8; 5] =
+            core::array::from_fn(|i| writer.buffer.chars[last_row][i].read().ascii_character);
+        assert_eq!(&line, b"DONE ");
+        assert_eq!(writer.column_position, 4);
+
+        writer.row_position = row;
+        writer.column_position = col;
+    });
+}
+
+// Writes colored characters with
+// write_at and ensures read_at returns
+// the same glyph and colors, and None
+// outside of the screen.
+#[test_case]
+fn test_read_at_round_trip() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let last_row = BUFFER_HEIGHT - 1;
+        let last_col = BUFFER_WIDTH - 1;
+        let saved = [writer.cell(0, 0), writer.cell(last_row, last_col)];
+
+        writer
+            .write_at(0, 0, 'x', Color::Yellow, Color::Blue)
+            .unwrap();
+        writer
+            .write_at(last_row, last_col, '╬', Color::LightRed, Color::DarkGray)
+            .unwrap();
+        assert_eq!(
+            writer.read_at(0, 0),
+            Some(('x', Color::Yellow, Color::Blue))
+        );
+        assert_eq!(
+            writer.read_at(last_row, last_col),
+            Some(('╬', Color::LightRed, Color::DarkGray))
+        );
+        assert_eq!(writer.read_at(BUFFER_HEIGHT, 0), None);
+        assert_eq!(writer.read_at(0, BUFFER_WIDTH), None);
+
+        writer.set_cell(0, 0, saved[0]);
+        writer.set_cell(last_row, last_col, saved[1]);
+        writer.flush();
+    });
+}
+
+// Prints text, clears the screen and
+// ensures the bottom two rows only
+// hold spaces in the current color and
+// that the cursor and the hardware
+// cursor are at (0,0).
+#[test_case]
+fn test_clear_screen_blanks_rows() {
+    use x86_64::instructions::interrupts;
+
+    for _ in 0..BUFFER_HEIGHT {
+        println!("text that is cleared from the screen");
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.clear_screen(ClearMode::Discard);
+
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: writer.color_code,
+        };
+        for row in BUFFER_HEIGHT - 2..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                assert_eq!(writer.buffer.chars[row][col].read(), blank);
+            }
+        }
+        assert_eq!((writer.row_position, writer.column_position), (0, 0));
+        assert_eq!(read_crtc(CURSOR_LOCATION_HIGH), 0);
+        assert_eq!(read_crtc(CURSOR_LOCATION_LOW), 0);
+    });
+}
+
+// Prints text on the bottom row, clears
+// it in another color and ensures every
+// cell of the VGA buffer reads back as
+// a space in that color, like the
+// cell by cell clear did.
+#[test_case]
+fn test_clear_row_reads_back_blank() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let (row, col) = (writer.row_position, writer.column_position);
+        let color_code = writer.color_code;
+        writer.row_position = BUFFER_HEIGHT - 1;
+        writer.column_position = 0;
+        writer.write_string("\nrow that is cleared");
+        writer.flush();
+        let text = b"row that is cleared";
+        let line: [u8; 19] = core::array::from_fn(|i| {
+            writer.buffer.chars[BUFFER_HEIGHT - 1][i]
+                .read()
+                .ascii_character
+        });
+        assert_eq!(&line, text);
+
+        writer.set_color(Color::Yellow, Color::Blue);
+        writer.clear_row(BUFFER_HEIGHT - 1);
+        writer.flush();
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: writer.color_code,
+        };
+        for col in 0..BUFFER_WIDTH {
+            assert_eq!(writer.buffer.chars[BUFFER_HEIGHT - 1][col].read(), blank);
+        }
+
+        writer.color_code = color_code;
+        writer.row_position = row;
+        writer.column_position = col;
+    });
+}
+
+// Prints 100 lines, scrolls up 10 lines
+// and ensures the view shows the last
+// 10 captured lines above the top of
+// the live screen, then snaps back
+// to the bottom on new output.
+#[test_case]
+fn test_scrollback_scroll_up() {
+    use x86_64::instructions::interrupts;
+
+    for i in 0..100 {
+        println!("scrollback line {}", i);
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let live: Vec<Row> = (0..BUFFER_HEIGHT).map(|row| writer.read_row(row)).collect();
+        let history = writer.scrollback.len();
+        assert!(history >= 10);
+
+        writer.scroll_up(10);
+        for row in 0..10 {
+            assert!(writer.read_row(row) == *writer.scrollback.get(history - 10 + row));
+        }
+        for row in 10..BUFFER_HEIGHT {
+            assert!(writer.read_row(row) == live[row - 10]);
+        }
+
+        writer.write_byte(b'x');
+        assert_eq!(writer.scrollback.offset, 0);
+    });
+}
+
+// Writes red text between an SGR color
+// and a reset and ensures only the
+// three cells of RED are red, while
+// the escape bytes are not printed.
+#[test_case]
+fn test_ansi_sgr_color() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let color_code = writer.color_code;
+        writer.write_string("\n\x1b[31mRED\x1b[0mx");
+
+        let row = writer.row_position;
+        for col in 0..3 {
+            let cell = writer.buffer.chars[row][col].read();
+            assert_eq!(cell.ascii_character, b"RED"[col]);
+            assert_eq!(cell.color_code, ColorCode::new(Color::Red, Color::Black));
+        }
+        let after = writer.buffer.chars[row][3].read();
+        assert_eq!(after.ascii_character, b'x');
+        assert_eq!(after.color_code, DEFAULT_COLOR);
+        assert_eq!(writer.column_position, 4);
+
+        writer.color_code = color_code;
+    });
+}
+
+// Splits a cursor position sequence
+// across several writes and ensures
+// it still moves the cursor, and that
+// an unsupported sequence is dropped.
+#[test_case]
+fn test_ansi_split_sequence() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let (row, col) = (writer.row_position, writer.column_position);
+        writer.write_string("\n\x1b");
+        writer.write_string("[3;");
+        writer.write_string("5H");
+        assert_eq!(writer.row_position, 2);
+        assert_eq!(writer.column_position, 4);
+
+        writer.write_string("\x1b[?25lz");
+        assert_eq!(writer.buffer.chars[2][4].read().ascii_character, b'z');
+        assert_eq!(writer.column_position, 5);
+        assert_eq!(writer.ansi_state, AnsiState::Normal);
+
+        writer.row_position = row;
+        writer.column_position = col;
+    });
+}
+
+// Moves the cursor to row 5 with a
+// cursor position sequence and ensures
+// a newline moves it down one row
+// without scrolling the screen.
+#[test_case]
+fn test_newline_after_cursor_position() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let (row, col) = (writer.row_position, writer.column_position);
+        writer.write_string("\x1b[5;1Htop");
+        let top = writer.save_screen().chars;
+        let history = writer.scrollback.len();
+
+        writer.write_string("\n");
+        assert_eq!((writer.row_position, writer.column_position), (5, 0));
+        assert_eq!(writer.save_screen().chars, top);
+        assert_eq!(writer.scrollback.len(), history);
+
+        writer.row_position = row;
+        writer.column_position = col;
+    });
+}
+
+// Parses CSI parameter strings and
+// ensures empty parameters are None
+// and sequences with too many
+// parameters or a non-digit are
+// rejected.
+#[test_case]
+fn test_csi_params_fixed_array() {
+    fn parse(bytes: &[u8]) -> Result<CsiParams, ()> {
+        let mut buffer = CsiBuffer::new();
+        for &byte in bytes {
+            buffer.push(byte);
+        }
+        buffer.params()
+    }
+
+    let params = parse(b"3;;15").expect("valid parameters rejected");
+    assert_eq!(params.as_slice(), [Some(3), None, Some(15)]);
+    assert_eq!(parse(b"").map(|p| p.count), Ok(1));
+    assert_eq!(
+        parse(b"1;2;3;4;5;6;7;8").map(|p| p.count),
+        Ok(CSI_MAX_PARAMS)
+    );
+    assert_eq!(parse(b"1;;;;;;;;9"), Err(()));
+    assert_eq!(parse(b"1;x"), Err(()));
+}
+
+/// Cell sink that records the
+/// writes made to it.
+#[cfg(test)]
+struct CountingSink {
+    cells: Vec<(usize, usize, ScreenChar)>,
+    rows: Vec<usize>,
+}
+
+#[cfg(test)]
+impl CellSink for CountingSink {
+    fn write_cell(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.cells.push((row, col, character));
+    }
+
+    fn write_row(&mut self, row: usize, _cells: Row) {
+        self.rows.push(row);
+    }
+}
+
+// Changes one cell of a back array and
+// ensures the flush makes exactly one
+// write and a second flush none, that
+// a row that changed entirely is
+// written in one piece and that without
+// a shadow dirty rows are written whole.
+#[test_case]
+fn test_flush_rows_writes_changed_cells() {
+    let mut back = [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT];
+    let mut front = Box::new(back);
+    let mut sink = CountingSink {
+        cells: Vec::new(),
+        rows: Vec::new(),
+    };
+
+    back[3][7].ascii_character = b'x';
+    assert_eq!(
+        flush_rows(&back, Some(&mut front), ALL_ROWS, false, &mut sink),
+        1
+    );
+    assert_eq!(sink.cells, [(3, 7, back[3][7])]);
+    assert!(sink.rows.is_empty());
+
+    // Redrawing the same cell is
+    // not a change
+    assert_eq!(
+        flush_rows(&back, Some(&mut front), 1 << 3, false, &mut sink),
+        0
+    );
+    assert_eq!(sink.cells.len(), 1);
+
+    back[5] = [back[3][7]; BUFFER_WIDTH];
+    assert_eq!(
+        flush_rows(&back, Some(&mut front), 1 << 5, false, &mut sink),
+        BUFFER_WIDTH
+    );
+    assert_eq!(sink.rows, [5]);
+    assert_eq!(sink.cells.len(), 1);
+
+    assert_eq!(
+        flush_rows(&back, None, 1 << 3 | 1 << 9, false, &mut sink),
+        2 * BUFFER_WIDTH
+    );
+    assert_eq!(sink.rows, [5, 3, 9]);
+}
@@ -0,0 +1,162 @@
+//! Watchdog for the timer interrupt.
+//! If interrupts stay disabled or the
+//! timer IRQ is masked, the tick counter
+//! stops and halting waits forever. The
+//! watchdog measures time with the time
+//! stamp counter instead, and check
+//! reports a stall once the ticks have
+//! not advanced for a whole window. The
+//! idle path of the executor checks it
+//! before halting.
+
+use crate::{interrupts, serial_try_println};
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+/// Window in milliseconds used by
+/// the kernel entry point.
+pub const DEFAULT_WINDOW_MS: u64 = 1000;
+
+// Ticks the time stamp counter is
+// calibrated over
+const CALIBRATION_TICKS: u64 = 10;
+
+// Time stamp counter cycles the ticks
+// may stall for, 0 until init is called
+static WINDOW_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+// Tick count last seen and the time
+// stamp counter when it was seen
+static LAST_TICK: AtomicU64 = AtomicU64::new(0);
+static LAST_TSC: AtomicU64 = AtomicU64::new(0);
+
+// Set once the current stall is
+// reported, so it is printed once
+static REPORTED: AtomicBool = AtomicBool::new(false);
+
+// Number of stalls detected
+static STALLS: AtomicU64 = AtomicU64::new(0);
+
+/// Reads the time stamp counter.
+fn tsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Returns the number of time stamp
+/// counter cycles in one millisecond,
+/// measured against the timer ticks.
+/// Interrupts have to be enabled.
+fn calibrate() -> u64 {
+    // Start counting on a tick edge
+    let start = interrupts::ticks();
+    while interrupts::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+
+    let start_tick = interrupts::ticks();
+    let start_tsc = tsc();
+    while interrupts::ticks() - start_tick < CALIBRATION_TICKS {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = tsc() - start_tsc;
+
+    (elapsed / interrupts::ticks_to_ms(CALIBRATION_TICKS).max(1)).max(1)
+}
+
+/// Starts the watchdog with a window
+/// of window_ms milliseconds. The
+/// timer has to be ticking, since the
+/// time stamp counter is calibrated
+/// against it.
+/// window_ms:    longest time without a tick
+pub fn init(window_ms: u64) {
+    assert!(
+        x86_64::instructions::interrupts::are_enabled(),
+        "watchdog::init called with interrupts disabled"
+    );
+
+    let cycles_per_ms = calibrate();
+    pet();
+    WINDOW_CYCLES.store(cycles_per_ms.saturating_mul(window_ms), Ordering::Relaxed);
+}
+
+/// Starts a new window from now, for
+/// code that knowingly keeps the timer
+/// from ticking for a while.
+pub fn pet() {
+    LAST_TICK.store(interrupts::ticks(), Ordering::Relaxed);
+    LAST_TSC.store(tsc(), Ordering::Relaxed);
+    REPORTED.store(false, Ordering::Relaxed);
+}
+
+/// Returns true if the ticks have not
+/// advanced for a whole window. The
+/// first check that sees a stall
+/// prints a warning to serial. Returns
+/// false until init is called.
+pub fn check() -> bool {
+    let window = WINDOW_CYCLES.load(Ordering::Relaxed);
+    if window == 0 {
+        return false;
+    }
+
+    let ticks = interrupts::ticks();
+    if ticks != LAST_TICK.load(Ordering::Relaxed) {
+        pet();
+        return false;
+    }
+
+    let stalled_for = tsc().wrapping_sub(LAST_TSC.load(Ordering::Relaxed));
+    if stalled_for < window {
+        return false;
+    }
+    if !REPORTED.swap(true, Ordering::Relaxed) {
+        STALLS.fetch_add(1, Ordering::Relaxed);
+        serial_try_println!(
+            "WATCHDOG: timer stalled at tick {}, check that interrupts are enabled and IRQ 0 is unmasked",
+            ticks
+        );
+    }
+    true
+}
+
+/// Returns the number of stalls
+/// detected since boot.
+pub fn stalls() -> u64 {
+    STALLS.load(Ordering::Relaxed)
+}
+
+// Masks the timer IRQ and ensures check
+// reports the stall within a few
+// windows, then unmasks it and ensures
+// the watchdog recovers once the ticks
+// advance again.
+#[test_case]
+fn test_watchdog_detects_masked_timer() {
+    use interrupts::InterruptIndex;
+
+    if crate::apic::timer_enabled() {
+        return;
+    }
+    let window = WINDOW_CYCLES.load(Ordering::Relaxed);
+    init(20);
+    let stalls_before = stalls();
+
+    interrupts::mask_irq(InterruptIndex::Timer.irq_line());
+    let deadline = tsc() + 4 * WINDOW_CYCLES.load(Ordering::Relaxed);
+    let mut detected = false;
+    while !detected && tsc() < deadline {
+        detected = check();
+        core::hint::spin_loop();
+    }
+    interrupts::unmask_irq(InterruptIndex::Timer.irq_line());
+
+    assert!(detected);
+    assert_eq!(stalls(), stalls_before + 1);
+
+    interrupts::delay_ms(30);
+    assert!(!check());
+    WINDOW_CYCLES.store(window, Ordering::Relaxed);
+}
@@ -0,0 +1,234 @@
+//! Randomized stress test for the
+//! global heap allocator. Thousands
+//! of random allocations, frees and
+//! reallocations are made, and every
+//! block is filled with a canary
+//! pattern that is checked to detect
+//! overlapping or corrupted blocks.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(abs_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use abs_os::serial_println;
+use alloc::{
+    alloc::{alloc, dealloc, realloc, Layout},
+    vec::Vec,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+/// Sets up the heap the same way as
+/// the heap_allocation tests and
+/// runs the fuzz test.
+fn main(boot_info: &'static BootInfo) -> ! {
+    use abs_os::{
+        allocator,
+        memory::{self, BootInfoFrameAllocator},
+    };
+    use x86_64::VirtAddr;
+
+    abs_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap_default(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    abs_os::test_panic_handler(info)
+}
+
+//// RANDOM NUMBERS
+
+// Fixed seed so a failing run can
+// be reproduced. It is printed at
+// the start of the test.
+const SEED: u64 = 0x_2545_f491_4f6c_dd1d;
+
+// Number of random operations
+// made by the fuzz test.
+const OPERATIONS: usize = 5000;
+
+// Live allocations are capped so
+// that the heap, including blocks
+// cached in the free lists, can
+// always satisfy a request. Any
+// null pointer is then a bug.
+const MAX_LIVE: usize = 8;
+const MAX_SIZE: usize = 2048;
+
+/// Xorshift generator used to
+/// pick the random operations.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in 0..n
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// Returns a random layout. Most
+    /// sizes are small, and alignments
+    /// are powers of two up to 64.
+    fn layout(&mut self) -> Layout {
+        let size = if self.below(10) == 0 {
+            512 + self.below(MAX_SIZE - 512)
+        } else {
+            1 + self.below(512)
+        };
+        let align = 1 << self.below(7);
+        Layout::from_size_align(size, align).unwrap()
+    }
+}
+
+//// TRACKED ALLOCATIONS
+
+/// Live allocation along with the
+/// canary byte it was filled with.
+struct Block {
+    ptr: *mut u8,
+    layout: Layout,
+    canary: u8,
+}
+
+impl Block {
+    /// Allocates and fills a new block,
+    /// panicking on a null pointer.
+    fn new(layout: Layout, canary: u8) -> Block {
+        let ptr = unsafe { alloc(layout) };
+        assert!(!ptr.is_null(), "allocation of {:?} failed", layout);
+        assert_eq!(ptr as usize % layout.align(), 0, "misaligned {:?}", layout);
+        let block = Block {
+            ptr,
+            layout,
+            canary,
+        };
+        block.fill(0, layout.size());
+        block
+    }
+
+    /// Writes the canary into bytes
+    /// start..end of the block.
+    fn fill(&self, start: usize, end: usize) {
+        for i in start..end {
+            unsafe { self.ptr.add(i).write_volatile(self.canary) };
+        }
+    }
+
+    /// Ensures every byte of the
+    /// block still holds the canary.
+    fn verify(&self) {
+        for i in 0..self.layout.size() {
+            let byte = unsafe { self.ptr.add(i).read_volatile() };
+            assert_eq!(
+                byte, self.canary,
+                "canary corrupted at byte {} of {:?}",
+                i, self.layout
+            );
+        }
+    }
+
+    /// Verifies and frees the block.
+    fn free(self) {
+        self.verify();
+        unsafe { dealloc(self.ptr, self.layout) };
+    }
+
+    /// Verifies the block and resizes
+    /// it, filling any new bytes.
+    fn resize(&mut self, new_size: usize) {
+        self.verify();
+        let ptr = unsafe { realloc(self.ptr, self.layout, new_size) };
+        assert!(!ptr.is_null(), "realloc of {:?} failed", self.layout);
+
+        let old_size = self.layout.size();
+        self.ptr = ptr;
+        self.layout = Layout::from_size_align(new_size, self.layout.align()).unwrap();
+        self.verify_prefix(old_size.min(new_size));
+        self.fill(old_size.min(new_size), new_size);
+    }
+
+    /// Ensures the first len bytes
+    /// were kept by a reallocation.
+    fn verify_prefix(&self, len: usize) {
+        for i in 0..len {
+            let byte = unsafe { self.ptr.add(i).read_volatile() };
+            assert_eq!(byte, self.canary, "realloc lost byte {}", i);
+        }
+    }
+}
+
+//// TESTS
+
+// Makes thousands of random allocations,
+// frees and reallocations, checking the
+// canary of every block before it is
+// freed or resized and periodically
+// checking all live blocks.
+#[test_case]
+fn random_alloc_free_realloc() {
+    serial_println!("seed {:#x}", SEED);
+
+    let mut rng = Rng(SEED);
+    let mut live: Vec<Block> = Vec::with_capacity(MAX_LIVE + 1);
+    for operation in 0..OPERATIONS {
+        let canary = operation as u8 ^ 0xa5;
+        match rng.below(10) {
+            // Allocate a new block, or free
+            // one if the cap is reached
+            0..=5 => {
+                if live.len() < MAX_LIVE {
+                    live.push(Block::new(rng.layout(), canary));
+                } else {
+                    let index = rng.below(live.len());
+                    live.swap_remove(index).free();
+                }
+            }
+
+            // Free a random live block
+            6..=8 => {
+                if !live.is_empty() {
+                    let index = rng.below(live.len());
+                    live.swap_remove(index).free();
+                }
+            }
+
+            // Resize a random live block
+            _ => {
+                if !live.is_empty() {
+                    let index = rng.below(live.len());
+                    let new_size = 1 + rng.below(MAX_SIZE);
+                    live[index].resize(new_size);
+                }
+            }
+        }
+
+        if operation % 100 == 0 {
+            live.iter().for_each(Block::verify);
+        }
+    }
+
+    for block in live {
+        block.free();
+    }
+}
@@ -0,0 +1,86 @@
+//! Test module that ensures that
+//! a division by zero is caught by
+//! the divide error handler instead
+//! of escalating to a double fault.
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use abs_os::serial_print;
+
+use core::panic::PanicInfo;
+
+// Function called when a panic
+// occurs that runs the panic
+// handler defined in src/lib.rs
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    abs_os::test_panic_handler(info);
+}
+
+// Entry point for the divide error
+// test that initializes the OS and
+// divides by zero. If the interrupt
+// handling succeeds, the handler
+// exits QEMU before the panic.
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("divide_error::divide_error...\t");
+
+    // Initialize GDT for
+    // the tests
+    abs_os::gdt::init();
+    init_test_idt();
+
+    // Cause a divide error
+    divide_by_zero();
+
+    panic!("Execution continued after divide error");
+}
+
+// Divides by zero with the div
+// instruction, since the division
+// operator panics before dividing.
+fn divide_by_zero() {
+    unsafe {
+        core::arch::asm!(
+            "div ecx",
+            in("ecx") 0,
+            inout("eax") 1 => _,
+            inout("edx") 0 => _,
+        );
+    }
+}
+
+use lazy_static::lazy_static;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+// Instantiate a static IDT used
+// for testing divide errors with
+// a custom divide error function.
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(test_divide_error_handler);
+        idt
+    };
+}
+
+// Test function called by the entry
+// point to this test module (_start).
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+use abs_os::{exit_qemu, serial_println, QemuExitCode};
+use x86_64::structures::idt::InterruptStackFrame;
+
+// Override of the x86 interrupt
+// function called when a divide
+// error occurs.
+extern "x86-interrupt" fn test_divide_error_handler(_stack_frame: InterruptStackFrame) {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
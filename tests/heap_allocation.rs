@@ -9,7 +9,7 @@
 
 extern crate alloc;
 
-use abs_os::allocator::HEAP_SIZE;
+use abs_os::allocator::{heap_stats, HEAP_MAX, HEAP_SIZE};
 use alloc::{boxed::Box, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
@@ -39,7 +39,13 @@ fn main(boot_info: &'static BootInfo) -> ! {
     // Initialize the heap using the
     // frame allocator and the memory
     // mapper created.
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap_default(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // Hand the mapper over for tests
+    // that need the page tables
+    let frame_allocator = frame_allocator.promote_to_tracked();
+    *memory::KERNEL_MEMORY.lock() = Some((mapper, frame_allocator));
 
     // Run the tests
     test_main();
@@ -83,13 +89,16 @@ fn large_vec() {
 // compared against the expected values.
 // This ensures that memory is
 // properly reused after being freed
-// when the variable x goes out of scope.
+// when the variable x goes out of scope,
+// whichever alloc-* feature is enabled.
 #[test_case]
 fn many_boxes() {
+    let allocations = heap_stats().allocations;
     for i in 0..HEAP_SIZE {
         let x = Box::new(i);
         assert_eq!(*x, i);
     }
+    assert_eq!(heap_stats().allocations, allocations);
 }
 
 // Tests that memory is able to
@@ -104,3 +113,47 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1);
 }
+
+// Calls init_heap a second time with
+// the mapper handed over by main and
+// ensures it is rejected instead of
+// remapping the heap.
+#[test_case]
+fn init_heap_twice() {
+    use abs_os::{
+        allocator,
+        error::KernelError,
+        memory::{EmptyFrameAllocator, KERNEL_MEMORY},
+    };
+
+    let mut memory = KERNEL_MEMORY.lock();
+    let (mapper, _) = memory.as_mut().expect("kernel memory not initialized");
+    let result = allocator::init_heap_default(mapper, &mut EmptyFrameAllocator);
+    assert_eq!(result, Err(KernelError::AlreadyInitialized));
+}
+
+// Issues a stats command and
+// decodes the response to make
+// sure it reflects the heap and
+// frames used by this test.
+#[test_case]
+fn stats_command() {
+    use abs_os::stats::{self, StatsResponse};
+
+    let _value = Box::new(7);
+    let frame = stats::handle_command(b"stats\n").expect("stats command not recognized");
+    let response = StatsResponse::from_bytes(&frame).expect("invalid stats response");
+
+    assert!(response.allocations >= 1);
+    assert!(response.heap_used > 0);
+    // The heap may have grown past
+    // HEAP_SIZE, so its size is taken
+    // from the stats
+    let heap = heap_stats();
+    let heap_size = heap.used + heap.free;
+    assert!((HEAP_SIZE..=HEAP_MAX).contains(&heap_size));
+    assert_eq!(response.heap_used + response.heap_free, heap_size as u64);
+    assert!(response.frames_allocated > 0);
+    assert!(response.frames_usable >= response.frames_allocated);
+    assert!(stats::handle_command(b"unknown").is_none());
+}
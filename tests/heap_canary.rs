@@ -0,0 +1,87 @@
+//! Test module that ensures that
+//! writing past the end of a heap
+//! allocation overwrites its canary
+//! and freeing it panics. Only built
+//! with the heap-canary feature.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use abs_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// Function called when the canary
+// check panics, which succeeds only
+// if the panic is the canary report.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    use core::fmt::Write;
+
+    // Match the message without
+    // allocating, the heap is corrupt
+    struct Contains<'a> {
+        needle: &'a str,
+        found: bool,
+    }
+    impl Write for Contains<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            self.found |= s.contains(self.needle);
+            Ok(())
+        }
+    }
+    let mut message = Contains {
+        needle: "heap canary clobbered",
+        found: false,
+    };
+    let _ = write!(message, "{}", info);
+
+    if message.found {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Error: {}", info);
+        exit_qemu(QemuExitCode::Failure);
+    }
+    loop {}
+}
+
+// Entry point for the canary test
+// that initializes the heap, writes
+// one byte past a boxed array and
+// frees it.
+fn main(boot_info: &'static BootInfo) -> ! {
+    use abs_os::{
+        allocator,
+        memory::{self, BootInfoFrameAllocator},
+    };
+
+    serial_print!("heap_canary::allocation_overrun...\t");
+
+    // Initialize the heap for the test
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap_default(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // Overrun the allocation by one byte
+    let array = Box::new([0u8; 24]);
+    let ptr = Box::into_raw(array) as *mut u8;
+    unsafe {
+        ptr.add(24).write_volatile(0xab);
+        drop(Box::from_raw(ptr as *mut [u8; 24]));
+    }
+
+    serial_println!("[failed]");
+    serial_println!("Execution continued after freeing a clobbered allocation");
+    exit_qemu(QemuExitCode::Failure);
+    loop {}
+}
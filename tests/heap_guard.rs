@@ -0,0 +1,110 @@
+//! Test module that ensures that
+//! writing past the end of the heap
+//! hits a page that is not mapped
+//! until the heap grows, and causes a
+//! page fault instead of corrupting
+//! the memory after the heap.
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use abs_os::{
+    allocator::{HEAP_SIZE, HEAP_START},
+    serial_print,
+};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// Function called when a panic
+// occurs that runs the panic
+// handler defined in src/lib.rs
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    abs_os::test_panic_handler(info);
+}
+
+// First byte after the heap
+const HEAP_END: usize = HEAP_START + HEAP_SIZE;
+
+// Entry point for the heap guard test
+// that initializes the heap and writes
+// one byte past its end. If the page
+// after it is unmapped, the page fault
+// handler exits QEMU before the panic.
+fn main(boot_info: &'static BootInfo) -> ! {
+    use abs_os::{
+        allocator,
+        memory::{self, BootInfoFrameAllocator},
+    };
+
+    serial_print!("heap_guard::heap_overrun...\t");
+
+    // Initialize GDT, memory and
+    // the heap for the test
+    abs_os::gdt::init();
+    init_test_idt();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap_default(&mut mapper, &mut frame_allocator)
+        .expect("heap initialization failed");
+
+    // The last heap byte is usable
+    let last = (HEAP_END - 1) as *mut u8;
+    unsafe { last.write_volatile(0xab) };
+
+    // Overrun the heap by one byte
+    let past_end = HEAP_END as *mut u8;
+    unsafe { past_end.write_volatile(0xab) };
+
+    panic!("Execution continued after writing past the heap");
+}
+
+use lazy_static::lazy_static;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+// Instantiate a static IDT used
+// for testing the heap overrun with
+// a custom page fault function.
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+// Test function called by the entry
+// point to this test module (main).
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+use abs_os::{exit_qemu, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+
+// Override of the x86 interrupt
+// function called when a page
+// fault occurs. Succeeds only if the
+// fault was caused by the page after
+// the heap.
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    if Cr2::read() == VirtAddr::new(HEAP_END as u64) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Accessed Address: {:?}", Cr2::read());
+        exit_qemu(QemuExitCode::Failure);
+    }
+    loop {}
+}
@@ -0,0 +1,74 @@
+//! Test module that ensures that
+//! an undefined instruction is caught
+//! by the invalid opcode handler
+//! instead of escalating to a
+//! double fault.
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use abs_os::serial_print;
+
+use core::panic::PanicInfo;
+
+// Function called when a panic
+// occurs that runs the panic
+// handler defined in src/lib.rs
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    abs_os::test_panic_handler(info);
+}
+
+// Entry point for the invalid opcode
+// test that initializes the OS and
+// runs ud2. If the interrupt handling
+// succeeds, the handler exits QEMU
+// before the panic.
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("invalid_opcode::invalid_opcode...\t");
+
+    // Initialize GDT for
+    // the tests
+    abs_os::gdt::init();
+    init_test_idt();
+
+    // Run an undefined instruction
+    unsafe { core::arch::asm!("ud2") };
+
+    panic!("Execution continued after invalid opcode");
+}
+
+use lazy_static::lazy_static;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+// Instantiate a static IDT used
+// for testing invalid opcodes with
+// a custom invalid opcode function.
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.invalid_opcode
+            .set_handler_fn(test_invalid_opcode_handler);
+        idt
+    };
+}
+
+// Test function called by the entry
+// point to this test module (_start).
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+use abs_os::{exit_qemu, serial_println, QemuExitCode};
+use x86_64::structures::idt::InterruptStackFrame;
+
+// Override of the x86 interrupt
+// function called when an invalid
+// opcode is run.
+extern "x86-interrupt" fn test_invalid_opcode_handler(_stack_frame: InterruptStackFrame) {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
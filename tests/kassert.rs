@@ -0,0 +1,51 @@
+//! Test module that ensures a failed
+//! kassert! writes its report line to
+//! serial and exits QEMU, even when
+//! SERIAL1 is locked.
+
+#![no_std]
+#![no_main]
+
+use abs_os::{
+    exit_qemu, kassert, kassert::set_exit_code, serial_print, serial_println, QemuExitCode,
+};
+
+use core::panic::PanicInfo;
+
+// Function called when a panic
+// occurs that runs the panic
+// handler defined in src/lib.rs
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    abs_os::test_panic_handler(info);
+}
+
+// Entry point of the test. A failed
+// kassert! exits with Failure, which
+// is checked first, then changed to
+// Success so the runner passes the
+// test when the failure path exits.
+// SERIAL1 is left locked so the
+// report has to force it open.
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("kassert::kassert_fails...\t");
+    if abs_os::kassert::exit_code() != QemuExitCode::Failure {
+        serial_println!("[failed]\n");
+        serial_println!("Error: kassert does not exit with Failure\n");
+        exit_qemu(QemuExitCode::Failure);
+    }
+    set_exit_code(QemuExitCode::Success);
+
+    let name = "abs_os";
+    core::mem::forget(abs_os::serial::SERIAL1.lock());
+    kassert!(name == "other");
+
+    // The report did not halt, so
+    // SERIAL1 is unlocked to say so
+    unsafe { abs_os::serial::SERIAL1.force_unlock() };
+    set_exit_code(QemuExitCode::Failure);
+    serial_println!("[test did not halt]");
+    exit_qemu(QemuExitCode::Failure);
+    loop {}
+}
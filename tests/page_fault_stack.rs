@@ -0,0 +1,122 @@
+//! Test module that ensures that the
+//! page fault handler runs on its own
+//! interrupt stack, so a page fault
+//! caused by a bad stack pointer is
+//! handled instead of turning into a
+//! double fault.
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use abs_os::serial_print;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+// Function called when a panic
+// occurs that runs the panic
+// handler defined in src/lib.rs
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    abs_os::test_panic_handler(info);
+}
+
+// Unmapped address the stack pointer
+// is moved to before pushing
+const BAD_STACK: u64 = 0x_7777_0000_1000;
+
+// Entry point for the page fault stack
+// test that points the stack pointer
+// at unmapped memory and pushes. The
+// push page faults, and the handler
+// exits QEMU from the page fault stack.
+fn main(_boot_info: &'static BootInfo) -> ! {
+    serial_print!("page_fault_stack::bad_stack_pointer...\t");
+
+    abs_os::gdt::init();
+    init_test_idt();
+
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {stack}",
+            "push rax",
+            stack = in(reg) BAD_STACK,
+            options(noreturn),
+        );
+    }
+}
+
+use lazy_static::lazy_static;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+// Instantiate a static IDT used for
+// testing with the page fault handler
+// on the page fault stack, and a double
+// fault handler that fails the test.
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(test_page_fault_handler)
+                .set_stack_index(abs_os::gdt::PAGE_FAULT_IST_INDEX);
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(abs_os::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+// Test function called by the entry
+// point to this test module (main).
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+use abs_os::{exit_qemu, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+
+// Override of the x86 interrupt
+// function called when a page fault
+// occurs. Succeeds only if the fault
+// was the push to the bad stack and
+// the handler runs on the page fault
+// stack.
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp) };
+    let stack = abs_os::gdt::page_fault_stack_range();
+
+    if Cr2::read() == VirtAddr::new(BAD_STACK - 8) && stack.contains(&VirtAddr::new(rsp)) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Accessed Address: {:?}", Cr2::read());
+        serial_println!("Handler Stack: {:#x}", rsp);
+        exit_qemu(QemuExitCode::Failure);
+    }
+    loop {}
+}
+
+// Override of the double fault handler.
+// Reaching it means the page fault
+// could not be delivered.
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    serial_println!("[failed]");
+    serial_println!("page fault escalated to a double fault");
+    exit_qemu(QemuExitCode::Failure);
+    loop {}
+}
@@ -0,0 +1,65 @@
+//! Test module that ensures a panic
+//! report is written with its banner
+//! even when the panic happens while
+//! the VGA writer is locked.
+
+#![no_std]
+#![no_main]
+
+use abs_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use core::{fmt, panic::PanicInfo};
+
+// Fixed size buffer the report is
+// written to, since this test does
+// not set up the heap.
+struct ReportBuffer {
+    bytes: [u8; 1024],
+    len: usize,
+}
+
+impl fmt::Write for ReportBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.bytes.len() {
+            return Err(fmt::Error);
+        }
+        self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+// Function called when the test
+// panics. The report is checked for
+// the banner and the message, then
+// printed to ensure the locked
+// WRITER does not deadlock it.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let mut buffer = ReportBuffer {
+        bytes: [0; 1024],
+        len: 0,
+    };
+    abs_os::write_panic_report(&mut buffer, info).expect("report too long");
+    let report = core::str::from_utf8(&buffer.bytes[..buffer.len]).expect("invalid report");
+
+    abs_os::panic_report(info);
+    if report.contains("KERNEL PANIC") && report.contains("expected panic") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        exit_qemu(QemuExitCode::Failure);
+    }
+    loop {}
+}
+
+// Entry point of the test that
+// panics while holding the WRITER.
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("panic_report::panic_report...\t");
+
+    core::mem::forget(abs_os::vga_buffer::WRITER.lock());
+    panic!("expected panic");
+}
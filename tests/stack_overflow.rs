@@ -78,11 +78,14 @@ use x86_64::structures::idt::InterruptStackFrame;
 
 // Override of the x86 interrupt
 // function called when a double
-// fault occurs.
+// fault occurs. The fault context is
+// dumped to check it works on the
+// double fault stack.
 extern "x86-interrupt" fn test_double_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
 ) -> ! {
+    abs_os::interrupts::dump_fault_context("DOUBLE FAULT", &stack_frame, Some(error_code));
     serial_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
     loop {}
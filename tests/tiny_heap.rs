@@ -0,0 +1,85 @@
+//! Integration tests that ensure an
+//! allocation that does not fit in a
+//! small heap fails with a null
+//! pointer instead of panicking.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(abs_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{alloc::Layout, panic::PanicInfo};
+
+entry_point!(main);
+
+// Start and size of the heap used
+// by these tests, away from the
+// default heap range.
+const TINY_HEAP_START: u64 = 0x_4444_8888_0000;
+const TINY_HEAP_SIZE: usize = 4096;
+
+/// Sets up a one page heap. The
+/// KERNEL_MEMORY is not filled in,
+/// so the heap cannot grow.
+fn main(boot_info: &'static BootInfo) -> ! {
+    use abs_os::{
+        allocator,
+        memory::{self, BootInfoFrameAllocator},
+    };
+    use x86_64::VirtAddr;
+
+    abs_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(
+        VirtAddr::new(TINY_HEAP_START),
+        TINY_HEAP_SIZE,
+        &mut mapper,
+        &mut frame_allocator,
+    )
+    .expect("heap initialization failed");
+
+    test_main();
+
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    abs_os::test_panic_handler(info)
+}
+
+//// TESTS
+
+// Ensures a small allocation is
+// placed in the tiny heap.
+#[test_case]
+fn small_allocation() {
+    let value = Box::new(41);
+    assert_eq!(*value, 41);
+
+    let addr = &*value as *const i32 as u64;
+    assert!((TINY_HEAP_START..TINY_HEAP_START + TINY_HEAP_SIZE as u64).contains(&addr));
+}
+
+// Ensures allocations larger than
+// the heap return null, and that
+// the heap still works afterwards.
+#[test_case]
+fn allocation_beyond_heap_fails() {
+    let layout = Layout::from_size_align(2 * TINY_HEAP_SIZE, 8).expect("invalid layout");
+    let ptr = unsafe { alloc::alloc::alloc(layout) };
+    assert!(ptr.is_null());
+
+    let mut vec: Vec<u8> = Vec::new();
+    assert!(vec.try_reserve(2 * TINY_HEAP_SIZE).is_err());
+
+    let value = Box::new(13);
+    assert_eq!(*value, 13);
+}
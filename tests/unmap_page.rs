@@ -0,0 +1,110 @@
+//! Test module that ensures that
+//! a page removed with unmap_page
+//! can no longer be accessed, and
+//! reading it page faults.
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use abs_os::serial_print;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::{
+    structures::paging::{Page, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+// Function called when a panic
+// occurs that runs the panic
+// handler defined in src/lib.rs
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    abs_os::test_panic_handler(info);
+}
+
+// Address of the page that is
+// mapped and then unmapped.
+const PAGE_ADDR: u64 = 0x_7777_0000_0000;
+
+// Entry point for the unmap test that
+// maps a page, writes to it, unmaps
+// it and reads it again. If the page
+// was unmapped, the page fault handler
+// exits QEMU before the panic.
+fn main(boot_info: &'static BootInfo) -> ! {
+    use abs_os::memory::{self, BootInfoFrameAllocator};
+
+    serial_print!("unmap_page::unmap_page...\t");
+
+    // Initialize GDT and memory
+    // for the tests
+    abs_os::gdt::init();
+    init_test_idt();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+
+    let page = Page::containing_address(VirtAddr::new(PAGE_ADDR));
+    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator)
+        .expect("mapping failed");
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe { ptr.write_volatile(0x_f021_f077_f065_f04e) };
+
+    // The example mapping points at
+    // the VGA buffer, so the frame is
+    // not deallocated
+    let frame = memory::unmap_page(page, &mut mapper).expect("unmap failed");
+    assert_eq!(frame, PhysFrame::containing_address(PhysAddr::new(0xb8000)));
+
+    // Access the unmapped page
+    unsafe { ptr.read_volatile() };
+
+    panic!("Execution continued after reading an unmapped page");
+}
+
+use lazy_static::lazy_static;
+use x86_64::structures::idt::InterruptDescriptorTable;
+
+// Instantiate a static IDT used
+// for testing the unmapped page with
+// a custom page fault function.
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+// Test function called by the entry
+// point to this test module (main).
+pub fn init_test_idt() {
+    TEST_IDT.load();
+}
+
+use abs_os::{exit_qemu, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
+
+// Override of the x86 interrupt
+// function called when a page
+// fault occurs. Succeeds only if the
+// fault was caused by the unmapped page.
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: PageFaultErrorCode,
+) {
+    use x86_64::registers::control::Cr2;
+
+    if Cr2::read() == VirtAddr::new(PAGE_ADDR) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]");
+        serial_println!("Accessed Address: {:?}", Cr2::read());
+        exit_qemu(QemuExitCode::Failure);
+    }
+    loop {}
+}